@@ -0,0 +1,200 @@
+/*
+Маскировка трафика в сторону эквалайзера - то же, что сервер делает в сторону клиента.
+Если данных VPN клиента меньше установленной скорости, досылаем заполнитель.
+Скорость по доле данных за long_term выбирает тот же регулятор, что и у сервера
+(speed-control, секция [speed] файла --config).
+*/
+use log::debug;
+use speed_control::{SpeedCorrectorCommand, SpeedForPeriod, SpeedPolicy, SpeedSettings, SpeedSetupParam, PERCENT_100};
+use splitter::MAX_BODY_SIZE;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+//скорость пересчитываем не на каждый пакет
+const ANALYZE_PERIOD: Duration = Duration::from_millis(100);
+//мелкие пакеты заполнителя не отправляем
+const MIN_BYTES_TO_FILL: usize = MAX_BODY_SIZE / 4;
+
+struct Sent {
+    at: Instant,
+    data: usize,
+    filler: usize,
+}
+
+pub struct UploadFiller {
+    settings: SpeedSettings,
+    policy: Box<dyn SpeedPolicy>,
+    //последняя установленная скорость (байт/мс), None - заполнитель отключен
+    last: Option<SpeedSetupParam>,
+    //отправленное за long_term
+    sent: VecDeque<Sent>,
+    analyzed_at: Option<Instant>,
+}
+
+impl UploadFiller {
+    pub fn new(settings: &SpeedSettings) -> UploadFiller {
+        Self {
+            settings: settings.clone(),
+            policy: settings.create_policy(),
+            last: None,
+            sent: VecDeque::new(),
+            analyzed_at: None,
+        }
+    }
+
+    pub fn data_was_sent(&mut self, size: usize) {
+        if size > 0 {
+            self.sent(size, 0);
+        }
+    }
+
+    pub fn filler_was_sent(&mut self, size: usize) {
+        self.sent(0, size);
+    }
+
+    /**
+        Заполнитель, который пора отправить (случайные байты в начале buf)
+    */
+    pub fn filler_packet<'a>(&self, buf: &'a mut [u8]) -> Option<&'a [u8]> {
+        let speed = self.last.as_ref()?.value;
+        let last = self.sent.back()?;
+        let sent_for = Duration::from_millis(((last.data + last.filler) / speed) as u64);
+        let idle_ms = last.at.elapsed().saturating_sub(sent_for).as_millis() as usize;
+        let size = (speed * idle_ms).min(buf.len());
+        if size <= MIN_BYTES_TO_FILL {
+            return None;
+        }
+        let packet = &mut buf[..size];
+        //в буфере могли остаться данные VPN клиента
+        if getrandom::fill(packet).is_err() {
+            packet.fill(0);
+        }
+        Some(packet)
+    }
+
+    fn sent(&mut self, data: usize, filler: usize) {
+        let now = Instant::now();
+        self.sent.push_back(Sent { at: now, data, filler });
+        if self.analyzed_at.is_none_or(|at| now - at >= ANALYZE_PERIOD) {
+            self.analyzed_at = Some(now);
+            self.correct(now);
+        }
+    }
+
+    fn correct(&mut self, now: Instant) {
+        let long_term = self.settings.long_term();
+        while self.sent.len() > 1 && self.sent.front().is_some_and(|sent| now - sent.at > long_term) {
+            self.sent.pop_front();
+        }
+        let Some(first) = self.sent.front() else {
+            return;
+        };
+        let mills = (now - first.at).as_millis() as usize;
+        let data: usize = self.sent.iter().map(|sent| sent.data).sum();
+        let amount = data + self.sent.iter().map(|sent| sent.filler).sum::<usize>();
+        //по короткому отрезку скорость не посчитать
+        if mills < self.settings.increase_period().as_millis() as usize || amount == 0 {
+            return;
+        }
+        let measured = SpeedForPeriod { speed: amount / mills, data_percent: data * PERCENT_100 / amount };
+        let Some(command) = self.policy.correct(&measured, self.last.as_ref(), now) else {
+            return;
+        };
+        debug!("Upload speed {} b/ms, data {}% -> {command:?}", measured.speed, measured.data_percent);
+        self.last = match command {
+            SpeedCorrectorCommand::SwitchOff => None,
+            SpeedCorrectorCommand::SetSpeed(speed) => Some(SpeedSetupParam::new(speed, now)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filler::{Sent, UploadFiller, MIN_BYTES_TO_FILL};
+    use speed_control::{SpeedPolicyKind, SpeedSettings, SpeedSetupParam};
+    use splitter::MAX_BODY_SIZE;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    /**
+        Отправленное за последнюю секунду с заданной скоростью (байт/мс)
+    */
+    fn sent_for_second(filler: &mut UploadFiller, data_speed: usize, filler_speed: usize) {
+        let now = Instant::now();
+        for step in (0..100).rev() {
+            let at = now - Duration::from_millis(step * 10);
+            filler.sent.push_back(Sent { at, data: data_speed * 10, filler: filler_speed * 10 });
+        }
+    }
+
+    /**
+        Скорость, установленная давно - регулятор может менять ее в любую сторону
+    */
+    fn set_long_ago(filler: &mut UploadFiller, speed: usize) {
+        let at = Instant::now() - filler.settings.decrease_period() * 2;
+        filler.last = Some(SpeedSetupParam::new(speed, at));
+    }
+
+    fn speed(filler: &UploadFiller) -> Option<usize> {
+        filler.last.as_ref().map(|last| last.value)
+    }
+
+    #[test]
+    fn filler_packet_test() {
+        let mut filler = UploadFiller::new(&SpeedSettings::default());
+        let mut buf = [0x11; MAX_BODY_SIZE];
+        filler.data_was_sent(100);
+        assert!(filler.filler_packet(&mut buf).is_none());
+
+        filler.last = Some(SpeedSetupParam::new(1000, Instant::now()));
+        assert!(filler.filler_packet(&mut buf).is_none());
+        sleep(Duration::from_millis(5));
+        let packet = filler.filler_packet(&mut buf).unwrap();
+        assert!(packet.len() > MIN_BYTES_TO_FILL);
+        assert!(!packet.iter().all(|byte| *byte == 0x11));
+    }
+
+    /**
+        Заполнитель включается, когда данных достаточно, и скорость растет вслед за данными.
+        Без данных скорость падает, пока заполнитель не отключится
+    */
+    #[test]
+    fn correct_test() {
+        let settings = SpeedSettings::default();
+        let mut filler = UploadFiller::new(&settings);
+        sent_for_second(&mut filler, 1000, 0);
+        filler.correct(Instant::now());
+        assert_eq!(Some(settings.enable_speed), speed(&filler));
+        set_long_ago(&mut filler, settings.enable_speed);
+        filler.correct(Instant::now());
+        assert!(speed(&filler).unwrap() >= 1000 + settings.up_acceleration - 20);
+
+        let mut filler = UploadFiller::new(&settings);
+        set_long_ago(&mut filler, 200);
+        sent_for_second(&mut filler, 10, 190);
+        filler.correct(Instant::now());
+        assert!(speed(&filler).unwrap() < 200);
+        filler.sent.clear();
+        set_long_ago(&mut filler, 200);
+        sent_for_second(&mut filler, 0, 120);
+        filler.correct(Instant::now());
+        assert_eq!(None, speed(&filler));
+    }
+
+    /**
+        Параметры [speed] и политика те же, что у сервера
+    */
+    #[test]
+    fn settings_test() {
+        let settings = SpeedSettings { policy: SpeedPolicyKind::Pi, enable_speed: 500, ..Default::default() };
+        let mut filler = UploadFiller::new(&settings);
+        sent_for_second(&mut filler, 1000, 0);
+        filler.correct(Instant::now());
+        assert_eq!(Some(500), speed(&filler));
+        //ПИ-регулятор: шаг зависит от отклонения, а не up_acceleration
+        set_long_ago(&mut filler, 1000);
+        filler.correct(Instant::now());
+        let speed = speed(&filler).unwrap();
+        assert!(speed > 1000 + settings.up_acceleration, "{speed}");
+    }
+}
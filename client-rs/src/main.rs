@@ -0,0 +1,127 @@
+/*
+Клиент эквалайзера на Rust (замена client-c): принимает OpenVPN на локальном порту,
+подключается к эквалайзеру, представляется и передает данные, вычитывая заполнитель.
+Собирается и для настольного Linux, и кросс-компиляцией для роутеров.
+*/
+use clap::Parser;
+use easy_error::{ensure, Error, ResultExt};
+use log::LevelFilter;
+use serde::Deserialize;
+use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
+use speed_control::SpeedSettings;
+use std::fs;
+use std::process::ExitCode;
+use std::str::FromStr;
+use tunnel::{run_tcp, run_udp, Connection};
+
+mod filler;
+mod tunnel;
+
+/**
+    Клиент эквалайзера: OpenVPN -> 127.0.0.1:12005 -> эквалайзер
+*/
+#[derive(Parser, Debug)]
+#[command(version, about, after_help = "\
+Example
+equalizer-client --name router-1 --server vpn.example.com:12010
+OpenVPN client config: remote 127.0.0.1 12005 tcp")]
+struct Cli {
+    /// Локальный адрес для OpenVPN клиента
+    #[arg(long, default_value = "127.0.0.1:12005")]
+    listen: String,
+    /// Адрес эквалайзера (или локальный конец SSH туннеля)
+    #[arg(long, default_value = "127.0.0.1:12010")]
+    server: String,
+    /// Имя клиента, по нему сервер выбирает маршрут и ключ
+    #[arg(long)]
+    name: String,
+    /// Файл секрета клиента (если сервер требует аутентификацию)
+    #[arg(long)]
+    key: Option<String>,
+    /// Файл общего ключа шифрования потока
+    #[cfg(feature = "crypto")]
+    #[arg(long)]
+    psk: Option<String>,
+    /// VPN по UDP (OpenVPN udp, WireGuard): сервер запущен с --mode udp
+    #[arg(long)]
+    udp: bool,
+    /// Не маскировать трафик к эквалайзеру (без заполнителя клиента)
+    #[arg(long)]
+    no_upload_filler: bool,
+    /// Файл настроек: секция [speed] - регулятор заполнителя, как у сервера
+    #[arg(long)]
+    config: Option<String>,
+    /// Уровень логирования: error, warn, info, debug, trace
+    #[arg(long, default_value = "info")]
+    log_level: String,
+}
+
+/**
+    Файл настроек клиента (--config)
+*/
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+    speed: SpeedSettings,
+}
+
+impl Settings {
+    fn from_file(path: &str) -> Result<Settings, Error> {
+        let content = fs::read_to_string(path).context(format!("Не удалось прочитать файл настроек {path}"))?;
+        let settings: Settings = toml::from_str(&content).context(format!("Ошибка в файле настроек {path}"))?;
+        Ok(settings)
+    }
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            println!("{}", error_chain(&e));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    let level = LevelFilter::from_str(&cli.log_level).context(format!("Неизвестный уровень логирования {}", cli.log_level))?;
+    TermLogger::init(level, Config::default(), TerminalMode::Mixed, ColorChoice::Auto).context("Логгер проинициализирован")?;
+    #[cfg(feature = "crypto")]
+    let psk = cli.psk.as_deref().map(load_key).transpose()?;
+    #[cfg(not(feature = "crypto"))]
+    let psk = None;
+    let settings = cli.config.as_deref().map(Settings::from_file).transpose()?.unwrap_or_default();
+    settings.speed.validate()?;
+    let connection = Connection {
+        server: cli.server,
+        name: cli.name,
+        key: cli.key.as_deref().map(load_key).transpose()?,
+        psk,
+        upload_filler: (!cli.no_upload_filler).then_some(settings.speed),
+    };
+    if cli.udp {
+        run_udp(connection, &cli.listen)
+    } else {
+        run_tcp(connection, &cli.listen)
+    }
+}
+
+/**
+    Ключ - строка в файле, пробелы и перевод строки по краям не учитываются (как на сервере)
+*/
+fn load_key(path: &str) -> Result<Vec<u8>, Error> {
+    let content = fs::read_to_string(path).context(format!("Не удалось прочитать файл ключа {path}"))?;
+    let key = content.trim();
+    ensure!(!key.is_empty(), "Пустой ключ в файле {path}");
+    Ok(key.as_bytes().to_vec())
+}
+
+fn error_chain(e: &Error) -> String {
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    message
+}
@@ -0,0 +1,350 @@
+/*
+Подключение к эквалайзеру и перекачка данных.
+Каждое подключение представляется (TYPE_HELLO, при необходимости аутентификация и шифрование),
+данные идут через DataStreamVpn, заполнитель вычитывается и выбрасывается -
+он нужен только для маскировки канала.
+Если сервер выдал номер сессии, после обрыва переподключаемся с ним:
+OpenVPN обрыва не замечает, недошедшие пакеты повторяются (см. splitter session.rs).
+*/
+use crate::filler::UploadFiller;
+use easy_error::{bail, err_msg, Error, ResultExt};
+use log::{info, warn};
+use splitter::client_side_split::{split_client_stream_resumable, split_client_stream_with, ClientSideSplit};
+use splitter::handshake::{client_handshake, client_resume, Hello, CAPABILITY_CRC32, CAPABILITY_RESUME};
+#[cfg(feature = "crypto")]
+use splitter::secure_transport::SecureTransport;
+use splitter::session::{Session, SharedSession};
+use speed_control::SpeedSettings;
+use splitter::transport::Transport;
+use splitter::udp::UdpClientAdapter;
+use splitter::{MAX_BODY_SIZE, READ_START_AWAIT_TIMEOUT};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+//пауза перед новым подключением к эквалайзеру
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+//столько пытаемся восстановить сессию (сервер ждет resume_grace_ms, обычно 10 секунд)
+const RESUME_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Split = ClientSideSplit<'static, Box<dyn Transport>>;
+
+/**
+    Куда и как подключаемся
+*/
+#[derive(Clone)]
+pub struct Connection {
+    pub server: String,
+    pub name: String,
+    //секрет клиента, если на сервере включена аутентификация
+    pub key: Option<Vec<u8>>,
+    //общий ключ шифрования потока, None - поток открытый (внутри SSH туннеля)
+    pub psk: Option<Vec<u8>>,
+    //регулятор заполнителя к эквалайзеру (см. filler.rs), None - трафик к эквалайзеру не маскируем
+    pub upload_filler: Option<SpeedSettings>,
+}
+
+impl Connection {
+    /**
+        Заполнитель к эквалайзеру, None - не маскируем
+    */
+    pub fn upload_filler(&self) -> Option<UploadFiller> {
+        self.upload_filler.as_ref().map(UploadFiller::new)
+    }
+
+    /**
+        Подключение с приветствием, поток разделен на данные и заполнитель.
+        Сессия - если сервер поддерживает восстановление
+    */
+    pub fn open(&self) -> Result<(Split, Option<SharedSession>), Error> {
+        let mut stream = self.connect()?;
+        let hello = Hello::new(&self.name, CAPABILITY_CRC32 | CAPABILITY_RESUME);
+        let ack = client_handshake(&mut stream, &hello, self.key.as_deref(), HANDSHAKE_TIMEOUT)?;
+        info!("Connected to {} as {}, protocol v{}", self.server, self.name, ack.version);
+        Ok(match ack.session {
+            Some(id) => {
+                let session = Session::new(id, ack.frame_options()).shared();
+                (split_client_stream_resumable(stream, session.clone()), Some(session))
+            }
+            None => (split_client_stream_with(stream, ack.frame_options()), None),
+        })
+    }
+
+    /**
+        Переподключение после обрыва. Пока эквалайзер недоступен - повторяем,
+        отказ эквалайзера (сессия истекла) окончательный
+    */
+    pub fn resume(&self, session: &SharedSession) -> Option<Split> {
+        let start = Instant::now();
+        while start.elapsed() < RESUME_TIMEOUT {
+            let mut stream = match self.connect() {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("{e}");
+                    sleep(RECONNECT_DELAY);
+                    continue;
+                }
+            };
+            return match client_resume(&mut stream, session, self.key.as_deref(), HANDSHAKE_TIMEOUT) {
+                Ok(()) => {
+                    info!("Session resumed on {}", self.server);
+                    Some(split_client_stream_resumable(stream, session.clone()))
+                }
+                Err(e) => {
+                    warn!("{e}");
+                    None
+                }
+            };
+        }
+        warn!("Equalizer is unreachable for {:?}", RESUME_TIMEOUT);
+        None
+    }
+
+    fn connect(&self) -> Result<Box<dyn Transport>, Error> {
+        let stream = connect(&self.server)?;
+        stream.set_nodelay(true).context("Set nodelay")?;
+        self.secure(stream)
+    }
+
+    #[cfg(feature = "crypto")]
+    fn secure(&self, stream: TcpStream) -> Result<Box<dyn Transport>, Error> {
+        Ok(match &self.psk {
+            Some(psk) => Box::new(SecureTransport::connect(stream, Some(psk))?),
+            None => Box::new(stream),
+        })
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn secure(&self, stream: TcpStream) -> Result<Box<dyn Transport>, Error> {
+        if self.psk.is_some() {
+            bail!("Клиент собран без шифрования (feature crypto)");
+        }
+        Ok(Box::new(stream))
+    }
+}
+
+fn connect(address: &str) -> Result<TcpStream, Error> {
+    let mut last_error = None;
+    for socket_address in address.to_socket_addrs().context(format!("Адрес {address}"))? {
+        match TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) => Err(e).context(format!("Connect to {address}")),
+        None => bail!("Адрес {address} не найден"),
+    }
+}
+
+/**
+    Каждое подключение OpenVPN (или браузера в режиме socks5 сервера) -
+    отдельное подключение к эквалайзеру в своем потоке
+*/
+pub fn run_tcp(connection: Connection, listen: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(listen).context(format!("Bind {listen}"))?;
+    info!("Listen {listen}, equalizer {}", connection.server);
+    for local in listener.incoming() {
+        let local = match local {
+            Ok(local) => local,
+            Err(e) => {
+                warn!("Accept failed: {e}");
+                continue;
+            }
+        };
+        let connection = connection.clone();
+        let spawned = thread::Builder::new()
+            .name("tunnel".to_string())
+            .spawn(move || {
+                let peer = local.peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+                info!("Local client {peer} connected");
+                match connection.open() {
+                    Ok((split, session)) => serve(&connection, &local, split, session),
+                    Err(e) => warn!("{peer}: {e}"),
+                }
+                let _ = local.shutdown(Shutdown::Both);
+                info!("Local client {peer} disconnected");
+            });
+        if let Err(e) = spawned {
+            warn!("Tunnel thread: {e}");
+        }
+    }
+    Ok(())
+}
+
+/**
+    До закрытия локального подключения, с восстановлением сессии после обрывов
+*/
+fn serve(connection: &Connection, local: &TcpStream, mut split: Split, session: Option<SharedSession>) {
+    let mut filler = connection.upload_filler();
+    loop {
+        let result = pump(local, &split, filler.as_mut());
+        split.data_stream.shutdown();
+        let Err(e) = result else {
+            return;
+        };
+        warn!("{e}");
+        match session.as_ref().and_then(|session| connection.resume(session)) {
+            Some(resumed) => split = resumed,
+            None => return,
+        }
+    }
+}
+
+/**
+    Ok - локальное подключение закрыто, Err - обрыв связи с эквалайзером
+*/
+pub fn pump<T: Transport>(mut local: &TcpStream, split: &ClientSideSplit<'_, T>, mut filler: Option<&mut UploadFiller>) -> Result<(), Error> {
+    //чтение эквалайзера тоже ждет не дольше READ_START_AWAIT_TIMEOUT
+    local.set_read_timeout(Some(READ_START_AWAIT_TIMEOUT)).context("Set local read timeout")?;
+    let mut buf = vec![0; MAX_BODY_SIZE];
+    loop {
+        match local.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(size) => {
+                split.data_stream.write_all(&buf[..size])?;
+                if let Some(filler) = filler.as_deref_mut() {
+                    filler.data_was_sent(size);
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if let Some(filler) = filler.as_deref_mut() {
+                    send_filler(filler, split, &mut buf)?;
+                }
+            }
+            Err(e) => {
+                warn!("Local read: {e}");
+                return Ok(());
+            }
+        }
+        let size = split.data_stream.read(&mut buf)?;
+        if size > 0 {
+            if let Err(e) = local.write_all(&buf[..size]) {
+                warn!("Local write: {e}");
+                return Ok(());
+            }
+        } else if split.is_closed() {
+            bail!("Equalizer closed connection");
+        }
+        while split.filler_stream.read(&mut buf)? > 0 {}
+    }
+}
+
+fn send_filler<T: Transport>(filler: &mut UploadFiller, split: &ClientSideSplit<'_, T>, buf: &mut [u8]) -> Result<(), Error> {
+    if let Some(packet) = filler.filler_packet(buf) {
+        split.filler_stream.write_all(packet)?;
+        filler.filler_was_sent(packet.len());
+    }
+    Ok(())
+}
+
+/**
+    OpenVPN (udp) или WireGuard: одно подключение к эквалайзеру на все датаграммы,
+    при обрыве подключаемся заново
+*/
+pub fn run_udp(connection: Connection, listen: &str) -> Result<(), Error> {
+    let mut adapter = UdpClientAdapter::bind(listen)?;
+    info!("Listen udp {listen}, equalizer {}", connection.server);
+    loop {
+        match connection.open() {
+            Ok((mut split, session)) => loop {
+                let e = pump_udp(&mut adapter, &split, connection.upload_filler());
+                split.data_stream.shutdown();
+                warn!("{e}");
+                match session.as_ref().and_then(|session| connection.resume(session)) {
+                    Some(resumed) => split = resumed,
+                    None => break,
+                }
+            },
+            Err(e) => warn!("{e}"),
+        }
+        sleep(RECONNECT_DELAY);
+    }
+}
+
+/**
+    Работает до обрыва связи с эквалайзером
+*/
+fn pump_udp<T: Transport>(adapter: &mut UdpClientAdapter, split: &ClientSideSplit<'_, T>, mut filler: Option<UploadFiller>) -> Error {
+    let mut buf = vec![0; MAX_BODY_SIZE];
+    loop {
+        match adapter.step(split) {
+            Ok(false) if split.is_closed() => return err_msg("Equalizer closed connection"),
+            Ok(_) => {}
+            Err(e) => return e,
+        }
+        if let Some(filler) = filler.as_mut() {
+            filler.data_was_sent(adapter.take_sent());
+            if let Err(e) = send_filler(filler, split, &mut buf) {
+                return e;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tunnel::{pump, Connection};
+    use splitter::handshake::{read_identification, send_hello_ack, HelloAck, Identification, CAPABILITY_RESUME, SUPPORTED_CAPABILITIES};
+    use splitter::server_side_split::split_server_stream_with;
+    use speed_control::SpeedSettings;
+    use splitter::{FrameDecoder, MAX_BODY_SIZE};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /**
+        Мок эквалайзера: отвечает на приветствие (без восстановления сессий), шлет заполнитель
+        и возвращает данные обратно, после первого эха закрывает соединение
+    */
+    #[test]
+    fn pump_test() {
+        let equalizer = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = equalizer.local_addr().unwrap().to_string();
+        let equalizer_join = thread::spawn(move || {
+            let mut stream = equalizer.accept().unwrap().0;
+            stream.set_nonblocking(true).unwrap();
+            let mut decoder = FrameDecoder::new();
+            let start = Instant::now();
+            let hello = loop {
+                if let Some(Identification::Hello(hello)) = read_identification(&mut stream, &mut decoder).unwrap() {
+                    break hello;
+                }
+                assert!(start.elapsed() < Duration::from_secs(2));
+            };
+            assert_eq!("router-1", hello.client_name);
+            let ack = HelloAck::accept(&hello, SUPPORTED_CAPABILITIES & !CAPABILITY_RESUME).unwrap();
+            send_hello_ack(&mut stream, &ack).unwrap();
+            let mut split = split_server_stream_with(stream, ack.frame_options());
+            split.filler_stream.write_all(&[0; 100]).unwrap();
+            let mut buf = [0; MAX_BODY_SIZE];
+            let mut size = 0;
+            while size == 0 {
+                size = split.data_stream.read(&mut buf).unwrap();
+            }
+            split.data_stream.write_all(&buf[..size]).unwrap();
+            split.data_stream.shutdown();
+        });
+
+        let local_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut openvpn = TcpStream::connect(local_listener.local_addr().unwrap()).unwrap();
+        let local = local_listener.accept().unwrap().0;
+        let connection = Connection { server, name: "router-1".to_string(), key: None, psk: None, upload_filler: Some(SpeedSettings::default()) };
+        let client_join = thread::spawn(move || {
+            let (split, session) = connection.open().unwrap();
+            assert!(session.is_none());
+            assert!(pump(&local, &split, connection.upload_filler().as_mut()).is_err());
+        });
+        openvpn.write_all(b"11111").unwrap();
+        let mut buf = [0; 5];
+        openvpn.read_exact(&mut buf).unwrap();
+        assert_eq!(b"11111", &buf);
+        equalizer_join.join().unwrap();
+        client_join.join().unwrap();
+    }
+}
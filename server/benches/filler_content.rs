@@ -0,0 +1,22 @@
+/*
+Скорость генераторов заполнителя: ChaCha должен успевать за каналом (100 Мбит/с - 12.5 МБ/с с запасом).
+cargo bench --bench filler_content
+*/
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use equalizer::core::filler_content::FillerContentKind;
+use equalizer::objects::ONE_PACKET_MAX_SIZE;
+use std::hint::black_box;
+
+fn filler_content(c: &mut Criterion) {
+    let mut group = c.benchmark_group("filler_content");
+    group.throughput(Throughput::Bytes(ONE_PACKET_MAX_SIZE as u64));
+    for kind in [FillerContentKind::Zero, FillerContentKind::Random] {
+        let mut content = kind.create();
+        let mut buf = [0; ONE_PACKET_MAX_SIZE];
+        group.bench_function(format!("{kind:?}"), |b| b.iter(|| content.fill(black_box(&mut buf))));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, filler_content);
+criterion_main!(benches);
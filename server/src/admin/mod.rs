@@ -0,0 +1,158 @@
+/*
+Управляющий сокет: список клиентов, их скорость и доля заполнителя,
+закрепление скорости, режим без заполнителя, отключение клиента, доступность VPN серверов.
+Запросы принимает отдельный поток, выполняет оркестратор (invoke), ответ возвращается по каналу.
+Доступность VPN серверов поток отдает сам (общее с проверкой состояние).
+Сокет доступен только владельцу процесса (0600).
+По указанному пути удаляется только брошенный сокет (никто не слушает), любой другой файл - ошибка запуска.
+*/
+//разбор ответов нужен клиенту сокета (equalizerctl)
+pub mod protocol;
+
+use crate::admin::protocol::{AdminRequest, ClientStatus, ERR, OK};
+use crate::entry::health::UpstreamHealth;
+use easy_error::{bail, Error, ResultExt};
+use log::{error, info, warn};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::thread::{sleep, JoinHandle};
+use std::time::Duration;
+
+const ACCEPT_DELAY: Duration = Duration::from_millis(50);
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+//оркестратор проверяет запросы каждые 50-100мс
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub type AdminReply = Result<Vec<ClientStatus>, String>;
+
+/**
+    Запрос к оркестратору и канал для ответа
+*/
+pub struct AdminCall {
+    pub request: AdminRequest,
+    pub ct_reply: Sender<AdminReply>,
+}
+
+/**
+    Сокет создается сразу (ошибки видны при запуске), запросы обслуживаются в отдельном потоке
+*/
+pub fn start_admin(
+    path: String,
+    ct_admin: Sender<AdminCall>,
+    health: UpstreamHealth,
+    stop_application_request: Receiver<bool>,
+) -> Result<JoinHandle<()>, Error> {
+    remove_stale(&path)?;
+    let listener = bind_private(&path)?;
+    listener.set_nonblocking(true).context("Set nonblocking for admin socket")?;
+    info!("Admin socket {path}");
+    let join = thread::Builder::new()
+        .name("admin".to_string()).spawn(move || {
+        loop {
+            while let Ok((stream, _)) = listener.accept() {
+                if let Err(e) = handle_connection(stream, &ct_admin, &health) {
+                    warn!("Admin request failed {e}");
+                }
+            }
+            if stop_application_request.try_recv().is_ok() {
+                break;
+            }
+            sleep(ACCEPT_DELAY);
+        }
+        let _ = fs::remove_file(&path);
+        info!("Exit from admin thread");
+    }).context("admin thread started")?;
+    Ok(join)
+}
+
+/**
+    Сокет, оставшийся от упавшего процесса, удаляем. Живой сокет (другой эквалайзер)
+    и не сокет (опечатка в пути) не трогаем
+*/
+fn remove_stale(path: &str) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context(format!("admin_socket {path}")),
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("admin_socket {path}: файл уже существует и это не сокет");
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => bail!("admin_socket {path}: сокет занят другим процессом"),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            fs::remove_file(path).context(format!("Не удалось удалить старый сокет {path}"))
+        }
+        Err(e) => Err(e).context(format!("admin_socket {path}")),
+    }
+}
+
+/**
+    Между созданием сокета и сменой прав к нему мог бы подключиться кто угодно:
+    создаем его в каталоге, доступном только владельцу (0700), и уже с правами 0600 переносим на место
+*/
+fn bind_private(path: &str) -> Result<UnixListener, Error> {
+    let dir = format!("{path}.{}.tmp", std::process::id());
+    fs::DirBuilder::new().mode(0o700).create(&dir).context(format!("Не удалось создать каталог {dir}"))?;
+    let private_path = format!("{dir}/admin.sock");
+    let result = UnixListener::bind(&private_path)
+        .context(format!("Не удалось создать управляющий сокет {path}"))
+        .and_then(|listener| {
+            fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600)).context("Права управляющего сокета")?;
+            fs::rename(&private_path, path).context(format!("Не удалось перенести управляющий сокет в {path}"))?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&dir);
+    result
+}
+
+fn handle_connection(stream: UnixStream, ct_admin: &Sender<AdminCall>, health: &UpstreamHealth) -> Result<(), Error> {
+    stream.set_nonblocking(false).context("Restore blocking mode")?;
+    stream.set_read_timeout(Some(READ_TIMEOUT)).context("Set admin read timeout")?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line).context("Read admin request")?;
+    let reply = match AdminRequest::parse(&line) {
+        Ok(AdminRequest::Upstreams) => Ok(health.statuses().iter().map(ToString::to_string).collect()),
+        Ok(request) => {
+            info!("Admin request: {request}");
+            call_orchestrator(request, ct_admin)
+                .map(|statuses| statuses.iter().map(ToString::to_string).collect())
+        }
+        Err(e) => Err(e.ctx),
+    };
+    write_reply(&stream, reply)
+}
+
+fn call_orchestrator(request: AdminRequest, ct_admin: &Sender<AdminCall>) -> AdminReply {
+    let (ct_reply, cr_reply) = channel();
+    if ct_admin.send(AdminCall { request, ct_reply }).is_err() {
+        error!("Admin pipe is broken");
+        return Err("Оркестратор не запущен".to_string());
+    }
+    cr_reply.recv_timeout(REPLY_TIMEOUT)
+        .unwrap_or_else(|_| Err("Оркестратор не ответил".to_string()))
+}
+
+/**
+    reply - строки состояния (клиентов или VPN серверов) или описание ошибки
+*/
+fn write_reply(mut stream: &UnixStream, reply: Result<Vec<String>, String>) -> Result<(), Error> {
+    let mut response = String::new();
+    match reply {
+        Ok(lines) => {
+            response.push_str(OK);
+            response.push('\n');
+            for line in lines {
+                response.push_str(&line);
+                response.push('\n');
+            }
+        }
+        Err(message) => response.push_str(&format!("{ERR} {message}\n")),
+    }
+    stream.write_all(response.as_bytes()).context("Write admin reply")
+}
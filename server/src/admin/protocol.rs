@@ -0,0 +1,234 @@
+/*
+Протокол управляющего сокета (Unix socket).
+Одно подключение - один запрос: строка команды, в ответ
+   OK или ERR описание
+   и строки с состоянием клиентов или VPN серверов (поля через табуляцию)
+после чего сервер закрывает соединение.
+Тем же модулем пользуется equalizerctl.
+*/
+use easy_error::{bail, ensure, Error, ResultExt};
+use std::fmt;
+
+pub const DEFAULT_ADMIN_SOCKET: &str = "/tmp/equalizer.sock";
+pub const OK: &str = "OK";
+pub const ERR: &str = "ERR";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminRequest {
+    //все подключенные клиенты
+    Clients,
+    Stats(String),
+    //закрепить скорость (байт/мс), регулятор для клиента отключается
+    SetSpeed(String, usize),
+    //закрепить режим без заполнителя
+    Free(String),
+    //вернуть клиента регулятору
+    Release(String),
+    Kick(String),
+    //доступность VPN серверов (отвечает сам поток сокета, без оркестратора)
+    Upstreams,
+}
+
+/**
+    Кто управляет скоростью клиента
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlMode {
+    Auto,
+    Pinned,
+    Free,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientStatus {
+    pub key: String,
+    pub percent_data: usize,
+    pub percent_filler: usize,
+    //байт/мс
+    pub calculated_speed: usize,
+    //последняя установленная скорость, None - заполнитель отключен
+    pub target_speed: Option<usize>,
+    pub mode: ControlMode,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamStatus {
+    pub address: String,
+    pub healthy: bool,
+    //неудачных проверок (подключений) подряд
+    pub failures: u64,
+    //сколько мс назад проверяли, None - еще не проверяли
+    pub checked_ms_ago: Option<u64>,
+}
+
+impl AdminRequest {
+    pub fn parse(line: &str) -> Result<AdminRequest, Error> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let request = match parts.as_slice() {
+            ["clients"] => AdminRequest::Clients,
+            ["stats", key] => AdminRequest::Stats(key.to_string()),
+            ["set-speed", key, speed] => {
+                let speed = speed.parse().context(format!("Скорость должна быть числом (байт/мс): {speed}"))?;
+                ensure!(speed > 0, "Скорость должна быть больше 0");
+                AdminRequest::SetSpeed(key.to_string(), speed)
+            }
+            ["free", key] => AdminRequest::Free(key.to_string()),
+            ["release", key] => AdminRequest::Release(key.to_string()),
+            ["kick", key] => AdminRequest::Kick(key.to_string()),
+            ["upstreams"] => AdminRequest::Upstreams,
+            _ => bail!("Неизвестная команда '{}'", line.trim()),
+        };
+        Ok(request)
+    }
+}
+
+impl fmt::Display for AdminRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminRequest::Clients => write!(f, "clients"),
+            AdminRequest::Stats(key) => write!(f, "stats {key}"),
+            AdminRequest::SetSpeed(key, speed) => write!(f, "set-speed {key} {speed}"),
+            AdminRequest::Free(key) => write!(f, "free {key}"),
+            AdminRequest::Release(key) => write!(f, "release {key}"),
+            AdminRequest::Kick(key) => write!(f, "kick {key}"),
+            AdminRequest::Upstreams => write!(f, "upstreams"),
+        }
+    }
+}
+
+impl ControlMode {
+    fn parse(value: &str) -> Result<ControlMode, Error> {
+        match value {
+            "auto" => Ok(ControlMode::Auto),
+            "pinned" => Ok(ControlMode::Pinned),
+            "free" => Ok(ControlMode::Free),
+            _ => bail!("Неизвестный режим {value}"),
+        }
+    }
+}
+
+impl fmt::Display for ControlMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlMode::Auto => write!(f, "auto"),
+            ControlMode::Pinned => write!(f, "pinned"),
+            ControlMode::Free => write!(f, "free"),
+        }
+    }
+}
+
+/**
+    ключ, % данных, % заполнителя, скорость, установленная скорость (- если отключен), режим
+*/
+impl fmt::Display for ClientStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let target_speed = self.target_speed.map(|speed| speed.to_string()).unwrap_or("-".to_string());
+        write!(f, "{}\t{}\t{}\t{}\t{}\t{}", self.key, self.percent_data, self.percent_filler,
+               self.calculated_speed, target_speed, self.mode)
+    }
+}
+
+impl ClientStatus {
+    pub fn parse(line: &str) -> Result<ClientStatus, Error> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [key, percent_data, percent_filler, calculated_speed, target_speed, mode] = fields.as_slice() else {
+            bail!("Ожидается 6 полей: {line}");
+        };
+        let number = |value: &str| value.parse::<usize>().context(format!("Ожидается число: {value}"));
+        Ok(ClientStatus {
+            key: key.to_string(),
+            percent_data: number(percent_data)?,
+            percent_filler: number(percent_filler)?,
+            calculated_speed: number(calculated_speed)?,
+            target_speed: match *target_speed {
+                "-" => None,
+                speed => Some(number(speed)?),
+            },
+            mode: ControlMode::parse(mode)?,
+        })
+    }
+}
+
+/**
+    адрес, up или down, неудач подряд, мс с последней проверки (- если не проверяли)
+*/
+impl fmt::Display for UpstreamStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.healthy { "up" } else { "down" };
+        let checked = self.checked_ms_ago.map(|ms| ms.to_string()).unwrap_or("-".to_string());
+        write!(f, "{}\t{}\t{}\t{}", self.address, state, self.failures, checked)
+    }
+}
+
+impl UpstreamStatus {
+    pub fn parse(line: &str) -> Result<UpstreamStatus, Error> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [address, state, failures, checked] = fields.as_slice() else {
+            bail!("Ожидается 4 поля: {line}");
+        };
+        let number = |value: &str| value.parse::<u64>().context(format!("Ожидается число: {value}"));
+        Ok(UpstreamStatus {
+            address: address.to_string(),
+            healthy: match *state {
+                "up" => true,
+                "down" => false,
+                _ => bail!("Неизвестное состояние {state}"),
+            },
+            failures: number(failures)?,
+            checked_ms_ago: match *checked {
+                "-" => None,
+                ms => Some(number(ms)?),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //файл подключается и в equalizerctl - без crate::admin
+    use super::{AdminRequest, ClientStatus, ControlMode, UpstreamStatus};
+
+    #[test]
+    fn request_round_trip_test() {
+        let requests = [
+            AdminRequest::Clients,
+            AdminRequest::Stats("router-1".to_string()),
+            AdminRequest::SetSpeed("router-1".to_string(), 1048),
+            AdminRequest::Free("router-1".to_string()),
+            AdminRequest::Release("router-1".to_string()),
+            AdminRequest::Kick("router-1".to_string()),
+            AdminRequest::Upstreams,
+        ];
+        for request in requests {
+            assert_eq!(request, AdminRequest::parse(&request.to_string()).unwrap());
+        }
+        assert!(AdminRequest::parse("set-speed router-1 fast").is_err());
+        assert!(AdminRequest::parse("reboot").is_err());
+    }
+
+    #[test]
+    fn status_round_trip_test() {
+        let status = ClientStatus {
+            key: "router-1".to_string(),
+            percent_data: 80,
+            percent_filler: 20,
+            calculated_speed: 1200,
+            target_speed: None,
+            mode: ControlMode::Free,
+        };
+        assert_eq!(status, ClientStatus::parse(&status.to_string()).unwrap());
+    }
+
+    #[test]
+    fn upstream_round_trip_test() {
+        let status = UpstreamStatus {
+            address: "127.0.0.1:1194".to_string(),
+            healthy: false,
+            failures: 3,
+            checked_ms_ago: Some(1200),
+        };
+        assert_eq!(status, UpstreamStatus::parse(&status.to_string()).unwrap());
+        let status = UpstreamStatus { healthy: true, failures: 0, checked_ms_ago: None, ..status };
+        assert_eq!(status, UpstreamStatus::parse(&status.to_string()).unwrap());
+    }
+}
@@ -0,0 +1,146 @@
+/*
+Управление запущенным эквалайзером через управляющий сокет (см. admin/protocol.rs).
+Нужен при работе службой: строка статистики в консоли там не видна.
+*/
+use clap::{Parser, Subcommand};
+use easy_error::{bail, ensure, Error, ResultExt};
+use equalizer::admin::protocol::{AdminRequest, ClientStatus, UpstreamStatus, DEFAULT_ADMIN_SOCKET, ERR, OK};
+use equalizer::speed::{mbit_to_native, native_to_regular};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process::exit;
+use std::thread::sleep;
+use std::time::Duration;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+    Управление эквалайзером: клиенты, скорость, отключение
+*/
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Управляющий сокет эквалайзера (admin_socket)
+    #[arg(long, default_value = DEFAULT_ADMIN_SOCKET)]
+    socket: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Подключенные клиенты
+    Clients,
+    /// Состояние клиента
+    Stats { key: String },
+    /// Закрепить скорость клиента, Мбит/с (регулятор для него отключается)
+    SetSpeed { key: String, mbit: usize },
+    /// Отключить заполнитель клиента
+    Free { key: String },
+    /// Вернуть клиента регулятору скорости
+    Release { key: String },
+    /// Отключить клиента
+    Kick { key: String },
+    /// Доступность VPN серверов
+    Upstreams,
+    /// Обновлять список клиентов
+    Watch {
+        /// Период обновления, мс
+        #[arg(long, default_value_t = 1000)]
+        interval: u64,
+    },
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli) {
+        eprintln!("{}", e.ctx);
+        exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    let request = match cli.command {
+        Command::Clients => AdminRequest::Clients,
+        Command::Stats { key } => AdminRequest::Stats(key),
+        Command::SetSpeed { key, mbit } => {
+            ensure!(mbit > 0, "Скорость должна быть больше 0");
+            AdminRequest::SetSpeed(key, mbit_to_native(mbit))
+        }
+        Command::Free { key } => AdminRequest::Free(key),
+        Command::Release { key } => AdminRequest::Release(key),
+        Command::Kick { key } => AdminRequest::Kick(key),
+        Command::Upstreams => return upstreams(&cli.socket),
+        Command::Watch { interval } => return watch(&cli.socket, Duration::from_millis(interval)),
+    };
+    let statuses = request_statuses(&cli.socket, &request)?;
+    if !statuses.is_empty() {
+        print_statuses(&statuses);
+    } else if request == AdminRequest::Clients {
+        println!("Нет подключенных клиентов");
+    }
+    Ok(())
+}
+
+fn watch(socket: &str, interval: Duration) -> Result<(), Error> {
+    loop {
+        let statuses = request_statuses(socket, &AdminRequest::Clients)?;
+        //очистить экран
+        print!("\x1b[2J\x1b[H");
+        if statuses.is_empty() {
+            println!("Нет подключенных клиентов");
+        } else {
+            print_statuses(&statuses);
+        }
+        std::io::stdout().flush().context("Flush stdout")?;
+        sleep(interval);
+    }
+}
+
+fn upstreams(socket: &str) -> Result<(), Error> {
+    let reply = request_lines(socket, &AdminRequest::Upstreams)?;
+    let statuses = reply.iter().map(|line| UpstreamStatus::parse(line)).collect::<Result<Vec<_>, _>>()?;
+    println!("{:<32} {:>5} {:>8} {:>10}", "upstream", "state", "failures", "checked");
+    for status in statuses {
+        let state = if status.healthy { "up" } else { "down" };
+        let checked = status.checked_ms_ago.map(|ms| format!("{}s ago", ms / 1000)).unwrap_or("-".to_string());
+        println!("{:<32} {:>5} {:>8} {:>10}", status.address, state, status.failures, checked);
+    }
+    Ok(())
+}
+
+fn request_statuses(socket: &str, request: &AdminRequest) -> Result<Vec<ClientStatus>, Error> {
+    request_lines(socket, request)?.iter().map(|line| ClientStatus::parse(line)).collect()
+}
+
+/**
+    Строки ответа после OK
+*/
+fn request_lines(socket: &str, request: &AdminRequest) -> Result<Vec<String>, Error> {
+    let reply = send_request(socket, request)?;
+    let mut lines = reply.lines();
+    match lines.next() {
+        Some(OK) => Ok(lines.map(str::to_string).collect()),
+        Some(line) if line.starts_with(ERR) => bail!("{}", line[ERR.len()..].trim()),
+        _ => bail!("Неожиданный ответ эквалайзера: {reply}"),
+    }
+}
+
+fn send_request(socket: &str, request: &AdminRequest) -> Result<String, Error> {
+    let mut stream = UnixStream::connect(socket)
+        .context(format!("Не удалось подключиться к {socket} (эквалайзер запущен?)"))?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT)).context("Set read timeout")?;
+    stream.write_all(format!("{request}\n").as_bytes()).context("Send request")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).context("Read reply")?;
+    Ok(reply)
+}
+
+fn print_statuses(statuses: &[ClientStatus]) {
+    println!("{:<24} {:>6} {:>8} {:>10} {:>10}  mode", "client", "data", "filler", "speed", "target");
+    for status in statuses {
+        let target_speed = status.target_speed.map(native_to_regular).unwrap_or("-".to_string());
+        println!("{:<24} {:>5}% {:>7}% {:>10} {:>10}  {}", status.key, status.percent_data, status.percent_filler,
+                 native_to_regular(status.calculated_speed), target_speed, status.mode);
+    }
+}
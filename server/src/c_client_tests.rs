@@ -63,7 +63,7 @@ mod tests {
         let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic::default()));
         //дальше готовимся принимать клиентов
         let (ct_stop, cr_stop) = channel();
-        let join = start_listen(PROXY_LISTEN_PORT_WSL, VPN_LISTEN_PORT, ct_vpn, cr_stop).unwrap();
+        let join = start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT_WSL), format!("127.0.0.1:{}", VPN_LISTEN_PORT).into(), None, None, ct_vpn, cr_stop).unwrap();
         sleep(Duration::from_millis(200));
 
         let client_stream = TcpStream::connect(format!("127.0.0.1:{}", CLIENT_PROXY_LISTEN_PORT_WSL)).unwrap();
@@ -1,15 +1,23 @@
 /*
 Следит за количеством переданных данных VPN->client
-Если полезных данных недостаточно, дает данные (пока пустые пакеты)
+Если полезных данных недостаточно, дает данные (содержимое см. filler_content.rs)
 Поддерживается максимальный битрейт в течении 3-10 секунд, после чего
 идет медленное затухание
 */
-use crate::objects::{HotPotatoInfo, Packet, SentPacket, MAX_STAT_COUNT, ONE_PACKET_MAX_SIZE};
+use crate::core::filler_content::FillerContent;
+use crate::objects::{HotPotatoInfo, Packet, SentPacket, MAX_STAT_COUNT};
+#[cfg(test)]
+use crate::objects::ONE_PACKET_MAX_SIZE;
+use crate::settings::FillerSettings;
+use log::warn;
 use std::ops::{Sub};
 use std::time::{Duration, Instant};
-const ANALYZE_PERIOD_MS: u64 = 100;
-const MIN_BYTES_TO_FILL:usize = ONE_PACKET_MAX_SIZE/4;
+//значения по умолчанию, переопределяются секцией [filler] файла настроек
+pub(crate) const ANALYZE_PERIOD_MS: u64 = 100;
+#[cfg(test)]
 const OLD_AGE: Duration = Duration::from_millis(ANALYZE_PERIOD_MS);
+//пакет меньше - слишком много заголовков на полезные данные
+pub(crate) const MIN_PACKET_SIZE: usize = 256;
 enum PacketType {
     Data,
     Filler,
@@ -57,16 +65,38 @@ pub struct Filler {
     queue: Vec<SentPacketType>,
     //bytes per ms
     speed: usize,
+    content: Box<dyn FillerContent>,
+    //статистика старше уходит в регулятор
+    old_age: Duration,
+    packet_size: usize,
+    //мелкие пакеты заполнителя не отправляем
+    min_bytes_to_fill: usize,
 }
 
 impl Filler {
 
-    pub fn new(speed: usize) -> Filler {
+    #[cfg(test)]
+    pub fn new(speed: usize, content: Box<dyn FillerContent>) -> Filler {
+        Filler::with_limits(speed, content, OLD_AGE, ONE_PACKET_MAX_SIZE)
+    }
+
+    pub fn with_settings(speed: usize, settings: &FillerSettings) -> Filler {
+        Filler::with_limits(speed, settings.content.create(), settings.analyze_period(), settings.packet_size)
+    }
+
+    fn with_limits(speed: usize, content: Box<dyn FillerContent>, old_age: Duration, packet_size: usize) -> Filler {
         let queue: Vec<SentPacketType> = Vec::new();
-        Self { queue, speed }
+        Self { queue, speed, content, old_age, packet_size, min_bytes_to_fill: packet_size / 4 }
     }
 
+    /**
+        Скорость 0 не ставим (на нее делим) - остается прежняя
+    */
     pub fn set_speed(&mut self, speed: usize) {
+        if speed == 0 {
+            warn!("Нулевая скорость заполнителя пропущена, остается {}", self.speed);
+            return;
+        }
         self.speed = speed;
     }
 
@@ -80,7 +110,7 @@ impl Filler {
 
     pub fn clean_almost_full(&mut self) -> Option<HotPotatoInfo> {
         let now = Instant::now();
-        let old_threshold = now.sub(OLD_AGE);
+        let old_threshold = now.sub(self.old_age);
         let mut data_count = 0;
         let mut filler_count = 0;
         for i in 0..self.queue.len() {
@@ -103,11 +133,11 @@ impl Filler {
     }
 
     /*
-    очищаем информацию о пакетах, которые старше 100мс
+    очищаем информацию о пакетах, которые старше old_age (100мс)
      */
     pub fn clean(&mut self) -> HotPotatoInfo {
         let now = Instant::now();
-        let old_threshold = now.sub(OLD_AGE);
+        let old_threshold = now.sub(self.old_age);
         let mut result = HotPotatoInfo::default();
 
         while let Some(pack) = self.queue.first() {
@@ -137,26 +167,47 @@ impl Filler {
         if let Some(last) = self.queue.last() {
             return self.get_space(&last.packet);
         }
-        ONE_PACKET_MAX_SIZE
+        self.packet_size
     }
     /*
         Подсчитываем сколько надо доотправить для поддержания скорости
         S = v*t, S = количество байт
      */
-    pub fn get_filler_packet(&self) -> Option<Packet> {
+    pub fn get_filler_packet(&mut self) -> Option<Packet> {
         if let Some(last) = self.queue.last() {
             let last = last.packet;
             let bytes_to_fill = self.get_space(&last);
-            if bytes_to_fill > MIN_BYTES_TO_FILL {
-                if bytes_to_fill > ONE_PACKET_MAX_SIZE {
-                    return Some(Packet::new_packet(ONE_PACKET_MAX_SIZE));
-                }
-                return Some(Packet::new_packet(bytes_to_fill));
+            if bytes_to_fill > self.min_bytes_to_fill {
+                let mut packet = Packet::new_packet(bytes_to_fill.min(self.packet_size));
+                self.content.fill(&mut packet.buf[..packet.size]);
+                return Some(packet);
             }
         }
         None
     }
 
+    /*
+        Момент, когда для отправки станет доступно space байт.
+        None - уже доступно (или еще ничего не отправлялось)
+     */
+    pub fn space_available_at(&self, space: usize) -> Option<Instant> {
+        let last = self.queue.last()?.packet;
+        let duration_sent = Duration::from_millis((last.sent_size / self.speed) as u64);
+        let duration_space = Duration::from_millis((space / self.speed) as u64 + 1);
+        let at = last.sent_date + duration_sent + duration_space;
+        if at > Instant::now() {
+            return Some(at);
+        }
+        None
+    }
+
+    /*
+        Момент, когда get_filler_packet вернет пакет
+     */
+    pub fn filler_available_at(&self) -> Option<Instant> {
+        self.space_available_at(self.min_bytes_to_fill)
+    }
+
     fn get_space(&self, from_packet: &SentPacket) -> usize {
         let duration_to_now = Instant::now().sub(from_packet.sent_date);
         let duration_sent = Duration::from_millis((from_packet.sent_size / self.speed) as u64);
@@ -173,9 +224,10 @@ impl Filler {
 mod tests {
 
     use std::thread::sleep;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use log::{info};
     use crate::core::filler::{Filler, OLD_AGE};
+    use crate::core::filler_content::ZeroContent;
     use crate::tests::test_init::initialize_logger;
 
     pub const INITIAL_SPEED: usize = 1024 * 1024 / 1000;
@@ -183,7 +235,7 @@ mod tests {
     #[test]
     fn filler_test() {
         initialize_logger();
-        let mut filler = Filler::new(INITIAL_SPEED);
+        let mut filler = Filler::new(INITIAL_SPEED, Box::new(ZeroContent));
         filler.data_was_sent(1);
         sleep(Duration::from_millis(5));
         let fill_packet = filler.get_filler_packet();
@@ -196,7 +248,7 @@ mod tests {
 
     #[test]
     fn clean_test() {
-        let mut filler = Filler::new(INITIAL_SPEED);
+        let mut filler = Filler::new(INITIAL_SPEED, Box::new(ZeroContent));
         filler.data_was_sent(10);
         sleep(OLD_AGE);
         sleep(Duration::from_millis(1));
@@ -207,4 +259,18 @@ mod tests {
         let info = filler.clean();
         assert_eq!(0, info.data_count);
     }
+
+    #[test]
+    fn filler_available_at_test() {
+        let mut filler = Filler::new(INITIAL_SPEED, Box::new(ZeroContent));
+        assert!(filler.filler_available_at().is_none());
+        //на скорость делим - 0 пропускается
+        filler.set_speed(0);
+        filler.data_was_sent(1);
+        let at = filler.filler_available_at().unwrap();
+        assert!(filler.get_filler_packet().is_none());
+        sleep(at - Instant::now());
+        assert!(filler.filler_available_at().is_none());
+        assert!(filler.get_filler_packet().is_some());
+    }
 }
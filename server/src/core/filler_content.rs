@@ -0,0 +1,181 @@
+/*
+Содержимое пакетов заполнителя.
+Нулевые пакеты сжимаются в ничто и отличаются от шифротекста OpenVPN,
+если внешний слой (SSH, TLS) когда-нибудь будет снят.
+Генератор выбирается для всего сервера (--filler), у каждой пары свой экземпляр.
+*/
+use easy_error::{bail, ensure, Error, ResultExt};
+use serde::Deserialize;
+use std::fmt;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use std::fs;
+use std::sync::Arc;
+use crate::objects::ONE_PACKET_MAX_SIZE;
+
+pub trait FillerContent: Send {
+    fn fill(&mut self, buf: &mut [u8]);
+}
+
+/**
+    Способ заполнения, выбранный для сервера.
+    В файле настроек - строка, как и в --filler
+*/
+#[derive(Clone, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum FillerContentKind {
+    //как раньше - нули
+    Zero,
+    //поток ChaCha12 со случайным ключом
+    #[default]
+    Random,
+    //по кругу байты из записанного файла (например шифротекст OpenVPN)
+    Replay(Arc<Vec<u8>>),
+}
+
+impl FillerContentKind {
+    /**
+        zero | random | replay:путь_к_файлу
+    */
+    pub fn parse(value: &str) -> Result<FillerContentKind, Error> {
+        match value.split_once(':') {
+            None if value == "zero" => Ok(FillerContentKind::Zero),
+            None if value == "random" => Ok(FillerContentKind::Random),
+            Some(("replay", path)) => {
+                let recording = fs::read(path).context(format!("Не удалось прочитать запись {path}"))?;
+                ensure!(recording.len() >= ONE_PACKET_MAX_SIZE,
+                    "В записи {path} должно быть хотя бы {ONE_PACKET_MAX_SIZE} байт");
+                Ok(FillerContentKind::Replay(Arc::new(recording)))
+            }
+            _ => bail!("Неизвестный тип заполнителя {value}, ожидается zero, random или replay:путь"),
+        }
+    }
+
+    pub fn create(&self) -> Box<dyn FillerContent> {
+        match self {
+            FillerContentKind::Zero => Box::new(ZeroContent),
+            FillerContentKind::Random => Box::new(RandomContent::new()),
+            FillerContentKind::Replay(recording) => Box::new(ReplayContent::new(recording.clone())),
+        }
+    }
+}
+
+impl TryFrom<String> for FillerContentKind {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<FillerContentKind, Error> {
+        FillerContentKind::parse(&value)
+    }
+}
+
+//запись не выводим
+impl fmt::Debug for FillerContentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FillerContentKind::Zero => write!(f, "zero"),
+            FillerContentKind::Random => write!(f, "random"),
+            FillerContentKind::Replay(recording) => write!(f, "replay({} bytes)", recording.len()),
+        }
+    }
+}
+
+pub struct ZeroContent;
+
+impl FillerContent for ZeroContent {
+    fn fill(&mut self, buf: &mut [u8]) {
+        buf.fill(0);
+    }
+}
+
+pub struct RandomContent {
+    rng: ChaCha12Rng,
+}
+
+impl RandomContent {
+    pub fn new() -> RandomContent {
+        let mut seed = [0; 32];
+        getrandom::fill(&mut seed).expect("Системный генератор случайных чисел");
+        Self {
+            rng: ChaCha12Rng::from_seed(seed),
+        }
+    }
+}
+
+impl Default for RandomContent {
+    fn default() -> RandomContent {
+        RandomContent::new()
+    }
+}
+
+impl FillerContent for RandomContent {
+    fn fill(&mut self, buf: &mut [u8]) {
+        self.rng.fill_bytes(buf);
+    }
+}
+
+pub struct ReplayContent {
+    recording: Arc<Vec<u8>>,
+    offset: usize,
+}
+
+impl ReplayContent {
+    pub fn new(recording: Arc<Vec<u8>>) -> ReplayContent {
+        //разные пары начинают с разных мест
+        let mut start = [0; 8];
+        getrandom::fill(&mut start).expect("Системный генератор случайных чисел");
+        let offset = u64::from_le_bytes(start) as usize % recording.len();
+        Self { recording, offset }
+    }
+}
+
+impl FillerContent for ReplayContent {
+    fn fill(&mut self, buf: &mut [u8]) {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let size = (buf.len() - filled).min(self.recording.len() - self.offset);
+            buf[filled..filled + size].copy_from_slice(&self.recording[self.offset..self.offset + size]);
+            filled += size;
+            self.offset = (self.offset + size) % self.recording.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::filler_content::{FillerContentKind, ReplayContent, FillerContent};
+    use crate::objects::ONE_PACKET_MAX_SIZE;
+    use std::sync::Arc;
+
+    /**
+        Скорость генератора - benches/filler_content.rs
+    */
+    #[test]
+    fn random_content_test() {
+        let mut content = FillerContentKind::Random.create();
+        let mut buf = [0; ONE_PACKET_MAX_SIZE];
+        content.fill(&mut buf);
+        assert!(buf.iter().filter(|byte| **byte == 0).count() < ONE_PACKET_MAX_SIZE / 64);
+        let first = buf;
+        content.fill(&mut buf);
+        assert_ne!(first, buf);
+    }
+
+    #[test]
+    fn replay_content_test() {
+        let recording: Vec<u8> = (0..=255).collect();
+        let mut content = ReplayContent::new(Arc::new(recording));
+        let mut buf = [0; 600];
+        content.fill(&mut buf);
+        for i in 1..buf.len() {
+            assert_eq!(buf[i - 1].wrapping_add(1), buf[i]);
+        }
+    }
+
+    #[test]
+    fn parse_test() {
+        assert!(matches!(FillerContentKind::parse("zero"), Ok(FillerContentKind::Zero)));
+        assert!(matches!(FillerContentKind::parse("random"), Ok(FillerContentKind::Random)));
+        assert!(FillerContentKind::parse("replay:/nonexistent").is_err());
+        assert!(FillerContentKind::parse("ones").is_err());
+    }
+}
@@ -1,4 +1,8 @@
 pub mod filler;
+//чем заполняются пакеты заполнителя
+pub mod filler_content;
+//пул потоков, который продвигает все пары по готовности сокетов и таймерам
+pub mod reactor;
 /**
    Работает подготовленная пара Основного канала и Канал-заполнитель
    Если кто-то из них отваливается - завершаем работу инстанса
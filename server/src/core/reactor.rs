@@ -0,0 +1,436 @@
+/*
+Реактор: все пары обслуживаются небольшим фиксированным пулом потоков.
+Поток спит в epoll (mio) пока не станет готов к чтению или записи один из сокетов
+(запись не ждет освобождения буфера - недописанное задача дописывает по готовности),
+не подойдет срок таймера задачи (например отправка заполнителя)
+или оркестратор не пришлет команду (Waker).
+Сокеты регистрируются edge-triggered, поэтому задача продвигается пока
+ей есть что делать (но не больше STEP_BUDGET шагов за раз, чтобы не мешать соседям).
+Задача может сменить сокеты (клиент переподключился) - тогда они регистрируются заново.
+У каждого сокета задачи свой Token (номер задачи и сокета) - задаче сообщается,
+какой сокет и когда стал готов к записи (см. ReactorTask::writable).
+*/
+use easy_error::Error;
+use log::{error, info, warn};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const WAKE_TOKEN: Token = Token(usize::MAX);
+const EVENTS_CAPACITY: usize = 256;
+//даже без событий продвигаем все задачи с таким периодом (статистика, команды)
+const TICK_PERIOD: Duration = Duration::from_millis(20);
+const STEP_BUDGET: usize = 64;
+//сокетов у задачи не больше (клиент и VPN сервер)
+const MAX_TASK_FDS: usize = 4;
+//на маленьком VPS больше потоков не нужно
+const MAX_DEFAULT_THREADS: usize = 4;
+
+/**
+    Работа, которую продвигает реактор
+*/
+pub trait ReactorTask: Send {
+    //true - что-то было сделано, имеет смысл повторить
+    fn step(&mut self) -> Result<bool, Error>;
+    //когда задачу нужно продвинуть, даже если сокеты молчат
+    fn deadline(&self) -> Option<Instant>;
+    fn is_running(&self) -> bool;
+    //дескрипторы, готовность которых ждем
+    fn raw_fds(&self) -> Vec<RawFd>;
+    //задача завершена (ошибка, закрытие сокета или остановка снаружи)
+    fn finish(&mut self);
+    //raw_fds изменились с прошлого вызова: закрытый сокет больше не завершает задачу
+    fn take_fds_changed(&mut self) -> bool {
+        false
+    }
+    //сокет fd стал готов к записи в момент at (до step)
+    fn writable(&mut self, _fd: RawFd, _at: Instant) {}
+}
+
+pub struct Reactor {
+    workers: Vec<WorkerHandle>,
+    next: usize,
+}
+
+struct WorkerHandle {
+    ct_task: Sender<Box<dyn ReactorTask>>,
+    waker: Arc<Waker>,
+    running: Arc<AtomicBool>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+struct Worker {
+    poll: Poll,
+    cr_task: Receiver<Box<dyn ReactorTask>>,
+    running: Arc<AtomicBool>,
+    tasks: HashMap<usize, Entry>,
+    next_id: usize,
+}
+
+struct Entry {
+    task: Box<dyn ReactorTask>,
+    fds: Vec<RawFd>,
+    //есть необработанное событие (или не хватило STEP_BUDGET)
+    ready: bool,
+    //сокет закрыт другой стороной - дочитываем и завершаем
+    closed: bool,
+    //задача сменила сокеты, нужна повторная регистрация
+    fds_changed: bool,
+}
+
+pub fn default_threads() -> usize {
+    thread::available_parallelism()
+        .map(|threads| threads.get())
+        .unwrap_or(1)
+        .min(MAX_DEFAULT_THREADS)
+}
+
+impl Reactor {
+    pub fn new(threads: usize) -> Reactor {
+        let workers = (0..threads.max(1))
+            .map(|index| Worker::thread_start(index).expect("reactor thread started"))
+            .collect();
+        Self { workers, next: 0 }
+    }
+
+    /**
+        Задачи распределяются по потокам по кругу.
+        Waker будит поток задачи (новая команда, остановка)
+    */
+    pub fn spawn(&mut self, task: Box<dyn ReactorTask>) -> Arc<Waker> {
+        let worker = &self.workers[self.next % self.workers.len()];
+        self.next += 1;
+        if worker.ct_task.send(task).is_err() {
+            error!("Reactor thread is gone");
+        }
+        if let Err(e) = worker.waker.wake() {
+            warn!("Failed to wake reactor thread {}", e);
+        }
+        worker.waker.clone()
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        for worker in self.workers.iter() {
+            worker.running.store(false, Ordering::Relaxed);
+            let _ = worker.waker.wake();
+        }
+        for worker in self.workers.iter_mut() {
+            if let Some(handle) = worker.join_handle.take() {
+                if let Err(e) = handle.join() {
+                    error!("Failed to join reactor thread: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Worker {
+    fn thread_start(index: usize) -> Result<WorkerHandle, std::io::Error> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKE_TOKEN)?);
+        let (ct_task, cr_task) = channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let mut worker = Worker {
+            poll,
+            cr_task,
+            running: running.clone(),
+            tasks: HashMap::new(),
+            next_id: 0,
+        };
+        let join_handle = thread::Builder::new()
+            .name(format!("reactor-{index}"))
+            .spawn(move || {
+                worker.run();
+                info!("Exit from reactor thread {index}");
+            })?;
+        Ok(WorkerHandle {
+            ct_task,
+            waker,
+            running,
+            join_handle: Some(join_handle),
+        })
+    }
+
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        let mut last_tick = Instant::now();
+        while self.running.load(Ordering::Relaxed) {
+            let timeout = self.poll_timeout(last_tick);
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() != ErrorKind::Interrupted {
+                    error!("Reactor poll failed {}", e);
+                    break;
+                }
+            }
+            //по Waker не знаем чья команда - продвигаем всех
+            let mut all = false;
+            let now = Instant::now();
+            for event in events.iter() {
+                if event.token() == WAKE_TOKEN {
+                    all = true;
+                } else if let Some(entry) = self.tasks.get_mut(&(event.token().0 / MAX_TASK_FDS)) {
+                    entry.ready = true;
+                    entry.closed |= event.is_read_closed() || event.is_error();
+                    let fd = entry.fds.get(event.token().0 % MAX_TASK_FDS);
+                    if let (true, Some(fd)) = (event.is_writable(), fd) {
+                        entry.task.writable(*fd, now);
+                    }
+                }
+            }
+            if all {
+                self.receive_new_tasks();
+            }
+            if last_tick.elapsed() >= TICK_PERIOD {
+                last_tick = Instant::now();
+                all = true;
+            }
+            self.advance(all);
+        }
+        for (_, entry) in self.tasks.drain() {
+            Self::remove(&self.poll, entry);
+        }
+    }
+
+    fn poll_timeout(&self, last_tick: Instant) -> Duration {
+        let now = Instant::now();
+        let mut timeout = TICK_PERIOD.saturating_sub(now - last_tick);
+        for entry in self.tasks.values() {
+            if entry.ready {
+                return Duration::ZERO;
+            }
+            if let Some(deadline) = entry.task.deadline() {
+                timeout = timeout.min(deadline.saturating_duration_since(now));
+            }
+        }
+        timeout
+    }
+
+    fn receive_new_tasks(&mut self) {
+        while let Ok(task) = self.cr_task.try_recv() {
+            self.register(task);
+        }
+    }
+
+    fn register(&mut self, task: Box<dyn ReactorTask>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut entry = Entry {
+            fds: vec![],
+            task,
+            //данные могли прийти до регистрации
+            ready: true,
+            closed: false,
+            fds_changed: false,
+        };
+        if let Err(e) = Self::register_fds(&self.poll, id, &mut entry) {
+            error!("Failed to register socket in reactor {}", e);
+            Self::remove(&self.poll, entry);
+            return;
+        }
+        self.tasks.insert(id, entry);
+    }
+
+    fn register_fds(poll: &Poll, id: usize, entry: &mut Entry) -> Result<(), std::io::Error> {
+        for (index, fd) in entry.task.raw_fds().into_iter().take(MAX_TASK_FDS).enumerate() {
+            let token = Token(id * MAX_TASK_FDS + index);
+            poll.registry().register(&mut SourceFd(&fd), token, Interest::READABLE | Interest::WRITABLE)?;
+            entry.fds.push(fd);
+        }
+        Ok(())
+    }
+
+    /**
+        Старые сокеты снимаем все: закрытый дескриптор мог уже получить новый сокет с тем же номером
+    */
+    fn reregister(poll: &Poll, id: usize, entry: &mut Entry) -> Result<(), std::io::Error> {
+        for fd in entry.fds.drain(..) {
+            let _ = poll.registry().deregister(&mut SourceFd(&fd));
+        }
+        entry.fds_changed = false;
+        entry.closed = false;
+        //данные могли прийти до регистрации
+        entry.ready = true;
+        Self::register_fds(poll, id, entry)
+    }
+
+    fn advance(&mut self, all: bool) {
+        let now = Instant::now();
+        let mut finished = vec![];
+        for (id, entry) in self.tasks.iter_mut() {
+            let due = entry.task.deadline().is_some_and(|deadline| deadline <= now);
+            if (all || due || entry.ready) && !entry.advance() {
+                finished.push(*id);
+            } else if entry.fds_changed {
+                if let Err(e) = Self::reregister(&self.poll, *id, entry) {
+                    error!("Failed to register socket in reactor {}", e);
+                    finished.push(*id);
+                }
+            }
+        }
+        for id in finished {
+            if let Some(entry) = self.tasks.remove(&id) {
+                Self::remove(&self.poll, entry);
+            }
+        }
+    }
+
+    fn remove(poll: &Poll, mut entry: Entry) {
+        for fd in entry.fds.iter() {
+            let _ = poll.registry().deregister(&mut SourceFd(fd));
+        }
+        entry.task.finish();
+    }
+}
+
+impl Entry {
+    /**
+        false - задачу пора завершать
+    */
+    fn advance(&mut self) -> bool {
+        self.ready = false;
+        if !self.task.is_running() {
+            return false;
+        }
+        for _ in 0..STEP_BUDGET {
+            let step = self.task.step();
+            self.fds_changed |= self.task.take_fds_changed();
+            match step {
+                Ok(true) => {}
+                //все прочитано, если сокет закрыт - больше ничего не придет (если его не заменили)
+                Ok(false) => return !self.closed || self.fds_changed,
+                Err(e) => {
+                    error!("{:?} {} {}", e.cause, e.ctx, e.location);
+                    return false;
+                }
+            }
+        }
+        self.ready = true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::reactor::{Reactor, ReactorTask};
+    use easy_error::Error;
+    use std::io::{Read, Write};
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    struct TimerTask {
+        next: Instant,
+        steps: Arc<AtomicUsize>,
+        finished: Arc<AtomicBool>,
+    }
+
+    impl ReactorTask for TimerTask {
+        fn step(&mut self) -> Result<bool, Error> {
+            if Instant::now() >= self.next {
+                self.next += Duration::from_millis(5);
+                self.steps.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(false)
+        }
+
+        fn deadline(&self) -> Option<Instant> {
+            Some(self.next)
+        }
+
+        fn is_running(&self) -> bool {
+            true
+        }
+
+        fn raw_fds(&self) -> Vec<RawFd> {
+            vec![]
+        }
+
+        fn finish(&mut self) {
+            self.finished.store(true, Ordering::Relaxed);
+        }
+    }
+
+    //пишет пока сокет принимает, запоминает события готовности к записи
+    struct WriterTask {
+        stream: UnixStream,
+        writable: Arc<Mutex<Vec<(RawFd, Instant)>>>,
+    }
+
+    impl ReactorTask for WriterTask {
+        fn step(&mut self) -> Result<bool, Error> {
+            while self.stream.write(&[0; 1024]).is_ok() {}
+            Ok(false)
+        }
+
+        fn deadline(&self) -> Option<Instant> {
+            None
+        }
+
+        fn is_running(&self) -> bool {
+            true
+        }
+
+        fn raw_fds(&self) -> Vec<RawFd> {
+            vec![self.stream.as_raw_fd()]
+        }
+
+        fn finish(&mut self) {}
+
+        fn writable(&mut self, fd: RawFd, at: Instant) {
+            self.writable.lock().unwrap().push((fd, at));
+        }
+    }
+
+    /**
+        Задаче сообщается, какой сокет и когда освободился для записи
+    */
+    #[test]
+    fn writable_test() {
+        let (stream, mut reader) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        reader.set_nonblocking(true).unwrap();
+        let fd = stream.as_raw_fd();
+        let writable = Arc::new(Mutex::new(vec![]));
+        let mut reactor = Reactor::new(1);
+        reactor.spawn(Box::new(WriterTask { stream, writable: writable.clone() }));
+        sleep(Duration::from_millis(100));
+        let drained_at = Instant::now();
+        let mut buf = [0; 1024];
+        while reader.read(&mut buf).is_ok() {}
+        sleep(Duration::from_millis(100));
+        drop(reactor);
+        let writable = writable.lock().unwrap();
+        assert!(writable.iter().any(|(writable_fd, at)| *writable_fd == fd && *at >= drained_at), "{writable:?}");
+    }
+
+    #[test]
+    fn timer_task_test() {
+        let steps = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicBool::new(false));
+        let mut reactor = Reactor::new(1);
+        reactor.spawn(Box::new(TimerTask {
+            next: Instant::now(),
+            steps: steps.clone(),
+            finished: finished.clone(),
+        }));
+        sleep(Duration::from_millis(100));
+        drop(reactor);
+        //срабатывания по таймеру задачи, а не по TICK_PERIOD
+        let steps = steps.load(Ordering::Relaxed);
+        assert!(steps > 10 && steps <= 21, "{steps}");
+        assert!(finished.load(Ordering::Relaxed));
+    }
+}
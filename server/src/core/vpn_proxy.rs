@@ -1,25 +1,31 @@
+use std::os::fd::RawFd;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use crate::core::filler::Filler;
+use crate::core::reactor::{Reactor, ReactorTask};
+use crate::entry::session::Resume;
 use crate::objects::Pair;
+use crate::settings::FillerSettings;
 use crate::objects::ONE_PACKET_MAX_SIZE;
 use crate::objects::{ProxyState, RuntimeCommand};
 use crate::speed::{SpeedCorrectorCommand, SHUTDOWN_SPEED};
-use log::{debug, error, info};
+use log::{debug, info, warn};
+use mio::Waker;
 use std::sync::mpsc::{channel, Receiver, SendError, Sender, TryRecvError};
-use std::thread;
-use std::thread::{sleep, JoinHandle};
 use std::time::{Duration, Instant};
 use easy_error::{bail, Error, ResultExt};
+use splitter::server_side_split::split_server_stream_resumed;
 
 const A_FEW_SPACE: usize = 100;
-const BURNOUT_DELAY: Duration = Duration::from_micros(500);
+//при остановке пара закрывается, когда VPN сервер и клиент замолчали на это время
+const DRAIN_QUIET: Duration = Duration::from_millis(100);
 
 pub struct VpnProxy {
     ct_command: Sender<RuntimeCommand>,
     cr_state: Receiver<ProxyState>,
     running: Arc<AtomicBool>,
-    join_handle: Option<JoinHandle<()>>,
+    //будит поток реактора, который обслуживает пару
+    waker: Arc<Waker>,
     //подразумеваем что от одного VPN клиента может устанавливаться только одно подключение
     //будем использовать IP tun интерфейса
     pub key: String,
@@ -43,10 +49,12 @@ impl Proxy for VpnProxy {
         &mut self,
         command: RuntimeCommand,
     ) -> Result<(), SendError<RuntimeCommand>> {
-        self.ct_command.send(command)
+        self.ct_command.send(command)?;
+        self.wake();
+        Ok(())
     }
 }
-struct ThreadWorkingSet {
+struct WorkingSet {
     key: String,
     cr_command: Receiver<RuntimeCommand>,
     ct_state: Sender<ProxyState>,
@@ -54,187 +62,386 @@ struct ThreadWorkingSet {
     running: Arc<AtomicBool>,
     //without throttler & filler
     free_mode: bool,
+    //остановка сервера: заполнитель не шлем, дописываем данные и закрываемся
+    draining: bool,
+    drain_idle_since: Option<Instant>,
+    //ошибка последнего шага на стороне клиента (а не VPN сервера)
+    client_failed: bool,
+    //клиент с сессией отключился, ждем переподключения (см. session.rs)
+    detached_since: Option<Instant>,
+    //сокет клиента сменился - реактору перерегистрировать
+    fds_changed: bool,
+    //получено от клиента с прошлой отправки статистики
+    received_data: usize,
+    received_filler: usize,
+    filler: Filler,
+    //больше за раз не читаем - пакет к клиенту не длиннее (датаграмму читаем целиком)
+    packet_size: usize,
     //временный буфер
     buf: [u8; ONE_PACKET_MAX_SIZE],
 }
 
 impl VpnProxy {
-    pub fn new(pair: Pair) -> VpnProxy {
+    pub fn new(pair: Pair, filler_settings: &FillerSettings, reactor: &mut Reactor) -> VpnProxy {
         let (ct_command, cr_command) = channel();
         let (ct_state, cr_state) = channel();
         let key = pair.key.clone();
         let running = Arc::new(AtomicBool::new(true));
-        let thread_working_set = ThreadWorkingSet {
+        let working_set = WorkingSet {
             key: key.clone(),
             cr_command,
             ct_state: ct_state.clone(),
             running: running.clone(),
             free_mode: true,
+            draining: false,
+            drain_idle_since: None,
+            client_failed: false,
+            detached_since: None,
+            fds_changed: false,
+            received_data: 0,
+            received_filler: 0,
+            //цикл который использует заполнитель
+            filler: Filler::with_settings(SHUTDOWN_SPEED, filler_settings),
+            packet_size: if pair.datagram { ONE_PACKET_MAX_SIZE } else { filler_settings.packet_size },
             pair,
             buf: [0; ONE_PACKET_MAX_SIZE],
         };
-
-        let join_handle = ThreadWorkingSet::thread_start(thread_working_set);
-
+        let _ = ct_state.send(ProxyState::SetupComplete);
+        let waker = reactor.spawn(Box::new(working_set));
+        info!("Client {key} attached to reactor");
 
         Self {
             ct_command,
             cr_state,
             running,
-            join_handle: Some(join_handle),
+            waker,
             key: key.clone(),
         }
     }
+
+    fn wake(&self) {
+        if let Err(e) = self.waker.wake() {
+            warn!("Failed to wake proxy {} {}", self.key, e);
+        }
+    }
 }
 
+/**
+    Не ждем завершения: пару закроет поток реактора
+*/
 impl Drop for VpnProxy {
     fn drop(&mut self) {
         self.running.store(false, Ordering::Relaxed);
-        if let Some(handle) = self.join_handle.take() {
-            if let Err(e) = handle.join() {
-                error!("Failed to join thread: {:?}", e);
+        self.wake();
+        info!("Dropping VpnProxy {}.", self.key);
+    }
+}
+
+impl ReactorTask for WorkingSet {
+    fn step(&mut self) -> Result<bool, Error> {
+        if self.receive_resume() {
+            return Ok(true);
+        }
+        if let Some(detached_since) = self.detached_since {
+            return Ok(self.wait_resume(detached_since));
+        }
+        self.client_failed = false;
+        let result = self.flush().and_then(|_| if self.free_mode {
+            self.free_loop()
+        } else {
+            self.main_loop()
+        });
+        let some_work = match result {
+            Err(e) if self.client_failed && self.pair.session.is_some() => {
+                self.detach(&e.to_string());
+                return Ok(true);
             }
+            result => result?,
+        };
+        if self.pair.session.is_some() && self.pair.client_stream.is_closed() {
+            self.detach("соединение закрыто");
+            return Ok(true);
+        }
+        if self.draining {
+            //недописанное тоже ждем
+            self.check_drained(some_work || self.pair.client_stream.has_pending_writes() || self.pair.up_stream.has_pending_writes());
         }
-        info!("Dropping VpnProxy. Thread {}.",self.key);
+        Ok(some_work)
     }
-}
 
-impl ThreadWorkingSet {
-    pub fn thread_start(mut instance: ThreadWorkingSet) -> JoinHandle<()> {
-        thread::Builder::new()
-            .name(instance.key.clone())
-            .spawn(move || {
-                //цикл который использует заполнитель
-                let mut filler = Filler::new(SHUTDOWN_SPEED);
-                instance.ct_state.send(ProxyState::SetupComplete).unwrap();
-                info!("Client thread started");
-                loop {
-                    if !instance.running.load(Ordering::Relaxed) {
-                        instance.ct_state.send(ProxyState::Broken).unwrap();
-                        let _ = instance.pair.client_stream.shutdown();
-                        let _ = instance.pair.up_stream.shutdown();
-                        break;
-                    }
-                    let result = if instance.free_mode {
-                        instance.free_loop(&mut filler)
-                    } else {
-                        instance.main_loop(&mut filler)
-                    };
-                    match result {
-                        Err(e) => {
-                            instance.running.store(false, Ordering::Relaxed);
-                            error!("{:?} {} {}", e.cause, e.ctx, e.location);
-                            let _ = instance.ct_state.send(ProxyState::Broken);
-                            let _ = instance.pair.client_stream.shutdown();
-                            let _ = instance.pair.up_stream.shutdown();
-                            break;
-                        }
-                        Ok(_) => {}
-                    }
-                }
-                info!("Exit from thread {}", instance.key)
-            })
-            .expect("client_stream thread started")
+    fn deadline(&self) -> Option<Instant> {
+        if let (Some(detached_since), Some(session)) = (self.detached_since, &self.pair.session) {
+            return Some(detached_since + session.grace);
+        }
+        if self.draining {
+            return self.drain_idle_since.map(|since| since + DRAIN_QUIET);
+        }
+        if self.free_mode {
+            return None;
+        }
+        //когда освободится место для данных VPN сервера, или пора отправлять заполнитель
+        self.filler.space_available_at(A_FEW_SPACE)
+            .or_else(|| self.filler.filler_available_at())
     }
 
-    fn main_loop(
-        &mut self,
-        filler: &mut Filler,
-    ) -> Result<(), Error> {
-        let mut some_work = false;
-        //GET запрос на чтение нового видоса
-        let size= self.pair.client_stream.read(&mut self.buf[..])?;
-        if size > 0 {
-            //перенаправляем его VPN серверу
-            //trace!("->> {}", size);
-            self.pair.up_stream.write_all(&self.buf[..size])?;
-            some_work = true;
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    fn raw_fds(&self) -> Vec<RawFd> {
+        //пока клиент не переподключился - ждем только VPN сервер
+        let client_fd = match self.detached_since {
+            Some(_) => None,
+            //filler_stream использует тот же сокет что и client_stream
+            None => self.pair.client_stream.raw_fd(),
+        };
+        [client_fd, self.pair.up_stream.raw_fd()]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    fn take_fds_changed(&mut self) -> bool {
+        std::mem::take(&mut self.fds_changed)
+    }
+
+    fn writable(&mut self, fd: RawFd, at: Instant) {
+        //ожидание записи клиенту учитывает оценка канала (см. speed/bandwidth.rs)
+        if self.detached_since.is_none() && self.pair.client_stream.raw_fd() == Some(fd) {
+            self.pair.client_stream.writable(at);
         }
-        //если есть место
-        let available_space = filler.get_available_space();
-        if available_space > A_FEW_SPACE {
-            let vpn_incoming_data_size = self.pair.up_stream.read(&mut self.buf[..])?;
+    }
+
+    fn finish(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        //оркестратор мог уже удалить прокси
+        let _ = self.ct_state.send(ProxyState::Broken);
+        self.pair.client_stream.shutdown();
+        self.pair.up_stream.shutdown();
+        info!("Exit from proxy {}", self.key)
+    }
+}
+
+impl WorkingSet {
+    fn main_loop(&mut self) -> Result<bool, Error> {
+        //GET запрос на чтение нового видоса
+        let mut some_work = self.forward_client()?;
+        //если есть место, и клиент принял прежнее
+        let available_space = self.filler.get_available_space();
+        if available_space > A_FEW_SPACE && !self.pair.client_stream.has_pending_writes() {
+            let vpn_incoming_data_size = self.pair.up_stream.read(&mut self.buf[..self.packet_size])?;
             if vpn_incoming_data_size > 0  {
                 //trace!("=>> {}", vpn_incoming_data_size);
-                self.pair.client_stream.write_all(&self.buf[..vpn_incoming_data_size])?;
-                filler.data_was_sent(vpn_incoming_data_size);
+                self.pair.client_stream.write_all(&self.buf[..vpn_incoming_data_size])
+                    .inspect_err(|_| self.client_failed = true)?;
+                self.filler.data_was_sent(vpn_incoming_data_size);
                 some_work = true;
-            }else if let Some(packet) = filler.get_filler_packet(){
+            }else if let Some(packet) = self.filler.get_filler_packet(){
                 //trace!("=>> filler {}", packet.size);
-                self.pair.filler_stream.write_all(&packet.buf[..packet.size])?;
-                filler.filler_was_sent(packet.size);
+                self.pair.filler_stream.write_all(&packet.buf[..packet.size])
+                    .inspect_err(|_| self.client_failed = true)?;
+                self.filler.filler_was_sent(packet.size);
                 some_work = true;
             }
         }
-        if let Some(collected_info) = filler.clean_almost_full() {
-            let start = Instant::now();
-            self.ct_state.send(ProxyState::Info(collected_info))
-                .context("Send hot statistic info")?;
+        if self.send_collected_info()? {
             some_work = true;
-            if start.elapsed() > Duration::from_millis(3){
-                bail!("Долгая отправка данных по статистике");
-            }
         }
         if let Ok(command) = self.cr_command.try_recv() {
             match command {
                 RuntimeCommand::SetSpeed(speed_command) => {
                     if let SpeedCorrectorCommand::SetSpeed(speed) = speed_command {
                         debug!("speed was updated {speed}");
-                        filler.set_speed(speed);
+                        self.filler.set_speed(speed);
                     }else if let SpeedCorrectorCommand::SwitchOff = speed_command {
                         debug!("free mode enter");
                         self.free_mode = true;
                     }
                 }
+                RuntimeCommand::Drain => self.start_drain(),
             }
+            some_work = true;
         }
-        if !some_work {
-            sleep(BURNOUT_DELAY);
-        }
-        Ok(())
+        Ok(some_work)
     }
 
-    fn free_loop(
-        &mut self,
-        filler: &mut Filler,
-    ) -> Result<(), Error> {
-        let mut some_work = false;
-        let size= self.pair.client_stream.read(&mut self.buf[..])?;
-        if size > 0 {
-            self.pair.up_stream.write_all(&self.buf[..size])?;
-            some_work = true;
-        }
-        let vpn_incoming_data_size = self.pair.up_stream.read(&mut self.buf[..])?;
+    fn free_loop(&mut self) -> Result<bool, Error> {
+        let mut some_work = self.forward_client()?;
+        //клиент не принял прежнее - данные VPN сервера подождут в его сокете
+        let vpn_incoming_data_size = if self.pair.client_stream.has_pending_writes() {
+            0
+        } else {
+            self.pair.up_stream.read(&mut self.buf[..self.packet_size])?
+        };
         if vpn_incoming_data_size > 0  {
-            self.pair.client_stream.write_all(&self.buf[..vpn_incoming_data_size])?;
-            filler.data_was_sent(vpn_incoming_data_size);
+            self.pair.client_stream.write_all(&self.buf[..vpn_incoming_data_size])
+                .inspect_err(|_| self.client_failed = true)?;
+            self.filler.data_was_sent(vpn_incoming_data_size);
             some_work = true;
         }
-
-        if let Some(collected_info) = filler.clean_almost_full() {
-            let start = Instant::now();
-            self.ct_state.send(ProxyState::Info(collected_info))
-                .context("Send hot statistic info")?;
+        if self.send_collected_info()? {
             some_work = true;
-            if start.elapsed() > Duration::from_millis(3){
-                bail!("Долгая отправка данных по статистике");
-            }
         }
         if let Ok(command) = self.cr_command.try_recv() {
             match command {
                 RuntimeCommand::SetSpeed(speed_command) => {
                     if let SpeedCorrectorCommand::SetSpeed(speed) = speed_command {
-                        debug!("speed was set {speed}");
-                        filler.set_speed(speed);
-                        self.free_mode = false;
+                        if !self.draining {
+                            debug!("speed was set {speed}");
+                            self.filler.set_speed(speed);
+                            self.free_mode = false;
+                        }
                     }
                 }
+                RuntimeCommand::Drain => self.start_drain(),
             }
+            some_work = true;
         }
-        if !some_work {
-            sleep(BURNOUT_DELAY);
+        Ok(some_work)
+    }
+
+    /**
+        Данные клиента перенаправляем VPN серверу, заполнитель клиента только учитываем.
+        true - что-то прочитали
+    */
+    fn forward_client(&mut self) -> Result<bool, Error> {
+        //VPN сервер не принял прежнее - данные клиента подождут в его сокете
+        if self.pair.up_stream.has_pending_writes() {
+            return Ok(false);
         }
-        Ok(())
+        let size = self.pair.client_stream.read(&mut self.buf[..])
+            .inspect_err(|_| self.client_failed = true)?;
+        if size > 0 {
+            //trace!("->> {}", size);
+            self.pair.up_stream.write_all(&self.buf[..size])?;
+            self.received_data += size;
+            return Ok(true);
+        }
+        let filler_size = self.pair.client_stream.take_filler_received();
+        self.received_filler += filler_size;
+        Ok(filler_size > 0)
+    }
+
+    /**
+        Дописываем то, что сокеты не приняли (пришло событие готовности к записи или тик реактора)
+    */
+    fn flush(&mut self) -> Result<(), Error> {
+        self.pair.client_stream.flush()
+            .inspect_err(|_| self.client_failed = true)?;
+        self.pair.up_stream.flush()
+    }
+
+    /**
+        Клиент пропал - VPN сервер не трогаем (его данные ждут в буфере сокета), ждем переподключения
+    */
+    fn detach(&mut self, reason: &str) {
+        if let Some(session) = &self.pair.session {
+            info!("Client {} disconnected ({reason}), waiting {:?} for resume", self.key, session.grace);
+        }
+        self.pair.client_stream.shutdown();
+        self.detached_since = Some(Instant::now());
+        self.fds_changed = true;
+    }
+
+    /**
+        Пока клиента нет только запоминаем команды (закрепленная скорость не должна потеряться).
+        false - ждем дальше
+    */
+    fn wait_resume(&mut self, detached_since: Instant) -> bool {
+        while let Ok(command) = self.cr_command.try_recv() {
+            match command {
+                RuntimeCommand::SetSpeed(SpeedCorrectorCommand::SetSpeed(speed)) if !self.draining => {
+                    self.filler.set_speed(speed);
+                    self.free_mode = false;
+                }
+                RuntimeCommand::SetSpeed(SpeedCorrectorCommand::SetSpeed(_)) => {}
+                RuntimeCommand::SetSpeed(SpeedCorrectorCommand::SwitchOff) => self.free_mode = true,
+                RuntimeCommand::Drain => self.start_drain(),
+            }
+        }
+        let grace = self.pair.session.as_ref().map(|session| session.grace).unwrap_or_default();
+        if detached_since.elapsed() >= grace {
+            info!("Client {} didn't resume in {:?}", self.key, grace);
+            self.running.store(false, Ordering::Relaxed);
+        } else if self.draining {
+            //дописывать некому
+            info!("Client {} drained while disconnected", self.key);
+            self.running.store(false, Ordering::Relaxed);
+        }
+        false
+    }
+
+    /**
+        Клиент переподключился (в том числе если обрыв мы еще не заметили):
+        подтверждаем, повторяем недошедшее и продолжаем с новым сокетом.
+        true - транспорт клиента заменен
+    */
+    fn receive_resume(&mut self) -> bool {
+        let Some(session) = &self.pair.session else {
+            return false;
+        };
+        let Some(Resume { stream, received }) = session.try_resume() else {
+            return false;
+        };
+        let split = match split_server_stream_resumed(stream, session.state.clone(), received) {
+            Ok(split) => split,
+            Err(e) => {
+                warn!("Client {} failed to resume: {e}", self.key);
+                return false;
+            }
+        };
+        if self.detached_since.is_none() {
+            self.pair.client_stream.shutdown();
+        }
+        self.pair.client_stream = split.data_stream;
+        self.pair.filler_stream = split.filler_stream;
+        self.detached_since = None;
+        self.fds_changed = true;
+        info!("Client {} resumed, {} packets retransmitted", self.key, self.unacked());
+        true
+    }
+
+    fn unacked(&self) -> usize {
+        self.pair.session.as_ref()
+            .map(|session| session.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).unacked())
+            .unwrap_or(0)
+    }
+
+    fn start_drain(&mut self) {
+        debug!("drain {}", self.key);
+        self.draining = true;
+        self.free_mode = true;
+    }
+
+    /**
+        Все дописано - завершаем задачу (finish закроет обе стороны)
+    */
+    fn check_drained(&mut self, some_work: bool) {
+        if some_work {
+            self.drain_idle_since = None;
+            return;
+        }
+        let since = *self.drain_idle_since.get_or_insert_with(Instant::now);
+        if since.elapsed() >= DRAIN_QUIET {
+            info!("Client {} drained", self.key);
+            self.running.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn send_collected_info(&mut self) -> Result<bool, Error> {
+        if let Some(mut collected_info) = self.filler.clean_almost_full() {
+            collected_info.received_data = std::mem::take(&mut self.received_data);
+            collected_info.received_filler = std::mem::take(&mut self.received_filler);
+            collected_info.write_blocked = self.pair.client_stream.take_write_blocked();
+            let start = Instant::now();
+            self.ct_state.send(ProxyState::Info(collected_info))
+                .context("Send hot statistic info")?;
+            if start.elapsed() > Duration::from_millis(3){
+                bail!("Долгая отправка данных по статистике");
+            }
+            return Ok(true);
+        }
+        Ok(false)
     }
 }
 #[cfg(test)]
@@ -0,0 +1,110 @@
+/*
+Ключи клиентов для аутентификации общим секретом.
+Файл ключей - по строке на клиента:
+   имя_клиента секрет
+Пустые строки и строки начинающиеся с # пропускаются
+*/
+use easy_error::{bail, ensure, Error, ResultExt};
+use splitter::auth::{verify_response, verify_resume_response, Challenge, Response};
+use splitter::session::SessionId;
+use std::collections::HashMap;
+use std::fs;
+
+/**
+    Общий ключ шифрования потока (см. splitter::secure_transport) - файл с одной строкой
+*/
+pub fn load_psk(path: &str) -> Result<Vec<u8>, Error> {
+    let content = fs::read_to_string(path).context(format!("Не удалось прочитать файл ключа {path}"))?;
+    let psk = content.trim();
+    ensure!(!psk.is_empty(), "Пустой ключ в файле {path}");
+    Ok(psk.as_bytes().to_vec())
+}
+
+#[derive(Default, Clone)]
+pub struct ClientKeys {
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl ClientKeys {
+    pub fn load(path: &str) -> Result<ClientKeys, Error> {
+        let content = fs::read_to_string(path).context(format!("Не удалось прочитать файл ключей {path}"))?;
+        ClientKeys::parse(&content).context(format!("Ошибка в файле ключей {path}"))
+    }
+
+    pub fn parse(content: &str) -> Result<ClientKeys, Error> {
+        let mut keys = HashMap::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((name, secret)) = line.split_once(char::is_whitespace) else {
+                bail!("Строка {}: ожидается 'имя секрет'", index + 1);
+            };
+            let secret = secret.trim();
+            ensure!(!secret.is_empty(), "Строка {}: пустой секрет", index + 1);
+            ensure!(keys.insert(name.to_string(), secret.as_bytes().to_vec()).is_none(),
+                "Строка {}: клиент {} указан повторно", index + 1, name);
+        }
+        Ok(ClientKeys { keys })
+    }
+
+    pub fn get(&self, client_name: &str) -> Option<&[u8]> {
+        self.keys.get(client_name).map(|key| key.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /**
+        Проверка ответа клиента на запрос аутентификации
+    */
+    pub fn verify(&self, client_name: &str, challenge: &Challenge, response: &Response) -> Result<(), Error> {
+        let Some(key) = self.get(client_name) else {
+            //неизвестному клиенту отвечаем за то же время, что и известному
+            verify_response(&[], challenge, client_name, response);
+            bail!("Нет ключа для клиента {}", client_name);
+        };
+        ensure!(verify_response(key, challenge, client_name, response),
+            "Неверный ответ аутентификации клиента {}", client_name);
+        Ok(())
+    }
+
+    /**
+        Проверка ответа на запрос аутентификации при восстановлении сессии клиента client_name
+    */
+    pub fn verify_resume(&self, client_name: &str, challenge: &Challenge, session: &SessionId, response: &Response) -> Result<(), Error> {
+        let Some(key) = self.get(client_name) else {
+            bail!("Нет ключа для клиента {}", client_name);
+        };
+        ensure!(verify_resume_response(key, challenge, session, response),
+            "Неверный ответ аутентификации при восстановлении сессии клиента {}", client_name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::auth::ClientKeys;
+
+    #[test]
+    fn parse_keys_test() {
+        let keys = ClientKeys::parse("
+# роутеры
+router-1 secret one
+router-2\tsecret2
+").unwrap();
+        assert_eq!(2, keys.len());
+        assert_eq!(Some(&b"secret one"[..]), keys.get("router-1"));
+        assert_eq!(Some(&b"secret2"[..]), keys.get("router-2"));
+        assert_eq!(None, keys.get("router-3"));
+
+        assert!(ClientKeys::parse("router-1").is_err());
+        assert!(ClientKeys::parse("router-1 a\nrouter-1 b").is_err());
+    }
+}
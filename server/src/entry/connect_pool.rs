@@ -0,0 +1,96 @@
+/*
+Пул потоков подключения к серверам за эквалайзером (VPN серверы, цели SOCKS5).
+Подключение блокирующее (до CONNECT_TIMEOUT на каждый адрес), поэтому идет не в потоке рукопожатия.
+Потоков фиксированное число, очередь ограничена: при наплыве клиентов лишним отказываем,
+а не запускаем поток на каждого.
+Потоки завершаются вместе с пулом (когда закрывается очередь).
+*/
+use easy_error::{Error, ResultExt};
+use splitter::transport::Transport;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+//одновременных подключений на слушателя: перебор недоступных серверов занимает поток на CONNECT_TIMEOUT
+pub const CONNECT_WORKERS: usize = 16;
+//клиентов, ждущих свободного потока; остальным отказ
+pub const CONNECT_QUEUE: usize = 256;
+
+type Job = (Box<dyn Transport>, Box<dyn FnOnce(Box<dyn Transport>) + Send>);
+
+pub struct ConnectPool {
+    ct_job: SyncSender<Job>,
+}
+
+impl ConnectPool {
+    pub fn new(workers: usize, queue: usize) -> Result<ConnectPool, Error> {
+        let (ct_job, cr_job) = sync_channel::<Job>(queue);
+        let cr_job = Arc::new(Mutex::new(cr_job));
+        for number in 0..workers {
+            let cr_job = cr_job.clone();
+            thread::Builder::new()
+                .name(format!("connect_{number}"))
+                .spawn(move || work(cr_job))
+                .context("Connect thread")?;
+        }
+        Ok(Self { ct_job })
+    }
+
+    /**
+        Подключение клиента stream в свободном потоке.
+        Err - очередь заполнена, клиент возвращается для отказа
+    */
+    pub fn execute(
+        &self,
+        stream: Box<dyn Transport>,
+        connect: impl FnOnce(Box<dyn Transport>) + Send + 'static,
+    ) -> Result<(), Box<dyn Transport>> {
+        match self.ct_job.try_send((stream, Box::new(connect))) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full((stream, _)) | TrySendError::Disconnected((stream, _))) => Err(stream),
+        }
+    }
+}
+
+fn work(cr_job: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        //блокировка только на время ожидания задания, подключение идет без нее
+        let job = cr_job.lock().expect("connect queue").recv();
+        match job {
+            Ok((stream, connect)) => connect(stream),
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::connect_pool::ConnectPool;
+    use splitter::transport::Transport;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn full_queue_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let stream = || -> Box<dyn Transport> { Box::new(TcpStream::connect(address).unwrap()) };
+        let pool = ConnectPool::new(1, 1).unwrap();
+        let (ct_started, cr_started) = channel();
+        let (ct_release, cr_release) = channel::<()>();
+        //единственный поток занят медленным подключением
+        assert!(pool.execute(stream(), move |_| {
+            ct_started.send(()).unwrap();
+            let _ = cr_release.recv();
+        }).is_ok());
+        cr_started.recv_timeout(Duration::from_secs(1)).unwrap();
+        let (ct_done, cr_done) = channel();
+        assert!(pool.execute(stream(), move |_| ct_done.send(()).unwrap()).is_ok());
+        //очередь заполнена - клиент возвращается
+        assert!(pool.execute(stream(), |_| unreachable!()).is_err());
+        drop(ct_release);
+        cr_done.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(pool.execute(stream(), |_| {}).is_ok());
+    }
+}
@@ -1,97 +1,188 @@
-use splitter::server_side_split::split_server_stream;
-use crate::objects::{Pair, ONE_PACKET_MAX_SIZE};
-use log::{error, info};
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::sync::mpsc::{Receiver, Sender};
+use splitter::server_side_split::{split_server_stream_resumable, split_server_stream_with};
+use splitter::udp::UdpDataStream;
+use splitter::{DataStream, FrameOptions};
+use crate::objects::Pair;
+use clap::ValueEnum;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::fmt;
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread::{sleep, JoinHandle};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{io, thread};
-use splitter::{DataStream};
+use std::thread;
+use easy_error::{bail, ensure, Error, ResultExt};
+use crate::entry::auth::ClientKeys;
+use crate::entry::handshake::HandshakeStage;
+use crate::entry::health::{connect, UpstreamHealth, CONNECT_TIMEOUT};
+use crate::entry::routing::Router;
+use crate::entry::session::PairSession;
 use splitter::server_side_vpn_stream::VpnDataStream;
+use splitter::transport::Transport;
 
+/**
+    Что за сервис стоит за эквалайзером
+*/
+#[derive(Deserialize, ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerMode {
+    //OpenVPN (tcp): у клиента одно подключение, новое заменяет старое
+    #[default]
+    #[value(name = "openvpn")]
+    OpenVpn,
+    //любой TCP сервис (WireGuard через TCP, SOCKS, HTTP прокси):
+    //у клиента может быть много подключений одновременно, у каждого своя пара
+    Tcp,
+    //OpenVPN (udp) или WireGuard: пакет клиента - датаграмма серверу, как в openvpn одна пара на клиента
+    Udp,
+    //эквалайзер сам SOCKS5 сервер (upstream не нужен), у каждого подключения своя пара как в tcp
+    Socks5,
+}
+
+impl fmt::Display for ListenerMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerMode::OpenVpn => write!(f, "openvpn"),
+            ListenerMode::Tcp => write!(f, "tcp"),
+            ListenerMode::Udp => write!(f, "udp"),
+            ListenerMode::Socks5 => write!(f, "socks5"),
+        }
+    }
+}
+
+#[cfg(test)]
 pub fn start_listen(
-    client_accept_port: u16,
-    vpn_server_port: u16,
+    listen: String,
+    router: Router,
+    client_keys: Option<ClientKeys>,
+    psk: Option<Vec<u8>>,
+    ct_pair: Sender<Pair>,
+    stop_application_request: Receiver<bool>,
+) -> Result<JoinHandle<()>, Error> {
+    start_listen_with(listen, ListenerMode::OpenVpn, router, client_keys, psk, ct_pair, stop_application_request)
+}
+
+#[cfg(test)]
+pub fn start_listen_with(
+    listen: String,
+    mode: ListenerMode,
+    router: Router,
+    client_keys: Option<ClientKeys>,
+    psk: Option<Vec<u8>>,
+    ct_pair: Sender<Pair>,
+    stop_application_request: Receiver<bool>,
+) -> Result<JoinHandle<()>, Error> {
+    start_listen_on(bind(&listen)?, mode, router, client_keys, psk, ct_pair, stop_application_request)
+}
+
+/**
+    Порт открываем до запуска потоков: не открылся - сервер не запускается
+*/
+pub fn bind(listen: &str) -> Result<TcpListener, Error> {
+    let client_listener = TcpListener::bind(listen).context(format!("Не удалось открыть порт {listen}"))?;
+    client_listener.set_nonblocking(true).context(format!("Set nonblocking for {listen}"))?;
+    Ok(client_listener)
+}
+
+pub fn start_listen_on(
+    client_listener: TcpListener,
+    mode: ListenerMode,
+    router: Router,
+    client_keys: Option<ClientKeys>,
+    psk: Option<Vec<u8>>,
     ct_pair: Sender<Pair>,
     stop_application_request: Receiver<bool>,
-) -> thread::Result<JoinHandle<()>> {
-    let join = thread::Builder::new()
+) -> Result<JoinHandle<()>, Error> {
+    //без ключей SOCKS5 сервер - открытый прокси для любого, кто знает порт
+    ensure!(mode != ListenerMode::Socks5 || client_keys.is_some(), "Режим socks5 только с ключами клиентов (keys)");
+    let (ct_client, cr_client) = channel();
+    let handshake_stage = HandshakeStage::new(cr_client, ct_pair, mode, router, client_keys, psk)?;
+    thread::Builder::new()
         .name("server_listen".to_string()).spawn(move || {
-        let client_listener = TcpListener::bind(format!("0.0.0.0:{}", client_accept_port))
-            .expect("bind to client port");
-        client_listener
-            .set_nonblocking(true)
-            .expect("TODO: panic message");
         let sleep_ms = std::time::Duration::from_millis(50);
+        let handshake_join = HandshakeStage::thread_start(handshake_stage);
 
         loop {
-            match client_listener.accept() {
-                Ok((stream, _addr)) => {
-                    if let Ok(vpn_proxy) = handle_client(stream, vpn_server_port) {
-                        let result = ct_pair.send(vpn_proxy);
-                        if result.is_err() {
-                            error!("VPN pipe is broken");
-                        }
-                    }
+            //забираем все ожидающие подключения, приветствие ждем в отдельном потоке
+            while let Ok((stream, addr)) = client_listener.accept() {
+                println!("Client connected. Theirs address {:?}", addr);
+                if ct_client.send(stream).is_err() {
+                    error!("Handshake pipe is broken");
                 }
-                _ => {}
             }
             if let Ok(_) = stop_application_request.try_recv() {
                 break;
             }
             sleep(sleep_ms);
         }
-    }).expect("server_listen thread started");
-    Ok(join)
+        drop(ct_client);
+        let _ = handshake_join.join();
+    }).context("server_listen thread started")
 }
 
-fn handle_client(client_stream: TcpStream, vpn_server_port: u16) -> io::Result<Pair> {
-    println!(
-        "Client connected. Theirs address {:?}",
-        client_stream.peer_addr()?
-    );
-    let result = TcpStream::connect(format!("127.0.0.1:{}", vpn_server_port));
-    if result.is_ok() {
-        info!("Connected to the VPN server!");
-        Ok(Pair::new(result?, client_stream))
-    } else {
-        error!("Couldn't connect to VPN server...");
-        client_stream.shutdown(Shutdown::Both)?;
-        Err(result.err().unwrap())
+/**
+    Клиент опознан - подключаемся к первому ответившему VPN серверу из его маршрута
+*/
+pub fn connect_upstream(
+    client_stream: Box<dyn Transport>,
+    mode: ListenerMode,
+    upstreams: &[String],
+    health: &UpstreamHealth,
+    key: String,
+    options: FrameOptions,
+    session: Option<PairSession>,
+) -> Result<Pair, Error> {
+    for upstream in health.order(upstreams) {
+        match connect_to(upstream, mode) {
+            Ok(up_stream) => {
+                health.report(upstream, true);
+                info!("Connected to the VPN server {upstream} for {key}");
+                return Ok(Pair::new(up_stream, mode == ListenerMode::Udp, client_stream, key, options, session));
+            }
+            Err(e) => {
+                warn!("Couldn't connect to VPN server {upstream}: {e}");
+                health.report(upstream, false);
+            }
+        }
     }
+    error!("Couldn't connect to VPN server for {key}");
+    client_stream.shutdown();
+    bail!("Connect to VPN server: все серверы недоступны {:?}", upstreams)
+}
+
+fn connect_to(upstream: &str, mode: ListenerMode) -> Result<Box<dyn DataStream>, Error> {
+    Ok(match mode {
+        ListenerMode::OpenVpn | ListenerMode::Tcp => Box::new(VpnDataStream::new(connect(upstream, CONNECT_TIMEOUT)?)),
+        ListenerMode::Udp => Box::new(UdpDataStream::connect(upstream)?),
+        //к цели подключается socks::connect_target
+        ListenerMode::Socks5 => bail!("SOCKS5 без VPN сервера"),
+    })
 }
 
 impl Pair {
-    pub fn new(up_stream: TcpStream, client_stream: TcpStream) -> Pair {
-        let mut split = split_server_stream(client_stream);
-        let filler_stream = &mut split.filler_stream;
-        //ожидаем пол секунды (нужно узнать имя клиента)
-        sleep(Duration::from_millis(500));
-        let key = Pair::get_key(filler_stream);
+    /**
+        options - формат пакетов клиента, о котором договорились в рукопожатии,
+        datagram - up_stream читается по датаграмме, делить их на пакеты нельзя,
+        session - формат пакетов берется из сессии
+    */
+    pub fn new(
+        up_stream: Box<dyn DataStream>,
+        datagram: bool,
+        client_stream: Box<dyn Transport>,
+        key: String,
+        options: FrameOptions,
+        session: Option<PairSession>,
+    ) -> Pair {
+        let split = match &session {
+            Some(session) => split_server_stream_resumable(client_stream, session.state.clone()),
+            None => split_server_stream_with(client_stream, options),
+        };
         Pair {
-            up_stream: Box::new(VpnDataStream::new(up_stream)),
+            up_stream,
             client_stream: split.data_stream,
             filler_stream: split.filler_stream,
             key,
+            datagram,
+            session,
         }
     }
-
-    fn get_key(filler_stream: &mut Box<dyn DataStream>) -> String {
-        let mut buf: [u8; ONE_PACKET_MAX_SIZE] = [0; ONE_PACKET_MAX_SIZE];
-        if let Ok(size) = filler_stream.read(&mut buf){
-            if size > 1 && buf[0] == 0x01 {//TODO 0x01 - пакет авторизации добавить в какой-нибудь модуль обработку
-                if let Ok(key) = std::str::from_utf8(&buf[1..size]){
-                    return key.to_string();
-                }
-            }
-        }
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            .to_string()
-            .into()
-    }
 }
-
-
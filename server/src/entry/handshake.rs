@@ -0,0 +1,435 @@
+/*
+Стадия рукопожатия.
+Принятые подключения ждут приветствия клиента в отдельном потоке,
+не задерживая прием новых подключений. У каждого подключения свой крайний срок,
+опоздавшее (но уложившееся в срок) имя клиента не теряется.
+Опознанный клиент подключается к своему VPN серверу (см. routing.rs) в пуле подключений (см. connect_pool.rs)
+и уходит в оркестратор.
+Клиенту без маршрута отправляется отказ (TYPE_ERROR).
+В режиме tcp у каждого подключения свой ключ пары (ключ клиента#номер).
+В режиме socks5 после приветствия ждем запрос SOCKS5 и подключаемся к цели (см. socks.rs).
+Клиенту, договорившемуся о CAPABILITY_RESUME, выдается номер сессии (кроме режима socks5),
+переподключившийся с этим номером клиент передается своей паре (см. session.rs),
+при настроенных ключах клиентов - только после ответа на запрос аутентификации.
+*/
+use crate::entry::auth::ClientKeys;
+use crate::entry::connect_pool::{ConnectPool, CONNECT_QUEUE, CONNECT_WORKERS};
+use crate::entry::entry_point::{connect_upstream, ListenerMode};
+use crate::entry::routing::Router;
+use crate::entry::session::{PairSession, Resume};
+use crate::entry::socks::{connect_target, refuse, SocksMessage, SocksRequest, REPLY_GENERAL_FAILURE};
+use crate::objects::Pair;
+use easy_error::{bail, ensure, Error, ResultExt};
+use log::{error, info, warn};
+use splitter::auth::{new_challenge, Challenge};
+use splitter::{FrameDecoder, FrameOptions, MAX_BODY_SIZE};
+use splitter::handshake::{read_auth_response, read_data, read_identification, send_challenge, send_data, send_error, send_hello_ack, Hello, HelloAck, Identification, CAPABILITY_RESUME, SUPPORTED_CAPABILITIES};
+use splitter::session::SessionId;
+use std::mem;
+use splitter::secure_transport::SecureTransport;
+use splitter::transport::Transport;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::thread;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+//сколько ждем приветствия (и ответа на запрос аутентификации) от клиента
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const IDLE_DELAY: Duration = Duration::from_millis(5);
+//номер подключения для ключа пары в режимах tcp и socks5 (общий для всех слушателей)
+static CONNECTION_NUMBER: AtomicU64 = AtomicU64::new(0);
+
+enum HandshakeState {
+    //ждем TYPE_HELLO (или имя клиента старого образца)
+    AwaitHello,
+    //ждем ответа на запрос аутентификации
+    AwaitAuth { hello: Hello, challenge: Challenge },
+    //ждем ответа на запрос аутентификации при восстановлении сессии клиента client_name
+    AwaitResumeAuth { client_name: String, id: SessionId, received: u64, challenge: Challenge },
+    //клиент опознан, ждем приветствие и запрос SOCKS5
+    Socks { key: String, options: FrameOptions, request: SocksRequest },
+}
+
+enum Step {
+    //от клиента еще ничего не пришло
+    Wait,
+    //клиент опознан (ключ пары, формат пакетов и адреса его VPN серверов), можно подключаться
+    Complete(String, FrameOptions, Vec<String>),
+    //запрос SOCKS5 получен (ключ клиента, формат пакетов, адрес цели, данные вслед за запросом)
+    Connect(String, FrameOptions, String, Vec<u8>),
+    //клиент переподключился (номер сессии, сколько пакетов данных получил)
+    Resume(SessionId, u64),
+}
+
+struct PendingClient {
+    //поверх TCP может быть шифрование (SecureTransport)
+    stream: Box<dyn Transport>,
+    //клиент прошел обмен ключами с общим PSK - ошибки приветствия не прощаем
+    secure: bool,
+    //приветствие может прийти по частям
+    decoder: FrameDecoder,
+    deadline: Instant,
+    state: HandshakeState,
+    //выдана в подтверждении приветствия, уходит в пару
+    session: Option<PairSession>,
+}
+
+pub struct HandshakeStage {
+    cr_client: Receiver<TcpStream>,
+    ct_pair: Sender<Pair>,
+    mode: ListenerMode,
+    //адреса серверов за эквалайзером
+    router: Router,
+    client_keys: Option<ClientKeys>,
+    //общий ключ шифрования потока, None - поток открытый (внутри SSH туннеля)
+    psk: Option<Vec<u8>>,
+    pending: Vec<PendingClient>,
+    //подключения к серверам за эквалайзером
+    connect_pool: ConnectPool,
+}
+
+impl HandshakeStage {
+    pub fn new(
+        cr_client: Receiver<TcpStream>,
+        ct_pair: Sender<Pair>,
+        mode: ListenerMode,
+        router: Router,
+        client_keys: Option<ClientKeys>,
+        psk: Option<Vec<u8>>,
+    ) -> Result<HandshakeStage, Error> {
+        Ok(Self {
+            cr_client,
+            ct_pair,
+            mode,
+            router,
+            client_keys,
+            psk,
+            pending: vec![],
+            connect_pool: ConnectPool::new(CONNECT_WORKERS, CONNECT_QUEUE)?,
+        })
+    }
+
+    /**
+        Поток завершается, когда закрывается канал новых подключений
+    */
+    pub fn thread_start(mut instance: HandshakeStage) -> JoinHandle<()> {
+        thread::Builder::new()
+            .name("handshake".to_string())
+            .spawn(move || {
+                while instance.receive_new_clients() {
+                    instance.step_all();
+                    if !instance.pending.is_empty() {
+                        sleep(IDLE_DELAY);
+                    }
+                }
+                info!("Exit from handshake thread");
+            })
+            .expect("handshake thread started")
+    }
+
+    fn receive_new_clients(&mut self) -> bool {
+        //если ждать некого - спим до нового подключения
+        if self.pending.is_empty() {
+            match self.cr_client.recv() {
+                Ok(stream) => self.append(stream),
+                Err(_) => return false,
+            }
+        }
+        loop {
+            match self.cr_client.try_recv() {
+                Ok(stream) => self.append(stream),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    fn append(&mut self, stream: TcpStream) {
+        match PendingClient::new(stream, self.psk.as_deref()) {
+            Ok(client) => self.pending.push(client),
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    fn step_all(&mut self) {
+        let mut i = 0;
+        while i < self.pending.len() {
+            match self.pending[i].step(self.mode, self.client_keys.as_ref(), &self.router) {
+                Ok(Step::Wait) => {
+                    i += 1;
+                }
+                Ok(Step::Complete(key, options, _)) if self.mode == ListenerMode::Socks5 => {
+                    self.pending[i].await_socks(key, options);
+                    i += 1;
+                }
+                Ok(Step::Complete(key, options, upstreams)) => {
+                    let client = self.pending.swap_remove(i);
+                    self.complete(client.stream, key, options, upstreams, client.session);
+                }
+                Ok(Step::Connect(key, options, target, rest)) => {
+                    let client = self.pending.swap_remove(i);
+                    self.connect(client.stream, key, options, target, rest);
+                }
+                Ok(Step::Resume(id, received)) => {
+                    let client = self.pending.swap_remove(i);
+                    self.resume(client.stream, id, received);
+                }
+                Err(e) => {
+                    //не прошедшего проверку клиента отключаем до подключения к VPN серверу
+                    warn!("Client rejected: {}", e);
+                    let client = self.pending.swap_remove(i);
+                    client.stream.shutdown();
+                }
+            }
+        }
+    }
+
+    /**
+        У клиента может быть много подключений - у каждой пары свой ключ
+    */
+    fn pair_key(&self, key: String) -> String {
+        match self.mode {
+            ListenerMode::OpenVpn | ListenerMode::Udp => key,
+            ListenerMode::Tcp | ListenerMode::Socks5 => format!("{key}#{}", CONNECTION_NUMBER.fetch_add(1, Ordering::Relaxed) + 1),
+        }
+    }
+
+    /**
+        Подключаемся к VPN серверу в пуле подключений: перебор недоступных серверов (до CONNECT_TIMEOUT на каждый)
+        не задерживает рукопожатия остальных клиентов
+    */
+    fn complete(&mut self, stream: Box<dyn Transport>, key: String, options: FrameOptions, upstreams: Vec<String>, session: Option<PairSession>) {
+        let key = self.pair_key(key);
+        let mode = self.mode;
+        let health = self.router.health().clone();
+        let ct_pair = self.ct_pair.clone();
+        let queued = self.connect_pool.execute(stream, move |stream| {
+            let result = stream.set_nonblocking(false)
+                .context("Restore blocking mode")
+                .and_then(|_| connect_upstream(stream, mode, &upstreams, &health, key, options, session));
+            if let Ok(pair) = result {
+                if ct_pair.send(pair).is_err() {
+                    error!("VPN pipe is broken");
+                }
+            }
+        });
+        if let Err(mut stream) = queued {
+            warn!("Client rejected: очередь подключений заполнена");
+            let _ = send_error(&mut stream, "Сервер перегружен, повторите позже");
+            stream.shutdown();
+        }
+    }
+
+    /**
+        Подключаемся к цели SOCKS5 в пуле подключений, как и к VPN серверу
+    */
+    fn connect(&mut self, stream: Box<dyn Transport>, key: String, options: FrameOptions, target: String, rest: Vec<u8>) {
+        let key = self.pair_key(key);
+        let allow_local = self.router.local_targets();
+        let ct_pair = self.ct_pair.clone();
+        let queued = self.connect_pool.execute(stream, move |stream| {
+            match connect_target(stream, &target, &rest, key, options, allow_local) {
+                Ok(pair) => {
+                    if ct_pair.send(pair).is_err() {
+                        error!("VPN pipe is broken");
+                    }
+                }
+                Err(e) => warn!("SOCKS5 {target}: {e}"),
+            }
+        });
+        if let Err(stream) = queued {
+            warn!("Client rejected: очередь подключений заполнена");
+            refuse(stream, REPLY_GENERAL_FAILURE, options);
+        }
+    }
+
+    /**
+        Пара сама подтвердит восстановление и повторит недошедшие пакеты
+    */
+    fn resume(&self, stream: Box<dyn Transport>, id: SessionId, received: u64) {
+        match self.router.sessions().resume(&id, Resume { stream, received }) {
+            Ok(()) => info!("Client resumes session"),
+            Err(Resume { mut stream, .. }) => {
+                warn!("Client rejected: сессия не найдена");
+                let _ = send_error(&mut stream, "Сессия не найдена или истекла");
+                stream.shutdown();
+            }
+        }
+    }
+}
+
+impl PendingClient {
+    fn new(stream: TcpStream, psk: Option<&[u8]>) -> Result<PendingClient, Error> {
+        stream.set_nonblocking(true).context("Set nonblocking for handshake")?;
+        let stream: Box<dyn Transport> = match psk {
+            Some(psk) => Box::new(SecureTransport::accept(stream, Some(psk))?),
+            None => Box::new(stream),
+        };
+        Ok(Self {
+            stream,
+            secure: psk.is_some(),
+            decoder: FrameDecoder::new(),
+            deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+            state: HandshakeState::AwaitHello,
+            session: None,
+        })
+    }
+
+    /**
+        Если настроены ключи клиентов - клиент обязан пройти аутентификацию
+    */
+    fn step(&mut self, mode: ListenerMode, client_keys: Option<&ClientKeys>, router: &Router) -> Result<Step, Error> {
+        let expired = Instant::now() > self.deadline;
+        match mem::replace(&mut self.state, HandshakeState::AwaitHello) {
+            HandshakeState::AwaitHello => match read_identification(&mut self.stream, &mut self.decoder) {
+                Ok(Some(Identification::Hello(hello))) => {
+                    if client_keys.is_some() {
+                        //запрос получает и неизвестный клиент (отказ только после ответа) -
+                        //по отказу не узнать, какие имена настроены
+                        let challenge = new_challenge()?;
+                        send_challenge(&mut self.stream, &challenge).context("Send auth challenge")?;
+                        self.state = HandshakeState::AwaitAuth { hello, challenge };
+                        return Ok(Step::Wait);
+                    }
+                    self.accept(hello, mode, router)
+                }
+                Ok(Some(Identification::Resume(id, received))) => {
+                    //без аутентификации сессию перехватил бы любой, узнавший ее номер.
+                    //Неизвестную сессию отклоняем сразу - клиенту нужно начать заново
+                    if let (Some(_), Some(client_name)) = (client_keys, router.sessions().client_name(&id)) {
+                        let challenge = new_challenge()?;
+                        send_challenge(&mut self.stream, &challenge).context("Send resume auth challenge")?;
+                        self.state = HandshakeState::AwaitResumeAuth { client_name, id, received, challenge };
+                        return Ok(Step::Wait);
+                    }
+                    Ok(Step::Resume(id, received))
+                }
+                Ok(Some(Identification::Legacy(name))) => {
+                    ensure!(client_keys.is_none(), "Клиент {} старого образца не поддерживает аутентификацию", name);
+                    info!("Legacy client {}", name);
+                    unconfirmed(name, mode, router)
+                }
+                Ok(Some(Identification::Anonymous)) => {
+                    ensure!(client_keys.is_none(), "Клиент не представился");
+                    warn!("Client didn't introduce itself");
+                    unconfirmed(timestamp_key(), mode, router)
+                }
+                Ok(None) if expired => {
+                    ensure!(client_keys.is_none(), "Клиент не представился за {:?}", HANDSHAKE_TIMEOUT);
+                    warn!("Client didn't introduce itself in {:?}", HANDSHAKE_TIMEOUT);
+                    unconfirmed(timestamp_key(), mode, router)
+                }
+                Ok(None) => Ok(Step::Wait),
+                Err(e) => {
+                    ensure!(client_keys.is_none() && !self.secure, "Ошибка приветствия клиента {}", e);
+                    warn!("Failed to read client hello {}", e);
+                    unconfirmed(timestamp_key(), mode, router)
+                }
+            },
+            HandshakeState::AwaitAuth { hello, challenge } => {
+                if let Some(response) = read_auth_response(&mut self.stream, &mut self.decoder)? {
+                    if let Some(client_keys) = client_keys {
+                        client_keys.verify(&hello.client_name, &challenge, &response)?;
+                    }
+                    return self.accept(hello, mode, router);
+                }
+                ensure!(!expired, "Клиент {} не ответил на запрос аутентификации", hello.client_name);
+                self.state = HandshakeState::AwaitAuth { hello, challenge };
+                Ok(Step::Wait)
+            }
+            HandshakeState::AwaitResumeAuth { client_name, id, received, challenge } => {
+                if let Some(response) = read_auth_response(&mut self.stream, &mut self.decoder)? {
+                    if let Some(client_keys) = client_keys {
+                        client_keys.verify_resume(&client_name, &challenge, &id, &response)?;
+                    }
+                    return Ok(Step::Resume(id, received));
+                }
+                ensure!(!expired, "Клиент {} не ответил на запрос аутентификации", client_name);
+                self.state = HandshakeState::AwaitResumeAuth { client_name, id, received, challenge };
+                Ok(Step::Wait)
+            }
+            HandshakeState::Socks { key, options, mut request } => {
+                let mut buf = [0; MAX_BODY_SIZE];
+                while let Some(size) = read_data(&mut self.stream, &mut self.decoder, &mut buf)? {
+                    request.push(&buf[..size]);
+                }
+                while let Some(message) = request.next_message()? {
+                    match message {
+                        SocksMessage::Greeting(reply) => send_data(&mut self.stream, &reply, options)
+                            .context("Send SOCKS5 greeting")?,
+                        SocksMessage::Connect(target) => return Ok(Step::Connect(key, options, target, request.take_rest())),
+                        SocksMessage::Reject(reply, reason) => {
+                            let _ = send_data(&mut self.stream, &reply, options);
+                            bail!("{key}: {reason}");
+                        }
+                    }
+                }
+                ensure!(!expired, "Клиент {} не прислал запрос SOCKS5 за {:?}", key, HANDSHAKE_TIMEOUT);
+                self.state = HandshakeState::Socks { key, options, request };
+                Ok(Step::Wait)
+            }
+        }
+    }
+
+    /**
+        Клиент опознан - дальше пакеты в согласованном формате (декодер после приветствия пуст)
+    */
+    fn await_socks(&mut self, key: String, options: FrameOptions) {
+        self.decoder = FrameDecoder::with_options(options);
+        self.deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        self.state = HandshakeState::Socks { key, options, request: SocksRequest::default() };
+    }
+
+    fn accept(&mut self, hello: Hello, mode: ListenerMode, router: &Router) -> Result<Step, Error> {
+        let Some(upstreams) = route(&hello.client_name, mode, router) else {
+            let message = format!("Нет маршрута для клиента {}", hello.client_name);
+            send_error(&mut self.stream, &message).context("Send handshake error")?;
+            bail!("{message}");
+        };
+        let sessions = router.sessions();
+        let capabilities = match mode {
+            ListenerMode::OpenVpn | ListenerMode::Tcp | ListenerMode::Udp if sessions.enabled() => SUPPORTED_CAPABILITIES,
+            _ => SUPPORTED_CAPABILITIES & !CAPABILITY_RESUME,
+        };
+        let mut ack = HelloAck::accept(&hello, capabilities)?;
+        if ack.resumable() {
+            let session = sessions.open(&hello.client_name, ack.frame_options())?;
+            ack.session = Some(session.id());
+            self.session = Some(session);
+        }
+        info!("Client {} protocol v{}", hello.client_name, ack.version);
+        send_hello_ack(&mut self.stream, &ack).context("Send hello ack")?;
+        Ok(Step::Complete(hello.client_name, ack.frame_options(), upstreams))
+    }
+}
+
+/**
+    Клиент без подтверждения приветствия (старого образца или не представившийся):
+    отказ отправить некуда, без маршрута просто отключаем
+*/
+fn unconfirmed(key: String, mode: ListenerMode, router: &Router) -> Result<Step, Error> {
+    let Some(upstreams) = route(&key, mode, router) else {
+        bail!("Нет маршрута для клиента {key}");
+    };
+    Ok(Step::Complete(key, FrameOptions::default(), upstreams))
+}
+
+/**
+    SOCKS5 эквалайзер обслуживает сам - маршрут нужен только остальным режимам
+*/
+fn route(key: &str, mode: ListenerMode, router: &Router) -> Option<Vec<String>> {
+    match mode {
+        ListenerMode::Socks5 => Some(vec![]),
+        ListenerMode::OpenVpn | ListenerMode::Tcp | ListenerMode::Udp => router.route(key).map(<[String]>::to_vec),
+    }
+}
+
+fn timestamp_key() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .to_string()
+}
@@ -0,0 +1,192 @@
+/*
+Доступность VPN серверов.
+Отдельный поток периодически пробует подключиться (TCP) к каждому серверу из маршрутов,
+неудачное подключение клиента тоже отмечает сервер недоступным.
+При подключении клиента сначала пробуем доступные серверы (в порядке из настроек),
+недоступные - в последнюю очередь: проверка могла устареть.
+*/
+use crate::admin::protocol::UpstreamStatus;
+use easy_error::{bail, Error, ResultExt};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+//подключение клиента к VPN серверу
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const STOP_CHECK_PERIOD: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Default)]
+pub struct UpstreamHealth {
+    inner: Arc<Mutex<BTreeMap<String, UpstreamState>>>,
+}
+
+struct UpstreamState {
+    healthy: bool,
+    failures: u64,
+    checked_at: Option<Instant>,
+}
+
+impl UpstreamHealth {
+    /**
+        До первой проверки все серверы считаются доступными
+    */
+    #[cfg(test)]
+    pub fn new<'a>(addresses: impl IntoIterator<Item = &'a String>) -> UpstreamHealth {
+        let health = UpstreamHealth::default();
+        health.track(addresses);
+        health
+    }
+
+    /**
+        Добавить серверы к проверке (уже известные не меняются)
+    */
+    pub fn track<'a>(&self, addresses: impl IntoIterator<Item = &'a String>) {
+        let mut states = self.lock();
+        for address in addresses {
+            states.entry(address.clone()).or_insert_with(UpstreamState::new);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, UpstreamState>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        self.lock().keys().cloned().collect()
+    }
+
+    pub fn is_healthy(&self, address: &str) -> bool {
+        self.lock().get(address).map(|state| state.healthy).unwrap_or(true)
+    }
+
+    pub fn report(&self, address: &str, healthy: bool) {
+        let mut states = self.lock();
+        let state = states.entry(address.to_string()).or_insert_with(UpstreamState::new);
+        if state.healthy != healthy {
+            if healthy {
+                info!("Upstream {address} is up");
+            } else {
+                warn!("Upstream {address} is down");
+            }
+        }
+        state.healthy = healthy;
+        state.failures = if healthy { 0 } else { state.failures + 1 };
+        state.checked_at = Some(Instant::now());
+    }
+
+    /**
+        Порядок попыток подключения: доступные, затем остальные
+    */
+    pub fn order<'a>(&self, candidates: &'a [String]) -> Vec<&'a String> {
+        let (mut healthy, unhealthy): (Vec<&String>, Vec<&String>) = candidates.iter()
+            .partition(|address| self.is_healthy(address));
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    pub fn statuses(&self) -> Vec<UpstreamStatus> {
+        let now = Instant::now();
+        self.lock().iter().map(|(address, state)| UpstreamStatus {
+            address: address.clone(),
+            healthy: state.healthy,
+            failures: state.failures,
+            checked_ms_ago: state.checked_at.map(|at| now.duration_since(at).as_millis() as u64),
+        }).collect()
+    }
+}
+
+impl UpstreamState {
+    fn new() -> UpstreamState {
+        UpstreamState { healthy: true, failures: 0, checked_at: None }
+    }
+}
+
+/**
+    Подключение с ограничением времени (адрес может быть именем)
+*/
+pub fn connect(address: &str, timeout: Duration) -> Result<TcpStream, Error> {
+    connect_any(address.to_socket_addrs().context(format!("Адрес {address}"))?, address, timeout)
+}
+
+/**
+    Первое удачное подключение из уже разрешенных адресов, address - для сообщений об ошибках
+*/
+pub fn connect_any(addresses: impl IntoIterator<Item = SocketAddr>, address: &str, timeout: Duration) -> Result<TcpStream, Error> {
+    let mut last_error = None;
+    for socket_address in addresses {
+        match TcpStream::connect_timeout(&socket_address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) => Err(e).context(format!("Connect to {address}")),
+        None => bail!("Адрес {address} не найден"),
+    }
+}
+
+/**
+    Поток проверки доступности, period - пауза между проверками всех серверов
+*/
+pub fn start_health_check(
+    health: UpstreamHealth,
+    period: Duration,
+    stop_application_request: Receiver<bool>,
+) -> Result<JoinHandle<()>, Error> {
+    let join = thread::Builder::new()
+        .name("health_check".to_string()).spawn(move || {
+        'probe: loop {
+            for address in health.addresses() {
+                health.report(&address, connect(&address, PROBE_TIMEOUT).is_ok());
+            }
+            let start = Instant::now();
+            while start.elapsed() < period {
+                if stop_application_request.try_recv().is_ok() {
+                    break 'probe;
+                }
+                sleep(STOP_CHECK_PERIOD);
+            }
+        }
+        info!("Exit from health check thread");
+    }).context("health check thread started")?;
+    Ok(join)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::health::{start_health_check, UpstreamHealth};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn health_check_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let alive = listener.local_addr().unwrap().to_string();
+        //порт освобождается - подключиться к нему не выйдет
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let health = UpstreamHealth::new([&dead, &alive]);
+        assert_eq!(vec![&dead, &alive], health.order(&[dead.clone(), alive.clone()]));
+
+        let (ct_stop, cr_stop) = channel();
+        let join = start_health_check(health.clone(), Duration::from_millis(100), cr_stop).unwrap();
+        sleep(Duration::from_millis(300));
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+
+        assert!(health.is_healthy(&alive));
+        assert!(!health.is_healthy(&dead));
+        assert_eq!(vec![&alive, &dead], health.order(&[dead.clone(), alive.clone()]));
+        let statuses = health.statuses();
+        let dead_status = statuses.iter().find(|status| status.address == dead).unwrap();
+        assert!(dead_status.failures > 0);
+        assert!(dead_status.checked_ms_ago.is_some());
+    }
+}
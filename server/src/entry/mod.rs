@@ -1 +1,8 @@
+pub mod auth;
+pub mod connect_pool;
 pub mod entry_point;
+pub mod handshake;
+pub mod health;
+pub mod routing;
+pub mod session;
+pub mod socks;
@@ -0,0 +1,143 @@
+/*
+Маршрутизация клиентов по серверам (OpenVPN или другой TCP сервис, см. ListenerMode).
+Ключ клиента (имя из приветствия) -> адреса серверов через запятую
+(первый основной, остальные резервные, см. health.rs).
+Шаблон со * на конце задает группу клиентов по началу имени (побеждает самое длинное совпадение).
+Не попавшие ни в один маршрут клиенты уходят на серверы по умолчанию (upstream),
+если они не заданы - получают отказ (TYPE_ERROR).
+Переподключившийся клиент с номером сессии возвращается к своей паре (см. session.rs).
+*/
+use crate::entry::health::UpstreamHealth;
+use crate::entry::session::Sessions;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+const WILDCARD: char = '*';
+const SEPARATOR: char = ',';
+
+#[derive(Clone, Default)]
+pub struct Router {
+    exact: HashMap<String, Vec<String>>,
+    //(начало имени, адреса), отсортированы от длинного к короткому
+    prefixes: Vec<(String, Vec<String>)>,
+    default: Option<Vec<String>>,
+    //общая с потоком проверки (и другими слушателями) доступность серверов
+    health: UpstreamHealth,
+    //по умолчанию восстановление сессий отключено
+    sessions: Sessions,
+    //socks5: подключения к loopback и link-local адресам, по умолчанию запрещены
+    local_targets: bool,
+}
+
+impl Router {
+    /**
+        Адреса из маршрутов добавляются в health
+    */
+    pub fn new(default: Option<String>, routes: &BTreeMap<String, String>, health: UpstreamHealth) -> Router {
+        let mut exact = HashMap::new();
+        let mut prefixes = vec![];
+        for (pattern, upstreams) in routes {
+            match pattern.strip_suffix(WILDCARD) {
+                Some(prefix) => prefixes.push((prefix.to_string(), split_upstreams(upstreams))),
+                None => {
+                    exact.insert(pattern.clone(), split_upstreams(upstreams));
+                }
+            }
+        }
+        prefixes.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        let default = default.as_deref().map(split_upstreams);
+        health.track(exact.values()
+            .chain(prefixes.iter().map(|(_, upstreams)| upstreams))
+            .chain(default.iter())
+            .flatten());
+        Self { exact, prefixes, default, health, sessions: Sessions::default(), local_targets: false }
+    }
+
+    /**
+        Сессии общие для всех слушателей
+    */
+    pub fn with_sessions(mut self, sessions: Sessions) -> Router {
+        self.sessions = sessions;
+        self
+    }
+
+    /**
+        Цели SOCKS5 на самом сервере (127.0.0.1, 169.254.x.x) - только если разрешено явно
+    */
+    pub fn with_local_targets(mut self, allow: bool) -> Router {
+        self.local_targets = allow;
+        self
+    }
+
+    /**
+        Адреса VPN серверов клиента в порядке из настроек, None - маршрута нет
+    */
+    pub fn route(&self, key: &str) -> Option<&[String]> {
+        if let Some(upstreams) = self.exact.get(key) {
+            return Some(upstreams);
+        }
+        self.prefixes.iter()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .map(|(_, upstreams)| upstreams.as_slice())
+            .or(self.default.as_deref())
+    }
+
+    pub fn health(&self) -> &UpstreamHealth {
+        &self.health
+    }
+
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    pub fn local_targets(&self) -> bool {
+        self.local_targets
+    }
+}
+
+/**
+    Один VPN сервер для всех клиентов
+*/
+impl From<String> for Router {
+    fn from(upstream: String) -> Router {
+        Router::new(Some(upstream), &BTreeMap::new(), UpstreamHealth::default())
+    }
+}
+
+pub fn split_upstreams(value: &str) -> Vec<String> {
+    value.split(SEPARATOR)
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::health::UpstreamHealth;
+    use crate::entry::routing::Router;
+    use std::collections::BTreeMap;
+
+    fn route(router: &Router, key: &str) -> Option<String> {
+        router.route(key).map(|upstreams| upstreams.join(","))
+    }
+
+    #[test]
+    fn route_test() {
+        let routes = BTreeMap::from([
+            ("router-1".to_string(), "10.0.0.1:1194".to_string()),
+            ("office-*".to_string(), "10.0.0.2:1194, 10.0.0.4:1194".to_string()),
+            ("office-lab-*".to_string(), "10.0.0.3:1194".to_string()),
+        ]);
+        let router = Router::new(Some("127.0.0.1:1194".to_string()), &routes, UpstreamHealth::default());
+        assert_eq!(Some("10.0.0.1:1194".to_string()), route(&router, "router-1"));
+        assert_eq!(Some("10.0.0.2:1194,10.0.0.4:1194".to_string()), route(&router, "office-7"));
+        assert_eq!(Some("10.0.0.3:1194".to_string()), route(&router, "office-lab-2"));
+        assert_eq!(Some("127.0.0.1:1194".to_string()), route(&router, "router-2"));
+        assert_eq!(5, router.health().addresses().len());
+
+        let router = Router::new(None, &routes, UpstreamHealth::default());
+        assert_eq!(Some("10.0.0.1:1194".to_string()), route(&router, "router-1"));
+        assert_eq!(None, route(&router, "router-2"));
+    }
+}
@@ -0,0 +1,150 @@
+/*
+Восстановление сессий (протокол см. splitter session.rs).
+Пара клиента, договорившегося о CAPABILITY_RESUME, после обрыва не закрывается:
+подключение к VPN серверу ждет клиента grace, после чего пара закрывается как обычно.
+Переподключившегося клиента стадия рукопожатия находит по номеру сессии
+и передает его транспорт паре через канал.
+*/
+use easy_error::Error;
+use splitter::session::{new_session_id, Session, SessionId, SharedSession};
+use splitter::transport::Transport;
+use splitter::FrameOptions;
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/**
+    Клиент переподключился: новый транспорт и сколько пакетов данных он получил
+*/
+pub struct Resume {
+    pub stream: Box<dyn Transport>,
+    pub received: u64,
+}
+
+/**
+    Открытые сессии всех пар (общие для всех слушателей)
+*/
+#[derive(Clone, Default)]
+pub struct Sessions {
+    //сколько пара ждет переподключения, 0 - восстановление отключено
+    grace: Duration,
+    open: Arc<Mutex<HashMap<SessionId, OpenSession>>>,
+}
+
+struct OpenSession {
+    ct_resume: Sender<Resume>,
+    //ключом этого клиента подтверждается восстановление
+    client_name: String,
+}
+
+impl Sessions {
+    pub fn new(grace: Duration) -> Sessions {
+        Self {
+            grace,
+            open: Arc::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.grace.is_zero()
+    }
+
+    /**
+        Новая сессия, закрывается вместе с парой (PairSession::drop)
+    */
+    pub fn open(&self, client_name: &str, options: FrameOptions) -> Result<PairSession, Error> {
+        let id = new_session_id()?;
+        let (ct_resume, cr_resume) = channel();
+        self.lock().insert(id, OpenSession { ct_resume, client_name: client_name.to_string() });
+        Ok(PairSession {
+            state: Session::new(id, options).shared(),
+            grace: self.grace,
+            cr_resume,
+            sessions: self.clone(),
+            id,
+        })
+    }
+
+    /**
+        Err - сессии нет (истекла, пара закрыта или сервер перезапускался),
+        транспорт возвращается, чтобы отказать клиенту
+    */
+    pub fn resume(&self, id: &SessionId, resume: Resume) -> Result<(), Resume> {
+        match self.lock().get(id) {
+            Some(open) => open.ct_resume.send(resume).map_err(|e| e.0),
+            None => Err(resume),
+        }
+    }
+
+    /**
+        Кто открыл сессию (None - сессии нет)
+    */
+    pub fn client_name(&self, id: &SessionId) -> Option<String> {
+        self.lock().get(id).map(|open| open.client_name.clone())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SessionId, OpenSession>> {
+        self.open.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/**
+    Сессия пары: счетчики и неподтвержденные пакеты переживают транспорт клиента
+*/
+pub struct PairSession {
+    pub state: SharedSession,
+    pub grace: Duration,
+    cr_resume: Receiver<Resume>,
+    sessions: Sessions,
+    id: SessionId,
+}
+
+impl PairSession {
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    pub fn try_resume(&self) -> Option<Resume> {
+        self.cr_resume.try_recv().ok()
+    }
+}
+
+impl Drop for PairSession {
+    fn drop(&mut self) {
+        self.sessions.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::session::{Resume, Sessions};
+    use splitter::transport::memory_pipe;
+    use splitter::FrameOptions;
+    use std::time::Duration;
+
+    #[test]
+    fn sessions_test() {
+        let sessions = Sessions::new(Duration::from_secs(1));
+        assert!(sessions.enabled());
+        assert!(!Sessions::default().enabled());
+        let session = sessions.open("router-1", FrameOptions::default()).unwrap();
+        let id = session.id();
+        assert_eq!(Some("router-1".to_string()), sessions.client_name(&id));
+        let (stream, _wire) = memory_pipe();
+        assert!(sessions.resume(&id, Resume { stream: Box::new(stream), received: 3 }).is_ok());
+        assert_eq!(3, session.try_resume().unwrap().received);
+        assert!(session.try_resume().is_none());
+
+        drop(session);
+        assert_eq!(0, sessions.len());
+        assert_eq!(None, sessions.client_name(&id));
+        let (stream, _wire) = memory_pipe();
+        assert!(sessions.resume(&id, Resume { stream: Box::new(stream), received: 0 }).is_err());
+    }
+}
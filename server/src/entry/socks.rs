@@ -0,0 +1,286 @@
+/*
+Встроенный SOCKS5 сервер (RFC 1928): эквалайзер сам подключается к сайту вместо VPN сервера.
+Поддерживается только CONNECT без аутентификации (клиент уже опознан приветствием эквалайзера).
+Подключения к loopback и link-local адресам (сервисы самого сервера) по умолчанию запрещены.
+Приветствие и запрос SOCKS5 приходят пакетами TYPE_DATA на стадии рукопожатия,
+после подключения к цели пара ничем не отличается от пары с VPN сервером:
+заполнитель и регулятор скорости работают так же.
+*/
+use crate::entry::health::{connect_any, CONNECT_TIMEOUT};
+use crate::objects::Pair;
+use easy_error::{bail, Error, ResultExt};
+use log::info;
+use splitter::handshake::send_data;
+use splitter::server_side_vpn_stream::VpnDataStream;
+use splitter::transport::Transport;
+use splitter::FrameOptions;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+const COMMAND_CONNECT: u8 = 0x01;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+//версия, команда, резерв, тип адреса
+const REQUEST_HEADER_SIZE: usize = 4;
+const PORT_SIZE: usize = 2;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/**
+    Сообщение клиента SOCKS5, полученное целиком
+*/
+#[derive(Debug, PartialEq)]
+pub enum SocksMessage {
+    //ответ на приветствие, дальше ждем запрос
+    Greeting([u8; 2]),
+    //адрес цели (хост:порт)
+    Connect(String),
+    //отказ: ответ клиенту и причина для лога
+    Reject(Vec<u8>, String),
+}
+
+/**
+    Накапливает байты клиента: запрос может прийти в нескольких пакетах
+*/
+#[derive(Default)]
+pub struct SocksRequest {
+    buf: Vec<u8>,
+    greeted: bool,
+}
+
+impl SocksRequest {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /**
+        None - сообщение получено не целиком
+    */
+    pub fn next_message(&mut self) -> Result<Option<SocksMessage>, Error> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        if self.buf[0] != VERSION {
+            bail!("SOCKS5: неверная версия {:#02x}", self.buf[0]);
+        }
+        if self.greeted {
+            self.request()
+        } else {
+            self.greeting()
+        }
+    }
+
+    fn greeting(&mut self) -> Result<Option<SocksMessage>, Error> {
+        let Some(&methods_count) = self.buf.get(1) else {
+            return Ok(None);
+        };
+        let size = 2 + methods_count as usize;
+        if self.buf.len() < size {
+            return Ok(None);
+        }
+        let acceptable = self.buf[2..size].contains(&METHOD_NO_AUTH);
+        self.buf.drain(..size);
+        if !acceptable {
+            return Ok(Some(SocksMessage::Reject(vec![VERSION, METHOD_NOT_ACCEPTABLE],
+                "SOCKS5: клиент не поддерживает подключение без аутентификации".to_string())));
+        }
+        self.greeted = true;
+        Ok(Some(SocksMessage::Greeting([VERSION, METHOD_NO_AUTH])))
+    }
+
+    fn request(&mut self) -> Result<Option<SocksMessage>, Error> {
+        if self.buf.len() < REQUEST_HEADER_SIZE + 1 {
+            return Ok(None);
+        }
+        let command = self.buf[1];
+        if command != COMMAND_CONNECT {
+            return Ok(Some(SocksMessage::Reject(reply(REPLY_COMMAND_NOT_SUPPORTED, None),
+                format!("SOCKS5: команда {command:#02x} не поддерживается"))));
+        }
+        let address_type = self.buf[3];
+        let address_size = match address_type {
+            ADDRESS_IPV4 => 4,
+            ADDRESS_IPV6 => 16,
+            ADDRESS_DOMAIN => 1 + self.buf[REQUEST_HEADER_SIZE] as usize,
+            _ => return Ok(Some(SocksMessage::Reject(reply(REPLY_ADDRESS_NOT_SUPPORTED, None),
+                format!("SOCKS5: тип адреса {address_type:#02x} не поддерживается")))),
+        };
+        let size = REQUEST_HEADER_SIZE + address_size + PORT_SIZE;
+        if self.buf.len() < size {
+            return Ok(None);
+        }
+        let address = &self.buf[REQUEST_HEADER_SIZE..REQUEST_HEADER_SIZE + address_size];
+        let port = u16::from_be_bytes([self.buf[size - 2], self.buf[size - 1]]);
+        let target = match address_type {
+            ADDRESS_IPV4 => SocketAddr::from((<[u8; 4]>::try_from(address).unwrap(), port)).to_string(),
+            ADDRESS_IPV6 => SocketAddr::from((<[u8; 16]>::try_from(address).unwrap(), port)).to_string(),
+            _ => {
+                let host = std::str::from_utf8(&address[1..]).context("SOCKS5: имя хоста не UTF-8")?;
+                format!("{host}:{port}")
+            }
+        };
+        self.buf.drain(..size);
+        Ok(Some(SocksMessage::Connect(target)))
+    }
+
+    /**
+        Данные клиента, пришедшие вслед за запросом (не дожидаясь ответа) - для цели
+    */
+    pub fn take_rest(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/**
+    Ответ на запрос. bound - адрес эквалайзера в подключении к цели
+*/
+fn reply(code: u8, bound: Option<SocketAddr>) -> Vec<u8> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    let mut reply = vec![VERSION, code, 0];
+    match bound {
+        SocketAddr::V4(address) => {
+            reply.push(ADDRESS_IPV4);
+            reply.extend_from_slice(&address.ip().octets());
+        }
+        SocketAddr::V6(address) => {
+            reply.push(ADDRESS_IPV6);
+            reply.extend_from_slice(&address.ip().octets());
+        }
+    }
+    reply.extend_from_slice(&bound.port().to_be_bytes());
+    reply
+}
+
+/**
+    Адрес самого сервера или его локальной сети: открытый прокси к ним - дыра в безопасности
+*/
+fn is_local(address: IpAddr) -> bool {
+    match address.to_canonical() {
+        IpAddr::V4(address) => address.is_loopback() || address.is_link_local() || address.is_unspecified(),
+        //fe80::/10
+        IpAddr::V6(address) => address.is_loopback() || address.is_unspecified() || (address.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/**
+    Адреса цели, которые разрешено подключать. Только локальные адреса - PermissionDenied
+*/
+fn allowed_addresses(target: &str, allow_local: bool) -> Result<Vec<SocketAddr>, Error> {
+    let addresses: Vec<SocketAddr> = target.to_socket_addrs().context(format!("Адрес {target}"))?.collect();
+    let allowed: Vec<SocketAddr> = addresses.iter().copied()
+        .filter(|address| allow_local || !is_local(address.ip()))
+        .collect();
+    if allowed.is_empty() && !addresses.is_empty() {
+        return Err(io::Error::from(ErrorKind::PermissionDenied)).context(format!("Локальная цель {target} запрещена"));
+    }
+    Ok(allowed)
+}
+
+/**
+    Подключаемся к цели (в потоке пула подключений: медленный сайт не задерживает рукопожатия остальных).
+    Удачное подключение отвечает клиенту и становится парой.
+    allow_local - разрешены loopback и link-local цели
+*/
+pub fn connect_target(mut stream: Box<dyn Transport>, target: &str, rest: &[u8], key: String, options: FrameOptions, allow_local: bool) -> Result<Pair, Error> {
+    stream.set_nonblocking(false).context("Restore blocking mode")?;
+    let connected = allowed_addresses(target, allow_local)
+        .and_then(|addresses| connect_any(addresses, target, CONNECT_TIMEOUT));
+    match connected {
+        Ok(mut target_stream) => {
+            target_stream.write_all(rest).context("Write SOCKS5 early data")?;
+            send_data(&mut stream, &reply(REPLY_SUCCEEDED, target_stream.local_addr().ok()), options)
+                .context("Send SOCKS5 reply")?;
+            info!("SOCKS5 {key} connected to {target}");
+            Ok(Pair::new(Box::new(VpnDataStream::new(target_stream)), false, stream, key, options, None))
+        }
+        Err(e) => {
+            let code = match e.cause.as_deref().and_then(|cause| cause.downcast_ref::<io::Error>()) {
+                Some(cause) if cause.kind() == ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+                Some(cause) if cause.kind() == ErrorKind::PermissionDenied => REPLY_NOT_ALLOWED,
+                Some(_) => REPLY_HOST_UNREACHABLE,
+                None => REPLY_GENERAL_FAILURE,
+            };
+            refuse(stream, code, options);
+            Err(e)
+        }
+    }
+}
+
+/**
+    Отказ клиенту, запрос которого не выполнен
+*/
+pub fn refuse(mut stream: Box<dyn Transport>, code: u8, options: FrameOptions) {
+    let _ = send_data(&mut stream, &reply(code, None), options);
+    stream.shutdown();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::socks::{allowed_addresses, is_local, reply, SocksMessage, SocksRequest};
+
+    #[test]
+    fn socks_request_test() {
+        let mut request = SocksRequest::default();
+        request.push(&[5, 2, 0]);
+        assert_eq!(None, request.next_message().unwrap());
+        request.push(&[2, 5, 1, 0, 3, 11]);
+        assert_eq!(Some(SocksMessage::Greeting([5, 0])), request.next_message().unwrap());
+        assert_eq!(None, request.next_message().unwrap());
+        request.push(b"example.com");
+        request.push(&[1, 187, 0x16]);
+        assert_eq!(Some(SocksMessage::Connect("example.com:443".to_string())), request.next_message().unwrap());
+        assert_eq!(vec![0x16], request.take_rest());
+        assert_eq!(None, request.next_message().unwrap());
+
+        let mut request = SocksRequest::default();
+        request.push(&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80]);
+        assert_eq!(Some(SocksMessage::Greeting([5, 0])), request.next_message().unwrap());
+        assert_eq!(Some(SocksMessage::Connect("127.0.0.1:80".to_string())), request.next_message().unwrap());
+
+        let mut request = SocksRequest::default();
+        request.push(&[5, 1, 2]);
+        assert!(matches!(request.next_message().unwrap(), Some(SocksMessage::Reject(reply, _)) if reply == [5, 0xFF]));
+
+        let mut request = SocksRequest::default();
+        request.push(&[5, 1, 0, 5, 2, 0, 1, 127]);
+        request.next_message().unwrap();
+        //BIND
+        assert!(matches!(request.next_message().unwrap(), Some(SocksMessage::Reject(reply, _)) if reply[1] == 0x07));
+
+        let mut request = SocksRequest::default();
+        request.push(&[4, 1, 0, 80]);
+        assert!(request.next_message().is_err());
+    }
+
+    #[test]
+    fn reply_test() {
+        assert_eq!(vec![5, 0, 0, 1, 10, 0, 0, 1, 0x1F, 0x90], reply(0, Some("10.0.0.1:8080".parse().unwrap())));
+        assert_eq!(vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0], reply(5, None));
+    }
+
+    #[test]
+    fn local_target_test() {
+        for address in ["127.0.0.1", "127.8.0.1", "169.254.1.1", "0.0.0.0", "::1", "fe80::1", "::", "::ffff:127.0.0.1"] {
+            assert!(is_local(address.parse().unwrap()), "{address}");
+        }
+        for address in ["10.0.0.1", "93.184.216.34", "2001:db8::1", "fec0::1"] {
+            assert!(!is_local(address.parse().unwrap()), "{address}");
+        }
+        let e = allowed_addresses("127.0.0.1:80", false).unwrap_err();
+        let cause = e.cause.as_deref().and_then(|cause| cause.downcast_ref::<std::io::Error>()).unwrap();
+        assert_eq!(std::io::ErrorKind::PermissionDenied, cause.kind());
+        assert_eq!(1, allowed_addresses("127.0.0.1:80", true).unwrap().len());
+        assert_eq!(1, allowed_addresses("10.0.0.1:80", false).unwrap().len());
+    }
+}
@@ -0,0 +1,13 @@
+/*
+Эквалайзер библиотекой: сервер (main.rs), equalizerctl и бенчмарки пользуются одними модулями.
+*/
+pub mod admin;
+pub mod core;
+pub mod entry;
+pub mod objects;
+pub mod orchestrator;
+pub mod settings;
+pub mod speed;
+pub mod statistic;
+mod tests;
+mod c_client_tests;
@@ -1,73 +1,122 @@
 use std::io::Write;
-use std::sync::mpsc::channel;
-use std::thread::sleep;
+use std::process::{exit, ExitCode};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{sleep, JoinHandle};
 use std::time::Duration;
-use std::{env, thread};
+use std::thread;
 use std::fs::File;
-use entry::entry_point::start_listen;
-use log::LevelFilter;
-use simplelog::{ColorChoice, CombinedLogger, Config, SimpleLogger, TermLogger, TerminalMode, WriteLogger};
+use clap::Parser;
+use easy_error::{Error, ResultExt};
+use equalizer::admin::start_admin;
+use equalizer::entry::entry_point::{bind, start_listen_on};
+use equalizer::entry::health::{start_health_check, UpstreamHealth};
+use equalizer::entry::session::Sessions;
+use log::{info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use simplelog::{ColorChoice, CombinedLogger, Config, SharedLogger, SimpleLogger, TermLogger, TerminalMode, WriteLogger};
 
-use crate::orchestrator::Orchestrator;
-use crate::speed::{native_to_regular};
-use crate::statistic::{SimpleStatisticCollector, Summary};
+use equalizer::entry::auth::{load_psk, ClientKeys};
+use equalizer::orchestrator::Orchestrator;
+use equalizer::settings::{Cli, LogSettings, Settings};
+use equalizer::speed::native_to_regular;
+use equalizer::statistic::prometheus::{start_metrics, Metrics, PrometheusCollector};
+use equalizer::statistic::{SimpleStatisticCollector, StatisticCollector, StatisticCollectors, Summary};
 
-mod core;
-mod entry;
-mod tests;
-mod objects;
-mod orchestrator;
-mod speed;
-mod statistic;
-mod c_client_tests;
-
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        println!("Example usage: ./equalizer 12010 1194");
-        println!("12010 - to accept vpn clients");
-        println!("1194 - OpenVPN listening port (tcp)");
-        print!(
-            "
-On client side
-ssh -NT -L 12010:127.0.0.1:12010 -L vpn_server
-To run as service /absolute_path/equalizer 12010 1194 --service
-"
-        );
-        return;
+fn main() -> ExitCode {
+    let settings = match Settings::load(Cli::parse()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("{}", error_chain(&e));
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = init_logger(&settings) {
+        println!("{}", error_chain(&e));
+        return ExitCode::FAILURE;
     }
-    let proxy_listen_port: u16 = *&args.get(1).unwrap().parse().unwrap();
-    let vpn_listen_port: u16 = *&args.get(2).unwrap().parse().unwrap();
-    let service_mode: bool = args.len() > 3 && args.get(3).unwrap().eq("--service"); //TODO to use some lib
-    if service_mode {
-        SimpleLogger::init(LevelFilter::Info, Config::default()).expect("Логгер проинициализирован");
-    }else {
-        // Initialize logging to both console and file
-        CombinedLogger::init(vec![
-            // Console logger with colors
-            TermLogger::new(
-                LevelFilter::Debug,  // Logs everything from Debug and above
-                Config::default(),
-                TerminalMode::Mixed, // Mixed mode: colored output when supported
-                ColorChoice::Auto    // Automatically select color mode
-            ),
-            // File logger
-            WriteLogger::new(
-                LevelFilter::Trace,   // Logs Info and above to the file
-                Config::default(),
-                File::create("app.log").unwrap(),
-            ),
-        ]).expect("Логгер проинициализирован");
+    match run(settings) {
+        Ok(true) => ExitCode::SUCCESS,
+        //не все клиенты успели завершиться
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            println!("{}", error_chain(&e));
+            ExitCode::FAILURE
+        }
     }
+}
+
+/**
+    Работаем до SIGINT/SIGTERM, затем перестаем принимать клиентов
+    и даем подключенным дописать данные (drain_timeout_ms).
+    false - кого-то пришлось закрыть принудительно
+*/
+fn run(settings: Settings) -> Result<bool, Error> {
+    let client_keys = settings.keys.as_deref().map(ClientKeys::load).transpose()?;
+    let psk = settings.psk.as_deref().map(load_psk).transpose()?;
+    //до запуска потоков, чтобы сигнал не убил процесс посреди записи
+    let mut signals = Signals::new([SIGINT, SIGTERM]).context("Обработчик сигналов")?;
     let (ct_pair, cr_pair) = channel();
-    let (_ct_stop, cr_stop) = channel();
-    let join = start_listen(proxy_listen_port, vpn_listen_port, ct_pair, cr_stop).unwrap();
-    thread::Builder::new()
+    if let Some(client_keys) = &client_keys {
+        info!("Client authentication enabled, {} keys loaded", client_keys.len());
+    }
+    if psk.is_some() {
+        info!("Stream encryption enabled");
+    }
+    let service_mode = settings.service;
+    let drain_timeout = settings.drain_timeout();
+    //доступность серверов всех слушателей
+    let health = UpstreamHealth::default();
+    let sessions = Sessions::new(settings.resume_grace());
+    if sessions.enabled() {
+        info!("Session resumption enabled, grace {:?}", settings.resume_grace());
+    }
+    //все порты открываем до запуска потоков - не открылся один, не запускаемся вовсе
+    let listeners = settings.listeners().into_iter().map(|listener| {
+        info!("Listen {} ({}), upstream {}, {} routes", listener.listen, listener.mode, listener.upstream, listener.routes.len());
+        let router = listener.router(&health).with_sessions(sessions.clone());
+        Ok((bind(&listener.listen)?, listener, router))
+    }).collect::<Result<Vec<_>, Error>>()?;
+    //вспомогательные потоки (проверка VPN серверов, управляющий сокет, метрики) и их остановка
+    let mut helpers: Vec<(Sender<bool>, JoinHandle<()>)> = vec![];
+    if let Some(period) = settings.health_check_period() {
+        let (ct_stop, cr_stop) = channel();
+        helpers.push((ct_stop, start_health_check(health.clone(), period, cr_stop)?));
+    }
+    let (ct_admin, cr_admin) = channel();
+    if let Some(admin_socket) = settings.admin_socket {
+        let (ct_stop, cr_stop) = channel();
+        helpers.push((ct_stop, start_admin(admin_socket, ct_admin, health.clone(), cr_stop)?));
+    }
+    let mut metrics = None;
+    if let Some(address) = settings.metrics {
+        let (ct_stop, cr_stop) = channel();
+        let shared = Metrics::with_upstreams(health.clone());
+        helpers.push((ct_stop, start_metrics(address, shared.clone(), cr_stop)?));
+        metrics = Some(shared);
+    }
+    let mut listen_joins = vec![];
+    for (client_listener, listener, router) in listeners {
+        let (ct_stop, cr_stop) = channel();
+        let join = start_listen_on(client_listener, listener.mode, router, client_keys.clone(), psk.clone(),
+                                   ct_pair.clone(), cr_stop)?;
+        listen_joins.push((ct_stop, join));
+    }
+    drop(ct_pair);
+    let (ct_orchestrator_stop, cr_orchestrator_stop) = channel();
+    let orchestrator_join = thread::Builder::new()
         .name("orchestrator".to_string()).spawn(move || {
         let pause = Duration::from_millis(50);
-        let mut orchestrator =
-            Orchestrator::new(cr_pair, Box::new(SimpleStatisticCollector::default()));
+        let mut collector: Box<dyn StatisticCollector> = Box::new(SimpleStatisticCollector::default());
+        if let Some(metrics) = metrics {
+            collector = Box::new(StatisticCollectors::new(vec![collector, Box::new(PrometheusCollector::new(metrics))]));
+        }
+        let mut orchestrator = Orchestrator::with_settings(cr_pair, collector, settings.speed, settings.filler);
+        orchestrator.attach_admin(cr_admin);
         loop {
+            if let Ok(drain_timeout) = cr_orchestrator_stop.try_recv() {
+                return orchestrator.shutdown(drain_timeout);
+            }
             orchestrator.invoke();
             sleep(pause);
             orchestrator.invoke();
@@ -79,8 +128,55 @@ To run as service /absolute_path/equalizer 12010 1194 --service
                 }
             }
         }
-    }).expect("orchestrator thread started");
-    join.join().unwrap();
+    }).context("orchestrator thread started")?;
+
+    if let Some(signal) = signals.forever().next() {
+        info!("Signal {signal} received, stopping");
+    }
+    //повторный сигнал - не ждем клиентов
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            warn!("Second signal received, exiting without drain");
+            exit(130);
+        }
+    });
+    for (ct_stop, join) in listen_joins.into_iter().chain(helpers) {
+        let _ = ct_stop.send(true);
+        let _ = join.join();
+    }
+    let _ = ct_orchestrator_stop.send(drain_timeout);
+    let drained = orchestrator_join.join().unwrap_or(false);
+    info!("Stopped");
+    Ok(drained)
+}
+
+/**
+    Служба - только консоль (журнал системы), иначе цветная консоль и файл с подробным логом
+*/
+fn init_logger(settings: &Settings) -> Result<(), Error> {
+    let log: &LogSettings = &settings.log;
+    let level = log.level_filter()?;
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![];
+    if settings.service {
+        loggers.push(SimpleLogger::new(level, Config::default()));
+    } else {
+        loggers.push(TermLogger::new(level, Config::default(), TerminalMode::Mixed, ColorChoice::Auto));
+    }
+    if let Some(path) = &log.file {
+        let file = File::create(path).context(format!("Не удалось создать файл лога {path}"))?;
+        loggers.push(WriteLogger::new(log.file_level_filter()?, Config::default(), file));
+    }
+    CombinedLogger::init(loggers).context("Логгер проинициализирован")
+}
+
+fn error_chain(e: &Error) -> String {
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    message
 }
 
 fn print_client_info(collected_info: Vec<Summary>) {
@@ -1,5 +1,6 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use splitter::{DataStream, MAX_BODY_SIZE};
+use crate::entry::session::PairSession;
 use crate::speed::{SpeedCorrectorCommand};
 
 //размер одного tcp пакета (как правило не больше 1024 - 10_000 хватит для 100Мбит)
@@ -38,6 +39,10 @@ pub struct Pair {
     //от клиента к эквалайзеру (для получения данных-заполнителя)
     pub filler_stream: Box<dyn DataStream>,
     pub key: String,
+    //VPN сервер по UDP: читаем датаграмму целиком, пакет клиенту может быть длиннее filler.packet_size
+    pub datagram: bool,
+    //клиент договорился о восстановлении сессии: после обрыва пара ждет его переподключения
+    pub session: Option<PairSession>,
 }
 
 /*
@@ -45,11 +50,17 @@ pub struct Pair {
 200-100 мс назад
 Должны быть быстро куда-нибудь переданы или агрегированы
  */
+#[derive(Clone)]
 pub struct HotPotatoInfo {
     pub data_packets: [Option<SentPacket>; MAX_STAT_COUNT],
     pub data_count: usize,
     pub filler_packets: [Option<SentPacket>; MAX_STAT_COUNT],
     pub filler_count: usize,
+    //получено от клиента с прошлой информации, байт (в регулятор скорости не идет)
+    pub received_data: usize,
+    pub received_filler: usize,
+    //сколько запись клиенту ждала освобождения буфера отправки (оценка канала, speed/bandwidth.rs)
+    pub write_blocked: Duration,
 }
 
 impl Default for HotPotatoInfo {
@@ -59,6 +70,9 @@ impl Default for HotPotatoInfo {
             filler_count: 0,
             data_packets: [None; MAX_STAT_COUNT],
             filler_packets: [None; MAX_STAT_COUNT],
+            received_data: 0,
+            received_filler: 0,
+            write_blocked: Duration::ZERO,
         }
     }
 }
@@ -66,6 +80,8 @@ impl Default for HotPotatoInfo {
 //команды в сторону прокси (управление)
 pub enum RuntimeCommand {
     SetSpeed(SpeedCorrectorCommand),
+    //остановка сервера: дописать данные и закрыть пару
+    Drain,
 }
 
 //информация о состоянии прокси
@@ -1,38 +1,80 @@
 //владеет всеми инстансами VpnProxy
 //собирает статистику по ним и отправляет в анализатор изменения скорости
+//выполняет запросы управляющего сокета (admin)
 
+use crate::admin::protocol::{AdminRequest, ClientStatus, ControlMode};
+use crate::admin::{AdminCall, AdminReply};
+use crate::core::reactor::{default_threads, Reactor};
 use crate::core::vpn_proxy::{Proxy, VpnProxy};
 use crate::objects::Pair;
 use crate::objects::{ProxyState, RuntimeCommand};
-use crate::speed::SpeedCorrector;
+use crate::settings::{FillerSettings, SpeedSettings};
+use crate::speed::{SpeedCorrector, SpeedCorrectorCommand};
 use crate::statistic::{StatisticCollector, Summary};
 use log::{info, warn};
+use std::collections::HashMap;
 use std::ops::DerefMut;
 use std::sync::mpsc::Receiver;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 #[cfg(test)]
 use easy_error::{bail, ResultExt, Error};
 
+const DRAIN_CHECK_PERIOD: Duration = Duration::from_millis(10);
+
+#[derive(Default)]
+struct ClientControl {
+    //закреплено через управляющий сокет, команды регулятора не отправляются
+    pinned: Option<SpeedCorrectorCommand>,
+    //последняя отправленная прокси команда
+    last_command: Option<SpeedCorrectorCommand>,
+}
+
 pub struct Orchestrator {
     new_proxy_receiver: Receiver<Pair>,
     pub(crate) pairs: Vec<Box<dyn Proxy>>,
     stat: Box<dyn StatisticCollector>,
-    speed_corrector: SpeedCorrector
+    speed_corrector: SpeedCorrector,
+    filler_settings: FillerSettings,
+    //запросы управляющего сокета
+    admin_receiver: Option<Receiver<AdminCall>>,
+    control: HashMap<String, ClientControl>,
+    //удаляется последним: потоки реактора закрывают оставшиеся пары
+    reactor: Reactor,
 }
 
 impl Orchestrator {
+    #[cfg(test)]
     pub fn new(
         new_proxy_receiver: Receiver<Pair>,
         stat: Box<dyn StatisticCollector>,
+    ) -> Orchestrator {
+        Orchestrator::with_settings(new_proxy_receiver, stat, SpeedSettings::default(), FillerSettings::default())
+    }
+
+    pub fn with_settings(
+        new_proxy_receiver: Receiver<Pair>,
+        stat: Box<dyn StatisticCollector>,
+        speed_settings: SpeedSettings,
+        filler_settings: FillerSettings,
     ) -> Orchestrator {
         let pair: Vec<Box<dyn Proxy>> = vec![];
         Self {
             new_proxy_receiver,
             pairs: pair,
             stat,
-            speed_corrector: SpeedCorrector::new()
+            speed_corrector: SpeedCorrector::with_settings(speed_settings),
+            filler_settings,
+            admin_receiver: None,
+            control: HashMap::new(),
+            reactor: Reactor::new(default_threads()),
         }
     }
 
+    pub fn attach_admin(&mut self, admin_receiver: Receiver<AdminCall>) {
+        self.admin_receiver = Some(admin_receiver);
+    }
+
     pub fn invoke(&mut self) {
         loop {
             if !self.check_new_connections() {
@@ -40,9 +82,37 @@ impl Orchestrator {
             }
         }
         self.receive_proxy_state();
+        self.receive_admin_calls();
     }
 
 
+    /**
+        Остановка сервера: пары дописывают данные и закрываются сами,
+        не успевшие за drain_timeout закрываются принудительно.
+        true - все пары завершились вовремя
+    */
+    pub fn shutdown(&mut self, drain_timeout: Duration) -> bool {
+        //пары, переданные до остановки приема, тоже дописывают данные
+        while self.check_new_connections() {}
+        info!("Draining {} clients", self.pairs.len());
+        for proxy in self.pairs.iter_mut() {
+            if proxy.try_send_command(RuntimeCommand::Drain).is_err() {
+                warn!("Ошибка отправки команды остановки для {}", proxy.get_key());
+            }
+        }
+        let start = Instant::now();
+        while !self.pairs.is_empty() && start.elapsed() < drain_timeout {
+            self.receive_proxy_state();
+            sleep(DRAIN_CHECK_PERIOD);
+        }
+        let drained = self.pairs.is_empty();
+        if !drained {
+            warn!("{} clients were not drained in {:?}, closing", self.pairs.len(), drain_timeout);
+        }
+        self.pairs.clear();
+        drained
+    }
+
     pub fn calculate_and_get(&mut self) -> Option<Vec<Summary>> {
         self.stat.calculate_and_get()
     }
@@ -60,8 +130,13 @@ impl Orchestrator {
                     ProxyState::Info(collected_info) => {
                         if let Some(command) =
                             sc.append_and_get(proxy.get_key(), &collected_info) {
-                            if proxy.try_send_command(RuntimeCommand::SetSpeed(command)).is_err() {
-                                warn!("Ошибка отправки команды изменения скорости для {}", proxy.get_key());
+                            let control = self.control.entry(proxy.get_key().clone()).or_default();
+                            if control.pinned.is_none() {
+                                if proxy.try_send_command(RuntimeCommand::SetSpeed(command)).is_err() {
+                                    warn!("Ошибка отправки команды изменения скорости для {}", proxy.get_key());
+                                }
+                                control.last_command = Some(command);
+                                stat.speed_changed(proxy.get_key(), command);
                             }
                         }
                         stat.append_info(proxy.get_key(), collected_info);
@@ -70,6 +145,7 @@ impl Orchestrator {
                         info!("Broken {}", proxy.get_key());
                         stat.clear_info(proxy.get_key());
                         sc.clear_info(proxy.get_key());
+                        self.control.remove(proxy.get_key());
                         self.pairs.remove(i);
                         break;
                     }
@@ -106,7 +182,18 @@ impl Orchestrator {
 
     fn check_new_connections(&mut self) -> bool {
         if let Ok(main_channel) = self.new_proxy_receiver.try_recv() {
-            let proxy = VpnProxy::new(main_channel);
+            let mut proxy = VpnProxy::new(main_channel, &self.filler_settings, &mut self.reactor);
+            self.stat.client_connected(proxy.get_key());
+            //переподключившийся клиент начинает без заполнителя, закрепленное через сокет восстанавливаем
+            if let Some(control) = self.control.get_mut(proxy.get_key()) {
+                control.last_command = control.pinned;
+                if let Some(command) = control.pinned {
+                    if proxy.try_send_command(RuntimeCommand::SetSpeed(command)).is_err() {
+                        warn!("Ошибка отправки закрепленной скорости для {}", proxy.get_key());
+                    }
+                    self.stat.speed_changed(proxy.get_key(), command);
+                }
+            }
             for i in 0..self.pairs.len() {
                 if let Some(exist_proxy) = self.pairs.get(i) {
                     if proxy.get_key() == exist_proxy.get_key() {
@@ -121,5 +208,92 @@ impl Orchestrator {
         }
         false
     }
-}
 
+    fn receive_admin_calls(&mut self) {
+        let Some(admin_receiver) = &self.admin_receiver else {
+            return;
+        };
+        let calls: Vec<AdminCall> = admin_receiver.try_iter().collect();
+        for call in calls {
+            let reply = self.admin(call.request);
+            //клиент сокета мог не дождаться
+            let _ = call.ct_reply.send(reply);
+        }
+    }
+
+    fn admin(&mut self, request: AdminRequest) -> AdminReply {
+        match request {
+            AdminRequest::Clients => {
+                let keys: Vec<String> = self.pairs.iter().map(|proxy| proxy.get_key().clone()).collect();
+                Ok(self.statuses(&keys))
+            }
+            AdminRequest::Stats(key) => {
+                self.index_of(&key)?;
+                Ok(self.statuses(&[key]))
+            }
+            AdminRequest::SetSpeed(key, speed) => self.pin(key, Some(SpeedCorrectorCommand::SetSpeed(speed))),
+            AdminRequest::Free(key) => self.pin(key, Some(SpeedCorrectorCommand::SwitchOff)),
+            AdminRequest::Release(key) => self.pin(key, None),
+            AdminRequest::Kick(key) => {
+                let index = self.index_of(&key)?;
+                //VpnProxy::drop останавливает пару
+                self.pairs.remove(index);
+                self.stat.clear_info(&key);
+                self.speed_corrector.clear_info(&key);
+                self.control.remove(&key);
+                info!("Client {key} kicked");
+                Ok(vec![])
+            }
+            //отвечает поток сокета, до оркестратора не доходит
+            AdminRequest::Upstreams => Err("Состояние VPN серверов отдает управляющий сокет".to_string()),
+        }
+    }
+
+    /**
+        None - отдать клиента регулятору: он начинает заново из режима без заполнителя
+    */
+    fn pin(&mut self, key: String, pinned: Option<SpeedCorrectorCommand>) -> AdminReply {
+        let index = self.index_of(&key)?;
+        let command = pinned.unwrap_or(SpeedCorrectorCommand::SwitchOff);
+        self.pairs[index].try_send_command(RuntimeCommand::SetSpeed(command))
+            .map_err(|_| format!("Клиент {key} отключается"))?;
+        if pinned.is_none() {
+            self.speed_corrector.clear_info(&key);
+        }
+        let control = self.control.entry(key.clone()).or_default();
+        control.pinned = pinned;
+        control.last_command = Some(command);
+        self.stat.speed_changed(&key, command);
+        info!("Client {key} speed control {:?}", pinned);
+        Ok(self.statuses(&[key]))
+    }
+
+    fn index_of(&self, key: &String) -> Result<usize, String> {
+        self.pairs.iter().position(|proxy| proxy.get_key() == key)
+            .ok_or_else(|| format!("Клиент {key} не подключен"))
+    }
+
+    fn statuses(&mut self, keys: &[String]) -> Vec<ClientStatus> {
+        let summaries = self.stat.calculate_and_get().unwrap_or_default();
+        keys.iter().map(|key| {
+            let summary = summaries.iter().find(|summary| &summary.key == key);
+            let control = self.control.get(key);
+            let last_command = control.and_then(|control| control.last_command);
+            ClientStatus {
+                key: key.clone(),
+                percent_data: summary.map(|summary| summary.percent_data).unwrap_or(0),
+                percent_filler: summary.map(|summary| summary.percent_filler).unwrap_or(0),
+                calculated_speed: summary.map(|summary| summary.calculated_speed).unwrap_or(0),
+                target_speed: match last_command {
+                    Some(SpeedCorrectorCommand::SetSpeed(speed)) => Some(speed),
+                    _ => None,
+                },
+                mode: match control.and_then(|control| control.pinned) {
+                    None => ControlMode::Auto,
+                    Some(SpeedCorrectorCommand::SwitchOff) => ControlMode::Free,
+                    Some(SpeedCorrectorCommand::SetSpeed(_)) => ControlMode::Pinned,
+                },
+            }
+        }).collect()
+    }
+}
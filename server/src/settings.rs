@@ -0,0 +1,570 @@
+/*
+Настройки сервера.
+Значения по умолчанию < файл настроек (--config equalizer.toml) < параметры командной строки.
+Все проверяется при запуске, до открытия портов.
+Пример файла - Service/equalizer.toml
+*/
+use crate::core::filler_content::FillerContentKind;
+use crate::core::filler::{ANALYZE_PERIOD_MS, MIN_PACKET_SIZE};
+use crate::entry::entry_point::ListenerMode;
+use crate::entry::health::UpstreamHealth;
+use crate::entry::routing::{split_upstreams, Router};
+//параметры регулятора скорости (секция [speed]) общие с клиентом
+pub use crate::speed::{SpeedPolicyKind, SpeedSettings};
+use clap::{Parser, ValueEnum};
+use easy_error::{ensure, Error, ResultExt};
+use log::LevelFilter;
+use serde::Deserialize;
+use splitter::MAX_BODY_SIZE;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:12010";
+const DEFAULT_UPSTREAM: &str = "127.0.0.1:1194";
+const DEFAULT_LOG_FILE: &str = "app.log";
+const DEFAULT_DRAIN_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_HEALTH_CHECK_PERIOD_MS: u64 = 5000;
+
+/**
+    Маскировка Youtube трафика: эквалайзер между клиентами и OpenVPN (или другим TCP сервисом)
+*/
+#[derive(Parser, Debug, Default)]
+#[command(version, about, after_help = "\
+On client side
+ssh -NT -L 12010:127.0.0.1:12010 -L vpn_server
+Old style: ./equalizer 12010 1194 [--service]")]
+pub struct Cli {
+    /// Порт для клиентов и порт OpenVPN (старый формат запуска, вместо --listen/--upstream)
+    #[arg(num_args = 0..=3)]
+    pub ports: Vec<u16>,
+    /// Файл настроек (TOML)
+    #[arg(long)]
+    pub config: Option<String>,
+    /// Адрес для клиентов, например 0.0.0.0:12010
+    #[arg(long)]
+    pub listen: Option<String>,
+    /// Адреса OpenVPN серверов (tcp) по умолчанию через запятую, например 127.0.0.1:1194; пустая строка - только маршруты
+    #[arg(long)]
+    pub upstream: Option<String>,
+    /// Сервис за эквалайзером: openvpn (одно подключение на клиента), tcp (любой TCP сервис), udp (OpenVPN udp, WireGuard) или socks5 (эквалайзер сам SOCKS5 сервер)
+    #[arg(long, value_enum)]
+    pub mode: Option<ListenerMode>,
+    /// Запуск службой: без статистики в консоли
+    #[arg(long)]
+    pub service: bool,
+    /// Файл ключей клиентов (аутентификация)
+    #[arg(long)]
+    pub keys: Option<String>,
+    /// Файл общего ключа шифрования потока
+    #[arg(long)]
+    pub psk: Option<String>,
+    /// Содержимое заполнителя: random, zero или replay:путь
+    #[arg(long)]
+    pub filler: Option<String>,
+    /// Регулятор скорости: step (ступенчатый) или pi (ПИ-регулятор)
+    #[arg(long, value_enum)]
+    pub speed_policy: Option<SpeedPolicyArg>,
+    /// Управляющий сокет (equalizerctl), например /tmp/equalizer.sock; по умолчанию отключен
+    #[arg(long)]
+    pub admin_socket: Option<String>,
+    /// Адрес для Prometheus (GET /metrics), например 127.0.0.1:9898
+    #[arg(long)]
+    pub metrics: Option<String>,
+    /// Уровень логирования: error, warn, info, debug, trace
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Файл лога, пустая строка - только консоль
+    #[arg(long)]
+    pub log_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub listen: String,
+    pub mode: ListenerMode,
+    //серверы (через запятую, первый основной) для клиентов без маршрута,
+    //пустая строка - таким клиентам отказываем
+    pub upstream: String,
+    //ключ клиента (или начало ключа со *) -> адреса серверов через запятую
+    pub routes: BTreeMap<String, String>,
+    //socks5: разрешить цели на самом сервере (loopback, link-local)
+    pub allow_local_targets: bool,
+    //несколько портов со своими сервисами, если заданы - listen, mode, upstream и routes не используются
+    pub listeners: Vec<ListenerSettings>,
+    //как часто проверяем доступность VPN серверов, 0 - только при подключении клиентов
+    pub health_check_period_ms: u64,
+    pub service: bool,
+    pub keys: Option<String>,
+    pub psk: Option<String>,
+    //None или пустая строка - отключен
+    pub admin_socket: Option<String>,
+    //адрес http сервера метрик, None - отключен
+    pub metrics: Option<String>,
+    //при остановке столько ждем, пока клиенты дописывают данные
+    pub drain_timeout_ms: u64,
+    //столько пара ждет переподключения клиента после обрыва, 0 (по умолчанию) - сессии не восстанавливаются
+    pub resume_grace_ms: u64,
+    pub log: LogSettings,
+    pub speed: SpeedSettings,
+    pub filler: FillerSettings,
+}
+
+/**
+    Порт для клиентов и сервис за ним
+*/
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSettings {
+    pub listen: String,
+    #[serde(default)]
+    pub mode: ListenerMode,
+    #[serde(default)]
+    pub upstream: String,
+    #[serde(default)]
+    pub routes: BTreeMap<String, String>,
+    #[serde(default)]
+    pub allow_local_targets: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    //консоль; в режиме службы - единственный лог, если не задан file
+    pub level: String,
+    pub file: Option<String>,
+    pub file_level: String,
+}
+
+/**
+    --speed-policy (регулятор общий с клиентом и про командную строку не знает)
+*/
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum SpeedPolicyArg {
+    Step,
+    Pi,
+}
+
+impl From<SpeedPolicyArg> for SpeedPolicyKind {
+    fn from(arg: SpeedPolicyArg) -> SpeedPolicyKind {
+        match arg {
+            SpeedPolicyArg::Step => SpeedPolicyKind::Step,
+            SpeedPolicyArg::Pi => SpeedPolicyKind::Pi,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FillerSettings {
+    //как часто статистика отправки уходит в регулятор
+    pub analyze_period_ms: u64,
+    //максимальный размер пакета к клиенту (не больше MAX_BODY_SIZE splitter)
+    pub packet_size: usize,
+    pub content: FillerContentKind,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            listen: DEFAULT_LISTEN.to_string(),
+            mode: ListenerMode::OpenVpn,
+            upstream: DEFAULT_UPSTREAM.to_string(),
+            routes: BTreeMap::new(),
+            allow_local_targets: false,
+            listeners: vec![],
+            health_check_period_ms: DEFAULT_HEALTH_CHECK_PERIOD_MS,
+            service: false,
+            keys: None,
+            psk: None,
+            admin_socket: None,
+            metrics: None,
+            drain_timeout_ms: DEFAULT_DRAIN_TIMEOUT_MS,
+            resume_grace_ms: 0,
+            log: LogSettings::default(),
+            speed: SpeedSettings::default(),
+            filler: FillerSettings::default(),
+        }
+    }
+}
+
+impl Default for LogSettings {
+    fn default() -> LogSettings {
+        LogSettings {
+            level: "debug".to_string(),
+            file: Some(DEFAULT_LOG_FILE.to_string()),
+            file_level: "trace".to_string(),
+        }
+    }
+}
+
+impl Default for FillerSettings {
+    fn default() -> FillerSettings {
+        FillerSettings {
+            analyze_period_ms: ANALYZE_PERIOD_MS,
+            packet_size: MAX_BODY_SIZE,
+            content: FillerContentKind::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn load(cli: Cli) -> Result<Settings, Error> {
+        let mut settings = match &cli.config {
+            Some(path) => Settings::from_file(path)?,
+            None => Settings::default(),
+        };
+        settings.apply(cli)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn from_file(path: &str) -> Result<Settings, Error> {
+        let content = fs::read_to_string(path).context(format!("Не удалось прочитать файл настроек {path}"))?;
+        Settings::parse(&content).context(format!("Ошибка в файле настроек {path}"))
+    }
+
+    pub fn parse(content: &str) -> Result<Settings, Error> {
+        toml::from_str(content).context("Разбор TOML")
+    }
+
+    fn apply(&mut self, cli: Cli) -> Result<(), Error> {
+        //какому из нескольких слушателей они предназначены - не понять, молча не пропускаем
+        ensure!(self.listeners.is_empty() || (cli.ports.is_empty() && cli.listen.is_none() && cli.upstream.is_none() && cli.mode.is_none()),
+            "Порты, --listen, --upstream и --mode не сочетаются с [[listeners]] файла настроек");
+        if let Some(port) = cli.ports.first() {
+            self.listen = format!("0.0.0.0:{port}");
+        }
+        if let Some(port) = cli.ports.get(1) {
+            self.upstream = format!("127.0.0.1:{port}");
+        }
+        if cli.ports.len() > 2 {
+            //логгер еще не настроен
+            println!("Третий порт больше не используется и пропускается");
+        }
+        if cli.service {
+            self.service = true;
+            //служба пишет в журнал системы, файл - только если задан явно
+            if cli.config.is_none() {
+                self.log.level = "info".to_string();
+                self.log.file = None;
+            }
+        }
+        self.listen = cli.listen.unwrap_or(self.listen.clone());
+        self.upstream = cli.upstream.unwrap_or(self.upstream.clone());
+        self.mode = cli.mode.unwrap_or(self.mode);
+        self.keys = cli.keys.or(self.keys.take());
+        self.psk = cli.psk.or(self.psk.take());
+        if let Some(filler) = cli.filler {
+            self.filler.content = FillerContentKind::parse(&filler).context("--filler")?;
+        }
+        self.speed.policy = cli.speed_policy.map(SpeedPolicyKind::from).unwrap_or(self.speed.policy);
+        self.log.level = cli.log_level.unwrap_or(self.log.level.clone());
+        self.admin_socket = cli.admin_socket.or(self.admin_socket.take());
+        self.admin_socket = self.admin_socket.take().filter(|path| !path.is_empty());
+        self.metrics = cli.metrics.or(self.metrics.take());
+        self.log.file = cli.log_file.or(self.log.file.take());
+        //пустая строка - без файла
+        self.log.file = self.log.file.take().filter(|file| !file.is_empty());
+        Ok(())
+    }
+
+    /**
+        [[listeners]] или один слушатель из listen, mode, upstream и routes
+    */
+    pub fn listeners(&self) -> Vec<ListenerSettings> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        vec![ListenerSettings {
+            listen: self.listen.clone(),
+            mode: self.mode,
+            upstream: self.upstream.clone(),
+            routes: self.routes.clone(),
+            allow_local_targets: self.allow_local_targets,
+        }]
+    }
+
+    pub fn health_check_period(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.health_check_period_ms)).filter(|period| !period.is_zero())
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_millis(self.drain_timeout_ms)
+    }
+
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_millis(self.resume_grace_ms)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let listeners = self.listeners();
+        for (index, listener) in listeners.iter().enumerate() {
+            listener.validate()?;
+            ensure!(listener.mode != ListenerMode::Socks5 || self.keys.is_some(),
+                "{}: режим socks5 только с ключами клиентов (keys), иначе это открытый прокси", listener.listen);
+            ensure!(listeners[..index].iter().all(|other| other.listen != listener.listen),
+                "listeners: адрес {} указан дважды", listener.listen);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.parse::<SocketAddr>()
+                .context(format!("metrics: ожидается адрес:порт, получено '{metrics}'"))?;
+        }
+        self.log.level_filter()?;
+        self.log.file_level_filter()?;
+        self.speed.validate()?;
+        self.filler.validate()
+    }
+}
+
+impl ListenerSettings {
+    pub fn router(&self, health: &UpstreamHealth) -> Router {
+        let default = Some(self.upstream.clone()).filter(|upstream| !upstream.is_empty());
+        //UDP серверу подключение не проверить - в общую проверку (и список upstreams) не попадает
+        let health = match self.mode {
+            ListenerMode::Udp => UpstreamHealth::default(),
+            ListenerMode::OpenVpn | ListenerMode::Tcp => health.clone(),
+            //серверов нет
+            ListenerMode::Socks5 => return Router::default().with_local_targets(self.allow_local_targets),
+        };
+        Router::new(default, &self.routes, health)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        self.listen.parse::<SocketAddr>()
+            .context(format!("listen: ожидается адрес:порт, получено '{}'", self.listen))?;
+        if self.mode == ListenerMode::Socks5 {
+            //upstream и routes не используются
+            return Ok(());
+        }
+        ensure!(!self.upstream.is_empty() || !self.routes.is_empty(), "{}: не задан ни upstream, ни routes", self.listen);
+        if !self.upstream.is_empty() {
+            validate_upstream("upstream", &self.upstream)?;
+        }
+        for (pattern, upstream) in self.routes.iter() {
+            ensure!(!pattern.is_empty() && pattern != "*", "routes: пустой ключ клиента (для всех - upstream)");
+            validate_upstream(&format!("routes.{pattern}"), upstream)?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_upstream(name: &str, upstreams: &str) -> Result<(), Error> {
+    let upstreams = split_upstreams(upstreams);
+    ensure!(!upstreams.is_empty(), "{name}: не задан адрес");
+    for upstream in upstreams {
+        let addresses = upstream.to_socket_addrs()
+            .context(format!("{name}: ожидается адрес:порт, получено '{upstream}'"))?;
+        ensure!(addresses.count() > 0, "{name}: адрес '{upstream}' не найден");
+    }
+    Ok(())
+}
+
+impl LogSettings {
+    pub fn level_filter(&self) -> Result<LevelFilter, Error> {
+        parse_level(&self.level).context("log.level")
+    }
+
+    pub fn file_level_filter(&self) -> Result<LevelFilter, Error> {
+        parse_level(&self.file_level).context("log.file_level")
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, Error> {
+    LevelFilter::from_str(level).context(format!("Неизвестный уровень логирования '{level}'"))
+}
+
+impl FillerSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        ensure!((MIN_PACKET_SIZE..=MAX_BODY_SIZE).contains(&self.packet_size),
+            "filler: packet_size {} должен быть от {} до {}", self.packet_size, MIN_PACKET_SIZE, MAX_BODY_SIZE);
+        ensure!(self.analyze_period_ms > 0, "filler: analyze_period_ms должен быть больше 0");
+        Ok(())
+    }
+
+    pub fn analyze_period(&self) -> Duration {
+        Duration::from_millis(self.analyze_period_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::filler_content::FillerContentKind;
+    use crate::entry::entry_point::ListenerMode;
+    use crate::entry::health::UpstreamHealth;
+    use crate::settings::{Cli, Settings, SpeedSettings};
+    use crate::speed::SpeedPolicyKind;
+    use clap::Parser;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_test() {
+        let settings = Settings::parse(r#"
+            listen = "127.0.0.1:12010"
+            upstream = "127.0.0.1:1194"
+            resume_grace_ms = 10000
+            [log]
+            level = "info"
+            [speed]
+            policy = "pi"
+            target_percent = 70
+            pi_kp = 2.5
+            bandwidth_cap_percent = 0
+            [filler]
+            content = "zero"
+            packet_size = 4096
+        "#).unwrap();
+        settings.validate().unwrap();
+        assert_eq!("127.0.0.1:12010", settings.listen);
+        assert_eq!(70, settings.speed.target_percent);
+        //не указанное - по умолчанию
+        assert_eq!(SpeedSettings::default().free_play, settings.speed.free_play);
+        assert_eq!(SpeedPolicyKind::Pi, settings.speed.policy);
+        assert_eq!(2.5, settings.speed.pi_kp);
+        assert_eq!(0, settings.speed.bandwidth_cap_percent);
+        assert_eq!(4096, settings.filler.packet_size);
+        assert!(matches!(settings.filler.content, FillerContentKind::Zero));
+        assert_eq!(Duration::from_secs(10), settings.resume_grace());
+    }
+
+    #[test]
+    fn validation_test() {
+        assert!(Settings::parse("lissten = \"0.0.0.0:1\"").is_err());
+        assert!(Settings::parse("[filler]\ncontent = \"ones\"").is_err());
+        assert!(Settings::parse("[speed]\npolicy = \"pid\"").is_err());
+        let settings = Settings::parse("[speed]\npi_ki = -1.0").unwrap();
+        assert!(settings.validate().is_err());
+        let settings = Settings::parse("[speed]\nbandwidth_cap_percent = 120").unwrap();
+        assert!(settings.validate().is_err());
+        let settings = Settings::parse("[filler]\npacket_size = 100000").unwrap();
+        assert!(settings.validate().is_err());
+        let settings = Settings::parse("[speed]\ntarget_percent = 99").unwrap();
+        assert!(settings.validate().is_err());
+        let settings = Settings::parse("listen = \"12010\"").unwrap();
+        assert!(settings.validate().is_err());
+        let settings = Settings::parse("[log]\nlevel = \"loud\"").unwrap();
+        assert!(settings.validate().is_err());
+        let settings = Settings::parse("upstream = \"\"").unwrap();
+        assert!(settings.validate().is_err());
+        let settings = Settings::parse("[routes]\n\"router-1\" = \"1194\"").unwrap();
+        assert!(settings.validate().is_err());
+        let settings = Settings::parse("upstream = \"127.0.0.1:1194, 1195\"").unwrap();
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn routes_test() {
+        let settings = Settings::parse(r#"
+            upstream = ""
+            [routes]
+            "router-1" = "127.0.0.1:1195"
+            "office-*" = "127.0.0.1:1196, 127.0.0.1:1197"
+        "#).unwrap();
+        settings.validate().unwrap();
+        let router = settings.listeners()[0].router(&UpstreamHealth::default());
+        assert_eq!(Some(&["127.0.0.1:1195".to_string()][..]), router.route("router-1"));
+        assert_eq!(2, router.route("office-2").unwrap().len());
+        assert_eq!(None, router.route("router-2"));
+    }
+
+    #[test]
+    fn listeners_test() {
+        let settings = Settings::parse(r#"
+            [[listeners]]
+            listen = "0.0.0.0:12010"
+            upstream = "127.0.0.1:1194"
+            [[listeners]]
+            listen = "0.0.0.0:12011"
+            mode = "tcp"
+            upstream = "127.0.0.1:1080"
+        "#).unwrap();
+        settings.validate().unwrap();
+        let listeners = settings.listeners();
+        assert_eq!(2, listeners.len());
+        assert_eq!(ListenerMode::OpenVpn, listeners[0].mode);
+        assert_eq!(ListenerMode::Tcp, listeners[1].mode);
+
+        assert!(Settings::parse("[[listeners]]\nupstream = \"127.0.0.1:1194\"").is_err());
+        assert!(Settings::parse("[[listeners]]\nlisten = \"0.0.0.0:1\"\nmode = \"sctp\"").is_err());
+        let settings = Settings::parse("[[listeners]]\nlisten = \"0.0.0.0:1\"\nmode = \"udp\"\nupstream = \"127.0.0.1:1194\"").unwrap();
+        let listener = &settings.listeners()[0];
+        assert_eq!(ListenerMode::Udp, listener.mode);
+        let health = UpstreamHealth::default();
+        listener.router(&health);
+        assert!(health.addresses().is_empty());
+        //SOCKS5 обходится без upstream, но не без ключей клиентов
+        let settings = Settings::parse("[[listeners]]\nlisten = \"0.0.0.0:1\"\nmode = \"socks5\"").unwrap();
+        assert!(settings.validate().is_err());
+        let listener = &settings.listeners()[0];
+        assert_eq!(ListenerMode::Socks5, listener.mode);
+        assert!(!listener.router(&UpstreamHealth::default()).local_targets());
+        let settings = Settings::parse("keys = \"clients.keys\"\n[[listeners]]\nlisten = \"0.0.0.0:1\"\nmode = \"socks5\"\nallow_local_targets = true").unwrap();
+        settings.validate().unwrap();
+        assert!(settings.listeners()[0].router(&UpstreamHealth::default()).local_targets());
+        let settings = Settings::parse(r#"
+            [[listeners]]
+            listen = "0.0.0.0:12010"
+            upstream = "127.0.0.1:1194"
+            [[listeners]]
+            listen = "0.0.0.0:12010"
+            upstream = "127.0.0.1:1080"
+        "#).unwrap();
+        assert!(settings.validate().is_err());
+
+        let cli = Cli::parse_from(["equalizer", "--mode", "tcp", "--upstream", "127.0.0.1:1080"]);
+        let settings = Settings::load(cli).unwrap();
+        assert_eq!(ListenerMode::Tcp, settings.listeners()[0].mode);
+    }
+
+    /**
+        Пример из Service совпадает со значениями по умолчанию
+    */
+    #[test]
+    fn example_file_test() {
+        let settings = Settings::parse(include_str!("../Service/equalizer.toml")).unwrap();
+        settings.validate().unwrap();
+        assert_eq!(SpeedSettings::default(), settings.speed);
+    }
+
+    /**
+        Старый формат запуска ./equalizer 12010 1194 --service продолжает работать
+    */
+    #[test]
+    fn legacy_cli_test() {
+        let cli = Cli::parse_from(["equalizer", "12010", "1194", "--service"]);
+        let settings = Settings::load(cli).unwrap();
+        assert_eq!("0.0.0.0:12010", settings.listen);
+        assert_eq!("127.0.0.1:1194", settings.upstream);
+        assert!(settings.service);
+        assert!(settings.log.file.is_none());
+        //управляющий сокет и восстановление сессий только по явной просьбе
+        assert!(settings.admin_socket.is_none());
+        assert!(settings.resume_grace().is_zero());
+
+        let cli = Cli::parse_from(["equalizer", "--listen", "127.0.0.1:1", "--upstream", "127.0.0.1:2",
+            "--filler", "zero", "--log-file", "", "--speed-policy", "pi", "--admin-socket", "/tmp/equalizer.sock"]);
+        let settings = Settings::load(cli).unwrap();
+        assert_eq!("127.0.0.1:1", settings.listen);
+        assert_eq!(Some("/tmp/equalizer.sock"), settings.admin_socket.as_deref());
+        assert!(settings.log.file.is_none());
+        assert!(matches!(settings.filler.content, FillerContentKind::Zero));
+        assert_eq!(SpeedPolicyKind::Pi, settings.speed.policy);
+    }
+
+    /**
+        Адрес из командной строки при нескольких слушателях файла - ошибка, а не молчаливый пропуск
+    */
+    #[test]
+    fn listeners_cli_test() {
+        let config = "[[listeners]]\nlisten = \"0.0.0.0:1\"\nupstream = \"127.0.0.1:1194\"";
+        let mut settings = Settings::parse(config).unwrap();
+        assert!(settings.apply(Cli::parse_from(["equalizer", "--log-level", "info"])).is_ok());
+        for args in [&["equalizer", "--listen", "0.0.0.0:2"][..], &["equalizer", "--mode", "tcp"], &["equalizer", "12010"]] {
+            let mut settings = Settings::parse(config).unwrap();
+            assert!(settings.apply(Cli::parse_from(args)).is_err(), "{args:?}");
+        }
+    }
+}
@@ -0,0 +1,211 @@
+/*
+Оценка пропускной способности канала к клиенту (по мотивам BBR).
+Отправленное делим на интервалы SAMPLE_PERIOD, для каждого считаем скорость доставки.
+Если запись клиенту заметную часть интервала ждала освобождения буфера отправки
+(BLOCKED_PERCENT, от WouldBlock до готовности сокета к записи по событию реактора),
+скорость доставки и есть пропускная способность канала.
+Оценка - максимум таких измерений за BOTTLENECK_WINDOW. Интервал без ожидания,
+но быстрее текущей оценки, тоже ее поднимает (канал пропустил больше).
+Пока оценка есть, скорость заполнителя не выше cap_percent от нее - маскировка
+не отнимает полосу у данных. Без новых измерений оценка устаревает,
+ограничение снимается и регулятор снова нащупывает канал.
+Интервал, за который ничего не доставлено (запись стояла целиком), канал не оценивает.
+Если ограничение ниже скорости отключения заполнителя - заполнитель отключаем.
+*/
+use crate::speed::{SpeedCorrectorCommand, SpeedSetupParam, PERCENT_100};
+use log::debug;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const SAMPLE_PERIOD: Duration = Duration::from_millis(200);
+//доля интервала, которую запись ждала - канал забит
+const BLOCKED_PERCENT: usize = 10;
+const BOTTLENECK_WINDOW: Duration = Duration::from_secs(10);
+
+struct Sample {
+    from: Instant,
+    delivered: usize,
+    blocked: Duration,
+}
+
+impl Sample {
+    fn new(from: Instant) -> Sample {
+        Self {
+            from,
+            delivered: 0,
+            blocked: Duration::ZERO,
+        }
+    }
+}
+
+pub struct BandwidthEstimator {
+    //0 - не ограничиваем
+    cap_percent: usize,
+    //ниже этой скорости заполнитель отключается
+    shutdown_speed: usize,
+    sample: Option<Sample>,
+    //(время измерения, байт/мс) за BOTTLENECK_WINDOW
+    measured: VecDeque<(Instant, usize)>,
+}
+
+impl BandwidthEstimator {
+    pub fn new(cap_percent: usize, shutdown_speed: usize) -> BandwidthEstimator {
+        Self {
+            cap_percent,
+            shutdown_speed,
+            sample: None,
+            measured: VecDeque::new(),
+        }
+    }
+
+    /**
+        Отправлено клиенту delivered байт, при этом сокет клиента не принимал данные blocked
+    */
+    pub fn sent(&mut self, delivered: usize, blocked: Duration, now: Instant) {
+        //отправленное до начала первого интервала не учитываем
+        let Some(sample) = self.sample.as_mut() else {
+            self.sample = Some(Sample::new(now));
+            return;
+        };
+        sample.delivered += delivered;
+        sample.blocked += blocked;
+        let elapsed = now.duration_since(sample.from);
+        if elapsed < SAMPLE_PERIOD {
+            return;
+        }
+        let Sample { delivered, blocked, .. } = std::mem::replace(sample, Sample::new(now));
+        let rate = delivered / elapsed.as_millis() as usize;
+        if rate == 0 {
+            debug!("bandwidth: за {elapsed:?} доставлено {delivered} байт, blocked {blocked:?} - не оцениваем");
+            return;
+        }
+        let saturated = blocked.as_micros() * PERCENT_100 as u128 >= elapsed.as_micros() * BLOCKED_PERCENT as u128;
+        let bottleneck = self.bottleneck(now);
+        if saturated || bottleneck.is_some_and(|bottleneck| rate > bottleneck) {
+            debug!("bandwidth: {rate} b/ms, blocked {blocked:?} за {elapsed:?}, оценка была {bottleneck:?}");
+            self.measured.push_back((now, rate));
+        }
+    }
+
+    /**
+        Пропускная способность канала, байт/мс (None - канал ни разу не забили)
+    */
+    pub fn bottleneck(&mut self, now: Instant) -> Option<usize> {
+        while self.measured.front().is_some_and(|(at, _)| now.duration_since(*at) > BOTTLENECK_WINDOW) {
+            self.measured.pop_front();
+        }
+        self.measured.iter().map(|(_, rate)| *rate).max()
+    }
+
+    /**
+        Команда регулятора с учетом канала: скорость не выше cap_percent от пропускной способности.
+        Если канал оказался уже установленной скорости - снижаем ее, даже когда регулятор молчит
+    */
+    pub fn limit(&mut self, command: Option<SpeedCorrectorCommand>, last: Option<&SpeedSetupParam>, now: Instant)
+        -> Option<SpeedCorrectorCommand> {
+        if self.cap_percent == 0 {
+            return command;
+        }
+        let Some(cap) = self.bottleneck(now).map(|bottleneck| bottleneck * self.cap_percent / PERCENT_100) else {
+            return command;
+        };
+        match command {
+            Some(SpeedCorrectorCommand::SetSpeed(_)) | None if cap < self.shutdown_speed && (command.is_some() || last.is_some()) => {
+                debug!("bandwidth: канал {cap} ниже скорости отключения {}", self.shutdown_speed);
+                Some(SpeedCorrectorCommand::SwitchOff)
+            }
+            Some(SpeedCorrectorCommand::SetSpeed(speed)) if speed > cap => {
+                debug!("bandwidth: скорость {speed} ограничена каналом {cap}");
+                Some(SpeedCorrectorCommand::SetSpeed(cap))
+            }
+            None if last.is_some_and(|last| last.value > cap) => {
+                debug!("bandwidth: канал {cap} уже установленной скорости");
+                Some(SpeedCorrectorCommand::SetSpeed(cap))
+            }
+            command => command,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::speed::bandwidth::{BandwidthEstimator, BOTTLENECK_WINDOW, SAMPLE_PERIOD};
+    use crate::speed::{SpeedCorrectorCommand, SpeedSetupParam, SHUTDOWN_SPEED};
+    use std::time::{Duration, Instant};
+
+    /**
+        Интервал SAMPLE_PERIOD с заданной скоростью (байт/мс) и ожиданием записи
+    */
+    fn sent_for_sample(estimator: &mut BandwidthEstimator, speed: usize, blocked: Duration, now: &mut Instant) {
+        *now += SAMPLE_PERIOD;
+        estimator.sent(speed * SAMPLE_PERIOD.as_millis() as usize, blocked, *now);
+    }
+
+    #[test]
+    fn bottleneck_test() {
+        let mut estimator = BandwidthEstimator::new(95, SHUTDOWN_SPEED);
+        let mut now = Instant::now();
+        estimator.sent(1000, Duration::ZERO, now);
+        //запись не ждала - канал неизвестен
+        sent_for_sample(&mut estimator, 1000, Duration::ZERO, &mut now);
+        assert_eq!(None, estimator.bottleneck(now));
+        sent_for_sample(&mut estimator, 1000, Duration::from_millis(50), &mut now);
+        assert_eq!(Some(1000), estimator.bottleneck(now));
+        //медленнее без ожидания - оценку не меняет, быстрее - поднимает
+        sent_for_sample(&mut estimator, 500, Duration::ZERO, &mut now);
+        assert_eq!(Some(1000), estimator.bottleneck(now));
+        sent_for_sample(&mut estimator, 1200, Duration::ZERO, &mut now);
+        assert_eq!(Some(1200), estimator.bottleneck(now));
+        //устарела
+        now += BOTTLENECK_WINDOW * 2;
+        assert_eq!(None, estimator.bottleneck(now));
+    }
+
+    #[test]
+    fn limit_test() {
+        let mut estimator = BandwidthEstimator::new(95, SHUTDOWN_SPEED);
+        let mut now = Instant::now();
+        estimator.sent(1000, Duration::ZERO, now);
+        let command = Some(SpeedCorrectorCommand::SetSpeed(2000));
+        assert_eq!(command, estimator.limit(command, None, now));
+
+        sent_for_sample(&mut estimator, 1000, Duration::from_millis(100), &mut now);
+        assert_eq!(Some(SpeedCorrectorCommand::SetSpeed(950)), estimator.limit(command, None, now));
+        let command = Some(SpeedCorrectorCommand::SetSpeed(900));
+        assert_eq!(command, estimator.limit(command, None, now));
+        assert_eq!(Some(SpeedCorrectorCommand::SwitchOff), estimator.limit(Some(SpeedCorrectorCommand::SwitchOff), None, now));
+        //установленная скорость выше канала
+        let last = SpeedSetupParam::new(1500, now);
+        assert_eq!(Some(SpeedCorrectorCommand::SetSpeed(950)), estimator.limit(None, Some(&last), now));
+        let last = SpeedSetupParam::new(950, now);
+        assert_eq!(None, estimator.limit(None, Some(&last), now));
+
+        let mut estimator = BandwidthEstimator::new(0, SHUTDOWN_SPEED);
+        estimator.sent(0, Duration::ZERO, now);
+        sent_for_sample(&mut estimator, 1000, Duration::from_millis(100), &mut now);
+        assert_eq!(None, estimator.limit(None, Some(&SpeedSetupParam::new(1500, now)), now));
+    }
+
+    /**
+        Запись простояла весь интервал и ничего не доставила - нулевую скорость канала не запоминаем.
+        Ограничение ниже скорости отключения - отключаем заполнитель, а не ставим скорость 0
+    */
+    #[test]
+    fn stalled_sample_test() {
+        let mut estimator = BandwidthEstimator::new(95, SHUTDOWN_SPEED);
+        let mut now = Instant::now();
+        estimator.sent(0, Duration::ZERO, now);
+        sent_for_sample(&mut estimator, 0, SAMPLE_PERIOD, &mut now);
+        assert_eq!(None, estimator.bottleneck(now));
+        let command = Some(SpeedCorrectorCommand::SetSpeed(1000));
+        assert_eq!(command, estimator.limit(command, None, now));
+
+        let mut estimator = BandwidthEstimator::new(1, SHUTDOWN_SPEED);
+        estimator.sent(0, Duration::ZERO, now);
+        sent_for_sample(&mut estimator, 50, SAMPLE_PERIOD, &mut now);
+        assert_eq!(Some(50), estimator.bottleneck(now));
+        assert_eq!(Some(SpeedCorrectorCommand::SwitchOff), estimator.limit(command, None, now));
+        assert_eq!(Some(SpeedCorrectorCommand::SwitchOff), estimator.limit(None, Some(&SpeedSetupParam::new(1000, now)), now));
+        assert_eq!(None, estimator.limit(None, None, now));
+    }
+}
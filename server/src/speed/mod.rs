@@ -2,10 +2,15 @@ use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io::BufWriter;
 use std::time::Instant;
-use std::time::Duration;
 use log::{log_enabled, Level};
+use crate::speed::bandwidth::BandwidthEstimator;
+//как по статистике выбирается скорость (ступенчатый или ПИ-регулятор) - общее с клиентом
+pub use speed_control::{SpeedCorrectorCommand, SpeedForPeriod, SpeedPolicy, SpeedPolicyKind, SpeedSettings, SpeedSetupParam};
+pub(crate) use speed_control::{PERCENT_100, SHUTDOWN_SPEED};
 
 pub mod speed_correction;
+//пропускная способность канала к клиенту - выше нее заполнитель не поднимается
+pub mod bandwidth;
 mod modify_collected_info;
 mod speed_calculation;
 mod packets_logging;
@@ -14,27 +19,25 @@ mod packets_logging;
 
 //все время шлем данные, чтобы впн-у не пришлось свой keep-alive слать
 
-//скорость ниже которой мы отключаем филлер (не до жиру - быть бы живу)
-pub(crate) const SHUTDOWN_SPEED : usize = 100 * 1024 / 1000;//Убрать ссылки, инициализировать объекты по-требованию
-pub(crate) const ENABLE_SPEED : usize = 150 * 1024 / 1000;
-pub const M_COND: usize = (1024 * 1024 / 10) / 1000;//TODO move
-pub const TO_MB: usize = 1024 * 1024; //TODO move
+pub const TO_MB: usize = 1024 * 1024;
 pub const TO_KB: usize = 1024;
-const PERCENT_100: usize = 100;
-pub(crate) const LONG_TERM: Duration = Duration::from_secs(3);
-//меняем скорость не чаще этого периода
-pub(crate) const INCREASE_SPEED_PERIOD: Duration = Duration::from_millis(500);
-pub(crate) const DECREASE_SPEED_PERIOD: Duration = Duration::from_secs(10);
 
 /*
-Пересчитать байт/мс в Мбит/с
+Пересчитать байт/мс в Мбит/с (с округлением)
  */
 pub fn native_to_regular(speed: usize) -> String {
     let bit_per_s = speed * 1000 * 8;
-    if speed > M_COND {
-        return format!("{}MBit", bit_per_s / TO_MB);
+    if bit_per_s >= TO_MB {
+        return format!("{}MBit", (bit_per_s + TO_MB / 2) / TO_MB);
     }
-    return format!("{}KBit", bit_per_s / TO_KB);
+    format!("{}KBit", (bit_per_s + TO_KB / 2) / TO_KB)
+}
+
+/*
+Мбит/с в байт/мс
+ */
+pub fn mbit_to_native(mbit: usize) -> usize {
+    mbit * TO_MB / 8 / 1000
 }
 
 /**
@@ -49,62 +52,36 @@ pub fn to_regular_speed(bytes_ms: usize) -> usize {
     bytes_ms / 105
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum SpeedCorrectorCommand {
-    SwitchOff,
-    SetSpeed(usize),
-}
-
-impl PartialEq for SpeedCorrectorCommand {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (SpeedCorrectorCommand::SwitchOff, SpeedCorrectorCommand::SwitchOff) => true,
-            (SpeedCorrectorCommand::SetSpeed(a), SpeedCorrectorCommand::SetSpeed(b)) => a == b,
-            _ => false,
-        }
-    }
-}
-
 pub struct SpeedCorrector {
     collected_info: HashMap<String, Info>,
+    settings: SpeedSettings,
 }
 
-struct SpeedForPeriod {
-    speed: usize,
-    data_percent: usize, //0-100
-}
-struct SpeedSetupParam {
-    command_time: Instant,
-    value: usize
-}
-
-impl SpeedSetupParam {
-    pub(crate) fn new(speed: usize, command_time: Instant) -> SpeedSetupParam {
-        Self {
-            value: speed,
-            command_time
-        }
-    }
-}
-
-#[derive(Default)]
 struct Info {
     sent_data: VecDeque<TimeSpanSentDataInfo>,
     //последняя установленная скорость
     last_speed_command: Option<SpeedSetupParam>,
     sequence_data: u64,
     speed_logging: Option<SpeedLogging>,
+    //у каждого клиента свой регулятор (со своим состоянием)
+    policy: Box<dyn SpeedPolicy>,
+    bandwidth: BandwidthEstimator,
 }
 
 
 impl Info {
-    pub fn new() -> Self {
+    pub fn new(settings: &SpeedSettings) -> Self {
         let speed_logging = if log_enabled!(Level::Trace) {
             Some(SpeedLogging::new())
         } else { None };
-        let mut info = Info::default();
-        info.speed_logging = speed_logging;
-        info
+        Self {
+            sent_data: VecDeque::new(),
+            last_speed_command: None,
+            sequence_data: 0,
+            speed_logging,
+            policy: settings.create_policy(),
+            bandwidth: BandwidthEstimator::new(settings.bandwidth_cap_percent, settings.shutdown_speed),
+        }
     }
     fn next_sequence_data(&mut self) -> u64 {
         self.sequence_data += 1;
@@ -125,3 +102,14 @@ struct TimeSpanSentDataInfo {
     filler_size: usize,
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::speed::{mbit_to_native, native_to_regular};
+
+    #[test]
+    fn speed_conversion_test() {
+        assert_eq!("10MBit", native_to_regular(mbit_to_native(10)));
+        assert_eq!("100MBit", native_to_regular(mbit_to_native(100)));
+        assert_eq!("781KBit", native_to_regular(100));
+    }
+}
@@ -35,6 +35,7 @@ pub fn append_new_data(hp: &HotPotatoInfo, info: &mut Info) -> u64 {
         filler_size,
     };
 
+    info.bandwidth.sent(data_size + filler_size, hp.write_blocked, data.from);
     if let Some(log) = info.speed_logging.as_mut() {
         log.append_new_data_log(&data);
     }
@@ -79,6 +80,7 @@ mod tests {
     use std::thread::sleep;
     use std::time::{Duration, Instant};
     use log::trace;
+    use crate::speed::SpeedSettings;
     use crate::speed::{Info};
     use crate::speed::{TimeSpanSentDataInfo};
     use crate::speed::modify_collected_info::clear_old_data;
@@ -92,7 +94,7 @@ mod tests {
     fn clear_data_test() {
         initialize_logger();
         let mut id = 0;
-        let mut info = Info::default();
+        let mut info = Info::new(&SpeedSettings::default());
         let window = Duration::from_secs(2);
         let mut queue_size = 0;
         for _i in 0..50 {
@@ -4,33 +4,30 @@ SHORT_TERM - для целей повышения скорости - TODO
 держим пропорцию 80/20 (полезных данных по отношению к заполнителю)
 Если за LONG_TERM пропорция снизилась на 70/30 - снижаем скорость
 Если не снизилась (не ниже 75/25) и SHORT_TERM перешел в другую сторону 90/10 - повышаем скорость
+Как именно меняется скорость - решает политика из настроек (крейт speed-control, общий с клиентом)
  */
 use crate::objects::HotPotatoInfo;
 use crate::speed::modify_collected_info::{append_new_data, clear_old_data};
 use crate::speed::speed_calculation::get_speed;
-use crate::speed::{Info, SpeedCorrector, SpeedCorrectorCommand, SpeedForPeriod, LONG_TERM, INCREASE_SPEED_PERIOD, SHUTDOWN_SPEED, DECREASE_SPEED_PERIOD, PERCENT_100, ENABLE_SPEED, SpeedSetupParam};
+use crate::speed::{Info, SpeedCorrector, SpeedCorrectorCommand, SpeedSettings, SpeedSetupParam};
 use std::collections::HashMap;
-use std::ops::Add;
 use std::time::{Instant};
 use log::{debug, trace};
 
-const TARGET_PERCENT: usize = 80;
 //для быстрого отключения филлера при слабом канале
 //const LOW_SPEED_PROPORTION: usize = 90;
-//свободный ход в %. Если отклонились от целевого значения на эту величину - ничего не предпринимаем.
-const FREE_PLAY: usize = 2;
-//Если процент полезных данных ниже этого значения - уменьшаем скорость (скорость избыточна)
-const DOWN_TRIGGER: usize = TARGET_PERCENT - FREE_PLAY;
-//Если процент полезных данных выше этого значения - увеличиваем скорость (скорость недостаточна для компенсации всплеска)
-const UP_TRIGGER: usize = TARGET_PERCENT + FREE_PLAY;
-const UP_ACCELERATION: usize = 70;
-const DOWN_ACCELERATION: usize = 50;
 
 
 impl SpeedCorrector {
-    pub fn new() -> SpeedCorrector {
+    #[cfg(test)]
+    pub(crate) fn new() -> SpeedCorrector {
+        SpeedCorrector::with_settings(SpeedSettings::default())
+    }
+
+    pub fn with_settings(settings: SpeedSettings) -> SpeedCorrector {
         Self {
             collected_info: HashMap::new(),
+            settings,
         }
     }
 
@@ -40,16 +37,16 @@ impl SpeedCorrector {
     //#[inline(never)]
     pub fn append_and_get(
         &mut self,
-        key: &String,
+        key: &str,
         hp: &HotPotatoInfo,
     ) -> Option<SpeedCorrectorCommand> {
-        if !self.collected_info.contains_key(key) {
-            self.collected_info.insert(key.clone(), Info::new());
-        }
-        let info = self.collected_info.get_mut(key).unwrap();
+        let settings = &self.settings;
+        let info = self.collected_info.entry(key.to_string())
+            .or_insert_with(|| Info::new(settings));
+        let long_term = settings.long_term();
         let before_size = info.sent_data.len();
         let new_id = append_new_data(hp, info);
-        clear_old_data(info, LONG_TERM);
+        clear_old_data(info, long_term);
         let after_size = info.sent_data.len();
         trace!("#{new_id} before_size: {}, after_size: {}", before_size, after_size);
 
@@ -58,27 +55,14 @@ impl SpeedCorrector {
         }else{
             Instant::now()
         };
-        let last_correction_date = if info.last_speed_command.is_some() {
-            let command_time = &info.last_speed_command.as_ref().unwrap().command_time;
-            Some(command_time)
-        }else{
-            None
-        };
         let mut command = None;
-        if let Some(long_term_speed) = get_speed(LONG_TERM, &info.sent_data) {
+        if let Some(long_term_speed) = get_speed(long_term, &info.sent_data) {
             if let Some(log) = info.speed_logging.as_mut() {
-                log.get_speed_log(LONG_TERM, &info.sent_data, &long_term_speed);
-            }
-            trace!("calculated speed {} {}%", long_term_speed.speed, long_term_speed.data_percent);
-            if last_correction_date.is_none_or(|time| time.add(INCREASE_SPEED_PERIOD) < now)
-                && long_term_speed.data_percent > UP_TRIGGER {
-                    debug!("increase due percent {} #{new_id}", long_term_speed.data_percent);
-                command = Self::increase_command(&long_term_speed, info);
-            } else if last_correction_date.is_none_or(|time| time.add(DECREASE_SPEED_PERIOD) < now)
-                && long_term_speed.data_percent < DOWN_TRIGGER {
-                debug!("decrease due percent {} #{new_id}", long_term_speed.data_percent);
-                command = Self::decrease_command(&long_term_speed);
+                log.get_speed_log(long_term, &info.sent_data, &long_term_speed);
             }
+            trace!("calculated speed {} {}% #{new_id}", long_term_speed.speed, long_term_speed.data_percent);
+            command = info.policy.correct(&long_term_speed, info.last_speed_command.as_ref(), now);
+            command = info.bandwidth.limit(command, info.last_speed_command.as_ref(), now);
             //не удалось посчитать скорость, но мы ее уже ранее считали (большие задержки - отпускаем все)
         } else if info.last_speed_command.is_none() {
             debug!("Недостаточно данных для анализа - отключаем");
@@ -98,8 +82,8 @@ impl SpeedCorrector {
         command
     }
 
-    pub fn clear_info(&mut self, key: &String) {
-        let _ = self.collected_info.remove(&key.clone());
+    pub fn clear_info(&mut self, key: &str) {
+        let _ = self.collected_info.remove(key);
     }
 
 
@@ -111,41 +95,13 @@ impl SpeedCorrector {
         }
         None
     }
-
-    fn decrease_command(current_speed: &SpeedForPeriod) -> Option<SpeedCorrectorCommand> {
-        let new_speed  = current_speed.speed as i32 - DOWN_ACCELERATION as i32;
-        if new_speed < SHUTDOWN_SPEED as i32 {
-            return Some(SpeedCorrectorCommand::SwitchOff)
-        }
-        Some(SpeedCorrectorCommand::SetSpeed(new_speed as usize))
-    }
-    fn increase_command(current_speed: &SpeedForPeriod, info: &Info) -> Option<SpeedCorrectorCommand> {
-        //предыдущая запрошенная скорость
-        if let Some(prev_requested_command) = &info.last_speed_command {
-            if current_speed.speed < Self::minus_7p(prev_requested_command.value) {
-                debug!("Текущая скорость {} ниже запрошенной {}, (уперлись в пропускную способность)",
-                        current_speed.speed, prev_requested_command.value);
-                return None;
-            }
-        }
-        //новая увеличенная скорость основанная на данных за последние пол секунды
-        if info.last_speed_command.is_none() && current_speed.speed > ENABLE_SPEED {
-            Some(SpeedCorrectorCommand::SetSpeed(ENABLE_SPEED))
-        }else {
-            Some(SpeedCorrectorCommand::SetSpeed(current_speed.speed + UP_ACCELERATION))
-        }
-    }
-
-    fn minus_7p(value: usize) -> usize{
-        value * 93 / PERCENT_100
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::objects::{HotPotatoInfo, SentPacket, MAX_STAT_COUNT};
-    use crate::speed::speed_correction::{FREE_PLAY, PERCENT_100, TARGET_PERCENT};
-    use crate::speed::{to_native_speed, to_regular_speed, SpeedCorrector, SpeedCorrectorCommand, SHUTDOWN_SPEED};
+    use speed_control::{BANDWIDTH_CAP_PERCENT, FREE_PLAY, TARGET_PERCENT};
+    use crate::speed::{to_native_speed, PERCENT_100, to_regular_speed, SpeedCorrector, SpeedCorrectorCommand, SHUTDOWN_SPEED};
     use crate::tests::test_init::initialize_logger;
     use log::{debug, info};
     use rand::{Rng};
@@ -256,6 +212,33 @@ mod tests {
         assert!(switch_off);
     }
 
+    /**
+    Те же 50MBit/s, но запись клиенту все время ждет - канал забит.
+    Скорость не поднимается выше bandwidth_cap_percent от пропускной способности
+     */
+    #[test]
+    fn bandwidth_cap_test() {
+        initialize_logger();
+        let key = String::from("test");
+        let mut speed_corrector = SpeedCorrector::new();
+        let bytes_per_ms = to_native_speed(50);
+        let duration = Duration::from_millis(10);
+
+        let mut speed_setup_request = 0;
+        for _i in 0..70 {
+            let from = Instant::now();
+            let mut hp = get_mock_hp(bytes_per_ms * 10, PERCENT_100, 5, from, duration);
+            hp.write_blocked = duration / 2;
+            sleep(duration);
+            if let Some(SpeedCorrectorCommand::SetSpeed(speed)) = speed_corrector.append_and_get(&key, &hp) {
+                speed_setup_request = speed;
+            }
+        }
+        info!("requested_speed: {speed_setup_request}, bottleneck: {bytes_per_ms}");
+        assert!(speed_setup_request > 0);
+        assert!(speed_setup_request <= bytes_per_ms * BANDWIDTH_CAP_PERCENT / PERCENT_100);
+    }
+
     /*
        let mut rng = rand::rng();
 
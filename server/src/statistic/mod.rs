@@ -1,9 +1,12 @@
 use crate::objects::{HotPotatoInfo, SentPacket};
-use crate::speed::{SHUTDOWN_SPEED};
+use crate::speed::{SpeedCorrectorCommand, SHUTDOWN_SPEED};
 use std::ops::Sub;
 use std::time::Duration;
 use std::time::Instant;
 
+//метрики для Prometheus (/metrics)
+pub mod prometheus;
+
 #[derive(Debug, Default)]
 pub struct Summary {
     pub key: String,
@@ -19,6 +22,51 @@ pub trait StatisticCollector {
     fn append_info(&mut self, key: &String, info: HotPotatoInfo);
     fn clear_info(&mut self, key: &String);
     fn calculate_and_get(&mut self) -> Option<Vec<Summary>>;
+    //подключился клиент (в том числе повторно с тем же ключом)
+    fn client_connected(&mut self, _key: &String) {}
+    //прокси отправлена команда скорости (регулятором или через управляющий сокет)
+    fn speed_changed(&mut self, _key: &String, _command: SpeedCorrectorCommand) {}
+}
+
+/**
+    Несколько сборщиков сразу (консоль и метрики).
+    Сводку отдает первый сборщик, который ее посчитал
+*/
+pub struct StatisticCollectors {
+    collectors: Vec<Box<dyn StatisticCollector>>,
+}
+
+impl StatisticCollectors {
+    pub fn new(collectors: Vec<Box<dyn StatisticCollector>>) -> StatisticCollectors {
+        Self { collectors }
+    }
+}
+
+impl StatisticCollector for StatisticCollectors {
+    fn append_info(&mut self, key: &String, info: HotPotatoInfo) {
+        if let Some((last, others)) = self.collectors.split_last_mut() {
+            for collector in others {
+                collector.append_info(key, info.clone());
+            }
+            last.append_info(key, info);
+        }
+    }
+
+    fn clear_info(&mut self, key: &String) {
+        self.collectors.iter_mut().for_each(|collector| collector.clear_info(key));
+    }
+
+    fn calculate_and_get(&mut self) -> Option<Vec<Summary>> {
+        self.collectors.iter_mut().find_map(|collector| collector.calculate_and_get())
+    }
+
+    fn client_connected(&mut self, key: &String) {
+        self.collectors.iter_mut().for_each(|collector| collector.client_connected(key));
+    }
+
+    fn speed_changed(&mut self, key: &String, command: SpeedCorrectorCommand) {
+        self.collectors.iter_mut().for_each(|collector| collector.speed_changed(key, command));
+    }
 }
 
 //заглушка для тестов и работы в режиме службы
@@ -0,0 +1,302 @@
+/*
+Метрики клиентов для Prometheus.
+Счетчики копит оркестратор (через StatisticCollector), отдает отдельный поток
+по GET /metrics в текстовом формате Prometheus. Слушать стоит только локальный адрес.
+*/
+use crate::entry::health::UpstreamHealth;
+use crate::objects::HotPotatoInfo;
+use crate::speed::SpeedCorrectorCommand;
+use crate::statistic::{SimpleStatisticCollector, StatisticCollector, Summary};
+use easy_error::{Error, ResultExt};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+const ACCEPT_DELAY: Duration = Duration::from_millis(50);
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/**
+    Общие для оркестратора и потока /metrics данные
+*/
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<MetricsInner>>,
+    //доступность VPN серверов (см. entry/health.rs)
+    upstreams: UpstreamHealth,
+}
+
+#[derive(Default)]
+struct MetricsInner {
+    //доля данных и скорость за последние 300мс
+    rolling: SimpleStatisticCollector,
+    clients: HashMap<String, ClientMetrics>,
+}
+
+struct ClientMetrics {
+    connected_at: Instant,
+    data_bytes: u64,
+    filler_bytes: u64,
+    //от клиента (маскировка в сторону эквалайзера)
+    received_data_bytes: u64,
+    received_filler_bytes: u64,
+    //байт/мс, None - заполнитель отключен
+    target_speed: Option<usize>,
+}
+
+impl ClientMetrics {
+    fn new() -> ClientMetrics {
+        ClientMetrics {
+            connected_at: Instant::now(),
+            data_bytes: 0,
+            filler_bytes: 0,
+            received_data_bytes: 0,
+            received_filler_bytes: 0,
+            //прокси начинает без заполнителя
+            target_speed: None,
+        }
+    }
+}
+
+pub struct PrometheusCollector {
+    metrics: Metrics,
+}
+
+impl PrometheusCollector {
+    pub fn new(metrics: Metrics) -> PrometheusCollector {
+        Self { metrics }
+    }
+}
+
+impl StatisticCollector for PrometheusCollector {
+    fn append_info(&mut self, key: &String, info: HotPotatoInfo) {
+        let mut inner = self.metrics.lock();
+        let client = inner.clients.entry(key.clone()).or_insert_with(ClientMetrics::new);
+        client.data_bytes += info.data_packets[..info.data_count].iter()
+            .flatten().map(|packet| packet.sent_size as u64).sum::<u64>();
+        client.filler_bytes += info.filler_packets[..info.filler_count].iter()
+            .flatten().map(|packet| packet.sent_size as u64).sum::<u64>();
+        client.received_data_bytes += info.received_data as u64;
+        client.received_filler_bytes += info.received_filler as u64;
+        inner.rolling.append_info(key, info);
+    }
+
+    fn clear_info(&mut self, key: &String) {
+        let mut inner = self.metrics.lock();
+        inner.clients.remove(key);
+        inner.rolling.clear_info(key);
+    }
+
+    //для консоли и управляющего сокета есть SimpleStatisticCollector
+    fn calculate_and_get(&mut self) -> Option<Vec<Summary>> {
+        None
+    }
+
+    fn client_connected(&mut self, key: &String) {
+        let mut inner = self.metrics.lock();
+        inner.clients.insert(key.clone(), ClientMetrics::new());
+        inner.rolling.clear_info(key);
+    }
+
+    fn speed_changed(&mut self, key: &String, command: SpeedCorrectorCommand) {
+        let mut inner = self.metrics.lock();
+        let client = inner.clients.entry(key.clone()).or_insert_with(ClientMetrics::new);
+        client.target_speed = match command {
+            SpeedCorrectorCommand::SetSpeed(speed) => Some(speed),
+            SpeedCorrectorCommand::SwitchOff => None,
+        };
+    }
+}
+
+impl Metrics {
+    pub fn with_upstreams(upstreams: UpstreamHealth) -> Metrics {
+        Self { inner: Default::default(), upstreams }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MetricsInner> {
+        //паника в другом потоке не должна лишать нас метрик
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /**
+        Текстовый формат Prometheus (version 0.0.4)
+    */
+    pub fn render(&self) -> String {
+        let mut inner = self.lock();
+        let summaries = inner.rolling.calculate_and_get().unwrap_or_default();
+        let mut keys: Vec<&String> = inner.clients.keys().collect();
+        keys.sort();
+        let mut out = String::new();
+        metric_header(&mut out, "equalizer_clients", "gauge", "Подключенные клиенты");
+        let _ = writeln!(out, "equalizer_clients {}", keys.len());
+        let now = Instant::now();
+        let mut family = |name: &str, kind: &str, help: &str, value: &dyn Fn(&String, &ClientMetrics) -> String| {
+            metric_header(&mut out, name, kind, help);
+            for key in keys.iter() {
+                let client = &inner.clients[*key];
+                let _ = writeln!(out, "{name}{{client=\"{}\"}} {}", escape_label(key), value(key, client));
+            }
+        };
+        let summary = |key: &String| summaries.iter().find(|summary| &summary.key == key);
+        family("equalizer_data_bytes_total", "counter", "Отправлено клиенту полезных данных",
+               &|_, client| client.data_bytes.to_string());
+        family("equalizer_filler_bytes_total", "counter", "Отправлено клиенту заполнителя",
+               &|_, client| client.filler_bytes.to_string());
+        family("equalizer_received_data_bytes_total", "counter", "Получено от клиента полезных данных",
+               &|_, client| client.received_data_bytes.to_string());
+        family("equalizer_received_filler_bytes_total", "counter", "Получено от клиента заполнителя",
+               &|_, client| client.received_filler_bytes.to_string());
+        family("equalizer_data_percent", "gauge", "Доля полезных данных за последние 300мс",
+               &|key, _| summary(key).map(|summary| summary.percent_data).unwrap_or(0).to_string());
+        family("equalizer_calculated_speed_bytes_per_second", "gauge", "Скорость к клиенту за последние 300мс",
+               &|key, _| (summary(key).map(|summary| summary.calculated_speed).unwrap_or(0) * 1000).to_string());
+        family("equalizer_target_speed_bytes_per_second", "gauge", "Установленная скорость (0 - без заполнителя)",
+               &|_, client| (client.target_speed.unwrap_or(0) * 1000).to_string());
+        family("equalizer_free_mode", "gauge", "1 - заполнитель отключен",
+               &|_, client| u8::from(client.target_speed.is_none()).to_string());
+        family("equalizer_connection_age_seconds", "gauge", "Время с подключения клиента",
+               &|_, client| format!("{:.3}", now.duration_since(client.connected_at).as_secs_f64()));
+        let upstreams = self.upstreams.statuses();
+        metric_header(&mut out, "equalizer_upstream_up", "gauge", "Доступность VPN сервера (1 - доступен)");
+        for upstream in upstreams.iter() {
+            let _ = writeln!(out, "equalizer_upstream_up{{upstream=\"{}\"}} {}", escape_label(&upstream.address), u8::from(upstream.healthy));
+        }
+        metric_header(&mut out, "equalizer_upstream_failures", "gauge", "Неудачных проверок VPN сервера подряд");
+        for upstream in upstreams.iter() {
+            let _ = writeln!(out, "equalizer_upstream_failures{{upstream=\"{}\"}} {}", escape_label(&upstream.address), upstream.failures);
+        }
+        out
+    }
+}
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/**
+    HTTP сервер /metrics. Адрес занимается сразу (ошибки видны при запуске)
+*/
+pub fn start_metrics(
+    address: String,
+    metrics: Metrics,
+    stop_application_request: Receiver<bool>,
+) -> Result<JoinHandle<()>, Error> {
+    let listener = TcpListener::bind(&address).context(format!("Не удалось открыть адрес метрик {address}"))?;
+    listener.set_nonblocking(true).context("Set nonblocking for metrics")?;
+    info!("Metrics http://{address}/metrics");
+    let join = thread::Builder::new()
+        .name("metrics".to_string()).spawn(move || {
+        loop {
+            while let Ok((stream, _)) = listener.accept() {
+                if let Err(e) = handle_scrape(stream, &metrics) {
+                    warn!("Metrics request failed {e}");
+                }
+            }
+            if stop_application_request.try_recv().is_ok() {
+                break;
+            }
+            sleep(ACCEPT_DELAY);
+        }
+        info!("Exit from metrics thread");
+    }).context("metrics thread started")?;
+    Ok(join)
+}
+
+fn handle_scrape(mut stream: TcpStream, metrics: &Metrics) -> Result<(), Error> {
+    stream.set_nonblocking(false).context("Restore blocking mode")?;
+    stream.set_read_timeout(Some(READ_TIMEOUT)).context("Set metrics read timeout")?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line).context("Read metrics request")?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let response = if request_line.starts_with("GET ") && (path == "/metrics" || path.starts_with("/metrics?")) {
+        let body = metrics.render();
+        format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).context("Write metrics response")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::health::UpstreamHealth;
+    use crate::objects::{HotPotatoInfo, SentPacket};
+    use crate::speed::SpeedCorrectorCommand;
+    use crate::statistic::prometheus::{start_metrics, Metrics, PrometheusCollector};
+    use crate::statistic::StatisticCollector;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc::channel;
+    use std::time::Instant;
+
+    const METRICS_ADDRESS: &str = "127.0.0.1:11300";
+
+    #[test]
+    fn metrics_test() {
+        let metrics = Metrics::default();
+        let mut collector = PrometheusCollector::new(metrics.clone());
+        let key = "router-\"1\"".to_string();
+        collector.client_connected(&key);
+        let mut info = HotPotatoInfo { data_count: 1, filler_count: 1, received_data: 200, received_filler: 50, ..Default::default() };
+        info.data_packets[0] = Some(SentPacket { sent_date: Instant::now(), sent_size: 3_000 });
+        info.filler_packets[0] = Some(SentPacket { sent_date: Instant::now(), sent_size: 1_000 });
+        collector.append_info(&key, info);
+        let text = metrics.render();
+        assert!(text.contains("equalizer_clients 1\n"), "{text}");
+        assert!(text.contains("equalizer_data_bytes_total{client=\"router-\\\"1\\\"\"} 3000\n"), "{text}");
+        assert!(text.contains("equalizer_filler_bytes_total{client=\"router-\\\"1\\\"\"} 1000\n"), "{text}");
+        assert!(text.contains("equalizer_received_data_bytes_total{client=\"router-\\\"1\\\"\"} 200\n"), "{text}");
+        assert!(text.contains("equalizer_received_filler_bytes_total{client=\"router-\\\"1\\\"\"} 50\n"), "{text}");
+        assert!(text.contains("equalizer_data_percent{client=\"router-\\\"1\\\"\"} 75\n"), "{text}");
+        assert!(text.contains("equalizer_free_mode{client=\"router-\\\"1\\\"\"} 1\n"), "{text}");
+
+        collector.speed_changed(&key, SpeedCorrectorCommand::SetSpeed(1048));
+        let text = metrics.render();
+        assert!(text.contains("equalizer_target_speed_bytes_per_second{client=\"router-\\\"1\\\"\"} 1048000\n"), "{text}");
+        assert!(text.contains("equalizer_free_mode{client=\"router-\\\"1\\\"\"} 0\n"), "{text}");
+
+        collector.clear_info(&key);
+        assert!(metrics.render().contains("equalizer_clients 0\n"));
+
+        let upstream = "127.0.0.1:1194".to_string();
+        let health = UpstreamHealth::new([&upstream]);
+        let metrics = Metrics::with_upstreams(health.clone());
+        health.report(&upstream, false);
+        let text = metrics.render();
+        assert!(text.contains("equalizer_upstream_up{upstream=\"127.0.0.1:1194\"} 0\n"), "{text}");
+        assert!(text.contains("equalizer_upstream_failures{upstream=\"127.0.0.1:1194\"} 1\n"), "{text}");
+    }
+
+    #[test]
+    fn metrics_http_test() {
+        let metrics = Metrics::default();
+        let (ct_stop, cr_stop) = channel();
+        let join = start_metrics(METRICS_ADDRESS.to_string(), metrics, cr_stop).unwrap();
+        let mut stream = TcpStream::connect(METRICS_ADDRESS).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.contains("equalizer_clients 0"), "{response}");
+
+        let mut stream = TcpStream::connect(METRICS_ADDRESS).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+}
@@ -19,10 +19,13 @@ pub mod test_init {
 #[cfg(test)]
 mod tests {
     
+    use std::collections::BTreeMap;
     use std::fs::File;
     use std::io::{Read, Write};
-    use std::net::{TcpListener, TcpStream};
-    use std::ops::Deref;
+    use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
     use std::rc::Rc;
     use std::sync::mpsc;
     use std::sync::mpsc::{channel, Sender};
@@ -33,12 +36,27 @@ mod tests {
     use rand::Rng;
     use rand::rngs::ThreadRng;
     use serial_test::serial;
-    use splitter::client_side_split::{split_client_stream, squash, DataStreamFiller, DataStreamVpn};
+    use splitter::client_side_split::{split_client_stream, split_client_stream_resumable, split_client_stream_with, squash, ClientSideSplit, DataStreamFiller, DataStreamVpn};
+    use splitter::secure_transport::SecureTransport;
+    use splitter::server_side_vpn_stream::VpnDataStream;
+    use splitter::FrameOptions;
+    use splitter::udp::UdpClientAdapter;
+    use splitter::handshake::{client_handshake, client_resume, Hello, CAPABILITIES_NONE, CAPABILITY_CRC32, CAPABILITY_RESUME};
+    use splitter::session::Session;
+    use crate::admin::protocol::{ClientStatus, ControlMode, UpstreamStatus, ERR, OK};
+    use crate::admin::start_admin;
+    use crate::core::reactor::Reactor;
+    use crate::core::vpn_proxy::VpnProxy;
     use crate::orchestrator::Orchestrator;
     use crate::statistic::{NoStatistic, SimpleStatisticCollector, StatisticCollector};
     use crate::tests::test_init::initialize_logger;
+    use crate::entry::auth::ClientKeys;
     use crate::entry::entry_point::*;
-    use crate::objects::{RuntimeCommand, ONE_PACKET_MAX_SIZE};
+    use crate::entry::health::UpstreamHealth;
+    use crate::entry::routing::Router;
+    use crate::entry::session::Sessions;
+    use crate::objects::{Pair, RuntimeCommand, ONE_PACKET_MAX_SIZE};
+    use crate::settings::{FillerSettings, SpeedSettings};
     use crate::speed::{to_native_speed, to_regular_speed, SpeedCorrectorCommand};
 
     const TEST_BUF_SIZE: usize = 100 * 1024;
@@ -65,15 +83,16 @@ mod tests {
             Box::new(NoStatistic::default())));
         //дальше готовимся принимать клиентов
         let (ct_stop, cr_stop) = channel();
-        let join = start_listen(PROXY_LISTEN_PORT+offset, VPN_LISTEN_PORT+offset, ct_vpn, cr_stop).unwrap();
+        let join = start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+offset), format!("127.0.0.1:{}", VPN_LISTEN_PORT+offset).into(), None, None, ct_vpn, cr_stop).unwrap();
         sleep(Duration::from_millis(200));
         let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+offset)).unwrap();
+        //к VPN серверу эквалайзер подключается только после приветствия клиента
+        let split = client_hello(client_stream);
         let vpn_stream = mock_vpn_listener.incoming().next().unwrap().unwrap();
         vpn_stream.set_read_timeout(Some(Duration::from_millis(10))).expect("Должен быть не блокирующий метод чтения");
-        let split = split_client_stream(client_stream);
-
-        send_client_name_packet(split.filler_stream.clone());
         trace!("Ждем подключения Заполнителя");
+        //пара уходит в оркестратор из потока подключения к VPN серверу
+        sleep(Duration::from_millis(100));
         orchestrator.invoke();
         TestStreams {vpn_stream,
             client_data_stream: split.data_stream,
@@ -82,13 +101,18 @@ mod tests {
             join_handle: (ct_stop, join) }
     }
 
-    fn send_client_name_packet(filler_stream: Rc<dyn DataStreamFiller>) {
-        let mut buf: [u8; 16] = [0; 16];
-        buf[0] = 0x01;
-        let client_name = TEST_CLIENT_NAME.as_bytes();
-        buf[1..client_name.len()+1].copy_from_slice(client_name);
+    fn client_hello<'a>(mut client_stream: TcpStream) -> ClientSideSplit<'a> {
         info!("Отправляем имя клиента");
-        filler_stream.deref().write_all(&buf[..client_name.len()+1]).unwrap();
+        //пакеты с CRC32 - так же как у клиентов, которые его поддерживают
+        let ack = client_handshake(&mut client_stream, &Hello::new(TEST_CLIENT_NAME, CAPABILITY_CRC32),
+                         None, Duration::from_secs(2)).unwrap();
+        split_client_stream_with(client_stream, ack.frame_options())
+    }
+
+    fn client_hello_with_key<'a>(mut client_stream: TcpStream, key: &[u8]) -> ClientSideSplit<'a> {
+        let ack = client_handshake(&mut client_stream, &Hello::new(TEST_CLIENT_NAME, CAPABILITY_CRC32),
+                         Some(key), Duration::from_secs(2)).unwrap();
+        split_client_stream_with(client_stream, ack.frame_options())
     }
 
 
@@ -108,7 +132,7 @@ mod tests {
         let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic::default()));
         //дальше готовимся принимать клиентов
         let (ct_stop, cr_stop) = channel();
-        start_listen(PROXY_LISTEN_PORT, VPN_LISTEN_PORT, ct_vpn, cr_stop).unwrap();
+        start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT), format!("127.0.0.1:{}", VPN_LISTEN_PORT).into(), None, None, ct_vpn, cr_stop).unwrap();
         sleep(Duration::from_millis(500));
 
         //Создаем 2 массива по 1MB заполняем случайными данными
@@ -119,6 +143,7 @@ mod tests {
         let vpn_to_proxy = get_random_buf();
 
         let proxy_vpn_stream = mock_vpn_listener.incoming().next().unwrap().unwrap();
+        sleep(Duration::from_millis(100));
         orchestrator.invoke();
 
         //в двух разных потоках отправляем данные случайными порциями от 10 до 2000 за раз.
@@ -158,8 +183,8 @@ mod tests {
 
         stream.set_read_timeout(Some(Duration::from_millis(10))).expect("Должен быть не блокирующий метод чтения");
         let split= split_client_stream(stream);
-        let stream = split.data_stream.clone();
-        let filler = split.filler_stream.clone();
+        let stream = &split.data_stream;
+        let filler = &split.filler_stream;
         let mut rng = rand::rng();
         let mut write_left_size = TEST_BUF_SIZE; //сколько байт осталось записать из буфера
         let mut write_offset = 0; //смещение указателя
@@ -468,6 +493,790 @@ mod tests {
         join_handle.1.join().unwrap();
     }
 
+    /**
+       Занятый порт - ошибка при запуске, а не паника в потоке слушателя
+     */
+    #[test]
+    fn bind_error_test() {
+        let busy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let error = bind(&busy.local_addr().unwrap().to_string()).unwrap_err();
+        assert!(error.ctx.contains("Не удалось открыть порт"), "{}", error.ctx);
+    }
+
+    /**
+       Клиент с неверным ключом отключается до подключения к VPN серверу,
+       клиент с верным ключом проходит
+     */
+    #[test]
+    fn client_auth_test() {
+        initialize_logger();
+        const OFFSET: u16 = 5;
+        let mock_vpn_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        mock_vpn_listener.set_nonblocking(true).unwrap();
+        let client_keys = ClientKeys::parse(&format!("{TEST_CLIENT_NAME} secret")).unwrap();
+        let (ct_vpn, _cr_vpn) = channel();
+        let (ct_stop, cr_stop) = channel();
+        let join = start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET).into(), Some(client_keys), None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let result = client_handshake(&mut client_stream, &Hello::new(TEST_CLIENT_NAME, CAPABILITIES_NONE),
+                                      Some(b"wrong"), Duration::from_secs(2));
+        assert!(result.is_err());
+        assert!(mock_vpn_listener.accept().is_err(), "VPN сервер не должен видеть неаутентифицированного клиента");
+
+        //неизвестному имени запрос аутентификации отправляется так же, как известному
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let error = client_handshake(&mut client_stream, &Hello::new("unknown", CAPABILITIES_NONE),
+                                     None, Duration::from_secs(2)).unwrap_err();
+        assert!(error.ctx.contains("требует аутентификацию"), "{}", error.ctx);
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let result = client_handshake(&mut client_stream, &Hello::new("unknown", CAPABILITIES_NONE),
+                                      Some(b"secret"), Duration::from_secs(2));
+        assert!(result.is_err());
+        assert!(mock_vpn_listener.accept().is_err(), "VPN сервер не должен видеть неизвестного клиента");
+
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        client_handshake(&mut client_stream, &Hello::new(TEST_CLIENT_NAME, CAPABILITIES_NONE),
+                         Some(b"secret"), Duration::from_secs(2)).unwrap();
+        sleep(Duration::from_millis(100));
+        assert!(mock_vpn_listener.accept().is_ok());
+
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+       Порт эквалайзера открыт наружу без SSH: поток шифруется,
+       клиент с другим PSK не проходит рукопожатие
+     */
+    #[test]
+    fn secure_client_test() {
+        initialize_logger();
+        const OFFSET: u16 = 7;
+        const PSK: &[u8] = b"very-long-secret";
+        let mock_vpn_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        mock_vpn_listener.set_nonblocking(true).unwrap();
+        let (ct_vpn, cr_vpn) = channel();
+        let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic));
+        let (ct_stop, cr_stop) = channel();
+        let join = start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET).into(), None, Some(PSK.to_vec()), ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let mut client_stream = SecureTransport::connect(client_stream, Some(b"wrong")).unwrap();
+        let result = client_handshake(&mut client_stream, &Hello::new(TEST_CLIENT_NAME, CAPABILITY_CRC32),
+                                      None, Duration::from_secs(1));
+        assert!(result.is_err());
+        assert!(mock_vpn_listener.accept().is_err(), "VPN сервер не должен видеть клиента с неверным PSK");
+
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let mut client_stream = SecureTransport::connect(client_stream, Some(PSK)).unwrap();
+        let ack = client_handshake(&mut client_stream, &Hello::new(TEST_CLIENT_NAME, CAPABILITY_CRC32),
+                                   None, Duration::from_secs(2)).unwrap();
+        let split = split_client_stream_with(client_stream, ack.frame_options());
+        sleep(Duration::from_millis(100));
+        let mut vpn_stream = mock_vpn_listener.accept().unwrap().0;
+        vpn_stream.set_nonblocking(false).unwrap();
+        vpn_stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        orchestrator.invoke();
+
+        split.data_stream.write_all(b"11111").unwrap();
+        let mut buf = [0; ONE_PACKET_MAX_SIZE];
+        vpn_stream.read_exact(&mut buf[..5]).unwrap();
+        assert_eq!(b"11111", &buf[..5]);
+        vpn_stream.write_all(b"22222").unwrap();
+        let start = Instant::now();
+        let mut size = 0;
+        while size == 0 && start.elapsed() < Duration::from_secs(2) {
+            size = split.data_stream.read(&mut buf).unwrap();
+        }
+        assert_eq!(b"22222", &buf[..size]);
+
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+       Управляющий сокет: список клиентов, закрепление скорости,
+       режим без заполнителя, возврат регулятору и отключение клиента
+     */
+    #[test]
+    fn admin_socket_test() {
+        initialize_logger();
+        let admin_socket = format!("/tmp/equalizer_test_{}.sock", std::process::id());
+        let TestStreams {
+            mut vpn_stream,
+            client_data_stream: _client_data_stream,
+            client_filler_stream: _client_filler_stream,
+            mut orchestrator,
+            join_handle
+        } = create_test_streams(8, None);
+        let (ct_admin, cr_admin) = channel();
+        let (ct_admin_stop, cr_admin_stop) = channel();
+        let upstream = format!("127.0.0.1:{}", VPN_LISTEN_PORT+8);
+        let health = UpstreamHealth::new([&upstream]);
+        let admin_join = start_admin(admin_socket.clone(), ct_admin, health.clone(), cr_admin_stop).unwrap();
+        orchestrator.attach_admin(cr_admin);
+        let mode = std::fs::metadata(&admin_socket).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+
+        let status = admin_status(&mut orchestrator, &admin_socket, "clients");
+        assert_eq!(TEST_CLIENT_NAME, status.key);
+        assert_eq!(ControlMode::Auto, status.mode);
+        let status = admin_status(&mut orchestrator, &admin_socket, &format!("set-speed {TEST_CLIENT_NAME} 1048"));
+        assert_eq!(ControlMode::Pinned, status.mode);
+        assert_eq!(Some(1048), status.target_speed);
+        let status = admin_status(&mut orchestrator, &admin_socket, &format!("free {TEST_CLIENT_NAME}"));
+        assert_eq!(ControlMode::Free, status.mode);
+        assert_eq!(None, status.target_speed);
+        let status = admin_status(&mut orchestrator, &admin_socket, &format!("release {TEST_CLIENT_NAME}"));
+        assert_eq!(ControlMode::Auto, status.mode);
+
+        let reply = admin_request(&mut orchestrator, &admin_socket, "stats nobody");
+        assert!(reply[0].starts_with(ERR), "{:?}", reply);
+        let reply = admin_request(&mut orchestrator, &admin_socket, "reboot");
+        assert!(reply[0].starts_with(ERR), "{:?}", reply);
+        health.report(&upstream, false);
+        let reply = admin_request(&mut orchestrator, &admin_socket, "upstreams");
+        assert_eq!(OK, reply[0], "{:?}", reply);
+        let status = UpstreamStatus::parse(&reply[1]).unwrap();
+        assert_eq!(upstream, status.address);
+        assert!(!status.healthy);
+
+        let reply = admin_request(&mut orchestrator, &admin_socket, &format!("kick {TEST_CLIENT_NAME}"));
+        assert_eq!(vec![OK.to_string()], reply);
+        assert_eq!(0, orchestrator.get_pairs_count());
+        //пару закрывает поток реактора
+        let mut buf = [0; 10];
+        let start = Instant::now();
+        while !matches!(vpn_stream.read(&mut buf), Ok(0)) {
+            assert!(start.elapsed() < Duration::from_secs(2), "VPN соединение клиента не закрыто");
+        }
+
+        ct_admin_stop.send(true).unwrap();
+        admin_join.join().unwrap();
+        join_handle.0.send(true).unwrap();
+        join_handle.1.join().unwrap();
+    }
+
+    /**
+       По пути управляющего сокета удаляется только брошенный сокет
+     */
+    #[test]
+    fn admin_socket_path_test() {
+        initialize_logger();
+        let admin_socket = format!("/tmp/equalizer_path_test_{}.sock", std::process::id());
+        let health = UpstreamHealth::default();
+        std::fs::write(&admin_socket, "not a socket").unwrap();
+        let (ct_admin, _cr_admin) = channel();
+        let (_ct_stop, cr_stop) = channel();
+        assert!(start_admin(admin_socket.clone(), ct_admin.clone(), health.clone(), cr_stop).is_err());
+        assert_eq!("not a socket", std::fs::read_to_string(&admin_socket).unwrap());
+        std::fs::remove_file(&admin_socket).unwrap();
+
+        //сокет упавшего процесса
+        drop(UnixListener::bind(&admin_socket).unwrap());
+        let (ct_stop, cr_stop) = channel();
+        let admin_join = start_admin(admin_socket.clone(), ct_admin.clone(), health.clone(), cr_stop).unwrap();
+        //сокет работающего эквалайзера
+        let (_ct_second_stop, cr_second_stop) = channel();
+        assert!(start_admin(admin_socket.clone(), ct_admin, health, cr_second_stop).is_err());
+        assert!(UnixStream::connect(&admin_socket).is_ok());
+        ct_stop.send(true).unwrap();
+        admin_join.join().unwrap();
+    }
+
+    /**
+       Запрос к сокету из отдельного потока, пока оркестратор крутится в этом
+     */
+    fn admin_request(orchestrator: &mut Orchestrator, admin_socket: &str, request: &str) -> Vec<String> {
+        let admin_socket = admin_socket.to_string();
+        let request = format!("{request}\n");
+        let handle = thread::spawn(move || {
+            let mut stream = UnixStream::connect(admin_socket).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        });
+        while !handle.is_finished() {
+            orchestrator.invoke();
+            sleep(Duration::from_millis(10));
+        }
+        handle.join().unwrap().lines().map(|line| line.to_string()).collect()
+    }
+
+    fn admin_status(orchestrator: &mut Orchestrator, admin_socket: &str, request: &str) -> ClientStatus {
+        let reply = admin_request(orchestrator, admin_socket, request);
+        assert_eq!(OK, reply[0], "{:?}", reply);
+        assert_eq!(2, reply.len(), "{:?}", reply);
+        ClientStatus::parse(&reply[1]).unwrap()
+    }
+
+    /**
+       При остановке данные, уже отправленные VPN сервером, доходят до клиента,
+       после чего обе стороны закрываются
+     */
+    #[test]
+    fn drain_test() {
+        initialize_logger();
+        const DATA_SIZE: usize = 5_000;
+        let TestStreams {
+            mut vpn_stream,
+            client_data_stream,
+            client_filler_stream: _client_filler_stream,
+            mut orchestrator,
+            join_handle
+        } = create_test_streams(9, None);
+        let data = get_random_buf();
+        vpn_stream.write_all(&data[..DATA_SIZE]).unwrap();
+        join_handle.0.send(true).unwrap();
+        join_handle.1.join().unwrap();
+
+        assert!(orchestrator.shutdown(Duration::from_secs(2)));
+        assert_eq!(0, orchestrator.get_pairs_count());
+        let mut received = vec![0; DATA_SIZE];
+        let mut read = 0;
+        while read < DATA_SIZE {
+            let size = client_data_stream.read(&mut received[read..]).unwrap();
+            assert!(size > 0, "Клиент получил только {read} байт");
+            read += size;
+        }
+        assert_eq!(&data[..DATA_SIZE], &received[..]);
+        let mut buf = [0; 10];
+        let start = Instant::now();
+        while !matches!(vpn_stream.read(&mut buf), Ok(0)) {
+            assert!(start.elapsed() < Duration::from_secs(2), "VPN соединение клиента не закрыто");
+        }
+    }
+
+    /**
+       Клиент с маршрутом уходит на свой VPN сервер,
+       клиент без маршрута (сервера по умолчанию нет) получает отказ
+     */
+    #[test]
+    fn routing_test() {
+        initialize_logger();
+        const OFFSET: u16 = 10;
+        let mock_vpn_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        let (ct_vpn, cr_vpn) = channel();
+        let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic));
+        let routes = BTreeMap::from([
+            (format!("{TEST_CLIENT_NAME}*"), format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)),
+        ]);
+        let (ct_stop, cr_stop) = channel();
+        let join = start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), Router::new(None, &routes, UpstreamHealth::default()),
+                                None, None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let error = client_handshake(&mut client_stream, &Hello::new("stranger", CAPABILITIES_NONE),
+                                     None, Duration::from_secs(2)).unwrap_err();
+        assert!(error.ctx.contains("Нет маршрута для клиента stranger"), "{}", error.ctx);
+
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let _split = client_hello(client_stream);
+        let _vpn_stream = mock_vpn_listener.accept().unwrap().0;
+        sleep(Duration::from_millis(100));
+        orchestrator.invoke();
+        assert_eq!(1, orchestrator.get_pairs_count());
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+       Основной VPN сервер клиента не отвечает - подключаемся к резервному,
+       основной отмечается недоступным
+     */
+    #[test]
+    fn failover_test() {
+        initialize_logger();
+        const OFFSET: u16 = 11;
+        //на основном порту никто не слушает
+        let dead_upstream = format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET);
+        let mock_vpn_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET+1)).unwrap();
+        let (ct_vpn, cr_vpn) = channel();
+        let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic));
+        let router = Router::from(format!("{dead_upstream}, 127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET+1));
+        let health = router.health().clone();
+        let (ct_stop, cr_stop) = channel();
+        let join = start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), router,
+                                None, None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let _split = client_hello(client_stream);
+        let _vpn_stream = mock_vpn_listener.accept().unwrap().0;
+        sleep(Duration::from_millis(100));
+        orchestrator.invoke();
+        assert_eq!(1, orchestrator.get_pairs_count());
+        assert!(!health.is_healthy(&dead_upstream));
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+       Пока один клиент ждет ответа своего VPN сервера, остальные подключаются без задержки
+     */
+    #[test]
+    fn slow_upstream_test() {
+        initialize_logger();
+        const OFFSET: u16 = 18;
+        //очередь подключений "зависшего" сервера заполнена - новые подключения ждут CONNECT_TIMEOUT
+        let slow_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        assert_eq!(0, unsafe { libc::listen(slow_listener.as_raw_fd(), 0) });
+        let _queued = TcpStream::connect(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        let mock_vpn_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET+1)).unwrap();
+        let (ct_vpn, cr_vpn) = channel();
+        let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic));
+        let routes = BTreeMap::from([
+            ("slow".to_string(), format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)),
+        ]);
+        let router = Router::new(Some(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET+1)), &routes, UpstreamHealth::default());
+        let (ct_stop, cr_stop) = channel();
+        let join = start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), router, None, None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let mut slow_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        client_handshake(&mut slow_stream, &Hello::new("slow", CAPABILITIES_NONE), None, Duration::from_secs(2)).unwrap();
+        sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let _split = client_hello(client_stream);
+        let _vpn_stream = mock_vpn_listener.accept().unwrap().0;
+        assert!(start.elapsed() < Duration::from_secs(1), "{:?}", start.elapsed());
+        sleep(Duration::from_millis(100));
+        orchestrator.invoke();
+        assert_eq!(1, orchestrator.get_pairs_count());
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+       Режим tcp: у клиента несколько подключений одновременно, каждое - своя пара
+       (в режиме openvpn новое подключение заменило бы старое)
+     */
+    #[test]
+    fn tcp_mode_test() {
+        initialize_logger();
+        const OFFSET: u16 = 13;
+        let mock_upstream_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        let (ct_vpn, cr_vpn) = channel();
+        let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic));
+        let (ct_stop, cr_stop) = channel();
+        let join = start_listen_with(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), ListenerMode::Tcp,
+                                     format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET).into(), None, None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let mut splits = vec![];
+        let mut upstream_streams = vec![];
+        for _ in 0..2 {
+            let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+            splits.push(client_hello(client_stream));
+            upstream_streams.push(mock_upstream_listener.accept().unwrap().0);
+        }
+        sleep(Duration::from_millis(100));
+        orchestrator.invoke();
+        orchestrator.invoke();
+        assert_eq!(2, orchestrator.get_pairs_count());
+        //у каждого подключения свои данные
+        for (i, (split, upstream_stream)) in splits.iter().zip(upstream_streams.iter_mut()).enumerate() {
+            upstream_stream.write_all(&[i as u8; 10]).unwrap();
+            let mut buf = [0; 10];
+            let size = split.data_stream.read(&mut buf).unwrap();
+            assert_eq!(&[i as u8; 10][..], &buf[..size]);
+        }
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+       Пара поверх только что установленных подключений: (прокси, сокет VPN сервера, сокет клиента)
+     */
+    fn proxy_pair(reactor: &mut Reactor, listener: &TcpListener, key: &str) -> (VpnProxy, TcpStream, TcpStream) {
+        let vpn_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let up_stream = listener.accept().unwrap().0;
+        let client_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server_stream = listener.accept().unwrap().0;
+        let pair = Pair::new(Box::new(VpnDataStream::new(up_stream)), false, Box::new(server_stream),
+                             key.to_string(), FrameOptions::default(), None);
+        (VpnProxy::new(pair, &FillerSettings::default(), reactor), vpn_stream, client_stream)
+    }
+
+    /**
+       Клиент одной пары не читает - поток реактора его не ждет, соседняя пара работает
+     */
+    #[test]
+    fn stuck_client_test() {
+        initialize_logger();
+        let mut reactor = Reactor::new(1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (_stuck_proxy, stuck_vpn_stream, stuck_client_stream) = proxy_pair(&mut reactor, &listener, "stuck");
+        let mut flood = stuck_vpn_stream.try_clone().unwrap();
+        let flooder = thread::spawn(move || {
+            let buf = [0; ONE_PACKET_MAX_SIZE];
+            while flood.write_all(&buf).is_ok() {}
+        });
+        //буферы сокетов клиента заполнены
+        sleep(Duration::from_millis(300));
+
+        let (_proxy, mut vpn_stream, client_stream) = proxy_pair(&mut reactor, &listener, "live");
+        let split = split_client_stream(client_stream);
+        let start = Instant::now();
+        vpn_stream.write_all(b"12345").unwrap();
+        assert_eq!(b"12345".to_vec(), read_split(&split));
+        assert!(start.elapsed() < Duration::from_millis(500), "{:?}", start.elapsed());
+
+        stuck_vpn_stream.shutdown(Shutdown::Both).unwrap();
+        stuck_client_stream.shutdown(Shutdown::Both).unwrap();
+        flooder.join().unwrap();
+    }
+
+    fn read_split(split: &ClientSideSplit<'_>) -> Vec<u8> {
+        let mut buf = [0; TEST_BUF_SIZE];
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            let size = split.data_stream.read(&mut buf).unwrap();
+            if size > 0 {
+                return buf[..size].to_vec();
+            }
+        }
+        vec![]
+    }
+
+    /**
+        Обрыв соединения клиента с сессией: подключение к VPN серверу остается,
+        переподключившийся клиент получает недошедшие данные.
+        Не вернувшийся за grace клиент отключается как обычно
+     */
+    #[test]
+    fn session_resume_test() {
+        initialize_logger();
+        const OFFSET: u16 = 16;
+        let mock_upstream_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        let (ct_vpn, cr_vpn) = channel();
+        let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic));
+        let (ct_stop, cr_stop) = channel();
+        let sessions = Sessions::new(Duration::from_millis(500));
+        let router = Router::from(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).with_sessions(sessions.clone());
+        let join = start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), router, None, None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let ack = client_handshake(&mut client_stream, &Hello::new(TEST_CLIENT_NAME, CAPABILITY_CRC32 | CAPABILITY_RESUME),
+                                   None, Duration::from_secs(2)).unwrap();
+        let session = Session::new(ack.session.expect("номер сессии"), ack.frame_options()).shared();
+        let split = split_client_stream_resumable(client_stream, session.clone());
+        let mut upstream_stream = mock_upstream_listener.accept().unwrap().0;
+        upstream_stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        sleep(Duration::from_millis(100));
+        orchestrator.invoke();
+        assert_eq!(1, orchestrator.get_pairs_count());
+
+        split.data_stream.write_all(b"1").unwrap();
+        let mut buf = [0; 10];
+        upstream_stream.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(b"1", &buf[..1]);
+        upstream_stream.write_all(b"2").unwrap();
+        assert_eq!(b"2".to_vec(), read_split(&split));
+
+        //обрыв: ответ VPN сервера уходит в никуда
+        split.data_stream.shutdown();
+        drop(split);
+        sleep(Duration::from_millis(50));
+        upstream_stream.write_all(b"3").unwrap();
+        sleep(Duration::from_millis(100));
+        orchestrator.invoke();
+        assert_eq!(1, orchestrator.get_pairs_count());
+
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        client_resume(&mut client_stream, &session, None, Duration::from_secs(2)).unwrap();
+        let split = split_client_stream_resumable(client_stream, session.clone());
+        assert_eq!(b"3".to_vec(), read_split(&split));
+        split.data_stream.write_all(b"4").unwrap();
+        upstream_stream.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(b"4", &buf[..1]);
+        //к VPN серверу не переподключались
+        mock_upstream_listener.set_nonblocking(true).unwrap();
+        assert!(mock_upstream_listener.accept().is_err());
+
+        split.data_stream.shutdown();
+        drop(split);
+        sleep(Duration::from_millis(800));
+        orchestrator.invoke();
+        assert_eq!(0, orchestrator.get_pairs_count());
+        assert_eq!(0, upstream_stream.read(&mut buf).unwrap());
+        assert_eq!(0, sessions.len());
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+        При ключах клиентов сессию восстанавливает только знающий ключ ее клиента
+    */
+    #[test]
+    fn session_resume_auth_test() {
+        initialize_logger();
+        const OFFSET: u16 = 17;
+        let mock_upstream_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        let (ct_vpn, cr_vpn) = channel();
+        let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic));
+        let (ct_stop, cr_stop) = channel();
+        let sessions = Sessions::new(Duration::from_secs(5));
+        let router = Router::from(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).with_sessions(sessions.clone());
+        let client_keys = ClientKeys::parse(&format!("{TEST_CLIENT_NAME} secret\nrouter-2 other")).unwrap();
+        let join = start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), router, Some(client_keys), None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let ack = client_handshake(&mut client_stream, &Hello::new(TEST_CLIENT_NAME, CAPABILITY_CRC32 | CAPABILITY_RESUME),
+                                   Some(b"secret"), Duration::from_secs(2)).unwrap();
+        let session = Session::new(ack.session.expect("номер сессии"), ack.frame_options()).shared();
+        let split = split_client_stream_resumable(client_stream, session.clone());
+        let mut upstream_stream = mock_upstream_listener.accept().unwrap().0;
+        upstream_stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        sleep(Duration::from_millis(100));
+        orchestrator.invoke();
+        assert_eq!(1, orchestrator.get_pairs_count());
+        split.data_stream.shutdown();
+        drop(split);
+        sleep(Duration::from_millis(100));
+
+        //без ключа и с ключом другого клиента
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let error = client_resume(&mut client_stream, &session, None, Duration::from_secs(2)).unwrap_err();
+        assert!(error.to_string().contains("аутентификац"), "{error}");
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        assert!(client_resume(&mut client_stream, &session, Some(b"other"), Duration::from_secs(2)).is_err());
+        orchestrator.invoke();
+        assert_eq!(1, orchestrator.get_pairs_count());
+        assert_eq!(1, sessions.len());
+
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        client_resume(&mut client_stream, &session, Some(b"secret"), Duration::from_secs(2)).unwrap();
+        let split = split_client_stream_resumable(client_stream, session.clone());
+        split.data_stream.write_all(b"1").unwrap();
+        let mut buf = [0; 1];
+        upstream_stream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"1", &buf);
+        //к VPN серверу не переподключались
+        mock_upstream_listener.set_nonblocking(true).unwrap();
+        assert!(mock_upstream_listener.accept().is_err());
+
+        split.data_stream.shutdown();
+        drop(split);
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+        VPN сервер по UDP: датаграммы не склеиваются и не режутся по filler.packet_size
+    */
+    #[test]
+    fn udp_mode_test() {
+        initialize_logger();
+        const OFFSET: u16 = 14;
+        let mock_vpn_server = UdpSocket::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        mock_vpn_server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let (ct_vpn, cr_vpn) = channel();
+        let filler = FillerSettings { packet_size: 512, ..FillerSettings::default() };
+        let mut orchestrator = Orchestrator::with_settings(cr_vpn, Box::new(NoStatistic), SpeedSettings::default(), filler);
+        let (ct_stop, cr_stop) = channel();
+        let join = start_listen_with(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), ListenerMode::Udp,
+                                     format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET).into(), None, None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let split = client_hello(client_stream);
+        let mut adapter = UdpClientAdapter::bind("127.0.0.1:0").unwrap();
+        let vpn_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        vpn_client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        vpn_client.connect(adapter.local_addr().unwrap()).unwrap();
+        sleep(Duration::from_millis(100));
+        orchestrator.invoke();
+        assert_eq!(1, orchestrator.get_pairs_count());
+
+        vpn_client.send(&[1; 1200]).unwrap();
+        vpn_client.send(&[2; 10]).unwrap();
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(200) {
+            adapter.step(&split).unwrap();
+        }
+        let mut buf = [0; ONE_PACKET_MAX_SIZE];
+        let (size, equalizer) = mock_vpn_server.recv_from(&mut buf).unwrap();
+        assert_eq!(&[1; 1200][..], &buf[..size]);
+        let size = mock_vpn_server.recv(&mut buf).unwrap();
+        assert_eq!(&[2; 10][..], &buf[..size]);
+
+        mock_vpn_server.send_to(&[3; 1400], equalizer).unwrap();
+        mock_vpn_server.send_to(&[4; 20], equalizer).unwrap();
+        let mut received = vec![];
+        let start = Instant::now();
+        while received.len() < 2 && start.elapsed() < Duration::from_secs(1) {
+            adapter.step(&split).unwrap();
+            if let Ok(size) = vpn_client.recv(&mut buf) {
+                received.push(buf[..size].to_vec());
+            }
+        }
+        assert_eq!(vec![vec![3; 1400], vec![4; 20]], received);
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+        SOCKS5 без ключей клиентов не запускается, цели на самом сервере по умолчанию запрещены
+    */
+    #[test]
+    fn socks5_local_target_test() {
+        initialize_logger();
+        const OFFSET: u16 = 20;
+        let (ct_vpn, _cr_vpn) = channel();
+        let (_ct_stop, cr_stop) = channel();
+        assert!(start_listen_with(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), ListenerMode::Socks5,
+                                  Router::default(), None, None, ct_vpn.clone(), cr_stop).is_err());
+
+        let target_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        target_listener.set_nonblocking(true).unwrap();
+        let (ct_stop, cr_stop) = channel();
+        let client_keys = ClientKeys::parse(&format!("{TEST_CLIENT_NAME} secret")).unwrap();
+        let join = start_listen_with(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), ListenerMode::Socks5,
+                                     Router::default(), Some(client_keys), None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let split = client_hello_with_key(client_stream, b"secret");
+        let read = |size: usize| {
+            let mut buf = [0; ONE_PACKET_MAX_SIZE];
+            let mut received = vec![];
+            let start = Instant::now();
+            while received.len() < size && start.elapsed() < Duration::from_secs(2) {
+                let read_size = split.data_stream.read(&mut buf).unwrap();
+                received.extend_from_slice(&buf[..read_size]);
+            }
+            received
+        };
+        split.data_stream.write_all(&[5, 1, 0]).unwrap();
+        assert_eq!(vec![5, 0], read(2));
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&(VPN_LISTEN_PORT+OFFSET).to_be_bytes());
+        split.data_stream.write_all(&request).unwrap();
+        //0x02 - запрещено правилами
+        assert_eq!(&[5, 2], &read(10)[..2]);
+        assert!(target_listener.accept().is_err());
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+        Эквалайзер сам SOCKS5 сервер: после ответа на CONNECT пара работает как с VPN сервером
+    */
+    #[test]
+    fn socks5_mode_test() {
+        initialize_logger();
+        const OFFSET: u16 = 15;
+        let target_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        let (ct_vpn, cr_vpn) = channel();
+        let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic));
+        let (ct_stop, cr_stop) = channel();
+        let client_keys = ClientKeys::parse(&format!("{TEST_CLIENT_NAME} secret")).unwrap();
+        let join = start_listen_with(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), ListenerMode::Socks5,
+                                     Router::default().with_local_targets(true), Some(client_keys), None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let read = |split: &ClientSideSplit, size: usize| {
+            let mut buf = [0; ONE_PACKET_MAX_SIZE];
+            let mut received = vec![];
+            let start = Instant::now();
+            while received.len() < size && start.elapsed() < Duration::from_secs(2) {
+                let read_size = split.data_stream.read(&mut buf).unwrap();
+                received.extend_from_slice(&buf[..read_size]);
+            }
+            received
+        };
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let split = client_hello_with_key(client_stream, b"secret");
+        split.data_stream.write_all(&[5, 1, 0]).unwrap();
+        assert_eq!(vec![5, 0], read(&split, 2));
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&(VPN_LISTEN_PORT+OFFSET).to_be_bytes());
+        //данные вслед за запросом не теряются
+        request.extend_from_slice(b"GET");
+        split.data_stream.write_all(&request).unwrap();
+        let mut target_stream = target_listener.accept().unwrap().0;
+        let reply = read(&split, 10);
+        assert_eq!(&[5, 0, 0, 1], &reply[..4]);
+        let mut buf = [0; 3];
+        target_stream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"GET", &buf);
+
+        sleep(Duration::from_millis(100));
+        orchestrator.invoke();
+        assert_eq!(1, orchestrator.get_pairs_count());
+        target_stream.write_all(b"200 OK").unwrap();
+        assert_eq!(b"200 OK".to_vec(), read(&split, 6));
+        split.data_stream.write_all(b" /").unwrap();
+        let mut buf = [0; 2];
+        target_stream.read_exact(&mut buf).unwrap();
+        assert_eq!(b" /", &buf);
+
+        //BIND не поддерживается
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+        let split = client_hello_with_key(client_stream, b"secret");
+        split.data_stream.write_all(&[5, 1, 0]).unwrap();
+        assert_eq!(vec![5, 0], read(&split, 2));
+        split.data_stream.write_all(&[5, 2, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+        assert_eq!(0x07, read(&split, 10)[1]);
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
+    /**
+       Одновременно подключается несколько клиентов, которые представляются с опозданием.
+       Прием подключений не должен задерживаться, а имена клиентов - теряться
+     */
+    #[test]
+    fn handshake_burst_test() {
+        initialize_logger();
+        const OFFSET: u16 = 6;
+        const CLIENTS_COUNT: usize = 5;
+        let mock_vpn_listener = TcpListener::bind(format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET)).unwrap();
+        let (ct_vpn, cr_vpn) = channel();
+        let mut orchestrator = Orchestrator::new(cr_vpn, Box::new(NoStatistic));
+        let (ct_stop, cr_stop) = channel();
+        let join = start_listen(format!("0.0.0.0:{}", PROXY_LISTEN_PORT+OFFSET), format!("127.0.0.1:{}", VPN_LISTEN_PORT+OFFSET).into(), None, None, ct_vpn, cr_stop).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let start = Instant::now();
+        let clients: Vec<JoinHandle<TcpStream>> = (0..CLIENTS_COUNT).map(|i| {
+            thread::spawn(move || {
+                let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", PROXY_LISTEN_PORT+OFFSET)).unwrap();
+                //раньше имя ожидалось не дольше 500мс
+                sleep(Duration::from_millis(700));
+                client_handshake(&mut client_stream, &Hello::new(&format!("late_client_{i}"), CAPABILITIES_NONE),
+                                 None, Duration::from_secs(2)).unwrap();
+                client_stream
+            })
+        }).collect();
+        let vpn_streams: Vec<TcpStream> = (0..CLIENTS_COUNT)
+            .map(|_| mock_vpn_listener.accept().unwrap().0)
+            .collect();
+        let _client_streams: Vec<TcpStream> = clients.into_iter().map(|client| client.join().unwrap()).collect();
+        let elapsed = start.elapsed();
+        info!("{CLIENTS_COUNT} clients handshake took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500));
+
+        sleep(Duration::from_millis(100));
+        orchestrator.invoke();
+        for i in 0..CLIENTS_COUNT {
+            orchestrator.send_command(&format!("late_client_{i}"),
+                                      RuntimeCommand::SetSpeed(SpeedCorrectorCommand::SwitchOff)).unwrap();
+        }
+        assert_eq!(CLIENTS_COUNT, vpn_streams.len());
+        ct_stop.send(true).unwrap();
+        join.join().unwrap();
+    }
+
     /**
        Проверяем что начиная с минимальной скорости мы достигаем максимальной скорости (1Gbit/s)
      */
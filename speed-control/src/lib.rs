@@ -0,0 +1,356 @@
+/*
+Регулятор скорости заполнителя - общий для сервера (заполнитель к клиенту) и клиента
+(заполнитель к эквалайзеру). Скорости в байт/мс (1048 байт/мс ~ 10 Мбит/с).
+Статистику отправки (скорость и доля полезных данных за long_term) собирает каждая сторона сама,
+решает политика:
+step - ступенчатая: при доле данных выше target_percent + free_play
+       скорость +up_acceleration (не чаще increase_period), ниже target_percent - free_play
+       -down_acceleration (не чаще decrease_period)
+pi   - ПИ-регулятор: скорость меняется пропорционально отклонению доли данных
+       от target_percent и накопленному отклонению, в обе стороны не чаще increase_period
+Включение (enable_speed) и отключение (shutdown_speed) заполнителя у обеих одинаковые.
+Параметры - секция [speed] файла настроек, --speed-policy разбирает сервер (settings.rs).
+*/
+use easy_error::{ensure, Error};
+use log::debug;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+//значения по умолчанию, переопределяются секцией [speed] файла настроек
+//скорость ниже которой мы отключаем филлер (не до жиру - быть бы живу)
+pub const SHUTDOWN_SPEED: usize = 100 * 1024 / 1000;
+pub const ENABLE_SPEED: usize = 150 * 1024 / 1000;
+pub const TARGET_PERCENT: usize = 80;
+//свободный ход в %. Если отклонились от целевого значения на эту величину - ничего не предпринимаем.
+pub const FREE_PLAY: usize = 2;
+pub const UP_ACCELERATION: usize = 70;
+pub const DOWN_ACCELERATION: usize = 50;
+pub const LONG_TERM: Duration = Duration::from_secs(3);
+//меняем скорость не чаще этого периода
+pub const INCREASE_SPEED_PERIOD: Duration = Duration::from_millis(500);
+pub const DECREASE_SPEED_PERIOD: Duration = Duration::from_secs(10);
+//% скорости на процент отклонения доли данных
+pub const PI_KP: f64 = 1.0;
+//% скорости на процент·секунду накопленного отклонения
+pub const PI_KI: f64 = 0.5;
+pub const BANDWIDTH_CAP_PERCENT: usize = 95;
+pub const PERCENT_100: usize = 100;
+//накопленное отклонение не растет бесконечно, пока скорость упирается в канал
+const MAX_INTEGRAL: f64 = 200.0;
+//за одну команду скорость меняется не больше чем на столько %
+const MAX_ADJUSTMENT: f64 = 50.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SpeedCorrectorCommand {
+    SwitchOff,
+    SetSpeed(usize),
+}
+
+pub struct SpeedForPeriod {
+    pub speed: usize,
+    pub data_percent: usize, //0-100
+}
+
+pub struct SpeedSetupParam {
+    pub command_time: Instant,
+    pub value: usize
+}
+
+impl SpeedSetupParam {
+    pub fn new(speed: usize, command_time: Instant) -> SpeedSetupParam {
+        Self {
+            value: speed,
+            command_time
+        }
+    }
+}
+
+/**
+    Параметры регулятора скорости
+*/
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpeedSettings {
+    //как меняем скорость
+    pub policy: SpeedPolicyKind,
+    //доля полезных данных, которую держим, %
+    pub target_percent: usize,
+    //отклонение от target_percent, на которое не реагируем, %
+    pub free_play: usize,
+    pub up_acceleration: usize,
+    pub down_acceleration: usize,
+    //ниже этой скорости заполнитель отключается
+    pub shutdown_speed: usize,
+    //с этой скорости заполнитель включается
+    pub enable_speed: usize,
+    //окно расчета скорости
+    pub long_term_ms: u64,
+    //скорость повышаем и понижаем не чаще
+    pub increase_period_ms: u64,
+    pub decrease_period_ms: u64,
+    //коэффициенты ПИ-регулятора (policy = "pi"): % скорости на % отклонения и на %·с накопленного
+    pub pi_kp: f64,
+    pub pi_ki: f64,
+    //скорость заполнителя не выше этой доли измеренной пропускной способности канала, %
+    //(оценку канала ведет сервер, speed/bandwidth.rs), 0 - не ограничиваем
+    pub bandwidth_cap_percent: usize,
+}
+
+impl Default for SpeedSettings {
+    fn default() -> SpeedSettings {
+        SpeedSettings {
+            policy: SpeedPolicyKind::default(),
+            target_percent: TARGET_PERCENT,
+            free_play: FREE_PLAY,
+            up_acceleration: UP_ACCELERATION,
+            down_acceleration: DOWN_ACCELERATION,
+            shutdown_speed: SHUTDOWN_SPEED,
+            enable_speed: ENABLE_SPEED,
+            long_term_ms: LONG_TERM.as_millis() as u64,
+            increase_period_ms: INCREASE_SPEED_PERIOD.as_millis() as u64,
+            decrease_period_ms: DECREASE_SPEED_PERIOD.as_millis() as u64,
+            pi_kp: PI_KP,
+            pi_ki: PI_KI,
+            bandwidth_cap_percent: BANDWIDTH_CAP_PERCENT,
+        }
+    }
+}
+
+impl SpeedSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        ensure!(self.target_percent > self.free_play && self.target_percent + self.free_play < 100,
+            "speed: target_percent {} ± free_play {} должен быть в пределах 0-100", self.target_percent, self.free_play);
+        ensure!(self.shutdown_speed > 0, "speed: shutdown_speed должен быть больше 0");
+        ensure!(self.enable_speed >= self.shutdown_speed,
+            "speed: enable_speed {} меньше shutdown_speed {}", self.enable_speed, self.shutdown_speed);
+        ensure!(self.up_acceleration > 0 && self.down_acceleration > 0, "speed: ускорение должно быть больше 0");
+        ensure!(self.long_term_ms > 0, "speed: long_term_ms должен быть больше 0");
+        ensure!(self.pi_kp.is_finite() && self.pi_kp >= 0.0 && self.pi_ki.is_finite() && self.pi_ki >= 0.0,
+            "speed: pi_kp {} и pi_ki {} не могут быть отрицательными", self.pi_kp, self.pi_ki);
+        ensure!(self.bandwidth_cap_percent <= 100,
+            "speed: bandwidth_cap_percent {} больше 100", self.bandwidth_cap_percent);
+        Ok(())
+    }
+
+    /**
+        Регулятор для нового клиента
+    */
+    pub fn create_policy(&self) -> Box<dyn SpeedPolicy> {
+        self.policy.create(self)
+    }
+
+    pub fn long_term(&self) -> Duration {
+        Duration::from_millis(self.long_term_ms)
+    }
+
+    pub fn increase_period(&self) -> Duration {
+        Duration::from_millis(self.increase_period_ms)
+    }
+
+    pub fn decrease_period(&self) -> Duration {
+        Duration::from_millis(self.decrease_period_ms)
+    }
+
+    //если процент полезных данных ниже - уменьшаем скорость (скорость избыточна)
+    pub fn down_trigger(&self) -> usize {
+        self.target_percent - self.free_play
+    }
+
+    //если процент полезных данных выше - увеличиваем скорость
+    pub fn up_trigger(&self) -> usize {
+        self.target_percent + self.free_play
+    }
+}
+
+pub trait SpeedPolicy: Send {
+    /**
+        measured - скорость и доля данных за long_term,
+        last - последняя установленная скорость (None - заполнитель отключен).
+        None - скорость не меняем
+    */
+    fn correct(&mut self, measured: &SpeedForPeriod, last: Option<&SpeedSetupParam>, now: Instant) -> Option<SpeedCorrectorCommand>;
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeedPolicyKind {
+    #[default]
+    Step,
+    Pi,
+}
+
+impl SpeedPolicyKind {
+    pub fn create(&self, settings: &SpeedSettings) -> Box<dyn SpeedPolicy> {
+        match self {
+            SpeedPolicyKind::Step => Box::new(StepPolicy { settings: settings.clone() }),
+            SpeedPolicyKind::Pi => Box::new(PiPolicy::new(settings.clone())),
+        }
+    }
+}
+
+pub struct StepPolicy {
+    settings: SpeedSettings,
+}
+
+impl SpeedPolicy for StepPolicy {
+    fn correct(&mut self, measured: &SpeedForPeriod, last: Option<&SpeedSetupParam>, now: Instant) -> Option<SpeedCorrectorCommand> {
+        let settings = &self.settings;
+        let corrected_before = |period: Duration| last.is_none_or(|last| last.command_time + period < now);
+        if corrected_before(settings.increase_period()) && measured.data_percent > settings.up_trigger() {
+            debug!("increase due percent {}", measured.data_percent);
+            self.increase_command(measured, last)
+        } else if corrected_before(settings.decrease_period()) && measured.data_percent < settings.down_trigger() {
+            debug!("decrease due percent {}", measured.data_percent);
+            self.decrease_command(measured)
+        } else {
+            None
+        }
+    }
+}
+
+impl StepPolicy {
+    fn decrease_command(&self, current_speed: &SpeedForPeriod) -> Option<SpeedCorrectorCommand> {
+        let new_speed = current_speed.speed as i32 - self.settings.down_acceleration as i32;
+        if new_speed < self.settings.shutdown_speed as i32 {
+            return Some(SpeedCorrectorCommand::SwitchOff)
+        }
+        Some(SpeedCorrectorCommand::SetSpeed(new_speed as usize))
+    }
+
+    fn increase_command(&self, current_speed: &SpeedForPeriod, last: Option<&SpeedSetupParam>) -> Option<SpeedCorrectorCommand> {
+        //предыдущая запрошенная скорость
+        if let Some(prev_requested_command) = last {
+            if current_speed.speed < minus_7p(prev_requested_command.value) {
+                debug!("Текущая скорость {} ниже запрошенной {}, (уперлись в пропускную способность)",
+                        current_speed.speed, prev_requested_command.value);
+                return None;
+            }
+        }
+        //новая увеличенная скорость основанная на данных за последние пол секунды
+        if last.is_none() && current_speed.speed > self.settings.enable_speed {
+            Some(SpeedCorrectorCommand::SetSpeed(self.settings.enable_speed))
+        }else {
+            Some(SpeedCorrectorCommand::SetSpeed(current_speed.speed + self.settings.up_acceleration))
+        }
+    }
+}
+
+pub struct PiPolicy {
+    settings: SpeedSettings,
+    //накопленное отклонение доли данных, процент·с
+    integral: f64,
+    updated_at: Option<Instant>,
+}
+
+impl PiPolicy {
+    fn new(settings: SpeedSettings) -> PiPolicy {
+        Self {
+            settings,
+            integral: 0.0,
+            updated_at: None,
+        }
+    }
+}
+
+impl SpeedPolicy for PiPolicy {
+    fn correct(&mut self, measured: &SpeedForPeriod, last: Option<&SpeedSetupParam>, now: Instant) -> Option<SpeedCorrectorCommand> {
+        let settings = &self.settings;
+        if last.is_some_and(|last| now < last.command_time + settings.increase_period()) {
+            return None;
+        }
+        let elapsed = self.updated_at.map(|at| now.duration_since(at).min(settings.long_term()));
+        self.updated_at = Some(now);
+        let Some(last) = last else {
+            //заполнитель отключен - включаем так же, как и ступенчатая
+            self.integral = 0.0;
+            return (measured.data_percent > settings.up_trigger() && measured.speed > settings.enable_speed)
+                .then_some(SpeedCorrectorCommand::SetSpeed(settings.enable_speed));
+        };
+        let error = measured.data_percent as f64 - settings.target_percent as f64;
+        if error.abs() <= settings.free_play as f64 {
+            return None;
+        }
+        if error > 0.0 && measured.speed < minus_7p(last.value) {
+            debug!("pi: скорость {} ниже запрошенной {}, не повышаем", measured.speed, last.value);
+            return None;
+        }
+        let elapsed = elapsed.unwrap_or_default().as_secs_f64();
+        self.integral = (self.integral + error * elapsed).clamp(-MAX_INTEGRAL, MAX_INTEGRAL);
+        let adjustment = (settings.pi_kp * error + settings.pi_ki * self.integral)
+            .clamp(-MAX_ADJUSTMENT, MAX_ADJUSTMENT);
+        let speed = (measured.speed as f64 * (1.0 + adjustment / PERCENT_100 as f64)) as usize;
+        debug!("pi: data {}%, integral {:.1}, speed {} -> {speed}", measured.data_percent, self.integral, measured.speed);
+        if speed < settings.shutdown_speed {
+            return Some(SpeedCorrectorCommand::SwitchOff);
+        }
+        (speed != last.value).then_some(SpeedCorrectorCommand::SetSpeed(speed))
+    }
+}
+
+fn minus_7p(value: usize) -> usize {
+    value * 93 / PERCENT_100
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{SpeedCorrectorCommand, SpeedForPeriod, SpeedPolicyKind, SpeedSettings, SpeedSetupParam};
+    use std::time::{Duration, Instant};
+
+    fn measured(speed: usize, data_percent: usize) -> SpeedForPeriod {
+        SpeedForPeriod { speed, data_percent }
+    }
+
+    /**
+        Включается как ступенчатая, дальше скорость идет за отклонением доли данных:
+        чем больше отклонение, тем больше шаг, накопленное отклонение добавляет
+    */
+    #[test]
+    fn pi_policy_test() {
+        let settings = SpeedSettings { policy: SpeedPolicyKind::Pi, ..Default::default() };
+        let mut policy = settings.create_policy();
+        let mut now = Instant::now();
+        assert_eq!(None, policy.correct(&measured(settings.enable_speed - 1, 100), None, now));
+        assert_eq!(Some(SpeedCorrectorCommand::SetSpeed(settings.enable_speed)),
+                   policy.correct(&measured(1000, 100), None, now));
+
+        let last = SpeedSetupParam::new(1000, now);
+        //слишком рано
+        assert_eq!(None, policy.correct(&measured(1000, 100), Some(&last), now));
+        now += settings.increase_period() * 2;
+        //в пределах free_play
+        assert_eq!(None, policy.correct(&measured(1000, settings.target_percent + 1), Some(&last), now));
+        now += Duration::from_secs(1);
+        let Some(SpeedCorrectorCommand::SetSpeed(small_step)) = policy.correct(&measured(1000, 85), Some(&last), now) else {
+            panic!("ожидается повышение скорости");
+        };
+        now += Duration::from_secs(1);
+        let Some(SpeedCorrectorCommand::SetSpeed(big_step)) = policy.correct(&measured(1000, 95), Some(&last), now) else {
+            panic!("ожидается повышение скорости");
+        };
+        assert!(1000 < small_step && small_step < big_step, "{small_step} {big_step}");
+        //уперлись в канал
+        assert_eq!(None, policy.correct(&measured(800, 95), Some(&last), now + Duration::from_secs(1)));
+
+        now += Duration::from_secs(1);
+        let Some(SpeedCorrectorCommand::SetSpeed(lower)) = policy.correct(&measured(1000, 40), Some(&last), now) else {
+            panic!("ожидается понижение скорости");
+        };
+        assert!(lower < 1000, "{lower}");
+        now += Duration::from_secs(1);
+        assert_eq!(Some(SpeedCorrectorCommand::SwitchOff),
+                   policy.correct(&measured(settings.shutdown_speed, 0), Some(&last), now));
+    }
+
+    #[test]
+    fn step_policy_test() {
+        let settings = SpeedSettings::default();
+        let mut policy = settings.create_policy();
+        let now = Instant::now();
+        let last = SpeedSetupParam::new(1000, now - settings.decrease_period() * 2);
+        assert_eq!(Some(SpeedCorrectorCommand::SetSpeed(1000 + settings.up_acceleration)),
+                   policy.correct(&measured(1000, 100), Some(&last), now));
+        assert_eq!(Some(SpeedCorrectorCommand::SetSpeed(1000 - settings.down_acceleration)),
+                   policy.correct(&measured(1000, 50), Some(&last), now));
+        assert_eq!(None, policy.correct(&measured(1000, settings.target_percent), Some(&last), now));
+    }
+}
@@ -0,0 +1,194 @@
+/*
+Асинхронный (tokio) вариант разделения потока, включается feature "async".
+Формат пакетов тот же что и в синхронной версии (см. packet.rs),
+асинхронная сторона совместима с синхронной и с client-c.
+Поток делится на половины чтения и записи, каналы данных и заполнителя
+пользуются ими по очереди через асинхронные мьютексы.
+read можно отменять (select!) - байты копятся в FrameDecoder до получения всего пакета.
+*/
+use crate::packet::{write_packet_with, Buffer, FrameDecoder, FrameOptions, ReadPacketInfo, TYPE_DATA, TYPE_FILLER};
+use crate::MAX_BODY_SIZE;
+use easy_error::{bail, ensure, Error, ResultExt};
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+
+pub struct AsyncServerSideSplit<S> {
+    pub data_stream: AsyncDataStream<S>,
+    pub filler_stream: AsyncDataStream<S>,
+}
+
+pub struct AsyncClientSideSplit<S> {
+    pub data_stream: AsyncDataStream<S>,
+    pub filler_stream: AsyncDataStream<S>,
+}
+
+/**
+    Канал одного типа пакетов (данные или заполнитель)
+*/
+pub struct AsyncDataStream<S> {
+    shared: Arc<Shared<S>>,
+    packet_type: u8,
+}
+
+struct Shared<S> {
+    reader: Mutex<Reader<S>>,
+    writer: Mutex<WriteHalf<S>>,
+    //клиентская сторона откладывает пакеты другого канала, серверная (как и синхронная) - отбрасывает
+    keep_foreign: bool,
+    options: FrameOptions,
+}
+
+struct Reader<S> {
+    read_half: ReadHalf<S>,
+    //накапливает части пакета, в том числе между отмененными вызовами read
+    decoder: FrameDecoder,
+    data_pending_queue: VecDeque<Vec<u8>>,
+    filler_pending_queue: VecDeque<Vec<u8>>,
+}
+
+pub fn split_server_stream_async<S>(stream: S) -> AsyncServerSideSplit<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    split_server_stream_async_with(stream, FrameOptions::default())
+}
+
+pub fn split_server_stream_async_with<S>(stream: S, options: FrameOptions) -> AsyncServerSideSplit<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let shared = Shared::new(stream, false, options);
+    AsyncServerSideSplit {
+        data_stream: AsyncDataStream::new(shared.clone(), TYPE_DATA),
+        filler_stream: AsyncDataStream::new(shared, TYPE_FILLER),
+    }
+}
+
+pub fn split_client_stream_async<S>(stream: S) -> AsyncClientSideSplit<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    split_client_stream_async_with(stream, FrameOptions::default())
+}
+
+pub fn split_client_stream_async_with<S>(stream: S, options: FrameOptions) -> AsyncClientSideSplit<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let shared = Shared::new(stream, true, options);
+    AsyncClientSideSplit {
+        data_stream: AsyncDataStream::new(shared.clone(), TYPE_DATA),
+        filler_stream: AsyncDataStream::new(shared, TYPE_FILLER),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Shared<S> {
+    fn new(stream: S, keep_foreign: bool, options: FrameOptions) -> Arc<Shared<S>> {
+        let (read_half, write_half) = split(stream);
+        Arc::new(Shared {
+            reader: Mutex::new(Reader {
+                read_half,
+                decoder: FrameDecoder::with_options(options),
+                data_pending_queue: VecDeque::new(),
+                filler_pending_queue: VecDeque::new(),
+            }),
+            writer: Mutex::new(write_half),
+            keep_foreign,
+            options,
+        })
+    }
+}
+
+impl<S> Clone for AsyncDataStream<S> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            packet_type: self.packet_type,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncDataStream<S> {
+    fn new(shared: Arc<Shared<S>>, packet_type: u8) -> AsyncDataStream<S> {
+        Self { shared, packet_type }
+    }
+
+    pub async fn write_all(&self, buf: &[u8]) -> Result<(), Error> {
+        ensure!(!buf.is_empty() && buf.len() <= MAX_BODY_SIZE, "Недопустимый размер пакета {}", buf.len());
+        //пакет целиком одной записью, чтобы не перемешаться с другим каналом
+        let mut packet = Vec::new();
+        write_packet_with(buf, self.packet_type, self.shared.options, &mut packet)?;
+        let mut writer = self.shared.writer.lock().await;
+        writer.write_all(&packet).await.context("Write packet in async split")?;
+        writer.flush().await.context("Flush async split")
+    }
+
+    /**
+        Ждет пакет своего типа. 0 - поток закрыт
+    */
+    pub async fn read(&self, dst: &mut [u8]) -> Result<usize, Error> {
+        let mut reader = self.shared.reader.lock().await;
+        loop {
+            if let Some(body) = reader.pending_queue(self.packet_type).pop_front() {
+                return copy_body(&body, dst);
+            }
+            let Some((packet_type, body)) = reader.next_packet().await? else {
+                return Ok(0);
+            };
+            if packet_type == self.packet_type {
+                return copy_body(&body, dst);
+            }
+            if self.shared.keep_foreign {
+                debug!("Получили чужеродный");
+                reader.pending_queue(packet_type).push_back(body);
+            } else {
+                warn!("Входящий пакет {:#02x} в канале {:#02x} пропущен", packet_type, self.packet_type);
+            }
+            //отпускаем мьютекс (он честный), чтобы отложенный пакет забрал ожидающий его канал
+            drop(reader);
+            reader = self.shared.reader.lock().await;
+        }
+    }
+
+    pub async fn shutdown(&self) {
+        let _ = self.shared.writer.lock().await.shutdown().await;
+    }
+}
+
+impl<S: AsyncRead> Reader<S> {
+    fn pending_queue(&mut self, packet_type: u8) -> &mut VecDeque<Vec<u8>> {
+        if packet_type == TYPE_DATA {
+            &mut self.data_pending_queue
+        } else {
+            &mut self.filler_pending_queue
+        }
+    }
+
+    async fn next_packet(&mut self) -> Result<Option<(u8, Vec<u8>)>, Error> {
+        let mut body: Buffer = [0; MAX_BODY_SIZE];
+        loop {
+            if let Some(packet_info) = self.decoder.take_packet(&mut body)? {
+                let ReadPacketInfo { packet_type, packet_size } = packet_info;
+                if packet_type != TYPE_DATA && packet_type != TYPE_FILLER {
+                    bail!("Мусор в данных")
+                }
+                return Ok(Some((packet_type, body[..packet_size].to_vec())));
+            }
+            let size = self.read_half.read(self.decoder.unfilled()).await.context("Async split read")?;
+            if size == 0 {
+                ensure!(self.decoder.is_empty(), "Поток закрыт посреди пакета");
+                return Ok(None);
+            }
+            self.decoder.advance(size)?;
+        }
+    }
+}
+
+fn copy_body(body: &[u8], dst: &mut [u8]) -> Result<usize, Error> {
+    ensure!(dst.len() >= body.len(), "Ожидается что хватит места на пакет. {} < {}", dst.len(), body.len());
+    dst[..body.len()].copy_from_slice(body);
+    Ok(body.len())
+}
@@ -0,0 +1,68 @@
+/*
+Аутентификация клиента общим секретом (challenge/response)
+После TYPE_HELLO сервер, у которого настроены ключи клиентов, отправляет
+   TYPE_AUTH_CHALLENGE: случайное число[32]
+Клиент отвечает
+   TYPE_AUTH_RESPONSE: HMAC-SHA256(ключ, случайное число + имя клиента)[32]
+Только после проверки ответа сервер отправляет TYPE_HELLO_ACK.
+Восстановление сессии (TYPE_RESUME) такой сервер тоже подтверждает только после ответа
+   TYPE_AUTH_RESPONSE: HMAC-SHA256(ключ, случайное число + номер сессии)[32]
+ключом клиента, открывшего сессию - одного номера сессии для перехвата мало
+*/
+use crate::session::SessionId;
+use easy_error::{ensure, Error, ResultExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const CHALLENGE_SIZE: usize = 32;
+pub const RESPONSE_SIZE: usize = 32;
+
+pub type Challenge = [u8; CHALLENGE_SIZE];
+pub type Response = [u8; RESPONSE_SIZE];
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn new_challenge() -> Result<Challenge, Error> {
+    let mut challenge = [0; CHALLENGE_SIZE];
+    getrandom::fill(&mut challenge).context("Не удалось получить случайное число")?;
+    Ok(challenge)
+}
+
+pub fn compute_response(key: &[u8], challenge: &Challenge, client_name: &str) -> Response {
+    finalize(new_mac(key, challenge, client_name.as_bytes()))
+}
+
+/**
+    Сравнение за постоянное время (средствами hmac)
+*/
+pub fn verify_response(key: &[u8], challenge: &Challenge, client_name: &str, response: &[u8]) -> bool {
+    new_mac(key, challenge, client_name.as_bytes()).verify_slice(response).is_ok()
+}
+
+pub fn compute_resume_response(key: &[u8], challenge: &Challenge, session: &SessionId) -> Response {
+    finalize(new_mac(key, challenge, session))
+}
+
+pub fn verify_resume_response(key: &[u8], challenge: &Challenge, session: &SessionId, response: &[u8]) -> bool {
+    new_mac(key, challenge, session).verify_slice(response).is_ok()
+}
+
+pub fn decode_challenge(body: &[u8]) -> Result<Challenge, Error> {
+    ensure!(body.len() == CHALLENGE_SIZE, "Недопустимый размер запроса аутентификации {}", body.len());
+    let mut challenge = [0; CHALLENGE_SIZE];
+    challenge.copy_from_slice(body);
+    Ok(challenge)
+}
+
+fn new_mac(key: &[u8], challenge: &Challenge, subject: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC принимает ключ любой длины");
+    mac.update(challenge);
+    mac.update(subject);
+    mac
+}
+
+fn finalize(mac: HmacSha256) -> Response {
+    let mut response = [0; RESPONSE_SIZE];
+    response.copy_from_slice(&mac.finalize().into_bytes());
+    response
+}
@@ -1,17 +1,19 @@
 use std::cell::RefCell;
 use std::collections::VecDeque;
 use crate::packet::*;
+use crate::session::{decode_counter, encode_counter, lock_session, SharedSession};
+use crate::transport::Transport;
 use crate::{MAX_BODY_SIZE, READ_START_AWAIT_TIMEOUT};
 use log::debug;
-use std::net::{Shutdown, TcpStream};
+use std::net::TcpStream;
 use std::rc::Rc;
 use easy_error::{bail, ensure, Error};
 
 
-pub struct ClientSideSplit<'a> {
+pub struct ClientSideSplit<'a, T: Transport + 'a = TcpStream> {
     pub data_stream: Rc<dyn DataStreamVpn + 'a>,
     pub filler_stream: Rc<dyn DataStreamFiller + 'a>,
-    stream: TcpStream
+    common: Rc<CommonDataStream<T>>
 }
 
 pub trait DataStreamVpn {
@@ -26,50 +28,121 @@ pub trait DataStreamFiller {
 }
 
 
-pub fn split_client_stream<'a>(client_stream: TcpStream) -> ClientSideSplit<'a> {
+pub fn split_client_stream<'a, T: Transport + 'a>(client_stream: T) -> ClientSideSplit<'a, T> {
+    split_client_stream_with(client_stream, FrameOptions::default())
+}
+
+/**
+    options - о чем договорились в рукопожатии (HelloAck::frame_options)
+*/
+pub fn split_client_stream_with<'a, T: Transport + 'a>(client_stream: T, options: FrameOptions) -> ClientSideSplit<'a, T> {
+    split(client_stream, options, None)
+}
+
+/**
+    Пакеты данных нумеруются и подтверждаются (см. session.rs).
+    После переподключения (handshake::client_resume) новый транспорт разделяется с той же сессией
+*/
+pub fn split_client_stream_resumable<'a, T: Transport + 'a>(client_stream: T, session: SharedSession) -> ClientSideSplit<'a, T> {
+    let options = lock_session(&session).options();
+    split(client_stream, options, Some(session))
+}
+
+fn split<'a, T: Transport + 'a>(client_stream: T, options: FrameOptions, session: Option<SharedSession>) -> ClientSideSplit<'a, T> {
     client_stream
         .set_read_timeout(Some(READ_START_AWAIT_TIMEOUT))
         .expect("Архитектура подразумевает не блокирующий метод чтения");
-    let ds = Rc::new(CommonDataStream::new(client_stream.try_clone().unwrap()));
+    let ds = Rc::new(CommonDataStream::new(client_stream, options, session));
     ClientSideSplit {
         data_stream: ds.clone(),
-        filler_stream: ds,
-        stream: client_stream
+        filler_stream: ds.clone(),
+        common: ds
     }
 }
 
-pub fn squash<'a>(split: ClientSideSplit) -> TcpStream {
-    split.stream
+impl<T: Transport> ClientSideSplit<'_, T> {
+    /**
+        Сервер закрыл соединение. Отложенные пакеты еще можно дочитать
+    */
+    pub fn is_closed(&self) -> bool {
+        self.common.decoder.borrow().is_closed()
+    }
 }
 
-struct CommonDataStream  {
-    pub client_stream: RefCell<TcpStream>,
-    //временный буфер в который получаем и заголовок и тело
+/**
+    Возвращает транспорт обратно.
+    Копии data_stream/filler_stream к этому моменту должны быть освобождены
+*/
+pub fn squash<T: Transport>(split: ClientSideSplit<T>) -> T {
+    let ClientSideSplit { data_stream, filler_stream, common } = split;
+    drop(data_stream);
+    drop(filler_stream);
+    match Rc::try_unwrap(common) {
+        Ok(common) => common.client_stream.into_inner(),
+        Err(_) => panic!("Каналы используются после squash"),
+    }
+}
+
+struct CommonDataStream<T: Transport> {
+    pub client_stream: RefCell<T>,
+    //временный буфер в который получаем тело
     pub temp_buf: RefCell<Buffer>,
+    //накапливает части пакета между вызовами read
+    decoder: RefCell<FrameDecoder>,
+    options: FrameOptions,
     data_pending_queue: RefCell<VecDeque<QueuedPacket>>,
-    filler_pending_queue: RefCell<VecDeque<QueuedPacket>>
+    filler_pending_queue: RefCell<VecDeque<QueuedPacket>>,
+    //None - без восстановления сессии
+    session: Option<SharedSession>,
 }
 
 
 
-impl CommonDataStream {
+impl<T: Transport> CommonDataStream<T> {
     fn new(
-        client_stream: TcpStream,
-    ) -> CommonDataStream {
+        client_stream: T,
+        options: FrameOptions,
+        session: Option<SharedSession>,
+    ) -> CommonDataStream<T> {
         let data_pending_queue: VecDeque<QueuedPacket> = VecDeque::new();
         let filler_pending_queue: VecDeque<QueuedPacket> = VecDeque::new();
         Self {
             client_stream : RefCell::new(client_stream),
             temp_buf: RefCell::new([0; MAX_BODY_SIZE]),
+            decoder: RefCell::new(FrameDecoder::with_options(options)),
+            options,
             data_pending_queue: RefCell::new(data_pending_queue),
-            filler_pending_queue: RefCell::new(filler_pending_queue)
+            filler_pending_queue: RefCell::new(filler_pending_queue),
+            session,
         }
     }
     pub fn write_as_packet(&self, packet_type: u8, buf: &[u8]) -> Result<(), Error> {
+        if let (Some(session), TYPE_DATA) = (&self.session, packet_type) {
+            lock_session(session).data_sent(buf);
+        }
         let stream = &mut *self.client_stream.borrow_mut();
-        write_packet(buf, packet_type, stream)
+        write_packet_with(buf, packet_type, self.options, stream)
     }
+    /**
+        Пакет данных считается полученным, когда отдан VPN (а не отложен в очередь):
+        очередь пропадает вместе с транспортом
+    */
     pub fn read_packet(&self, target_type: u8, redirect_type: u8, dst: &mut [u8]) -> Result<usize, Error> {
+        let size = self.receive(target_type, redirect_type, dst)?;
+        if let Some(session) = &self.session {
+            let mut session = lock_session(session);
+            if target_type == TYPE_DATA && size > 0 {
+                session.data_received();
+            }
+            if let Some(received) = session.ack_due() {
+                let stream = &mut *self.client_stream.borrow_mut();
+                write_packet_with(&encode_counter(received), TYPE_ACK, self.options, stream)?;
+                session.ack_sent(received);
+            }
+        }
+        Ok(size)
+    }
+    fn receive(&self, target_type: u8, redirect_type: u8, dst: &mut [u8]) -> Result<usize, Error> {
         //Если в методе read пришел чужой пакет - перенаправляем его получателю
         if target_type == TYPE_DATA && !self.data_pending_queue.borrow().is_empty() {
             if let Some(packet_body) = self.data_pending_queue.borrow_mut().pop_front() {
@@ -87,7 +160,7 @@ impl CommonDataStream {
 
         let stream = &mut *self.client_stream.borrow_mut();
         let temp_buf = &mut *self.temp_buf.borrow_mut();
-        if let Some(packet_info) = read_packet(temp_buf, stream)? {
+        if let Some(packet_info) = self.decoder.borrow_mut().read_packet(temp_buf, stream)? {
             let ReadPacketInfo {
                 packet_type,
                 packet_size,
@@ -103,6 +176,8 @@ impl CommonDataStream {
                 }else if packet_type==TYPE_DATA {
                     self.data_pending_queue.borrow_mut().push_back(packet_body);
                 }
+            } else if let (Some(session), TYPE_ACK) = (&self.session, packet_type) {
+                lock_session(session).acknowledge(decode_counter(&temp_buf[..packet_size])?)?;
             } else {
                 bail!("Мусор в данных")
             }
@@ -111,11 +186,11 @@ impl CommonDataStream {
     }
 
     pub fn shutdown(&self) {
-        let _ = self.client_stream.borrow_mut().shutdown(Shutdown::Both);
+        self.client_stream.borrow().shutdown();
     }
 }
 
-impl DataStreamVpn for CommonDataStream {
+impl<T: Transport> DataStreamVpn for CommonDataStream<T> {
     fn write_all(&self, buf: &[u8]) -> Result<(), Error> {
         self.write_as_packet(TYPE_DATA, buf)
     }
@@ -130,7 +205,7 @@ impl DataStreamVpn for CommonDataStream {
 }
 
 
-impl DataStreamFiller for CommonDataStream {
+impl<T: Transport> DataStreamFiller for CommonDataStream<T> {
     fn write_all(&self, buf: &[u8]) -> Result<(), Error> {
         self.write_as_packet(TYPE_FILLER, buf)
     }
@@ -0,0 +1,348 @@
+/*
+Рукопожатие клиента и сервера.
+Клиент первым пакетом отправляет TYPE_HELLO
+   версия[1], возможности[2], имя клиента[..]
+Сервер отвечает TYPE_HELLO_ACK
+   версия[1], принятые возможности[2]
+Если на сервере настроены ключи клиентов, перед TYPE_HELLO_ACK проходит
+обмен TYPE_AUTH_CHALLENGE / TYPE_AUTH_RESPONSE (см. auth.rs)
+   номер сессии[16] - только если договорились о CAPABILITY_RESUME (см. session.rs)
+Вместо TYPE_HELLO_ACK сервер может отказать клиенту: TYPE_ERROR
+   причина[..] (UTF-8), после чего закрывает соединение
+Версия и возможности позволяют менять формат обмена не ломая уже установленные роутеры.
+Старые клиенты (client-c) вместо TYPE_HELLO шлют пакет заполнителя 0x01 + имя,
+такие клиенты поддерживаются, но подтверждение им не отправляется.
+*/
+use crate::auth::{compute_response, compute_resume_response, decode_challenge, Challenge, Response, RESPONSE_SIZE};
+use crate::packet::*;
+use crate::session::{decode_counter, encode_counter, lock_session, Session, SessionId, SharedSession, RESUME_SIZE, SESSION_ID_SIZE};
+use crate::MAX_BODY_SIZE;
+use easy_error::{bail, ensure, Error, ResultExt};
+use crate::transport::Transport;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+//минимальная версия, с которой сервер еще умеет работать
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+//маркер имени клиента в пакете заполнителя (до появления TYPE_HELLO)
+pub const LEGACY_NAME_MARKER: u8 = 0x01;
+pub const MAX_CLIENT_NAME_LEN: usize = 64;
+
+//флаги возможностей (битовая маска)
+pub const CAPABILITIES_NONE: u16 = 0;
+//после рукопожатия пакеты идут с CRC32, мусор в потоке пропускается (см. FrameOptions)
+pub const CAPABILITY_CRC32: u16 = 0x0001;
+//пакеты данных подтверждаются, после обрыва сессию можно восстановить (см. session.rs)
+pub const CAPABILITY_RESUME: u16 = 0x0002;
+//возможности, которые поддерживает эта версия библиотеки
+pub const SUPPORTED_CAPABILITIES: u16 = CAPABILITY_CRC32 | CAPABILITY_RESUME;
+
+const HELLO_HEADER_SIZE: usize = 3;
+const HELLO_ACK_SIZE: usize = 3;
+//причина отказа - строка для лога клиента, не больше
+const MAX_ERROR_LEN: usize = 512;
+const ACK_POLL_TIMEOUT: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub version: u8,
+    pub capabilities: u16,
+    pub client_name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HelloAck {
+    pub version: u8,
+    pub capabilities: u16,
+    //выдает сервер, если договорились о CAPABILITY_RESUME
+    pub session: Option<SessionId>,
+}
+
+/**
+    Чем клиент представился серверу
+*/
+#[derive(Debug, PartialEq)]
+pub enum Identification {
+    Hello(Hello),
+    //клиент старого образца, знаем только имя
+    Legacy(String),
+    //клиент начал передачу данных не представившись (данные остаются в потоке)
+    Anonymous,
+    //клиент переподключился и продолжает сессию (номер сессии, получено пакетов данных)
+    Resume(SessionId, u64),
+}
+
+impl Identification {
+    pub fn client_name(&self) -> &str {
+        match self {
+            Identification::Hello(hello) => &hello.client_name,
+            Identification::Legacy(name) => name,
+            Identification::Anonymous | Identification::Resume(..) => "",
+        }
+    }
+}
+
+impl Hello {
+    pub fn new(client_name: &str, capabilities: u16) -> Hello {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities,
+            client_name: client_name.to_string(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(HELLO_HEADER_SIZE + self.client_name.len());
+        body.push(self.version);
+        body.extend_from_slice(&self.capabilities.to_le_bytes());
+        body.extend_from_slice(self.client_name.as_bytes());
+        body
+    }
+
+    pub fn decode(body: &[u8]) -> Result<Hello, Error> {
+        ensure!(body.len() > HELLO_HEADER_SIZE, "Слишком короткий пакет приветствия {}", body.len());
+        let client_name = parse_client_name(&body[HELLO_HEADER_SIZE..])?;
+        Ok(Hello {
+            version: body[0],
+            capabilities: u16::from_le_bytes([body[1], body[2]]),
+            client_name,
+        })
+    }
+}
+
+impl HelloAck {
+    /**
+        Ответ сервера: общая версия и пересечение возможностей
+    */
+    pub fn accept(hello: &Hello, server_capabilities: u16) -> Result<HelloAck, Error> {
+        ensure!(hello.version >= MIN_PROTOCOL_VERSION,
+            "Версия протокола клиента {} не поддерживается", hello.version);
+        Ok(HelloAck {
+            version: hello.version.min(PROTOCOL_VERSION),
+            capabilities: hello.capabilities & server_capabilities,
+            session: None,
+        })
+    }
+
+    pub fn resumable(&self) -> bool {
+        self.capabilities & CAPABILITY_RESUME != 0
+    }
+
+    /**
+        Формат пакетов после рукопожатия (split_*_stream_with)
+    */
+    pub fn frame_options(&self) -> FrameOptions {
+        let crc32 = self.capabilities & CAPABILITY_CRC32 != 0;
+        FrameOptions {
+            checksum: crc32,
+            resync: crc32,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = vec![self.version];
+        body.extend_from_slice(&self.capabilities.to_le_bytes());
+        if let Some(session) = &self.session {
+            body.extend_from_slice(session);
+        }
+        body
+    }
+
+    pub fn decode(body: &[u8]) -> Result<HelloAck, Error> {
+        ensure!(body.len() >= HELLO_ACK_SIZE, "Слишком короткое подтверждение {}", body.len());
+        let session = body.get(HELLO_ACK_SIZE..HELLO_ACK_SIZE + SESSION_ID_SIZE)
+            .map(|session| session.try_into().unwrap());
+        Ok(HelloAck {
+            version: body[0],
+            capabilities: u16::from_le_bytes([body[1], body[2]]),
+            session,
+        })
+    }
+}
+
+fn parse_client_name(buf: &[u8]) -> Result<String, Error> {
+    ensure!(!buf.is_empty() && buf.len() <= MAX_CLIENT_NAME_LEN, "Недопустимая длина имени клиента {}", buf.len());
+    let name = std::str::from_utf8(buf).context("Имя клиента не в UTF-8")?;
+    Ok(name.to_string())
+}
+
+/**
+    Серверная сторона: читаем первый пакет клиента.
+    None - клиент еще ничего не прислал (или прислал часть приветствия, она остается в decoder)
+*/
+pub fn read_identification<T: Transport + ?Sized>(stream: &mut T, decoder: &mut FrameDecoder) -> Result<Option<Identification>, Error> {
+    if decoder.is_empty() {
+        //сначала заглядываем не вычитывая, чтобы не потерять данные клиента без имени
+        let mut head = [0; HEADER_SIZE + 1];
+        let peeked = peek(&mut head, stream).context("Peek client hello")?;
+        if peeked < HEADER_SIZE {
+            return Ok(None);
+        }
+        match head[TYPE_BYTE_INDEX] {
+            TYPE_HELLO | TYPE_RESUME => {}
+            TYPE_FILLER if peeked <= HEADER_SIZE => return Ok(None),
+            TYPE_FILLER if head[HEADER_SIZE] == LEGACY_NAME_MARKER => {}
+            _ => return Ok(Some(Identification::Anonymous)),
+        }
+    }
+    let mut buf: Buffer = [0; MAX_BODY_SIZE];
+    if let Some(packet_info) = decoder.read_packet(&mut buf, stream)? {
+        let body = &buf[..packet_info.packet_size];
+        return match packet_info.packet_type {
+            TYPE_HELLO => Ok(Some(Identification::Hello(Hello::decode(body)?))),
+            TYPE_RESUME => {
+                ensure!(body.len() == RESUME_SIZE, "Недопустимый размер запроса восстановления {}", body.len());
+                let session = body[..SESSION_ID_SIZE].try_into().unwrap();
+                Ok(Some(Identification::Resume(session, decode_counter(&body[SESSION_ID_SIZE..])?)))
+            }
+            TYPE_FILLER if body[0] == LEGACY_NAME_MARKER => {
+                Ok(Some(Identification::Legacy(parse_client_name(&body[1..])?)))
+            }
+            _ => bail!("Первым пакетом ожидалось приветствие, получен {:#02x}", packet_info.packet_type),
+        };
+    }
+    Ok(None)
+}
+
+pub fn send_hello_ack<T: Write>(stream: &mut T, ack: &HelloAck) -> Result<(), Error> {
+    write_packet(&ack.encode(), TYPE_HELLO_ACK, stream)
+}
+
+/**
+    Отказ клиенту вместо подтверждения приветствия
+*/
+pub fn send_error<T: Write>(stream: &mut T, message: &str) -> Result<(), Error> {
+    let message = truncate_utf8(message, MAX_ERROR_LEN);
+    write_packet(message.as_bytes(), TYPE_ERROR, stream)
+}
+
+fn truncate_utf8(value: &str, max_len: usize) -> &str {
+    let mut len = value.len().min(max_len);
+    while !value.is_char_boundary(len) {
+        len -= 1;
+    }
+    &value[..len]
+}
+
+pub fn send_challenge<T: Write>(stream: &mut T, challenge: &Challenge) -> Result<(), Error> {
+    write_packet(challenge, TYPE_AUTH_CHALLENGE, stream)
+}
+
+/**
+    Серверная сторона: ответ клиента на TYPE_AUTH_CHALLENGE.
+    None - ответ еще не получен целиком
+*/
+pub fn read_auth_response<T: Read>(stream: &mut T, decoder: &mut FrameDecoder) -> Result<Option<Response>, Error> {
+    let mut buf: Buffer = [0; MAX_BODY_SIZE];
+    if let Some(packet_info) = decoder.read_packet(&mut buf, stream)? {
+        ensure!(packet_info.packet_type == TYPE_AUTH_RESPONSE,
+            "Ожидался ответ аутентификации, получен {:#02x}", packet_info.packet_type);
+        ensure!(packet_info.packet_size == RESPONSE_SIZE,
+            "Недопустимый размер ответа аутентификации {}", packet_info.packet_size);
+        let mut response = [0; RESPONSE_SIZE];
+        response.copy_from_slice(&buf[..RESPONSE_SIZE]);
+        return Ok(Some(response));
+    }
+    Ok(None)
+}
+
+/**
+    Серверная сторона: данные клиента до разделения потока (запрос SOCKS5).
+    decoder - с форматом пакетов, о котором договорились, заполнитель пропускается.
+    None - пакета данных пока нет
+*/
+pub fn read_data<T: Read + ?Sized>(stream: &mut T, decoder: &mut FrameDecoder, dst: &mut [u8]) -> Result<Option<usize>, Error> {
+    while let Some(packet_info) = decoder.read_packet(dst, stream)? {
+        match packet_info.packet_type {
+            TYPE_DATA => return Ok(Some(packet_info.packet_size)),
+            TYPE_FILLER => {}
+            packet_type => bail!("Ожидались данные, получен {:#02x}", packet_type),
+        }
+    }
+    Ok(None)
+}
+
+/**
+    Серверная сторона: данные клиенту до разделения потока (ответ SOCKS5)
+*/
+pub fn send_data<T: Write + ?Sized>(stream: &mut T, buf: &[u8], options: FrameOptions) -> Result<(), Error> {
+    write_packet_with(buf, TYPE_DATA, options, stream)
+}
+
+/**
+    Серверная сторона: сессия нашлась на новом транспорте - сообщаем, сколько получили,
+    и повторяем то, что не получил клиент
+*/
+pub fn accept_resume<T: Write + ?Sized>(stream: &mut T, session: &mut Session, peer_received: u64) -> Result<(), Error> {
+    write_packet(&encode_counter(session.received()), TYPE_RESUME_ACK, stream).context("Send resume ack")?;
+    session.retransmit(peer_received, stream)
+}
+
+/**
+    Клиентская сторона: переподключение после обрыва вместо приветствия.
+    key - общий секрет, если сервер требует аутентификацию (тот же, что в client_handshake).
+    Ошибка - сессию не восстановить, начинаем заново с client_handshake.
+    Вызывается до split_client_stream_resumable
+*/
+pub fn client_resume<T: Transport>(stream: &mut T, session: &SharedSession, key: Option<&[u8]>, timeout: Duration) -> Result<(), Error> {
+    stream.set_read_timeout(Some(ACK_POLL_TIMEOUT)).context("Set read timeout for resume")?;
+    let mut session = lock_session(session);
+    let mut request = session.id().to_vec();
+    request.extend_from_slice(&encode_counter(session.received()));
+    write_packet(&request, TYPE_RESUME, stream).context("Send resume")?;
+    let mut buf: Buffer = [0; MAX_BODY_SIZE];
+    let mut decoder = FrameDecoder::new();
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(packet_info) = decoder.read_packet(&mut buf, stream)? {
+            let body = &buf[..packet_info.packet_size];
+            match packet_info.packet_type {
+                TYPE_RESUME_ACK => return session.retransmit(decode_counter(body)?, stream),
+                TYPE_AUTH_CHALLENGE => {
+                    let Some(key) = key else {
+                        bail!("Сервер требует аутентификацию, но ключ не задан");
+                    };
+                    let response = compute_resume_response(key, &decode_challenge(body)?, &session.id());
+                    write_packet(&response, TYPE_AUTH_RESPONSE, stream).context("Send resume auth response")?;
+                }
+                TYPE_ERROR => bail!("Сервер отказал в восстановлении сессии: {}", String::from_utf8_lossy(body)),
+                packet_type => bail!("Ожидалось подтверждение восстановления, получен {:#02x}", packet_type),
+            }
+        }
+        ensure!(!decoder.is_closed(), "Сервер закрыл соединение не ответив на восстановление");
+    }
+    bail!("Сервер не подтвердил восстановление сессии за {:?}", timeout)
+}
+
+/**
+    Клиентская сторона: представляемся и ждем подтверждения не дольше timeout.
+    key - общий секрет, если сервер требует аутентификацию.
+    Вызывается до split_client_stream
+*/
+pub fn client_handshake<T: Transport>(stream: &mut T, hello: &Hello, key: Option<&[u8]>, timeout: Duration) -> Result<HelloAck, Error> {
+    stream.set_read_timeout(Some(ACK_POLL_TIMEOUT)).context("Set read timeout for handshake")?;
+    write_packet(&hello.encode(), TYPE_HELLO, stream).context("Send hello")?;
+    let mut buf: Buffer = [0; MAX_BODY_SIZE];
+    let mut decoder = FrameDecoder::new();
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(packet_info) = decoder.read_packet(&mut buf, stream)? {
+            let body = &buf[..packet_info.packet_size];
+            match packet_info.packet_type {
+                TYPE_HELLO_ACK => return HelloAck::decode(body),
+                TYPE_AUTH_CHALLENGE => {
+                    let Some(key) = key else {
+                        bail!("Сервер требует аутентификацию, но ключ не задан");
+                    };
+                    let response = compute_response(key, &decode_challenge(body)?, &hello.client_name);
+                    write_packet(&response, TYPE_AUTH_RESPONSE, stream).context("Send auth response")?;
+                }
+                TYPE_ERROR => bail!("Сервер отказал в подключении: {}", String::from_utf8_lossy(body)),
+                packet_type => bail!("Ожидалось подтверждение приветствия, получен {:#02x}", packet_type),
+            }
+        }
+        ensure!(!decoder.is_closed(), "Сервер закрыл соединение не ответив на приветствие");
+    }
+    bail!("Сервер не подтвердил приветствие за {:?}", timeout)
+}
@@ -1,10 +1,21 @@
+#[cfg(feature = "async")]
+pub mod async_split;
+pub mod auth;
 pub mod client_side_split;
+pub mod handshake;
 mod packet;
+#[cfg(feature = "crypto")]
+pub mod secure_transport;
 pub mod server_side_split;
 pub mod server_side_vpn_stream;
+pub mod session;
+pub mod transport;
+pub mod udp;
 mod tests;
 
-use std::time::Duration;
+pub use packet::{FrameDecoder, FrameOptions};
+use std::os::fd::RawFd;
+use std::time::{Duration, Instant};
 use easy_error::Error;
 
 pub const READ_START_AWAIT_TIMEOUT: Duration = Duration::from_millis(1);
@@ -13,5 +24,47 @@ pub trait DataStream: Send {
     fn write_all(&mut self, buf: &[u8]) -> Result<(), Error>;
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
     fn shutdown(&mut self);
+    /**
+        Дескриптор для ожидания готовности к чтению (epoll).
+        None - поток приходится опрашивать по таймеру
+    */
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+    /**
+        Дописываем то, что не принял неблокирующий транспорт (сокет снова готов к записи)
+    */
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    /**
+        Сокет из raw_fd снова готов к записи (событие epoll в момент at)
+    */
+    fn writable(&mut self, _at: Instant) {}
+    /**
+        Записанное отправлено не все - новая запись только удлинит очередь
+    */
+    fn has_pending_writes(&self) -> bool {
+        false
+    }
+    /**
+        Другая сторона закрыла соединение (read только возвращает 0)
+    */
+    fn is_closed(&self) -> bool {
+        false
+    }
+    /**
+        Сколько байт заполнителя другой стороны прочитано (и выброшено) с прошлого вызова
+    */
+    fn take_filler_received(&mut self) -> usize {
+        0
+    }
+    /**
+        Сколько записанное ждало освобождения буфера отправки с прошлого вызова:
+        от WouldBlock до готовности сокета к записи (канал к другой стороне не успевает)
+    */
+    fn take_write_blocked(&mut self) -> Duration {
+        Duration::ZERO
+    }
 }
 
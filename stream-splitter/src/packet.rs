@@ -1,29 +1,57 @@
 use easy_error::{bail, Error, ResultExt};
 use log::{debug, warn};
+use std::collections::VecDeque;
 use std::io;
 use std::io::{ErrorKind, Read, Write};
-use std::net::TcpStream;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use crate::transport::Transport;
 use crate::MAX_BODY_SIZE;
 /*
    0x54[1], тип[1], размер[2]
+   Если договорились о CAPABILITY_CRC32 (см. handshake.rs), после тела идет
+   CRC32 заголовка и тела [4]
 */
 pub const HEADER_SIZE: usize = 4;
+pub const CHECKSUM_SIZE: usize = 4;
 
 pub const FIRST_BYTE: u8 = 0x54;
 pub const TYPE_DATA: u8 = 0x55;
 pub const TYPE_FILLER: u8 = 0x56;
+//рукопожатие: клиент представляется, сервер подтверждает (см. handshake.rs)
+pub const TYPE_HELLO: u8 = 0x57;
+pub const TYPE_HELLO_ACK: u8 = 0x58;
+//проверка общего секрета клиента (см. auth.rs)
+pub const TYPE_AUTH_CHALLENGE: u8 = 0x59;
+pub const TYPE_AUTH_RESPONSE: u8 = 0x5A;
+//сервер отказал в подключении, в теле причина в UTF-8 (см. handshake.rs)
+pub const TYPE_ERROR: u8 = 0x5B;
+//подтверждение полученных данных и восстановление сессии (см. session.rs)
+pub const TYPE_ACK: u8 = 0x5C;
+pub const TYPE_RESUME: u8 = 0x5D;
+pub const TYPE_RESUME_ACK: u8 = 0x5E;
+//все известные типы пакетов (при поиске следующего заголовка остальное считаем мусором)
+const FIRST_TYPE: u8 = TYPE_DATA;
+const LAST_TYPE: u8 = TYPE_RESUME_ACK;
 pub const TYPE_BYTE_INDEX: usize = 1;
 pub const LENGTH_BYTE_LSB_INDEX: usize = 2;
 pub const LENGTH_BYTE_MSB_INDEX: usize = 3;
 //pub const DATA_BYTE_INDEX: usize = HEADER_SIZE;
 
+pub(crate) const WRITE_RETRY_DELAY: Duration = Duration::from_micros(100);
+pub(crate) const WRITE_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub type Buffer = [u8; MAX_BODY_SIZE];
 
-struct Header {
-    packet_type: u8,
-    packet_size: usize,
+/**
+    Формат пакетов, о котором договорились клиент и сервер
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameOptions {
+    //после тела идет CRC32
+    pub checksum: bool,
+    //при мусоре в потоке ищем следующий корректный заголовок вместо разрыва соединения
+    pub resync: bool,
 }
 
 pub struct ReadPacketInfo {
@@ -52,83 +80,236 @@ impl QueuedPacket {
     }
 }
 
-pub fn write_packet(buf: &[u8], packet_type: u8, stream: &mut TcpStream) -> Result<(), Error> {
+pub fn write_packet<T: Write + ?Sized>(buf: &[u8], packet_type: u8, stream: &mut T) -> Result<(), Error> {
+    write_packet_with(buf, packet_type, FrameOptions::default(), stream)
+}
+
+pub fn write_packet_with<T: Write + ?Sized>(buf: &[u8], packet_type: u8, options: FrameOptions, stream: &mut T) -> Result<(), Error> {
     let size = buf.len();
-    let mut head_buf = create_packet_header(packet_type, size);
-    stream
-        .write_all(&mut head_buf)
+    let head_buf = create_packet_header(packet_type, size);
+    write(&head_buf, stream)
         .context("Write header in write_packet")?;
     write(buf, stream)
         .context("Write data in write_packet")?;
-    stream.flush().context("Flush stream in write_packet")
+    if options.checksum {
+        write(&checksum(&head_buf, buf).to_le_bytes(), stream)
+            .context("Write checksum in write_packet")?;
+    }
+    flush(stream).context("Flush stream in write_packet")
+}
+
+fn checksum(header: &[u8], body: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(body);
+    hasher.finalize()
+}
+
+/**
+    Накапливает входящие байты между вызовами чтения и отдает пакет только целиком.
+    Никогда не ждет остаток пакета - медленный канал не приводит к разрыву.
+    Из транспорта читается ровно до конца текущего пакета, поэтому декодер
+    без начатого пакета (is_empty) можно заменить другим не потеряв данных.
+    В режиме resync мусор и пакеты с неверной контрольной суммой пропускаются
+    побайтно до следующего корректного заголовка.
+*/
+pub struct FrameDecoder {
+    options: FrameOptions,
+    //заголовок, тело и контрольная сумма текущего пакета
+    buf: Vec<u8>,
+    //сколько байт уже получено
+    filled: usize,
+    //размер тела, известен после получения корректного заголовка
+    packet_size: Option<usize>,
+    //пропущено байт с последнего корректного пакета
+    skipped: usize,
+    //пропущено байт за все время
+    dropped_bytes: u64,
+    //транспорт закрыт другой стороной
+    closed: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> FrameDecoder {
+        FrameDecoder::new()
+    }
 }
 
-pub fn read_packet(
-    tmp_buf: &mut [u8],
-    stream: &mut TcpStream,
-) -> Result<Option<ReadPacketInfo>, Error> {
-    if tmp_buf.len() < MAX_BODY_SIZE {
-        bail!("Ожидался буфер способный вместить пакета максимального размера")
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::with_options(FrameOptions::default())
+    }
+
+    pub fn with_options(options: FrameOptions) -> FrameDecoder {
+        Self {
+            options,
+            buf: vec![0; HEADER_SIZE + MAX_BODY_SIZE + CHECKSUM_SIZE],
+            filled: 0,
+            packet_size: None,
+            skipped: 0,
+            dropped_bytes: 0,
+            closed: false,
+        }
+    }
+
+    /**
+        Нет начатого пакета
+    */
+    pub fn is_empty(&self) -> bool {
+        self.filled == 0
+    }
+
+    /**
+        Сколько байт мусора пропущено в режиме resync
+    */
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped_bytes
+    }
+
+    /**
+        Чтение вернуло конец потока - больше ничего не придет
+        (отсутствие данных read_packet от закрытия не отличает)
+    */
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /**
+        Куда читать дальше: остаток заголовка или пакета (не дальше его конца)
+    */
+    pub fn unfilled(&mut self) -> &mut [u8] {
+        let end = match self.packet_size {
+            Some(packet_size) => self.frame_size(packet_size),
+            None => HEADER_SIZE,
+        };
+        let start = self.filled.min(end);
+        &mut self.buf[start..end]
+    }
+
+    /**
+        В unfilled() прочитано size байт. Заголовок проверяется как только получен
+    */
+    pub fn advance(&mut self, size: usize) -> Result<(), Error> {
+        self.filled += size;
+        self.parse_header()
     }
-    if let Some(header) = read_header(stream)? {
-        let Header {
-            packet_size,
-            packet_type,
-        } = header;
-        let mut offset: usize = 0;
-        let mut loop_counter = 0;
 
+    /**
+        Забираем собранный пакет, декодер готов к следующему
+    */
+    pub fn take_packet(&mut self, dst: &mut [u8]) -> Result<Option<ReadPacketInfo>, Error> {
         loop {
-            //читаем не больше чем надо
-            offset += read(&mut tmp_buf[offset..packet_size], stream)
-                .context(format!("Packet body read offset {offset}, packet_size {packet_size}"))?;
-            if offset == packet_size {
-                return Ok(Some(ReadPacketInfo {
-                    packet_size,
-                    packet_type,
-                }));
+            let Some(packet_size) = self.packet_size else {
+                return Ok(None);
+            };
+            let frame_size = self.frame_size(packet_size);
+            if self.filled < frame_size {
+                return Ok(None);
             }
-            loop_counter += 1;
-            sleep(Duration::from_millis(5));
-            if loop_counter == 3 {
-                warn!("Получение пакета затянулось ...")
+            if self.options.checksum && !self.checksum_matches(packet_size) {
+                if !self.options.resync {
+                    bail!("Контрольная сумма пакета не совпала");
+                }
+                //заголовок мог оказаться случайным совпадением - ищем следующий со второго байта
+                self.skip(1);
+                self.parse_header()?;
+                continue;
             }
-            if loop_counter > 1000 {
-                bail!("Слишком долгое получение пакета")
+            if dst.len() < packet_size {
+                bail!("Ожидается что хватит места на пакет. {} < {}", dst.len(), packet_size)
             }
+            dst[..packet_size].copy_from_slice(&self.buf[HEADER_SIZE..HEADER_SIZE + packet_size]);
+            let packet_type = self.buf[TYPE_BYTE_INDEX];
+            if self.skipped > 0 {
+                warn!("Поток восстановлен, пропущено {} байт (всего {})", self.skipped, self.dropped_bytes);
+                self.skipped = 0;
+            }
+            self.consume(frame_size);
+            self.parse_header()?;
+            return Ok(Some(ReadPacketInfo {
+                packet_type,
+                packet_size,
+            }));
         }
     }
-    Ok(None)
-}
 
-fn read_header(stream: &mut TcpStream) -> Result<Option<Header>, Error> {
-    let mut header_buf: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
-    let mut offset: usize = 0;
-    let mut loop_counter = 0;
-    while offset < HEADER_SIZE {
-        offset +=
-            read(&mut header_buf[offset..HEADER_SIZE], stream).context("Header packet read")?;
-        if offset == 0 {
-            //если при первой попытке ничего не удалось прочить - возможно данных просто нет
-            return Ok(None);
+    /**
+        Читаем что есть в транспорте.
+        None - пакет еще не собран (данных нет, или пришла только часть)
+    */
+    pub fn read_packet<T: Read + ?Sized>(
+        &mut self,
+        dst: &mut [u8],
+        stream: &mut T,
+    ) -> Result<Option<ReadPacketInfo>, Error> {
+        loop {
+            if let Some(packet_info) = self.take_packet(dst)? {
+                return Ok(Some(packet_info));
+            }
+            let size = match stream.read(self.unfilled()) {
+                Ok(0) => {
+                    self.closed = true;
+                    return Ok(None);
+                }
+                Ok(size) => size,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+                Err(e) => return Err(e).context(format!("Packet read, received {} of packet", self.filled)),
+            };
+            self.advance(size)?;
+        }
+    }
+
+    fn frame_size(&self, packet_size: usize) -> usize {
+        if self.options.checksum {
+            return HEADER_SIZE + packet_size + CHECKSUM_SIZE;
+        }
+        HEADER_SIZE + packet_size
+    }
+
+    fn parse_header(&mut self) -> Result<(), Error> {
+        while self.packet_size.is_none() && self.filled >= HEADER_SIZE {
+            match self.check_header() {
+                Ok(packet_size) => self.packet_size = Some(packet_size),
+                Err(_) if self.options.resync => self.skip(1),
+                Err(e) => return Err(e),
+            }
         }
-        loop_counter += 1;
-        if loop_counter > 1000 {
-            bail!("Слишком долгое получение заголовка пакета")
+        Ok(())
+    }
+
+    fn check_header(&self) -> Result<usize, Error> {
+        if self.buf[0] != FIRST_BYTE {
+            bail!("Первый байт должен быть маркером");
+        }
+        let packet_type = self.buf[TYPE_BYTE_INDEX];
+        if self.options.resync && !(FIRST_TYPE..=LAST_TYPE).contains(&packet_type) {
+            bail!("Неизвестный тип пакета {:#02x}", packet_type);
         }
+        calculate_packet_size(&self.buf[..HEADER_SIZE]).context("Packet size calculation")
     }
-    if header_buf[0] != FIRST_BYTE {
-        bail!("Первый байт должен быть маркером");
+
+    fn checksum_matches(&self, packet_size: usize) -> bool {
+        let end = HEADER_SIZE + packet_size;
+        let mut expected = [0; CHECKSUM_SIZE];
+        expected.copy_from_slice(&self.buf[end..end + CHECKSUM_SIZE]);
+        checksum(&self.buf[..HEADER_SIZE], &self.buf[HEADER_SIZE..end]) == u32::from_le_bytes(expected)
+    }
+
+    fn skip(&mut self, size: usize) {
+        self.consume(size);
+        self.skipped += size;
+        self.dropped_bytes += size as u64;
+    }
+
+    //сдвигаем оставшиеся байты в начало буфера
+    fn consume(&mut self, size: usize) {
+        self.buf.copy_within(size..self.filled, 0);
+        self.filled -= size;
+        self.packet_size = None;
     }
-    let packet_size =
-        calculate_packet_size(&header_buf).context("Packet size calculation")?;
-    Ok(Some(Header {
-        packet_type: header_buf[TYPE_BYTE_INDEX],
-        packet_size,
-    }))
 }
 
-fn calculate_packet_size(header_buf: &[u8]) -> Result<usize, Error> {
+pub(crate) fn calculate_packet_size(header_buf: &[u8]) -> Result<usize, Error> {
     let packet_size: usize =
         ((header_buf[LENGTH_BYTE_MSB_INDEX] as usize) << 8) | header_buf[LENGTH_BYTE_LSB_INDEX] as usize;
     if packet_size == 0 {
@@ -150,7 +331,7 @@ pub fn create_packet_header(packet_type: u8, data_len: usize) -> [u8; HEADER_SIZ
     header
 }
 
-pub(crate) fn read(buf: &mut [u8], stream: &mut TcpStream) -> Result<usize, io::Error> {
+pub(crate) fn read<T: Read + ?Sized>(buf: &mut [u8], stream: &mut T) -> Result<usize, io::Error> {
     match stream.read(buf) {
         Ok(size) => Ok(size),
         Err(e) => {
@@ -166,12 +347,176 @@ pub(crate) fn read(buf: &mut [u8], stream: &mut TcpStream) -> Result<usize, io::
     }
 }
 
-pub(crate) fn write(buf: &[u8], stream: &mut TcpStream) -> Result<usize, io::Error> {
+pub(crate) fn peek<T: Transport + ?Sized>(buf: &mut [u8], stream: &mut T) -> Result<usize, io::Error> {
+    match stream.peek(buf) {
+        Ok(size) => Ok(size),
+        Err(e) => {
+            match e.kind() {
+                ErrorKind::WouldBlock => Ok(0),
+                ErrorKind::TimedOut => Ok(0),
+                _ => Err(e)
+            }
+        }
+    }
+}
+
+/**
+    Транспорт может быть неблокирующим (рукопожатие) -
+    при переполненном буфере отправки ждем его освобождения, но не дольше WRITE_STALL_TIMEOUT.
+    Разделенный поток сервера не ждет, а копит (см. Outgoing)
+*/
+pub(crate) fn write<T: Write + ?Sized>(buf: &[u8], stream: &mut T) -> Result<usize, io::Error> {
     let size = buf.len();
     let mut offset = 0;
+    let mut stalled_since: Option<Instant> = None;
     while offset < size {
-        offset += stream
-            .write(&buf[offset..size])?;
+        match stream.write(&buf[offset..size]) {
+            Ok(written) => {
+                offset += written;
+                stalled_since = None;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if stalled_since.get_or_insert_with(Instant::now).elapsed() > WRITE_STALL_TIMEOUT {
+                    return Err(e);
+                }
+                sleep(WRITE_RETRY_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
     }
     Ok(size)
 }
+
+/**
+    flush тоже может упереться в буфер отправки (SecureTransport) - ждем так же как write
+*/
+fn flush<T: Write + ?Sized>(stream: &mut T) -> Result<(), io::Error> {
+    let mut stalled_since: Option<Instant> = None;
+    loop {
+        match stream.flush() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if stalled_since.get_or_insert_with(Instant::now).elapsed() > WRITE_STALL_TIMEOUT {
+                    return Err(e);
+                }
+                sleep(WRITE_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+/**
+    Исходящие пакеты неблокирующего транспорта. Пакет собирается как в поток (write_packet_with),
+    flush завершает пакет. Пакеты уходят в транспорт по одному (одна запись SecureTransport на пакет).
+    Что транспорт не принял, send допишет, когда сокет снова будет готов к записи -
+    поток, обслуживающий много пар, не засыпает
+*/
+#[derive(Default)]
+pub(crate) struct Outgoing {
+    //собираемый пакет
+    frame: Vec<u8>,
+    frames: VecDeque<Vec<u8>>,
+    //сколько байт первого пакета транспорт уже принял
+    offset: usize,
+    //транспорт принял пакет, но flush вернул WouldBlock
+    unflushed: bool,
+    //с какого момента транспорт ничего не принимает
+    stalled_since: Option<Instant>,
+    //с какого момента транспорт не принимает (вернул WouldBlock)
+    blocked_since: Option<Instant>,
+    //сколько транспорт не принимал (от WouldBlock до готовности к записи), еще не учтено снаружи
+    blocked: Duration,
+}
+
+impl Outgoing {
+    /**
+        Отправляем сколько примет транспорт.
+        Ошибка - транспорт сломан или ничего не принимает дольше WRITE_STALL_TIMEOUT
+    */
+    pub fn send<T: Write + ?Sized>(&mut self, stream: &mut T) -> Result<(), io::Error> {
+        let mut progress = false;
+        loop {
+            if self.unflushed {
+                match stream.flush() {
+                    Ok(()) => self.unflushed = false,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            let Some(frame) = self.frames.front() else {
+                break;
+            };
+            match stream.write(&frame[self.offset..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    progress = true;
+                    self.offset += written;
+                    if self.offset == frame.len() {
+                        self.frames.pop_front();
+                        self.offset = 0;
+                        self.unflushed = true;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let now = Instant::now();
+        if self.is_empty() {
+            self.stalled_since = None;
+            //события готовности не было (дописали по таймеру)
+            self.writable(now);
+            return Ok(());
+        }
+        self.blocked_since.get_or_insert(now);
+        if progress {
+            self.stalled_since = Some(now);
+        }
+        if now - *self.stalled_since.get_or_insert(now) > WRITE_STALL_TIMEOUT {
+            return Err(io::Error::new(ErrorKind::TimedOut, "Транспорт не принимает данные"));
+        }
+        Ok(())
+    }
+
+    /**
+        Все завершенные пакеты отправлены
+    */
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty() && !self.unflushed
+    }
+
+    /**
+        Сокет снова готов к записи (событие epoll в момент at) - ожидание закончилось
+    */
+    pub fn writable(&mut self, at: Instant) {
+        if let Some(since) = self.blocked_since.take() {
+            self.blocked += at.saturating_duration_since(since);
+        }
+    }
+
+    /**
+        Незаконченное ожидание учитываем по текущий момент - длинное не уходит целиком в будущий отчет
+    */
+    pub fn take_blocked(&mut self) -> Duration {
+        if let Some(since) = self.blocked_since.as_mut() {
+            let now = Instant::now();
+            self.blocked += now.saturating_duration_since(*since);
+            *since = now;
+        }
+        std::mem::take(&mut self.blocked)
+    }
+}
+
+impl Write for Outgoing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.frame.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.frame.is_empty() {
+            self.frames.push_back(std::mem::take(&mut self.frame));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+/*
+Шифрование потока, включается feature "crypto".
+Позволяет открыть порт эквалайзера наружу без SSH туннеля.
+Сразу после подключения каждая сторона отправляет свой одноразовый открытый ключ X25519[32].
+Ключи направлений выводятся HKDF-SHA256 из общего секрета Диффи-Хеллмана
+(солью служит общий ключ PSK, если задан - без него защиты от MITM нет).
+Дальше идут записи
+   размер шифротекста[2], шифротекст ChaCha20-Poly1305 (пакет splitter + тег[16])
+Одна запись на пакет (flush в write_packet) - заголовки 0x54 и тип пакета
+(данные или заполнитель) снаружи не видны.
+Неверный PSK обнаруживается на первой же записи (не сходится тег).
+*/
+use crate::packet::{write, CHECKSUM_SIZE, HEADER_SIZE};
+use crate::transport::Transport;
+use crate::MAX_BODY_SIZE;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use easy_error::{Error, ResultExt};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::RawFd;
+use std::time::Duration;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+const RECORD_HEADER_SIZE: usize = 2;
+//пакет целиком помещается в одну запись
+const MAX_PLAINTEXT_SIZE: usize = HEADER_SIZE + MAX_BODY_SIZE + CHECKSUM_SIZE;
+const MAX_RECORD_SIZE: usize = MAX_PLAINTEXT_SIZE + TAG_SIZE;
+const KEY_SIZE: usize = 32;
+const KDF_INFO: &[u8] = b"equalizer splitter v1";
+
+pub struct SecureTransport<T: Transport> {
+    stream: T,
+    initiator: bool,
+    secret: StaticSecret,
+    public_key: PublicKey,
+    peer_key: [u8; PUBLIC_KEY_SIZE],
+    peer_key_filled: usize,
+    //соль для вывода ключей, после получения ключа другой стороны не нужен
+    psk: Vec<u8>,
+    //появляются после получения ключа другой стороны
+    ciphers: Option<Ciphers>,
+    //принимаемая запись
+    record: Vec<u8>,
+    record_filled: usize,
+    //расшифрованное, но еще не прочитанное
+    plaintext: Vec<u8>,
+    plaintext_offset: usize,
+    //записанное, но еще не отправленное (до flush)
+    outgoing: Vec<u8>,
+    //зашифрованное, но не принятое неблокирующим транспортом
+    sending: Vec<u8>,
+}
+
+struct Ciphers {
+    send: ChaCha20Poly1305,
+    send_counter: u64,
+    receive: ChaCha20Poly1305,
+    receive_counter: u64,
+}
+
+impl<T: Transport> SecureTransport<T> {
+    /**
+        Клиентская сторона
+    */
+    pub fn connect(stream: T, psk: Option<&[u8]>) -> Result<SecureTransport<T>, Error> {
+        SecureTransport::new(stream, psk, true)
+    }
+
+    /**
+        Серверная сторона. Не ждет ключа клиента - он будет прочитан вместе с первыми данными
+    */
+    pub fn accept(stream: T, psk: Option<&[u8]>) -> Result<SecureTransport<T>, Error> {
+        SecureTransport::new(stream, psk, false)
+    }
+
+    fn new(mut stream: T, psk: Option<&[u8]>, initiator: bool) -> Result<SecureTransport<T>, Error> {
+        let mut secret = [0; KEY_SIZE];
+        getrandom::fill(&mut secret).context("Генерация ключа")?;
+        let secret = StaticSecret::from(secret);
+        let public_key = PublicKey::from(&secret);
+        write(public_key.as_bytes(), &mut stream).context("Send public key")?;
+        stream.flush().context("Flush public key")?;
+        Ok(Self {
+            stream,
+            initiator,
+            secret,
+            public_key,
+            peer_key: [0; PUBLIC_KEY_SIZE],
+            peer_key_filled: 0,
+            psk: psk.unwrap_or_default().to_vec(),
+            ciphers: None,
+            record: vec![0; RECORD_HEADER_SIZE + MAX_RECORD_SIZE],
+            record_filled: 0,
+            plaintext: Vec::with_capacity(MAX_PLAINTEXT_SIZE),
+            plaintext_offset: 0,
+            outgoing: Vec::with_capacity(MAX_PLAINTEXT_SIZE),
+            sending: Vec::with_capacity(RECORD_HEADER_SIZE + MAX_RECORD_SIZE),
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+
+    /**
+        Дочитываем ключ другой стороны. WouldBlock - еще не пришел
+    */
+    fn read_peer_key(&mut self) -> io::Result<()> {
+        while self.peer_key_filled < PUBLIC_KEY_SIZE {
+            let size = self.stream.read(&mut self.peer_key[self.peer_key_filled..])?;
+            if size == 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "Соединение закрыто до получения ключа"));
+            }
+            self.peer_key_filled += size;
+        }
+        let shared = self.secret.diffie_hellman(&PublicKey::from(self.peer_key));
+        if !shared.was_contributory() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Недопустимый ключ другой стороны"));
+        }
+        let (initiator_key, responder_key) = if self.initiator {
+            (*self.public_key.as_bytes(), self.peer_key)
+        } else {
+            (self.peer_key, *self.public_key.as_bytes())
+        };
+        let mut info = KDF_INFO.to_vec();
+        info.extend_from_slice(&initiator_key);
+        info.extend_from_slice(&responder_key);
+        let hkdf = Hkdf::<Sha256>::new(Some(&self.psk), shared.as_bytes());
+        self.psk.clear();
+        let mut keys = [0; KEY_SIZE * 2];
+        hkdf.expand(&info, &mut keys)
+            .map_err(|_| io::Error::other("Вывод ключей"))?;
+        let (to_responder, to_initiator) = keys.split_at(KEY_SIZE);
+        let (send, receive) = if self.initiator {
+            (to_responder, to_initiator)
+        } else {
+            (to_initiator, to_responder)
+        };
+        self.ciphers = Some(Ciphers {
+            send: ChaCha20Poly1305::new(send.into()),
+            send_counter: 0,
+            receive: ChaCha20Poly1305::new(receive.into()),
+            receive_counter: 0,
+        });
+        Ok(())
+    }
+
+    /**
+        Перед первой отправкой ключ другой стороны нужен обязательно.
+        Еще не пришел - WouldBlock без ожидания: поток стадии рукопожатия и реактора общий,
+        запись повторит вызывающий (очередь отправки, write_packet)
+    */
+    fn peer_key_for_write(&mut self) -> io::Result<()> {
+        match self.read_peer_key() {
+            Err(e) if is_would_block(&e) => Err(ErrorKind::WouldBlock.into()),
+            result => result,
+        }
+    }
+
+    /**
+        Получаем следующую запись целиком. WouldBlock - пришла только часть
+    */
+    fn receive_record(&mut self) -> io::Result<bool> {
+        if self.ciphers.is_none() {
+            self.read_peer_key()?;
+        }
+        loop {
+            let end = if self.record_filled < RECORD_HEADER_SIZE {
+                RECORD_HEADER_SIZE
+            } else {
+                let size = u16::from_le_bytes([self.record[0], self.record[1]]) as usize;
+                if !(TAG_SIZE..=MAX_RECORD_SIZE).contains(&size) {
+                    return Err(io::Error::new(ErrorKind::InvalidData, format!("Недопустимый размер записи {size}")));
+                }
+                RECORD_HEADER_SIZE + size
+            };
+            if self.record_filled == end && end > RECORD_HEADER_SIZE {
+                break;
+            }
+            let size = self.stream.read(&mut self.record[self.record_filled..end])?;
+            if size == 0 {
+                if self.record_filled > 0 {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof, "Соединение закрыто посреди записи"));
+                }
+                return Ok(false);
+            }
+            self.record_filled += size;
+        }
+        let ciphers = self.ciphers.as_mut().expect("ключи получены");
+        let (aad, ciphertext) = self.record[..self.record_filled].split_at(RECORD_HEADER_SIZE);
+        let plaintext = ciphers.receive
+            .decrypt(&nonce(ciphers.receive_counter), Payload { msg: ciphertext, aad })
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Запись не расшифровалась (неверный PSK?)"))?;
+        ciphers.receive_counter += 1;
+        self.record_filled = 0;
+        self.plaintext = plaintext;
+        self.plaintext_offset = 0;
+        Ok(true)
+    }
+
+    /**
+        Есть что отдать читающему. false - поток закрыт
+    */
+    fn fill_plaintext(&mut self) -> io::Result<bool> {
+        while self.plaintext_offset == self.plaintext.len() {
+            if !self.receive_record()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn pending_plaintext(&self) -> &[u8] {
+        &self.plaintext[self.plaintext_offset..]
+    }
+}
+
+impl<T: Transport> Read for SecureTransport<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.fill_plaintext()? {
+            return Ok(0);
+        }
+        let size = buf.len().min(self.pending_plaintext().len());
+        buf[..size].copy_from_slice(&self.pending_plaintext()[..size]);
+        self.plaintext_offset += size;
+        Ok(size)
+    }
+}
+
+impl<T: Transport> Write for SecureTransport<T> {
+    /**
+        Копим до flush - пакет уходит одной записью
+    */
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.ciphers.is_none() {
+            self.peer_key_for_write()?;
+        }
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let Some(ciphers) = self.ciphers.as_mut() else {
+            return self.stream.flush();
+        };
+        for chunk in self.outgoing.chunks(MAX_PLAINTEXT_SIZE) {
+            let size = ((chunk.len() + TAG_SIZE) as u16).to_le_bytes();
+            let ciphertext = ciphers.send
+                .encrypt(&nonce(ciphers.send_counter), Payload { msg: chunk, aad: &size })
+                .map_err(|_| io::Error::other("Шифрование записи"))?;
+            ciphers.send_counter += 1;
+            self.sending.extend_from_slice(&size);
+            self.sending.extend_from_slice(&ciphertext);
+        }
+        self.outgoing.clear();
+        while !self.sending.is_empty() {
+            match self.stream.write(&self.sending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.sending.drain(..written);
+                }
+                //WouldBlock: остаток допишет следующий flush
+                Err(e) => return Err(e),
+            }
+        }
+        self.stream.flush()
+    }
+}
+
+impl<T: Transport> Transport for SecureTransport<T> {
+    fn shutdown(&self) {
+        self.stream.shutdown();
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.stream.raw_fd()
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.fill_plaintext()? {
+            return Ok(0);
+        }
+        let size = buf.len().min(self.pending_plaintext().len());
+        buf[..size].copy_from_slice(&self.pending_plaintext()[..size]);
+        Ok(size)
+    }
+}
+
+//счетчик записей, в каждом направлении свой ключ
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce.into()
+}
+
+fn is_would_block(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
@@ -1,6 +1,11 @@
+use crate::handshake::accept_resume;
 use crate::packet::*;
-use crate::{DataStream, READ_START_AWAIT_TIMEOUT};
-use std::net::{Shutdown, TcpStream};
+use crate::session::{decode_counter, encode_counter, lock_session, SharedSession};
+use crate::transport::Transport;
+use crate::DataStream;
+use std::os::fd::RawFd;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use easy_error::{bail, Error, ResultExt};
 use log::warn;
 
@@ -9,50 +14,187 @@ pub struct ServerSideSplit {
     pub filler_stream: Box<dyn DataStream>,
 }
 
-pub fn split_server_stream<'a>(client_stream: TcpStream) -> ServerSideSplit {
-    client_stream
-        .set_read_timeout(Some(READ_START_AWAIT_TIMEOUT))
-        .expect("Архитектура подразумевает не блокирующий метод чтения");
-    let filler_stream = client_stream
-        .try_clone()
-        .expect("Failed to clone TcpStream");
+/**
+    Транспорт переводится в неблокирующий режим - готовность к чтению и записи
+    ожидается снаружи (epoll) по дескриптору из DataStream::raw_fd.
+    Что не принял транспорт, копится и дописывается в DataStream::flush.
+    Каналы данных и заполнителя делят один транспорт (клонировать можно не всякий)
+*/
+pub fn split_server_stream<T: Transport + 'static>(client_stream: T) -> ServerSideSplit {
+    split_server_stream_with(client_stream, FrameOptions::default())
+}
+
+/**
+    options - о чем договорились в рукопожатии (HelloAck::frame_options)
+*/
+pub fn split_server_stream_with<T: Transport + 'static>(client_stream: T, options: FrameOptions) -> ServerSideSplit {
+    split(client_stream, options, None)
+}
+
+/**
+    Пакеты данных нумеруются и подтверждаются (см. session.rs).
+    После переподключения клиента новый транспорт разделяется с той же сессией
+*/
+pub fn split_server_stream_resumable<T: Transport + 'static>(client_stream: T, session: SharedSession) -> ServerSideSplit {
+    let options = lock_session(&session).options();
+    split(client_stream, options, Some(session))
+}
+
+/**
+    Клиент переподключился (см. handshake::accept_resume): подтверждение и повтор недошедшего
+    уходят через очередь отправки - повторять может быть много.
+    Ошибка - сессию не восстановить, транспорт закрыт
+*/
+pub fn split_server_stream_resumed<T: Transport + 'static>(client_stream: T, session: SharedSession, peer_received: u64)
+    -> Result<ServerSideSplit, Error> {
+    let options = lock_session(&session).options();
+    let mut client_stream = SharedStream::new(client_stream, options, Some(session.clone()));
+    let accepted = accept_resume(&mut client_stream.outgoing, &mut lock_session(&session), peer_received)
+        .and_then(|_| client_stream.send());
+    if let Err(e) = accepted {
+        client_stream.stream.shutdown();
+        return Err(e);
+    }
+    Ok(into_split(client_stream))
+}
+
+fn split<T: Transport + 'static>(client_stream: T, options: FrameOptions, session: Option<SharedSession>) -> ServerSideSplit {
+    into_split(SharedStream::new(client_stream, options, session))
+}
+
+fn into_split<T: Transport + 'static>(client_stream: SharedStream<T>) -> ServerSideSplit {
+    let fd = client_stream.stream.raw_fd();
+    let client_stream = Arc::new(Mutex::new(client_stream));
     ServerSideSplit {
-        data_stream: Box::new(ClientDataStream::new(client_stream)),
-        filler_stream: Box::new(FillerDataStream::new(filler_stream)),
+        data_stream: Box::new(ClientDataStream::new(client_stream.clone(), fd)),
+        filler_stream: Box::new(FillerDataStream::new(client_stream)),
+    }
+}
+
+struct SharedStream<T: Transport> {
+    stream: T,
+    //каналы читают один поток - и части пакетов у них общие
+    decoder: FrameDecoder,
+    options: FrameOptions,
+    //None - клиент не договорился о восстановлении сессии
+    session: Option<SharedSession>,
+    //не принятое транспортом (данные, заполнитель и подтверждения - в порядке записи)
+    outgoing: Outgoing,
+    //заполнитель клиента (маскировка в его сторону), еще не учтенный снаружи
+    filler_received: usize,
+}
+
+impl<T: Transport> SharedStream<T> {
+    fn new(stream: T, options: FrameOptions, session: Option<SharedSession>) -> SharedStream<T> {
+        stream
+            .set_nonblocking(true)
+            .expect("Архитектура подразумевает не блокирующий метод чтения");
+        Self {
+            stream,
+            decoder: FrameDecoder::with_options(options),
+            options,
+            session,
+            outgoing: Outgoing::default(),
+            filler_received: 0,
+        }
+    }
+
+    /**
+        Подтверждения клиента разбираются здесь, наружу не попадают
+    */
+    fn read_packet(&mut self, dst: &mut [u8]) -> Result<Option<ReadPacketInfo>, Error> {
+        let Some(session) = &self.session else {
+            return self.decoder.read_packet(dst, &mut self.stream);
+        };
+        loop {
+            match self.decoder.read_packet(dst, &mut self.stream)? {
+                Some(packet_info) if packet_info.packet_type == TYPE_ACK => {
+                    let received = decode_counter(&dst[..packet_info.packet_size])?;
+                    lock_session(session).acknowledge(received)?;
+                }
+                packet_info => {
+                    self.confirm()?;
+                    return Ok(packet_info);
+                }
+            }
+        }
+    }
+
+    fn write_packet(&mut self, buf: &[u8], packet_type: u8) -> Result<(), Error> {
+        if let (Some(session), TYPE_DATA) = (&self.session, packet_type) {
+            //до отправки: оборванная на середине запись тоже повторяется
+            lock_session(session).data_sent(buf);
+        }
+        write_packet_with(buf, packet_type, self.options, &mut self.outgoing)?;
+        self.send()
+    }
+
+    fn send(&mut self) -> Result<(), Error> {
+        self.outgoing.send(&mut self.stream).context("Write to client")
+    }
+
+    fn data_received(&mut self) -> Result<(), Error> {
+        if let Some(session) = &self.session {
+            lock_session(session).data_received();
+            self.confirm()?;
+        }
+        Ok(())
+    }
+
+    fn confirm(&mut self) -> Result<(), Error> {
+        let Some(session) = &self.session else {
+            return Ok(());
+        };
+        let mut session = lock_session(session);
+        if let Some(received) = session.ack_due() {
+            write_packet_with(&encode_counter(received), TYPE_ACK, self.options, &mut self.outgoing)
+                .context("Write ack packet")?;
+            session.ack_sent(received);
+            drop(session);
+            self.send()?;
+        }
+        Ok(())
     }
 }
-fn shutdown_stream(stream: &TcpStream) {
-    let _ = stream.shutdown(Shutdown::Both);
+
+fn lock<T: Transport>(stream: &Mutex<SharedStream<T>>) -> MutexGuard<'_, SharedStream<T>> {
+    //транспорт не остается в несогласованном состоянии - паника в соседнем канале не важна
+    stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
 }
 
-pub struct ClientDataStream {
-    client_stream: TcpStream,
+pub struct ClientDataStream<T: Transport> {
+    client_stream: Arc<Mutex<SharedStream<T>>>,
+    fd: Option<RawFd>,
 }
 
-pub struct FillerDataStream {
-    client_stream: TcpStream,
+pub struct FillerDataStream<T: Transport> {
+    client_stream: Arc<Mutex<SharedStream<T>>>,
 }
 
-impl ClientDataStream {
-    fn new(client_stream: TcpStream) -> ClientDataStream {
+impl<T: Transport> ClientDataStream<T> {
+    fn new(client_stream: Arc<Mutex<SharedStream<T>>>, fd: Option<RawFd>) -> ClientDataStream<T> {
         Self {
             client_stream,
+            fd,
         }
     }
 }
 
-impl DataStream for ClientDataStream {
+impl<T: Transport> DataStream for ClientDataStream<T> {
     fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
-        write_packet(buf, TYPE_DATA, &mut self.client_stream)
+        lock(&self.client_stream).write_packet(buf, TYPE_DATA)
             .context("Write data packet in server side split")
     }
 
     fn read(&mut self, dst: &mut [u8]) -> Result<usize, Error> {
-        if let Some(packet_info) = read_packet(dst, &mut self.client_stream)? {
+        let mut client_stream = lock(&self.client_stream);
+        if let Some(packet_info) = client_stream.read_packet(dst)? {
             if packet_info.packet_type == TYPE_DATA {
+                client_stream.data_received()?;
                 return Ok(packet_info.packet_size);
             } else if packet_info.packet_type == TYPE_FILLER {
-                warn!("Входящий корректный пакет заполнителя, обработка которого еще не реализована");
+                //нужен только для маскировки - учитываем и выбрасываем
+                client_stream.filler_received += packet_info.packet_size;
             } else {
                 bail!("Мусор в данных")
             }
@@ -61,23 +203,51 @@ impl DataStream for ClientDataStream {
     }
 
     fn shutdown(&mut self) {
-        shutdown_stream(&self.client_stream);
+        lock(&self.client_stream).stream.shutdown();
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+
+    fn is_closed(&self) -> bool {
+        lock(&self.client_stream).decoder.is_closed()
+    }
+
+    fn take_filler_received(&mut self) -> usize {
+        std::mem::take(&mut lock(&self.client_stream).filler_received)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        lock(&self.client_stream).send()
+    }
+
+    fn writable(&mut self, at: Instant) {
+        lock(&self.client_stream).outgoing.writable(at);
+    }
+
+    fn has_pending_writes(&self) -> bool {
+        !lock(&self.client_stream).outgoing.is_empty()
+    }
+
+    fn take_write_blocked(&mut self) -> Duration {
+        lock(&self.client_stream).outgoing.take_blocked()
     }
 }
 
-impl FillerDataStream {
-    fn new(client_stream: TcpStream) -> FillerDataStream {
+impl<T: Transport> FillerDataStream<T> {
+    fn new(client_stream: Arc<Mutex<SharedStream<T>>>) -> FillerDataStream<T> {
         Self { client_stream }
     }
 }
 
-impl DataStream for FillerDataStream {
+impl<T: Transport> DataStream for FillerDataStream<T> {
     fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
-        write_packet(buf, TYPE_FILLER, &mut self.client_stream)
+        lock(&self.client_stream).write_packet(buf, TYPE_FILLER)
     }
 
     fn read(&mut self, dst: &mut [u8]) -> Result<usize, Error> {
-        if let Some(packet_info) = read_packet(dst, &mut self.client_stream)? {
+        if let Some(packet_info) = lock(&self.client_stream).read_packet(dst)? {
             if packet_info.packet_type == TYPE_FILLER {
                 return Ok(packet_info.packet_size);
             }else if packet_info.packet_type == TYPE_DATA {
@@ -90,6 +260,6 @@ impl DataStream for FillerDataStream {
     }
 
     fn shutdown(&mut self) {
-        shutdown_stream(&self.client_stream);
+        lock(&self.client_stream).stream.shutdown();
     }
 }
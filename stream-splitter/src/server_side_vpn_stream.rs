@@ -1,30 +1,44 @@
-use std::io::Write;
-use std::net::{Shutdown, TcpStream};
+use std::os::fd::RawFd;
 use easy_error::{Error, ResultExt};
-use crate::{packet, DataStream, READ_START_AWAIT_TIMEOUT};
+use crate::transport::Transport;
+use crate::packet::Outgoing;
+use crate::{packet, DataStream};
+use std::io::Write;
 
-pub struct VpnDataStream {
-    vpn_data_stream: TcpStream,
+pub struct VpnDataStream<T: Transport> {
+    vpn_data_stream: T,
+    //не принятое VPN сервером, дописывается в flush
+    outgoing: Outgoing,
 }
 
-impl VpnDataStream {
-    pub fn new(vpn_data_stream: TcpStream) -> VpnDataStream {
+impl<T: Transport> VpnDataStream<T> {
+    pub fn new(vpn_data_stream: T) -> VpnDataStream<T> {
         vpn_data_stream
-            .set_read_timeout(Some(READ_START_AWAIT_TIMEOUT))
+            .set_nonblocking(true)
             .expect("Архитектура подразумевает не блокирующий метод чтения");
         Self {
             vpn_data_stream,
+            outgoing: Outgoing::default(),
         }
     }
 }
 
 
-impl DataStream for VpnDataStream {
+impl<T: Transport> DataStream for VpnDataStream<T> {
     fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
-        packet::write(buf, &mut self.vpn_data_stream)
-            .context("Failed to write to vpn stream")?;
-        self.vpn_data_stream.flush()
-            .context("Failed to flush vpn stream")
+        self.outgoing.write_all(buf)
+            .and_then(|_| self.outgoing.flush())
+            .context("Failed to queue for vpn stream")?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.outgoing.send(&mut self.vpn_data_stream)
+            .context("Failed to write to vpn stream")
+    }
+
+    fn has_pending_writes(&self) -> bool {
+        !self.outgoing.is_empty()
     }
 
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
@@ -33,6 +47,10 @@ impl DataStream for VpnDataStream {
     }
 
     fn shutdown(&mut self) {
-        let _ = self.vpn_data_stream.shutdown(Shutdown::Both);
+        self.vpn_data_stream.shutdown();
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.vpn_data_stream.raw_fd()
     }
 }
\ No newline at end of file
@@ -0,0 +1,234 @@
+/*
+Восстановление сессии после обрыва соединения.
+Если договорились о CAPABILITY_RESUME, сервер выдает в TYPE_HELLO_ACK номер сессии[16].
+Пакеты данных нумеруются по порядку отправки (номер не передается - считают обе стороны),
+получатель время от времени подтверждает, сколько пакетов получил
+   TYPE_ACK: получено пакетов данных[8]
+Отправитель хранит неподтвержденные пакеты. После обрыва клиент подключается заново
+и вместо TYPE_HELLO шлет
+   TYPE_RESUME: номер сессии[16], получено пакетов данных[8]
+Сервер с ключами клиентов сначала запрашивает аутентификацию (см. auth.rs).
+Сервер (если пара еще ждет клиента) отвечает
+   TYPE_RESUME_ACK: получено пакетов данных[8]
+и обе стороны повторяют то, что другая сторона не получила.
+Неизвестную сессию сервер отклоняет (TYPE_ERROR) - клиент начинает заново с TYPE_HELLO.
+Заполнитель не нумеруется и не повторяется.
+*/
+use crate::packet::{write_packet_with, TYPE_DATA};
+use crate::FrameOptions;
+use easy_error::{ensure, Error, ResultExt};
+use log::warn;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+pub const SESSION_ID_SIZE: usize = 16;
+pub const COUNTER_SIZE: usize = 8;
+pub const RESUME_SIZE: usize = SESSION_ID_SIZE + COUNTER_SIZE;
+//подтверждаем не реже чем через столько пакетов данных
+const ACK_EVERY: u64 = 32;
+//и не позже чем через столько после получения
+const ACK_INTERVAL: Duration = Duration::from_millis(100);
+//больше неподтвержденных данных не храним - такую сессию уже не восстановить
+const MAX_UNACKED_BYTES: usize = 4 * 1024 * 1024;
+
+pub type SessionId = [u8; SESSION_ID_SIZE];
+
+pub fn new_session_id() -> Result<SessionId, Error> {
+    let mut id = [0; SESSION_ID_SIZE];
+    getrandom::fill(&mut id).context("Не удалось получить случайное число")?;
+    Ok(id)
+}
+
+/**
+    Счетчики и неподтвержденные пакеты одной стороны.
+    Переживает транспорт: после переподключения новый транспорт разделяется с той же сессией
+*/
+pub struct Session {
+    id: SessionId,
+    options: FrameOptions,
+    //отправленные, но еще не подтвержденные пакеты данных, первый из них под номером acked
+    unacked: VecDeque<Vec<u8>>,
+    unacked_bytes: usize,
+    acked: u64,
+    received: u64,
+    //сколько полученных пакетов уже подтвердили
+    confirmed: u64,
+    received_at: Option<Instant>,
+    //неподтвержденные пакеты пришлось выбросить
+    overflow: bool,
+}
+
+pub type SharedSession = Arc<Mutex<Session>>;
+
+impl Session {
+    /**
+        options - формат пакетов, о котором договорились в рукопожатии
+    */
+    pub fn new(id: SessionId, options: FrameOptions) -> Session {
+        Self {
+            id,
+            options,
+            unacked: VecDeque::new(),
+            unacked_bytes: 0,
+            acked: 0,
+            received: 0,
+            confirmed: 0,
+            received_at: None,
+            overflow: false,
+        }
+    }
+
+    pub fn shared(self) -> SharedSession {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    pub fn options(&self) -> FrameOptions {
+        self.options
+    }
+
+    /**
+        Сколько пакетов данных получено от другой стороны
+    */
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /**
+        Сколько отправленных пакетов данных еще не подтверждено
+    */
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    pub(crate) fn data_sent(&mut self, buf: &[u8]) {
+        if self.overflow {
+            return;
+        }
+        if self.unacked_bytes + buf.len() > MAX_UNACKED_BYTES {
+            warn!("Подтверждения не приходят, сессию после обрыва не восстановить");
+            self.overflow = true;
+            self.unacked.clear();
+            self.unacked_bytes = 0;
+            return;
+        }
+        self.unacked_bytes += buf.len();
+        self.unacked.push_back(buf.to_vec());
+    }
+
+    pub(crate) fn data_received(&mut self) {
+        self.received += 1;
+        self.received_at.get_or_insert_with(Instant::now);
+    }
+
+    /**
+        Другая сторона получила received пакетов данных (TYPE_ACK или восстановление сессии)
+    */
+    pub(crate) fn acknowledge(&mut self, received: u64) -> Result<(), Error> {
+        if self.overflow {
+            return Ok(());
+        }
+        let sent = self.acked + self.unacked.len() as u64;
+        ensure!(received <= sent, "Подтверждено {} пакетов, отправлено {}", received, sent);
+        while self.acked < received {
+            if let Some(packet) = self.unacked.pop_front() {
+                self.unacked_bytes -= packet.len();
+            }
+            self.acked += 1;
+        }
+        Ok(())
+    }
+
+    /**
+        Пора подтвердить полученные пакеты - сколько всего получено
+    */
+    pub(crate) fn ack_due(&self) -> Option<u64> {
+        let pending = self.received - self.confirmed;
+        let late = self.received_at.is_some_and(|received_at| received_at.elapsed() >= ACK_INTERVAL);
+        (pending >= ACK_EVERY || (pending > 0 && late)).then_some(self.received)
+    }
+
+    pub(crate) fn ack_sent(&mut self, received: u64) {
+        self.confirmed = received;
+        self.received_at = None;
+    }
+
+    /**
+        Новый транспорт: другая сторона получила peer_received пакетов,
+        остальные отправляем повторно (счетчик отправленных не меняется)
+    */
+    pub fn retransmit<T: Write + ?Sized>(&mut self, peer_received: u64, stream: &mut T) -> Result<(), Error> {
+        ensure!(!self.overflow, "Неподтвержденные пакеты выброшены, сессию не восстановить");
+        ensure!(peer_received >= self.acked, "Пакеты с {} уже выброшены, получено {}", peer_received, self.acked);
+        self.acknowledge(peer_received)?;
+        for packet in self.unacked.iter() {
+            write_packet_with(packet, TYPE_DATA, self.options, stream).context("Retransmit data packet")?;
+        }
+        //наше подтверждение могло потеряться, но о полученном другая сторона уже знает
+        self.ack_sent(self.received);
+        Ok(())
+    }
+}
+
+pub(crate) fn lock_session(session: &Mutex<Session>) -> MutexGuard<'_, Session> {
+    session.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub(crate) fn encode_counter(counter: u64) -> [u8; COUNTER_SIZE] {
+    counter.to_le_bytes()
+}
+
+pub(crate) fn decode_counter(body: &[u8]) -> Result<u64, Error> {
+    ensure!(body.len() == COUNTER_SIZE, "Недопустимый размер счетчика пакетов {}", body.len());
+    let mut counter = [0; COUNTER_SIZE];
+    counter.copy_from_slice(body);
+    Ok(u64::from_le_bytes(counter))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::{FrameDecoder, TYPE_DATA};
+    use crate::session::{new_session_id, Session, ACK_EVERY};
+    use crate::FrameOptions;
+
+    #[test]
+    fn retransmit_test() {
+        let mut session = Session::new(new_session_id().unwrap(), FrameOptions::default());
+        for packet in [b"1111", b"2222", b"3333"] {
+            session.data_sent(packet);
+        }
+        session.acknowledge(1).unwrap();
+        assert_eq!(2, session.unacked());
+        assert!(session.acknowledge(4).is_err());
+
+        let mut stream = vec![];
+        session.retransmit(2, &mut stream).unwrap();
+        assert_eq!(1, session.unacked());
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; 10];
+        let packet_info = decoder.read_packet(&mut buf, &mut stream.as_slice()).unwrap().unwrap();
+        assert_eq!(TYPE_DATA, packet_info.packet_type);
+        assert_eq!(b"3333", &buf[..packet_info.packet_size]);
+        //подтвержденное уже не повторить
+        assert!(session.retransmit(1, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn ack_due_test() {
+        let mut session = Session::new(new_session_id().unwrap(), FrameOptions::default());
+        assert_eq!(None, session.ack_due());
+        session.data_received();
+        assert_eq!(None, session.ack_due());
+        for _ in 1..ACK_EVERY {
+            session.data_received();
+        }
+        assert_eq!(Some(ACK_EVERY), session.ack_due());
+        session.ack_sent(ACK_EVERY);
+        assert_eq!(None, session.ack_due());
+    }
+}
@@ -17,17 +17,24 @@ pub mod test_init {
 
 #[cfg(test)]
 mod tests {
-    use crate::client_side_split::split_client_stream;
-    use crate::packet::{create_packet_header, TYPE_DATA};
-    use crate::server_side_split::split_server_stream;
+    use crate::client_side_split::{split_client_stream, split_client_stream_resumable, split_client_stream_with, squash};
+    use crate::auth::*;
+    use crate::handshake::*;
+    use crate::packet::{create_packet_header, write_packet_with, TYPE_DATA};
+    use crate::server_side_split::{split_server_stream, split_server_stream_resumable, split_server_stream_with};
+    use crate::session::{new_session_id, Session};
     use crate::tests::test_init::initialize_logger;
+    use crate::transport::{memory_pipe, Transport};
+    use crate::udp::{UdpClientAdapter, UdpDataStream};
+    use crate::DataStream;
     use log::{info, trace};
     use std::io::{ErrorKind, Read, Write};
-    use std::net::{TcpListener, TcpStream};
+    use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
+    use std::os::unix::net::UnixStream;
     use std::thread;
     use std::thread::sleep;
-    use std::time::Duration;
-    use crate::{MAX_BODY_SIZE, READ_START_AWAIT_TIMEOUT};
+    use std::time::{Duration, Instant};
+    use crate::{FrameDecoder, FrameOptions, MAX_BODY_SIZE, READ_START_AWAIT_TIMEOUT};
 
     /**
     Сервер после подключения к нему шлет
@@ -144,8 +151,13 @@ mod tests {
         let client_stream = TcpStream::connect(format!("127.0.0.1:{}", 11112)).unwrap();
         let split = split_client_stream(client_stream);
         let mut buf = [0; 1000];
-        let _ = split.data_stream.read(&mut buf).expect("read data");
+        //пакет собирается по частям за несколько вызовов read
+        let mut size = 0;
+        while size == 0 {
+            size = split.data_stream.read(&mut buf).expect("read data");
+        }
         join_handle.join().unwrap();
+        assert_eq!(1000, size);
         assert_eq!(1u8, buf[900]);
     }
 
@@ -253,4 +265,882 @@ mod tests {
         split.data_stream.write_all(&buf).unwrap();
     }
 
+    #[test]
+    fn hello_encode_decode_test() {
+        let hello = Hello::new("router-1", CAPABILITIES_NONE);
+        let decoded = Hello::decode(&hello.encode()).unwrap();
+        assert_eq!(hello, decoded);
+
+        let ack = HelloAck::accept(&decoded, SUPPORTED_CAPABILITIES).unwrap();
+        assert_eq!(ack, HelloAck::decode(&ack.encode()).unwrap());
+        assert_eq!(PROTOCOL_VERSION, ack.version);
+
+        //пустое имя недопустимо
+        assert!(Hello::decode(&Hello::new("", 0).encode()).is_err());
+    }
+
+    /**
+        Клиент представляется, сервер читает приветствие и отвечает подтверждением
+    */
+    #[test]
+    fn handshake_test() {
+        initialize_logger();
+        let client_listener =
+            TcpListener::bind(format!("127.0.0.1:{}", 51116)).expect("bind to client port");
+        let join_handle = thread::spawn(move || {
+            let mut stream = client_listener.accept().expect("client connected").0;
+            stream.set_read_timeout(Some(READ_START_AWAIT_TIMEOUT)).unwrap();
+            let mut decoder = FrameDecoder::new();
+            let identification = loop {
+                if let Some(identification) = read_identification(&mut stream, &mut decoder).unwrap() {
+                    break identification;
+                }
+            };
+            if let Identification::Hello(hello) = &identification {
+                let ack = HelloAck::accept(hello, SUPPORTED_CAPABILITIES).unwrap();
+                send_hello_ack(&mut stream, &ack).unwrap();
+            }
+            let mut split = split_server_stream(stream);
+            split.data_stream.write_all(b"11111").expect("write data");
+            identification
+        });
+
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", 51116)).unwrap();
+        let ack = client_handshake(&mut client_stream, &Hello::new("router-1", CAPABILITIES_NONE),
+                                   None, Duration::from_secs(1)).unwrap();
+        assert_eq!(CAPABILITIES_NONE, ack.capabilities);
+        let split = split_client_stream(client_stream);
+        let mut buf = [0; 5];
+        split.data_stream.read(&mut buf).expect("read data");
+        assert_eq!(b"11111", &buf[..5]);
+
+        let identification = join_handle.join().unwrap();
+        assert_eq!("router-1", identification.client_name());
+    }
+
+    /**
+        Сервер отказывает клиенту (нет маршрута), клиент получает причину
+    */
+    #[test]
+    fn handshake_error_test() {
+        initialize_logger();
+        let client_listener =
+            TcpListener::bind(format!("127.0.0.1:{}", 51121)).expect("bind to client port");
+        let join_handle = thread::spawn(move || {
+            let mut stream = client_listener.accept().expect("client connected").0;
+            stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let Some(Identification::Hello(hello)) = wait_identification(&mut stream, &mut FrameDecoder::new()) else {
+                panic!("Ожидалось приветствие");
+            };
+            send_error(&mut stream, &format!("Нет маршрута для клиента {}", hello.client_name)).unwrap();
+        });
+
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", 51121)).unwrap();
+        let error = client_handshake(&mut client_stream, &Hello::new("router-1", CAPABILITIES_NONE),
+                                     None, Duration::from_secs(1)).unwrap_err();
+        assert!(error.ctx.contains("Нет маршрута для клиента router-1"), "{}", error.ctx);
+        join_handle.join().unwrap();
+    }
+
+    /**
+        Сервер закрыл соединение не ответив - клиент не ждет таймаута
+    */
+    #[test]
+    fn handshake_closed_test() {
+        initialize_logger();
+        let (mut client_socket, server_socket) = UnixStream::pair().unwrap();
+        server_socket.shutdown(Shutdown::Write).unwrap();
+        let start = Instant::now();
+        let error = client_handshake(&mut client_socket, &Hello::new("router-1", CAPABILITIES_NONE),
+                                     None, Duration::from_secs(5)).unwrap_err();
+        assert!(error.ctx.contains("закрыл соединение"), "{}", error.ctx);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn auth_response_test() {
+        let challenge = new_challenge().unwrap();
+        let response = compute_response(b"secret", &challenge, "router-1");
+        assert!(verify_response(b"secret", &challenge, "router-1", &response));
+        assert!(!verify_response(b"other", &challenge, "router-1", &response));
+        assert!(!verify_response(b"secret", &challenge, "router-2", &response));
+        assert_ne!(challenge, new_challenge().unwrap());
+
+        let session = new_session_id().unwrap();
+        let response = compute_resume_response(b"secret", &challenge, &session);
+        assert!(verify_resume_response(b"secret", &challenge, &session, &response));
+        assert!(!verify_resume_response(b"other", &challenge, &session, &response));
+        assert!(!verify_resume_response(b"secret", &challenge, &new_session_id().unwrap(), &response));
+    }
+
+    /**
+        Сервер запрашивает подтверждение общего секрета перед подтверждением приветствия
+    */
+    #[test]
+    fn handshake_with_auth_test() {
+        initialize_logger();
+        let client_listener =
+            TcpListener::bind(format!("127.0.0.1:{}", 51118)).expect("bind to client port");
+        let join_handle = thread::spawn(move || {
+            let mut stream = client_listener.accept().expect("client connected").0;
+            stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let mut decoder = FrameDecoder::new();
+            let Some(Identification::Hello(hello)) = wait_identification(&mut stream, &mut decoder) else {
+                panic!("Ожидалось приветствие");
+            };
+            let challenge = new_challenge().unwrap();
+            send_challenge(&mut stream, &challenge).unwrap();
+            let response = loop {
+                if let Some(response) = read_auth_response(&mut stream, &mut decoder).unwrap() {
+                    break response;
+                }
+            };
+            let verified = verify_response(b"secret", &challenge, &hello.client_name, &response);
+            let ack = HelloAck::accept(&hello, SUPPORTED_CAPABILITIES).unwrap();
+            send_hello_ack(&mut stream, &ack).unwrap();
+            verified
+        });
+
+        let mut client_stream = TcpStream::connect(format!("127.0.0.1:{}", 51118)).unwrap();
+        client_handshake(&mut client_stream, &Hello::new("router-1", CAPABILITIES_NONE),
+                         Some(b"secret"), Duration::from_secs(1)).unwrap();
+        assert!(join_handle.join().unwrap());
+    }
+
+    /**
+        Клиент старого образца шлет имя в пакете заполнителя
+    */
+    #[test]
+    fn legacy_identification_test() {
+        initialize_logger();
+        let client_listener =
+            TcpListener::bind(format!("127.0.0.1:{}", 51117)).expect("bind to client port");
+        let join_handle = thread::spawn(move || {
+            let mut stream = client_listener.accept().expect("client connected").0;
+            stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            wait_identification(&mut stream, &mut FrameDecoder::new())
+        });
+
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", 51117)).unwrap();
+        let split = split_client_stream(client_stream);
+        split.filler_stream.write_all(b"\x01router-2").unwrap();
+
+        let identification = join_handle.join().unwrap();
+        assert_eq!(Some(Identification::Legacy("router-2".to_string())), identification);
+    }
+
+    /**
+        Клиент без имени сразу шлет данные - они не должны потеряться
+    */
+    #[test]
+    fn anonymous_identification_test() {
+        initialize_logger();
+        let client_listener =
+            TcpListener::bind(format!("127.0.0.1:{}", 51119)).expect("bind to client port");
+        let join_handle = thread::spawn(move || {
+            let mut stream = client_listener.accept().expect("client connected").0;
+            stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let identification = wait_identification(&mut stream, &mut FrameDecoder::new());
+            let mut split = split_server_stream(stream);
+            let mut buf = [0; MAX_BODY_SIZE];
+            let size = split.data_stream.read(&mut buf).unwrap();
+            (identification, buf[..size].to_vec())
+        });
+
+        let client_stream = TcpStream::connect(format!("127.0.0.1:{}", 51119)).unwrap();
+        let split = split_client_stream(client_stream);
+        split.data_stream.write_all(b"11111").unwrap();
+
+        let (identification, data) = join_handle.join().unwrap();
+        assert_eq!(Some(Identification::Anonymous), identification);
+        assert_eq!(b"11111", &data[..]);
+    }
+
+    /**
+        Приветствие может прийти по частям - ждем целиком, но не дольше секунды
+    */
+    fn wait_identification(stream: &mut TcpStream, decoder: &mut FrameDecoder) -> Option<Identification> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            if let Some(identification) = read_identification(stream, decoder).unwrap() {
+                return Some(identification);
+            }
+        }
+        None
+    }
+
+    /**
+        Разделение потока без настоящих портов
+    */
+    #[test]
+    fn memory_pipe_test() {
+        initialize_logger();
+        let (client_pipe, server_pipe) = memory_pipe();
+        let mut server_split = split_server_stream(server_pipe);
+        server_split.data_stream.write_all(b"11111").unwrap();
+        server_split.filler_stream.write_all(b"22222").unwrap();
+        server_split.data_stream.write_all(b"44444").unwrap();
+
+        let client_split = split_client_stream(client_pipe);
+        let mut buf = [0; MAX_BODY_SIZE];
+        //пакет данных откладывается для data_stream
+        assert_eq!(0, client_split.filler_stream.read(&mut buf).unwrap());
+        assert_eq!(5, client_split.filler_stream.read(&mut buf).unwrap());
+        assert_eq!(b"22222", &buf[..5]);
+        assert_eq!(5, client_split.data_stream.read(&mut buf).unwrap());
+        assert_eq!(b"11111", &buf[..5]);
+        assert_eq!(5, client_split.data_stream.read(&mut buf).unwrap());
+        assert_eq!(b"44444", &buf[..5]);
+        assert_eq!(0, client_split.data_stream.read(&mut buf).unwrap());
+
+        client_split.data_stream.write_all(b"33333").unwrap();
+        assert_eq!(5, server_split.data_stream.read(&mut buf).unwrap());
+        assert_eq!(b"33333", &buf[..5]);
+        //заполнитель клиента только учитывается
+        client_split.filler_stream.write_all(&[0; 100]).unwrap();
+        assert_eq!(0, server_split.data_stream.read(&mut buf).unwrap());
+        assert_eq!(100, server_split.data_stream.take_filler_received());
+        assert_eq!(0, server_split.data_stream.take_filler_received());
+
+        let client_pipe = squash(client_split);
+        client_pipe.shutdown();
+        assert!(server_split.data_stream.write_all(b"55555").is_err());
+    }
+
+    /**
+        Закрытие сервером отличается от отсутствия данных, отложенное дочитывается
+    */
+    #[test]
+    fn client_closed_test() {
+        initialize_logger();
+        let (client_pipe, server_pipe) = memory_pipe();
+        let mut server_split = split_server_stream(server_pipe);
+        let client_split = split_client_stream(client_pipe);
+        let mut buf = [0; MAX_BODY_SIZE];
+        assert_eq!(0, client_split.data_stream.read(&mut buf).unwrap());
+        assert!(!client_split.is_closed());
+        server_split.data_stream.write_all(b"11111").unwrap();
+        server_split.filler_stream.write_all(b"22222").unwrap();
+        server_split.data_stream.shutdown();
+        assert_eq!(0, client_split.filler_stream.read(&mut buf).unwrap());
+        assert_eq!(5, client_split.filler_stream.read(&mut buf).unwrap());
+        assert_eq!(0, client_split.filler_stream.read(&mut buf).unwrap());
+        assert!(client_split.is_closed());
+        assert_eq!(5, client_split.data_stream.read(&mut buf).unwrap());
+        assert_eq!(b"11111", &buf[..5]);
+    }
+
+    /**
+        Пакет приходит по байту - декодер не ждет и не рвет соединение
+    */
+    #[test]
+    fn frame_decoder_partial_test() {
+        initialize_logger();
+        let (mut client_pipe, mut server_pipe) = memory_pipe();
+        let mut packet = create_packet_header(TYPE_DATA, 5).to_vec();
+        packet.extend_from_slice(b"11111");
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; MAX_BODY_SIZE];
+        let start = Instant::now();
+        for byte in packet.iter() {
+            assert!(decoder.read_packet(&mut buf, &mut server_pipe).unwrap().is_none());
+            client_pipe.write_all(&[*byte]).unwrap();
+        }
+        assert!(!decoder.is_empty());
+        let packet_info = decoder.read_packet(&mut buf, &mut server_pipe).unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(TYPE_DATA, packet_info.packet_type);
+        assert_eq!(b"11111", &buf[..packet_info.packet_size]);
+        assert!(decoder.is_empty());
+
+        //мусор обнаруживается как только получен заголовок
+        client_pipe.write_all(&[0x00, TYPE_DATA, 5, 0]).unwrap();
+        assert!(decoder.read_packet(&mut buf, &mut server_pipe).is_err());
+        let mut decoder = FrameDecoder::new();
+        client_pipe.write_all(&create_packet_header(TYPE_DATA, 0)).unwrap();
+        assert!(decoder.read_packet(&mut buf, &mut server_pipe).is_err());
+    }
+
+    /**
+        Между частями пакета проходит больше прежнего лимита ожидания (1000 * 5мс)
+    */
+    #[test]
+    fn slow_link_test() {
+        initialize_logger();
+        let (mut client_pipe, server_pipe) = memory_pipe();
+        let mut split = split_server_stream(server_pipe);
+        let mut buf = [0; MAX_BODY_SIZE];
+        client_pipe.write_all(&create_packet_header(TYPE_DATA, 5)).unwrap();
+        client_pipe.write_all(b"11").unwrap();
+        let start = Instant::now();
+        assert_eq!(0, split.data_stream.read(&mut buf).unwrap());
+        assert!(start.elapsed() < Duration::from_millis(50));
+        sleep(Duration::from_millis(5100));
+        assert_eq!(0, split.data_stream.read(&mut buf).unwrap());
+        client_pipe.write_all(b"111").unwrap();
+        assert_eq!(5, split.data_stream.read(&mut buf).unwrap());
+        assert_eq!(b"11111", &buf[..5]);
+    }
+
+    #[test]
+    fn crc32_negotiation_test() {
+        let hello = Hello::new("router-1", CAPABILITY_CRC32);
+        let ack = HelloAck::accept(&hello, SUPPORTED_CAPABILITIES).unwrap();
+        assert_eq!(FrameOptions { checksum: true, resync: true }, ack.frame_options());
+        //старый клиент о CRC32 не знает
+        let ack = HelloAck::accept(&Hello::new("router-1", CAPABILITIES_NONE), SUPPORTED_CAPABILITIES).unwrap();
+        assert_eq!(FrameOptions::default(), ack.frame_options());
+    }
+
+    /**
+        Мусор и испорченный пакет пропускаются, соединение не рвется
+    */
+    #[test]
+    fn crc32_resync_test() {
+        initialize_logger();
+        let options = FrameOptions { checksum: true, resync: true };
+        let (client_pipe, server_pipe) = memory_pipe();
+        let mut server_split = split_server_stream_with(server_pipe, options);
+        let client_split = split_client_stream_with(client_pipe, options);
+        client_split.data_stream.write_all(b"11111").unwrap();
+        let mut client_pipe = squash(client_split);
+        //мусор, похожий на начало заголовка
+        client_pipe.write_all(&[0x54, 0x54, 0x00, 0xFF]).unwrap();
+        let mut corrupted = vec![];
+        write_packet_with(b"22222", TYPE_DATA, options, &mut corrupted).unwrap();
+        corrupted[6] ^= 0x01;
+        client_pipe.write_all(&corrupted).unwrap();
+        let client_split = split_client_stream_with(client_pipe, options);
+        client_split.data_stream.write_all(b"33333").unwrap();
+
+        let mut buf = [0; MAX_BODY_SIZE];
+        assert_eq!(5, server_split.data_stream.read(&mut buf).unwrap());
+        assert_eq!(b"11111", &buf[..5]);
+        assert_eq!(5, server_split.data_stream.read(&mut buf).unwrap());
+        assert_eq!(b"33333", &buf[..5]);
+
+        //без resync испорченный пакет - ошибка
+        let (mut client_pipe, mut server_pipe) = memory_pipe();
+        client_pipe.write_all(&corrupted).unwrap();
+        let mut decoder = FrameDecoder::with_options(FrameOptions { checksum: true, resync: false });
+        assert!(decoder.read_packet(&mut buf, &mut server_pipe).is_err());
+    }
+
+    #[test]
+    fn frame_decoder_dropped_bytes_test() {
+        initialize_logger();
+        let options = FrameOptions { checksum: true, resync: true };
+        let (mut client_pipe, mut server_pipe) = memory_pipe();
+        client_pipe.write_all(&[0x00, 0x01, 0x02]).unwrap();
+        write_packet_with(b"11111", TYPE_DATA, options, &mut client_pipe).unwrap();
+        let mut decoder = FrameDecoder::with_options(options);
+        let mut buf = [0; MAX_BODY_SIZE];
+        let packet_info = decoder.read_packet(&mut buf, &mut server_pipe).unwrap().unwrap();
+        assert_eq!(b"11111", &buf[..packet_info.packet_size]);
+        assert_eq!(3, decoder.dropped_bytes());
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn unix_socket_test() {
+        initialize_logger();
+        let (client_socket, server_socket) = UnixStream::pair().unwrap();
+        let join_handle = thread::spawn(move || {
+            let mut split = split_server_stream(server_socket);
+            assert!(split.data_stream.raw_fd().is_some());
+            let mut buf = [0; MAX_BODY_SIZE];
+            let mut size = 0;
+            while size == 0 {
+                size = split.data_stream.read(&mut buf).unwrap();
+            }
+            split.data_stream.write_all(&buf[..size]).unwrap();
+        });
+        let client_split = split_client_stream(client_socket);
+        client_split.data_stream.write_all(b"11111").unwrap();
+        join_handle.join().unwrap();
+        let mut buf = [0; MAX_BODY_SIZE];
+        let mut size = 0;
+        while size == 0 {
+            size = client_split.data_stream.read(&mut buf).unwrap();
+        }
+        assert_eq!(b"11111", &buf[..size]);
+    }
+
+    /**
+        Рукопожатие через Unix сокет (например за stunnel): сервер заглядывает в приветствие
+    */
+    #[test]
+    fn unix_socket_handshake_test() {
+        initialize_logger();
+        let (mut client_socket, mut server_socket) = UnixStream::pair().unwrap();
+        server_socket.set_nonblocking(true).unwrap();
+        let join_handle = thread::spawn(move || {
+            client_handshake(&mut client_socket, &Hello::new("router-1", CAPABILITY_CRC32),
+                             None, Duration::from_secs(1)).unwrap()
+        });
+        let mut decoder = FrameDecoder::new();
+        let start = Instant::now();
+        let identification = loop {
+            assert!(start.elapsed() < Duration::from_secs(1), "Приветствие не получено");
+            if let Some(identification) = read_identification(&mut server_socket, &mut decoder).unwrap() {
+                break identification;
+            }
+        };
+        let Identification::Hello(hello) = identification else {
+            panic!("Ожидалось приветствие");
+        };
+        assert_eq!("router-1", hello.client_name);
+        send_hello_ack(&mut server_socket, &HelloAck::accept(&hello, SUPPORTED_CAPABILITIES).unwrap()).unwrap();
+        assert_eq!(CAPABILITY_CRC32, join_handle.join().unwrap().capabilities);
+    }
+
+    /**
+        Клиент не читает - запись не ждет: что не влезло в буфер сокета, копится
+        и дописывается, когда клиент начнет читать
+    */
+    #[test]
+    fn pending_writes_test() {
+        initialize_logger();
+        let (mut client_socket, server_socket) = UnixStream::pair().unwrap();
+        let mut split = split_server_stream(server_socket);
+        //заведомо больше буфера сокета
+        const PACKETS: usize = 1000;
+        for _ in 0..PACKETS {
+            split.filler_stream.write_all(&[0; MAX_BODY_SIZE]).unwrap();
+        }
+        split.data_stream.write_all(b"11111").unwrap();
+        assert!(split.data_stream.has_pending_writes());
+
+        let reader = thread::spawn(move || {
+            let mut decoder = FrameDecoder::new();
+            let mut buf = [0; MAX_BODY_SIZE];
+            let mut fillers = 0;
+            loop {
+                match decoder.read_packet(&mut buf, &mut client_socket).unwrap() {
+                    Some(packet_info) if packet_info.packet_type == TYPE_DATA => break,
+                    Some(_) => fillers += 1,
+                    None => {}
+                }
+            }
+            assert_eq!(b"11111", &buf[..5]);
+            fillers
+        });
+        while split.data_stream.has_pending_writes() {
+            sleep(Duration::from_millis(1));
+            split.data_stream.flush().unwrap();
+        }
+        assert_eq!(PACKETS, reader.join().unwrap());
+        split.data_stream.shutdown();
+    }
+
+    /**
+        Клиент не успевает читать - время, пока записанное ждало отправки, учитывается
+    */
+    #[test]
+    fn write_blocked_test() {
+        initialize_logger();
+        let (mut client_socket, server_socket) = UnixStream::pair().unwrap();
+        let mut split = split_server_stream(server_socket);
+        split.data_stream.write_all(b"11111").unwrap();
+        assert_eq!(Duration::ZERO, split.data_stream.take_write_blocked());
+
+        let reader = thread::spawn(move || {
+            sleep(Duration::from_millis(200));
+            let mut buf = [0; MAX_BODY_SIZE];
+            while client_socket.read(&mut buf).unwrap() > 0 {}
+        });
+        //заведомо больше буфера сокета, новое пишем только когда прежнее ушло
+        let mut written = 0;
+        while written < 1000 || split.data_stream.has_pending_writes() {
+            if split.data_stream.has_pending_writes() {
+                sleep(Duration::from_millis(1));
+                split.data_stream.flush().unwrap();
+            } else {
+                split.filler_stream.write_all(&[0; MAX_BODY_SIZE]).unwrap();
+                written += 1;
+            }
+        }
+        let blocked = split.data_stream.take_write_blocked();
+        assert!(blocked >= Duration::from_millis(100), "{blocked:?}");
+        assert_eq!(Duration::ZERO, split.data_stream.take_write_blocked());
+        split.data_stream.shutdown();
+        reader.join().unwrap();
+    }
+
+    /**
+        Ожидание заканчивается событием готовности сокета к записи (а не моментом, когда дописали),
+        незаконченное учитывается по момент запроса
+    */
+    #[test]
+    fn write_blocked_writable_test() {
+        initialize_logger();
+        let (_client_socket, server_socket) = UnixStream::pair().unwrap();
+        let mut split = split_server_stream(server_socket);
+        while !split.data_stream.has_pending_writes() {
+            split.filler_stream.write_all(&[0; MAX_BODY_SIZE]).unwrap();
+        }
+        sleep(Duration::from_millis(100));
+        let blocked = split.data_stream.take_write_blocked();
+        assert!(blocked >= Duration::from_millis(100), "{blocked:?}");
+
+        sleep(Duration::from_millis(100));
+        let writable_at = Instant::now();
+        sleep(Duration::from_millis(100));
+        split.data_stream.writable(writable_at);
+        let blocked = split.data_stream.take_write_blocked();
+        assert!(blocked >= Duration::from_millis(100) && blocked < Duration::from_millis(200), "{blocked:?}");
+        assert_eq!(Duration::ZERO, split.data_stream.take_write_blocked());
+    }
+
+    /**
+        Датаграммы VPN клиента доходят до VPN сервера (и обратно) целиком и по одной,
+        заполнитель клиенту не мешает
+    */
+    #[test]
+    fn udp_datagram_test() {
+        initialize_logger();
+        let vpn_server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        vpn_server.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut up_stream = UdpDataStream::connect(&vpn_server.local_addr().unwrap().to_string()).unwrap();
+        assert!(up_stream.raw_fd().is_some());
+        let (client_pipe, server_pipe) = memory_pipe();
+        let mut server_split = split_server_stream(server_pipe);
+        let client_split = split_client_stream(client_pipe);
+        let mut adapter = UdpClientAdapter::bind("127.0.0.1:0").unwrap();
+        let vpn_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        vpn_client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        vpn_client.connect(adapter.local_addr().unwrap()).unwrap();
+
+        let big = [0x22; 1400];
+        vpn_client.send(b"1").unwrap();
+        vpn_client.send(&big).unwrap();
+        let mut buf = [0; MAX_BODY_SIZE];
+        let mut forwarded = 0;
+        let start = Instant::now();
+        while forwarded < 2 && start.elapsed() < Duration::from_secs(1) {
+            adapter.step(&client_split).unwrap();
+            let size = server_split.data_stream.read(&mut buf).unwrap();
+            if size > 0 {
+                up_stream.write_all(&buf[..size]).unwrap();
+                forwarded += 1;
+            }
+        }
+        let (size, equalizer) = vpn_server.recv_from(&mut buf).unwrap();
+        assert_eq!(b"1", &buf[..size]);
+        let size = vpn_server.recv(&mut buf).unwrap();
+        assert_eq!(&big[..], &buf[..size]);
+        assert_eq!(1 + big.len(), adapter.take_sent());
+
+        vpn_server.send_to(&big[..1000], equalizer).unwrap();
+        vpn_server.send_to(b"22", equalizer).unwrap();
+        server_split.filler_stream.write_all(b"33333").unwrap();
+        vpn_client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let mut received = vec![];
+        let start = Instant::now();
+        while received.len() < 2 && start.elapsed() < Duration::from_secs(1) {
+            let size = up_stream.read(&mut buf).unwrap();
+            if size > 0 {
+                server_split.data_stream.write_all(&buf[..size]).unwrap();
+            }
+            adapter.step(&client_split).unwrap();
+            if let Ok(size) = vpn_client.recv(&mut buf) {
+                received.push(buf[..size].to_vec());
+            }
+        }
+        assert_eq!(vec![big[..1000].to_vec(), b"22".to_vec()], received);
+    }
+
+    fn read_data(mut stream: impl FnMut(&mut [u8]) -> usize) -> Option<Vec<u8>> {
+        let mut buf = [0; MAX_BODY_SIZE];
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(300) {
+            let size = stream(&mut buf);
+            if size > 0 {
+                return Some(buf[..size].to_vec());
+            }
+        }
+        None
+    }
+
+    /**
+        Соединение оборвалось: часть пакетов не дошла в обе стороны.
+        Клиент переподключается с номером сессии, недошедшее повторяется без дублей
+    */
+    #[test]
+    fn session_resume_test() {
+        initialize_logger();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let id = new_session_id().unwrap();
+        let server_session = Session::new(id, FrameOptions::default()).shared();
+        let client_session = Session::new(id, FrameOptions::default()).shared();
+
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server_split = split_server_stream_resumable(listener.accept().unwrap().0, server_session.clone());
+        let client_split = split_client_stream_resumable(client, client_session.clone());
+        for body in [b"1", b"2", b"3"] {
+            client_split.data_stream.write_all(body).unwrap();
+        }
+        server_split.data_stream.write_all(b"a").unwrap();
+        server_split.data_stream.write_all(b"b").unwrap();
+        assert_eq!(Some(b"1".to_vec()), read_data(|buf| server_split.data_stream.read(buf).unwrap()));
+        assert_eq!(Some(b"a".to_vec()), read_data(|buf| client_split.data_stream.read(buf).unwrap()));
+        //"2", "3" и "b" остались в оборванном соединении
+        server_split.data_stream.shutdown();
+        drop(server_split);
+        drop(client_split);
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server = listener.accept().unwrap().0;
+        let server_join = thread::spawn(move || {
+            server.set_nonblocking(true).unwrap();
+            let Some(Identification::Resume(resumed, received)) = wait_identification(&mut server, &mut FrameDecoder::new()) else {
+                panic!("Ожидалось восстановление сессии");
+            };
+            assert_eq!(id, resumed);
+            assert_eq!(1, received);
+            accept_resume(&mut server, &mut server_session.lock().unwrap(), received).unwrap();
+            let mut server_split = split_server_stream_resumable(server, server_session);
+            let mut received = vec![];
+            while let Some(data) = read_data(|buf| server_split.data_stream.read(buf).unwrap()) {
+                received.push(data);
+            }
+            received
+        });
+        client_resume(&mut client, &client_session, None, Duration::from_secs(1)).unwrap();
+        let client_split = split_client_stream_resumable(client, client_session.clone());
+        assert_eq!(Some(b"b".to_vec()), read_data(|buf| client_split.data_stream.read(buf).unwrap()));
+        assert_eq!(None, read_data(|buf| client_split.data_stream.read(buf).unwrap()));
+        assert_eq!(vec![b"2".to_vec(), b"3".to_vec()], server_join.join().unwrap());
+        //все полученное клиентом подтверждено
+        assert_eq!(2, client_session.lock().unwrap().received());
+    }
+
+    #[test]
+    fn resume_unknown_session_test() {
+        initialize_logger();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server = listener.accept().unwrap().0;
+        let session = Session::new(new_session_id().unwrap(), FrameOptions::default()).shared();
+        server.set_nonblocking(true).unwrap();
+        let server_join = thread::spawn(move || {
+            let identification = wait_identification(&mut server, &mut FrameDecoder::new());
+            assert!(matches!(identification, Some(Identification::Resume(..))));
+            send_error(&mut server, "Сессия не найдена").unwrap();
+        });
+        let error = client_resume(&mut client, &session, None, Duration::from_secs(1)).unwrap_err();
+        assert!(error.to_string().contains("Сессия не найдена"), "{error}");
+        server_join.join().unwrap();
+    }
+}
+
+#[cfg(all(test, feature = "async"))]
+mod async_tests {
+    use crate::async_split::{split_client_stream_async, split_server_stream_async};
+    use crate::client_side_split::split_client_stream;
+    use crate::tests::test_init::initialize_logger;
+    use std::net::TcpStream;
+    use std::thread;
+    use tokio::io::duplex;
+    use tokio::net::TcpListener;
+
+    /**
+        Тот же порядок что и в all_received_test, но чтение каналов ждет своего пакета
+    */
+    #[tokio::test]
+    async fn async_all_received_test() {
+        initialize_logger();
+        let (client_stream, server_stream) = duplex(64 * 1024);
+        let server_split = split_server_stream_async(server_stream);
+        server_split.data_stream.write_all(b"11111").await.unwrap();
+        server_split.filler_stream.write_all(b"22222").await.unwrap();
+        server_split.filler_stream.write_all(b"33333").await.unwrap();
+        server_split.data_stream.write_all(b"44444").await.unwrap();
+
+        let client_split = split_client_stream_async(client_stream);
+        let mut buf = [0; 5];
+        client_split.filler_stream.read(&mut buf).await.unwrap();
+        assert_eq!(b"22222", &buf);
+        client_split.data_stream.read(&mut buf).await.unwrap();
+        assert_eq!(b"11111", &buf);
+        client_split.data_stream.read(&mut buf).await.unwrap();
+        assert_eq!(b"44444", &buf);
+        client_split.filler_stream.read(&mut buf).await.unwrap();
+        assert_eq!(b"33333", &buf);
+
+        server_split.data_stream.shutdown().await;
+        assert_eq!(0, client_split.data_stream.read(&mut buf).await.unwrap());
+    }
+
+    /**
+        Асинхронный сервер и синхронный клиент
+    */
+    #[tokio::test]
+    async fn async_sync_compatibility_test() {
+        initialize_logger();
+        let listener = TcpListener::bind("127.0.0.1:51120").await.unwrap();
+        let join_handle = thread::spawn(|| {
+            let client_stream = TcpStream::connect("127.0.0.1:51120").unwrap();
+            let split = split_client_stream(client_stream);
+            split.filler_stream.write_all(b"22222").unwrap();
+            split.data_stream.write_all(b"11111").unwrap();
+            let mut buf = [0; 5];
+            let mut size = 0;
+            while size == 0 {
+                size = split.data_stream.read(&mut buf).unwrap();
+            }
+            buf
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let server_split = split_server_stream_async(stream);
+        let mut buf = [0; 5];
+        //заполнитель от клиента серверу не нужен - пропускается
+        server_split.data_stream.read(&mut buf).await.unwrap();
+        assert_eq!(b"11111", &buf);
+        server_split.data_stream.write_all(b"44444").await.unwrap();
+
+        assert_eq!(b"44444", &join_handle.join().unwrap());
+    }
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod crypto_tests {
+    use crate::client_side_split::split_client_stream;
+    use crate::secure_transport::SecureTransport;
+    use crate::server_side_split::split_server_stream;
+    use crate::tests::test_init::initialize_logger;
+    use crate::packet::TYPE_DATA;
+    use crate::transport::{memory_pipe, MemoryPipe};
+    use crate::{FrameDecoder, MAX_BODY_SIZE};
+    use std::io::{ErrorKind, Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    const PSK: &[u8] = b"very-long-secret";
+    const SECRET_BODY: &[u8] = b"openvpn packet 1234567890";
+
+    //пересылаем все что есть из одного канала в другой, запоминая что видно снаружи
+    fn relay(from: &mut MemoryPipe, to: &mut MemoryPipe, wire: &mut Vec<u8>) {
+        let mut buf = [0; MAX_BODY_SIZE];
+        while let Ok(size) = from.read(&mut buf) {
+            if size == 0 {
+                break;
+            }
+            to.write_all(&buf[..size]).unwrap();
+            wire.extend_from_slice(&buf[..size]);
+        }
+    }
+
+    /**
+        Данные и заполнитель проходят, снаружи не видно ни тела, ни заголовков пакетов
+    */
+    #[test]
+    fn secure_transport_test() {
+        initialize_logger();
+        let (client_pipe, mut client_wire) = memory_pipe();
+        let (server_pipe, mut server_wire) = memory_pipe();
+        let server = SecureTransport::accept(server_pipe, Some(PSK)).unwrap();
+        let client = SecureTransport::connect(client_pipe, Some(PSK)).unwrap();
+        let mut wire = vec![];
+        relay(&mut server_wire, &mut client_wire, &mut wire);
+        let mut server_split = split_server_stream(server);
+        let client_split = split_client_stream(client);
+
+        client_split.data_stream.write_all(SECRET_BODY).unwrap();
+        client_split.filler_stream.write_all(&[0; 100]).unwrap();
+        relay(&mut client_wire, &mut server_wire, &mut wire);
+        let mut buf = [0; MAX_BODY_SIZE];
+        let size = server_split.data_stream.read(&mut buf).unwrap();
+        assert_eq!(SECRET_BODY, &buf[..size]);
+
+        server_split.filler_stream.write_all(&[0; 100]).unwrap();
+        server_split.data_stream.write_all(SECRET_BODY).unwrap();
+        relay(&mut server_wire, &mut client_wire, &mut wire);
+        assert_eq!(100, client_split.filler_stream.read(&mut buf).unwrap());
+        let size = client_split.data_stream.read(&mut buf).unwrap();
+        assert_eq!(SECRET_BODY, &buf[..size]);
+
+        assert!(!wire.windows(SECRET_BODY.len()).any(|window| window == SECRET_BODY));
+        assert!(!wire.windows(4).any(|window| window == [0x54, 0x56, 100, 0]));
+        assert!(!wire.windows(8).any(|window| window == [0; 8]));
+    }
+
+    /**
+        Клиент не читает - зашифрованные записи копятся и дописываются целыми
+    */
+    #[test]
+    fn secure_pending_writes_test() {
+        initialize_logger();
+        let (client_socket, server_socket) = UnixStream::pair().unwrap();
+        let server = SecureTransport::accept(server_socket, Some(PSK)).unwrap();
+        let mut client = SecureTransport::connect(client_socket, Some(PSK)).unwrap();
+        let mut server_split = split_server_stream(server);
+        const PACKETS: usize = 500;
+        for _ in 0..PACKETS {
+            server_split.filler_stream.write_all(&[0; MAX_BODY_SIZE]).unwrap();
+        }
+        server_split.data_stream.write_all(SECRET_BODY).unwrap();
+        assert!(server_split.data_stream.has_pending_writes());
+
+        let reader = thread::spawn(move || {
+            let mut decoder = FrameDecoder::new();
+            let mut buf = [0; MAX_BODY_SIZE];
+            let mut fillers = 0;
+            loop {
+                match decoder.read_packet(&mut buf, &mut client).unwrap() {
+                    Some(packet_info) if packet_info.packet_type == TYPE_DATA => {
+                        assert_eq!(SECRET_BODY, &buf[..packet_info.packet_size]);
+                        return fillers;
+                    }
+                    Some(_) => fillers += 1,
+                    None => {}
+                }
+            }
+        });
+        while server_split.data_stream.has_pending_writes() {
+            sleep(Duration::from_millis(1));
+            server_split.data_stream.flush().unwrap();
+        }
+        assert_eq!(PACKETS, reader.join().unwrap());
+    }
+
+    /**
+        Ключ клиента еще не пришел - запись не ждет его, а сразу возвращает WouldBlock
+    */
+    #[test]
+    fn secure_peer_key_would_block_test() {
+        initialize_logger();
+        let (client_socket, server_socket) = UnixStream::pair().unwrap();
+        server_socket.set_nonblocking(true).unwrap();
+        let mut server = SecureTransport::accept(server_socket, Some(PSK)).unwrap();
+        let start = Instant::now();
+        assert_eq!(ErrorKind::WouldBlock, server.write(SECRET_BODY).unwrap_err().kind());
+        assert!(start.elapsed() < Duration::from_millis(100), "{:?}", start.elapsed());
+
+        let mut client = SecureTransport::connect(client_socket, Some(PSK)).unwrap();
+        server.write_all(SECRET_BODY).unwrap();
+        server.flush().unwrap();
+        let mut buf = [0; MAX_BODY_SIZE];
+        let size = client.read(&mut buf).unwrap();
+        assert_eq!(SECRET_BODY, &buf[..size]);
+    }
+
+    #[test]
+    fn secure_transport_wrong_psk_test() {
+        initialize_logger();
+        let (client_pipe, mut client_wire) = memory_pipe();
+        let (server_pipe, mut server_wire) = memory_pipe();
+        let server = SecureTransport::accept(server_pipe, Some(PSK)).unwrap();
+        let client = SecureTransport::connect(client_pipe, Some(b"another-secret")).unwrap();
+        let mut wire = vec![];
+        relay(&mut server_wire, &mut client_wire, &mut wire);
+        let mut server_split = split_server_stream(server);
+        let client_split = split_client_stream(client);
+        client_split.data_stream.write_all(SECRET_BODY).unwrap();
+        relay(&mut client_wire, &mut server_wire, &mut wire);
+        let mut buf = [0; MAX_BODY_SIZE];
+        assert!(server_split.data_stream.read(&mut buf).is_err());
+    }
 }
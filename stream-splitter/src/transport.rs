@@ -0,0 +1,191 @@
+/*
+Транспорт, поверх которого работает разделение потока.
+Кроме TCP это может быть Unix сокет (например за stunnel), TLS поток
+или канал в памяти для тестов без настоящих портов.
+*/
+use std::collections::VecDeque;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub trait Transport: Read + Write + Send {
+    fn shutdown(&self);
+    //серверная сторона - чтение без блокировки, готовность ждем по raw_fd
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    //клиентская сторона - чтение с коротким таймаутом
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /**
+        Дескриптор для ожидания готовности (epoll).
+        None - транспорт приходится опрашивать по таймеру
+    */
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+    /**
+        Заглянуть в поток не вычитывая (определение клиента старого образца на сервере)
+    */
+    fn peek(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(ErrorKind::Unsupported.into())
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn shutdown(&self) {
+        (**self).shutdown()
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        (**self).set_nonblocking(nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).peek(buf)
+    }
+}
+
+impl Transport for TcpStream {
+    fn shutdown(&self) {
+        let _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        TcpStream::peek(self, buf)
+    }
+}
+
+impl Transport for UnixStream {
+    fn shutdown(&self) {
+        let _ = UnixStream::shutdown(self, Shutdown::Both);
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        //UnixStream::peek в std пока нестабилен
+        let peeked = unsafe { libc::recv(self.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), libc::MSG_PEEK) };
+        if peeked < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(peeked as usize)
+    }
+}
+
+/**
+    Один конец канала в памяти.
+    Чтение никогда не блокируется: нет данных - WouldBlock, закрыт - 0
+*/
+pub struct MemoryPipe {
+    incoming: Arc<PipeBuffer>,
+    outgoing: Arc<PipeBuffer>,
+}
+
+#[derive(Default)]
+struct PipeBuffer {
+    data: Mutex<VecDeque<u8>>,
+    closed: AtomicBool,
+}
+
+pub fn memory_pipe() -> (MemoryPipe, MemoryPipe) {
+    let first = Arc::new(PipeBuffer::default());
+    let second = Arc::new(PipeBuffer::default());
+    (
+        MemoryPipe { incoming: first.clone(), outgoing: second.clone() },
+        MemoryPipe { incoming: second, outgoing: first },
+    )
+}
+
+impl Read for MemoryPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut data = self.incoming.data.lock().unwrap();
+        if data.is_empty() {
+            if self.incoming.closed.load(Ordering::Relaxed) {
+                return Ok(0);
+            }
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let size = buf.len().min(data.len());
+        for (dst, src) in buf.iter_mut().zip(data.drain(..size)) {
+            *dst = src;
+        }
+        Ok(size)
+    }
+}
+
+impl Write for MemoryPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.outgoing.closed.load(Ordering::Relaxed) {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        self.outgoing.data.lock().unwrap().extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryPipe {
+    fn shutdown(&self) {
+        self.incoming.closed.store(true, Ordering::Relaxed);
+        self.outgoing.closed.store(true, Ordering::Relaxed);
+    }
+
+    fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.incoming.data.lock().unwrap();
+        if data.is_empty() {
+            if self.incoming.closed.load(Ordering::Relaxed) {
+                return Ok(0);
+            }
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let size = buf.len().min(data.len());
+        for (dst, src) in buf.iter_mut().zip(data.iter()) {
+            *dst = *src;
+        }
+        Ok(size)
+    }
+}
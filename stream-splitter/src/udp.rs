@@ -0,0 +1,193 @@
+/*
+VPN по UDP (OpenVPN в режиме udp, WireGuard) поверх потока эквалайзера.
+Одна датаграмма - один пакет TYPE_DATA, границы датаграмм сохраняются:
+серверная сторона отдает за одно чтение ровно один пакет (ClientDataStream::read),
+UdpDataStream за одно чтение - ровно одну датаграмму.
+
+VPN сервер <-UDP-> UdpDataStream (эквалайзер) <-TCP-> UdpClientAdapter (роутер) <-UDP-> VPN клиент
+*/
+use crate::client_side_split::ClientSideSplit;
+use crate::transport::Transport;
+use crate::{DataStream, MAX_BODY_SIZE};
+use easy_error::{bail, Error, ResultExt};
+use log::{debug, info, warn};
+use std::io;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::fd::{AsRawFd, RawFd};
+
+//за один шаг адаптера, чтобы поток клиента не задерживал встречный
+const MAX_DATAGRAMS_PER_STEP: usize = 64;
+
+/**
+    Канал к VPN серверу по UDP (серверная сторона, вместо VpnDataStream).
+    Буфер чтения должен вмещать датаграмму целиком - иначе ее хвост теряется
+*/
+pub struct UdpDataStream {
+    socket: UdpSocket,
+}
+
+impl UdpDataStream {
+    /**
+        Подключения в UDP нет - недоступный сервер здесь не обнаружить
+    */
+    pub fn connect(address: &str) -> Result<UdpDataStream, Error> {
+        let server = address.to_socket_addrs().context(format!("Адрес {address}"))?
+            .next();
+        let Some(server) = server else {
+            bail!("Адрес {address} не найден")
+        };
+        let local: SocketAddr = if server.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local).context("Bind udp socket")?;
+        socket.connect(server).context(format!("Connect to {address}"))?;
+        Ok(UdpDataStream::new(socket))
+    }
+
+    /**
+        socket уже подключен (connect) к VPN серверу
+    */
+    pub fn new(socket: UdpSocket) -> UdpDataStream {
+        socket
+            .set_nonblocking(true)
+            .expect("Архитектура подразумевает не блокирующий метод чтения");
+        Self { socket }
+    }
+}
+
+/**
+    Сервер еще не запущен или перезапускается - ICMP порт недоступен,
+    VPN сам повторит отправку
+*/
+fn is_refused(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::ConnectionRefused
+}
+
+impl DataStream for UdpDataStream {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        match self.socket.send(buf) {
+            Ok(_) => Ok(()),
+            //UDP и так допускает потери - не ждем освобождения буфера
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                debug!("Udp send buffer is full, datagram {} dropped", buf.len());
+                Ok(())
+            }
+            Err(e) if is_refused(&e) => {
+                warn!("Udp upstream refused datagram: {e}");
+                Ok(())
+            }
+            Err(e) => Err(e).context("Failed to send to udp upstream"),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.socket.recv(buf) {
+            Ok(size) => Ok(size),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(0),
+            Err(e) if is_refused(&e) => {
+                warn!("Udp upstream unreachable: {e}");
+                Ok(0)
+            }
+            Err(e) => Err(e).context("Udp upstream failed to read"),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        //соединения нет, сокет закроется вместе с парой
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.socket.as_raw_fd())
+    }
+}
+
+/**
+    Клиентская сторона: VPN клиент шлет датаграммы на локальный порт адаптера,
+    ответы уходят на адрес, с которого пришла последняя датаграмма
+*/
+pub struct UdpClientAdapter {
+    socket: UdpSocket,
+    peer: Option<SocketAddr>,
+    //на байт больше пакета - датаграмму, которая не влезет в пакет, видно по размеру
+    buf: Vec<u8>,
+    //отправлено эквалайзеру данных, еще не учтенных заполнителем клиента
+    sent: usize,
+}
+
+impl UdpClientAdapter {
+    pub fn bind(address: &str) -> Result<UdpClientAdapter, Error> {
+        let socket = UdpSocket::bind(address).context(format!("Bind udp {address}"))?;
+        socket.set_nonblocking(true).context("Udp nonblocking")?;
+        Ok(Self { socket, peer: None, buf: vec![0; MAX_BODY_SIZE + 1], sent: 0 })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.local_addr().context("Udp local address")
+    }
+
+    /**
+        Один проход: датаграммы VPN клиента -> эквалайзер, пакеты данных -> VPN клиенту,
+        заполнитель вычитывается и выбрасывается.
+        true - что-то передали, false - можно подождать
+    */
+    pub fn step<T: Transport>(&mut self, split: &ClientSideSplit<'_, T>) -> Result<bool, Error> {
+        let mut some_work = false;
+        for _ in 0..MAX_DATAGRAMS_PER_STEP {
+            let (size, peer) = match self.socket.recv_from(&mut self.buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).context("Udp client failed to read"),
+            };
+            some_work = true;
+            if self.peer != Some(peer) {
+                info!("Udp client {peer}");
+                self.peer = Some(peer);
+            }
+            if size > MAX_BODY_SIZE {
+                warn!("Датаграмма больше {MAX_BODY_SIZE} байт пропущена");
+                continue;
+            }
+            split.data_stream.write_all(&self.buf[..size])?;
+            self.sent += size;
+        }
+        for _ in 0..MAX_DATAGRAMS_PER_STEP {
+            let size = split.data_stream.read(&mut self.buf)?;
+            if size == 0 {
+                break;
+            }
+            some_work = true;
+            match self.peer {
+                Some(peer) => self.send_to(size, peer)?,
+                None => debug!("Udp client is unknown yet, datagram {size} dropped"),
+            }
+        }
+        for _ in 0..MAX_DATAGRAMS_PER_STEP {
+            if split.filler_stream.read(&mut self.buf)? == 0 {
+                break;
+            }
+            some_work = true;
+        }
+        Ok(some_work)
+    }
+
+    /**
+        Сколько байт данных отправлено эквалайзеру с прошлого вызова
+    */
+    pub fn take_sent(&mut self) -> usize {
+        std::mem::take(&mut self.sent)
+    }
+
+    fn send_to(&self, size: usize, peer: SocketAddr) -> Result<(), Error> {
+        match self.socket.send_to(&self.buf[..size], peer) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || is_refused(&e) => {
+                debug!("Udp client datagram {size} dropped: {e}");
+                Ok(())
+            }
+            Err(e) => Err(e).context("Udp client failed to send"),
+        }
+    }
+}